@@ -378,6 +378,26 @@ impl<B: BlockT> StateBackend<HashFor<B>> for BenchmarkingState<B> {
 		self.state.borrow().as_ref().map_or(Default::default(), |s| s.storage_root(delta))
 	}
 
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, Self::Transaction) where B::Hash: Ord {
+		self.state.borrow().as_ref().map_or(Default::default(), |s| s.storage_root_presorted(delta))
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: B::Hash,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<B::Hash> where B::Hash: Ord {
+		self.state.borrow().as_ref().and_then(|s| s.storage_root_incremental(root, transaction, delta))
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		self.state.borrow().as_ref().map_or(false, |s| s.supports_incremental_storage_root())
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -32,7 +32,7 @@ use sp_runtime::traits::{
 	Block as BlockT, Header as HeaderT, HashFor, NumberFor, One, Zero, CheckedSub,
 };
 use sp_runtime::generic::{BlockId, DigestItem, ChangesTrieSignal};
-use sp_state_machine::{ChangesTrieBuildCache, ChangesTrieCacheAction};
+use sp_state_machine::{ChangesTrieBuildCache, ChangesTrieCacheAction, ChangesTrieRootsCache};
 use crate::{Database, DbHash};
 use crate::utils::{self, Meta, meta_keys};
 use crate::cache::{
@@ -76,7 +76,7 @@ impl<Block: BlockT> From<DbCacheTransactionOps<Block>> for DbChangesTrieStorageT
 /// Changes tries storage.
 ///
 /// Stores all tries in separate DB column.
-/// Lock order: meta, tries_meta, cache, build_cache.
+/// Lock order: meta, tries_meta, cache, build_cache, roots_cache.
 pub struct DbChangesTrieStorage<Block: BlockT> {
 	db: Arc<dyn Database<DbHash>>,
 	meta_column: u32,
@@ -92,6 +92,9 @@ pub struct DbChangesTrieStorage<Block: BlockT> {
 	/// They're used to build digest blocks - instead of reading+parsing tries from db
 	/// we just use keys sets from the cache.
 	build_cache: RwLock<ChangesTrieBuildCache<Block::Hash, NumberFor<Block>>>,
+	/// Changes trie roots of recently imported blocks, used to build digest blocks without
+	/// reading the headers of the previous blocks from the db.
+	roots_cache: ChangesTrieRootsCache<Block::Hash, NumberFor<Block>>,
 }
 
 /// Persistent struct that contains all the changes tries metadata.
@@ -146,6 +149,7 @@ impl<Block: BlockT> DbChangesTrieStorage<Block> {
 				ComplexBlockId::new(finalized_hash, finalized_number),
 			))),
 			build_cache: RwLock::new(ChangesTrieBuildCache::new()),
+			roots_cache: ChangesTrieRootsCache::default(),
 			tries_meta: RwLock::new(tries_meta),
 		})
 	}
@@ -167,6 +171,16 @@ impl<Block: BlockT> DbChangesTrieStorage<Block> {
 			tx.set(self.changes_tries_column, key.as_ref(), &val);
 		}
 
+		// remember the changes trie root of the block. It is keyed by the block hash, so the
+		// entry stays valid even if the transaction is not committed
+		if let Some(root) = new_header.digest().log(DigestItem::as_changes_trie_root) {
+			let anchor = sp_state_machine::ChangesTrieAnchorBlockId {
+				hash: block.hash,
+				number: block.number,
+			};
+			self.roots_cache.insert(anchor, parent_block.hash, *root);
+		}
+
 		// if configuration has not been changed AND block is not finalized => nothing to do here
 		let new_configuration = match new_configuration {
 			Some(new_configuration) => new_configuration,
@@ -261,6 +275,7 @@ impl<Block: BlockT> DbChangesTrieStorage<Block> {
 		tx: &mut Transaction<DbHash>,
 		block: &ComplexBlockId<Block>,
 	) -> ClientResult<DbChangesTrieStorageTransaction<Block>> {
+		self.roots_cache.remove(&block.hash);
 		Ok(self.cache.0.write().transaction(tx)
 			.on_block_revert(block)?
 			.into_ops()
@@ -493,6 +508,10 @@ where
 	fn get(&self, key: &Block::Hash, _prefix: Prefix) -> Result<Option<Vec<u8>>, String> {
 		Ok(self.db.get(self.changes_tries_column, key.as_ref()))
 	}
+
+	fn roots_cache(&self) -> Option<&ChangesTrieRootsCache<Block::Hash, NumberFor<Block>>> {
+		Some(&self.roots_cache)
+	}
 }
 
 /// Read changes tries metadata from database.
@@ -671,6 +690,37 @@ mod tests {
 		assert_eq!(backend.changes_tries_storage.root(&anchor, 3), Ok(Some(changes2_2_0_root)));
 	}
 
+	#[test]
+	fn changes_trie_roots_are_cached_on_import() {
+		let backend = Backend::<Block>::new_test(1000, 100);
+
+		let changes1 = vec![(b"k1".to_vec(), b"v1".to_vec())];
+		let changes2_1 = vec![(b"k2".to_vec(), b"v2".to_vec())];
+		let changes2_2 = vec![(b"k3".to_vec(), b"v3".to_vec())];
+		let block0 = insert_header(&backend, 0, Default::default(), None, Default::default());
+		let block1 = insert_header(&backend, 1, block0, Some(changes1.clone()), Default::default());
+		let block2_1 = insert_header(&backend, 2, block1, Some(changes2_1.clone()), Default::default());
+		let block2_2 = insert_header(&backend, 2, block1, Some(changes2_2.clone()), Default::default());
+
+		let roots_cache = backend.changes_tries_storage.roots_cache().unwrap();
+		let (changes1_root, _) = prepare_changes(changes1);
+		let (changes2_1_root, _) = prepare_changes(changes2_1);
+		let (changes2_2_root, _) = prepare_changes(changes2_2);
+		let anchor2_1 = sp_state_machine::ChangesTrieAnchorBlockId { hash: block2_1, number: 2 };
+		let anchor2_2 = sp_state_machine::ChangesTrieAnchorBlockId { hash: block2_2, number: 2 };
+		assert_eq!(roots_cache.get(&anchor2_1, &2), Some(changes2_1_root));
+		assert_eq!(roots_cache.get(&anchor2_2, &2), Some(changes2_2_root));
+		assert_eq!(roots_cache.get(&anchor2_1, &1), Some(changes1_root));
+		assert_eq!(roots_cache.get(&anchor2_2, &1), Some(changes1_root));
+		// the genesis block has no changes trie
+		assert_eq!(roots_cache.get(&anchor2_1, &0), None);
+
+		// the root of the reverted best block is forgotten
+		backend.revert(1, false).unwrap();
+		assert_eq!(roots_cache.get(&anchor2_2, &2), None);
+		assert_eq!(roots_cache.get(&anchor2_1, &2), Some(changes2_1_root));
+	}
+
 	#[test]
 	fn changes_tries_are_pruned_on_finalization() {
 		let mut backend = Backend::<Block>::new_test(1000, 100);
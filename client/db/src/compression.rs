@@ -0,0 +1,104 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Compression of the trie nodes stored in the state column.
+//!
+//! Storage values are kept inline in their trie node, so large values are compressed by
+//! compressing the node before it is written and decompressing it when it is read back.
+//! The trie itself never sees the compressed form, so state roots and storage proofs are
+//! unchanged.
+//!
+//! Compression is only done with the `with-zstd-compression` feature. A database written
+//! with compression can't be read by a node built without it.
+
+use std::io;
+
+/// Nodes of at least this many bytes are compressed.
+pub const COMPRESSION_THRESHOLD: usize = 4 * 1024;
+
+/// First byte of a compressed node.
+///
+/// The only encoded trie node that starts with this byte is the empty node, which is a
+/// single byte long and never compressed.
+#[cfg(feature = "with-zstd-compression")]
+const COMPRESSED_NODE_PREFIX: u8 = 0;
+
+#[cfg(feature = "with-zstd-compression")]
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Compress the encoded `node` if it is larger than the threshold.
+///
+/// The node is returned unchanged if compressing it doesn't make it smaller.
+#[cfg(feature = "with-zstd-compression")]
+pub fn compress_node(node: Vec<u8>) -> Vec<u8> {
+	if node.len() < COMPRESSION_THRESHOLD {
+		return node
+	}
+
+	let mut compressed = vec![COMPRESSED_NODE_PREFIX];
+	match zstd::stream::copy_encode(&node[..], &mut compressed, COMPRESSION_LEVEL) {
+		Ok(()) if compressed.len() < node.len() => compressed,
+		_ => node,
+	}
+}
+
+/// Compress the encoded `node` if it is larger than the threshold.
+#[cfg(not(feature = "with-zstd-compression"))]
+pub fn compress_node(node: Vec<u8>) -> Vec<u8> {
+	node
+}
+
+/// Decompress a `node` that was read from the database.
+///
+/// Nodes that were stored uncompressed are returned unchanged.
+#[cfg(feature = "with-zstd-compression")]
+pub fn decompress_node(node: Vec<u8>) -> io::Result<Vec<u8>> {
+	if node.len() > 1 && node[0] == COMPRESSED_NODE_PREFIX {
+		zstd::stream::decode_all(&node[1..])
+	} else {
+		Ok(node)
+	}
+}
+
+/// Decompress a `node` that was read from the database.
+#[cfg(not(feature = "with-zstd-compression"))]
+pub fn decompress_node(node: Vec<u8>) -> io::Result<Vec<u8>> {
+	Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn small_nodes_are_not_compressed() {
+		let node = vec![0x80, 1, 2, 3];
+		assert_eq!(compress_node(node.clone()), node);
+		assert_eq!(decompress_node(vec![0]).unwrap(), vec![0]);
+	}
+
+	#[test]
+	fn large_nodes_roundtrip() {
+		let node = vec![0x80; 2 * COMPRESSION_THRESHOLD];
+		let stored = compress_node(node.clone());
+		if cfg!(feature = "with-zstd-compression") {
+			assert!(stored.len() < node.len());
+		}
+		assert_eq!(decompress_node(stored).unwrap(), node);
+	}
+}
@@ -36,6 +36,7 @@ pub mod bench;
 
 mod children;
 mod cache;
+mod compression;
 mod changes_tries_storage;
 mod storage_cache;
 #[cfg(any(feature = "with-kvdb-rocksdb", test))]
@@ -219,6 +220,26 @@ impl<B: BlockT> StateBackend<HashFor<B>> for RefTrackingState<B> {
 		self.state.storage_root(delta)
 	}
 
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, Self::Transaction) where B::Hash: Ord {
+		self.state.storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: B::Hash,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<B::Hash> where B::Hash: Ord {
+		self.state.storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		self.state.supports_incremental_storage_root()
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -760,7 +781,10 @@ impl<Block: BlockT> sp_state_machine::Storage<HashFor<Block>> for StorageDb<Bloc
 		} else {
 			self.state_db.get(key.as_ref(), self)
 		}
-		.map_err(|e| format!("Database backend error: {:?}", e))
+		.map_err(|e| format!("Database backend error: {:?}", e))?
+		.map(compression::decompress_node)
+		.transpose()
+		.map_err(|e| format!("Corrupted state node: {:?}", e))
 	}
 }
 
@@ -1165,10 +1189,11 @@ impl<Block: BlockT> Backend<Block> {
 					if rc > 0 {
 						ops += 1;
 						bytes += key.len() as u64 + val.len() as u64;
+						let val = compression::compress_node(val.to_vec());
 						if rc == 1 {
-							changeset.inserted.push((key, val.to_vec()));
+							changeset.inserted.push((key, val));
 						} else {
-							changeset.inserted.push((key.clone(), val.to_vec()));
+							changeset.inserted.push((key.clone(), val));
 							for _ in 0 .. rc - 1 {
 								changeset.inserted.push((key.clone(), Default::default()));
 							}
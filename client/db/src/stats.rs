@@ -33,6 +33,7 @@ pub struct StateUsageStats {
 	bytes_removed_nodes: AtomicU64,
 	reads_cache: AtomicU64,
 	bytes_read_cache: AtomicU64,
+	read_duration_nanos: AtomicU64,
 }
 
 impl StateUsageStats {
@@ -50,6 +51,7 @@ impl StateUsageStats {
 			bytes_removed_nodes: 0.into(),
 			reads_cache: 0.into(),
 			bytes_read_cache: 0.into(),
+			read_duration_nanos: 0.into(),
 		}
 	}
 
@@ -63,6 +65,11 @@ impl StateUsageStats {
 		}
 	}
 
+	/// Tally the time spent on a read that was not served by the cache.
+	pub fn tally_read_duration(&self, duration: std::time::Duration) {
+		self.read_duration_nanos.fetch_add(duration.as_nanos() as u64, AtomicOrdering::Relaxed);
+	}
+
 	/// Tally one key read.
 	pub fn tally_key_read(&self, key: &[u8], val: Option<&Vec<u8>>, cache: bool) {
 		self.tally_read(key.len() as u64 + val.as_ref().map(|x| x.len() as u64).unwrap_or(0), cache);
@@ -108,6 +115,7 @@ impl StateUsageStats {
 		self.bytes_removed_nodes.fetch_add(info.removed_nodes.bytes, AtomicOrdering::Relaxed);
 		self.reads_cache.fetch_add(info.cache_reads.ops, AtomicOrdering::Relaxed);
 		self.bytes_read_cache.fetch_add(info.cache_reads.bytes, AtomicOrdering::Relaxed);
+		self.tally_read_duration(info.read_duration);
 	}
 
 	/// Returns the collected `UsageInfo` and resets the internal state.
@@ -133,6 +141,9 @@ impl StateUsageStats {
 			//       imposing `MallocSizeOf` requirement on half of the codebase,
 			//       so it is an open question how to do it better
 			memory: 0,
+			read_duration: std::time::Duration::from_nanos(
+				self.read_duration_nanos.swap(0, AtomicOrdering::Relaxed),
+			),
 			started: self.started,
 			span: self.started.elapsed(),
 		}
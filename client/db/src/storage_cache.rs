@@ -27,7 +27,7 @@ use sp_core::hexdisplay::HexDisplay;
 use sp_core::storage::ChildInfo;
 use sp_state_machine::{
 	backend::Backend as StateBackend, TrieBackend, StorageKey, StorageValue,
-	StorageCollection, ChildStorageCollection,
+	StorageCollection, ChildStorageCollection, StorageReadHint,
 };
 use log::trace;
 use crate::{utils::Meta, stats::StateUsageStats};
@@ -498,27 +498,36 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Cachin
 	type TrieBackendStorage = S::TrieBackendStorage;
 
 	fn storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		self.storage_with_hint(key).map(|(value, _)| value)
+	}
+
+	fn storage_with_hint(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<Vec<u8>>, StorageReadHint), Self::Error> {
 		let local_cache = self.cache.local_cache.upgradable_read();
 		// Note that local cache makes that lru is not refreshed
 		if let Some(entry) = local_cache.storage.get(key).cloned() {
 			trace!("Found in local cache: {:?}", HexDisplay::from(&key));
 			self.usage.tally_key_read(key, entry.as_ref(), true);
 
-			return Ok(entry)
+			return Ok((entry, StorageReadHint::Cache))
 		}
 		let mut cache = self.cache.shared_cache.lock();
 		if Self::is_allowed(Some(key), None, &self.cache.parent_hash, &cache.modifications) {
 			if let Some(entry) = cache.lru_storage.get(key).map(|a| a.clone()) {
 				trace!("Found in shared cache: {:?}", HexDisplay::from(&key));
 				self.usage.tally_key_read(key, entry.as_ref(), true);
-				return Ok(entry)
+				return Ok((entry, StorageReadHint::Cache))
 			}
 		}
 		trace!("Cache miss: {:?}", HexDisplay::from(&key));
+		let started = std::time::Instant::now();
 		let value = self.state.storage(key)?;
+		self.usage.tally_read_duration(started.elapsed());
 		RwLockUpgradableReadGuard::upgrade(local_cache).storage.insert(key.to_vec(), value.clone());
 		self.usage.tally_key_read(key, value.as_ref(), false);
-		Ok(value)
+		Ok((value, StorageReadHint::Disk))
 	}
 
 	fn storage_hash(&self, key: &[u8]) -> Result<Option<B::Hash>, Self::Error> {
@@ -563,7 +572,9 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Cachin
 			}
 		}
 		trace!("Cache miss: {:?}", key);
+		let started = std::time::Instant::now();
 		let value = self.state.child_storage(child_info, &key.1[..])?;
+		self.usage.tally_read_duration(started.elapsed());
 
 		// just pass it through the usage counter
 		let value =	self.usage.tally_child_key_read(&key, value, false);
@@ -628,6 +639,26 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Cachin
 		self.state.storage_root(delta)
 	}
 
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, Self::Transaction) where B::Hash: Ord {
+		self.state.storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: B::Hash,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<B::Hash> where B::Hash: Ord {
+		self.state.storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		self.state.supports_incremental_storage_root()
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -742,6 +773,13 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Syncin
 		self.caching_state().storage(key)
 	}
 
+	fn storage_with_hint(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<Vec<u8>>, StorageReadHint), Self::Error> {
+		self.caching_state().storage_with_hint(key)
+	}
+
 	fn storage_hash(&self, key: &[u8]) -> Result<Option<B::Hash>, Self::Error> {
 		self.caching_state().storage_hash(key)
 	}
@@ -810,6 +848,26 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Syncin
 		self.caching_state().storage_root(delta)
 	}
 
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (B::Hash, Self::Transaction) where B::Hash: Ord {
+		self.caching_state().storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: B::Hash,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<B::Hash> where B::Hash: Ord {
+		self.caching_state().storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		self.caching_state().supports_incremental_storage_root()
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -1166,6 +1224,20 @@ mod tests {
 		assert_eq!(s.storage(&key).unwrap().unwrap(), vec![2]);
 	}
 
+	#[test]
+	fn storage_with_hint_reports_cache_hits() {
+		let shared = new_shared_cache::<Block>(256 * 1024, (0, 1));
+		let s = CachingState::new(
+			InMemoryBackend::<BlakeTwo256>::default(),
+			shared.clone(),
+			Some(H256::random()),
+		);
+
+		let key = H256::random()[..].to_vec();
+		assert_eq!(s.storage_with_hint(&key).unwrap(), (None, StorageReadHint::Disk));
+		assert_eq!(s.storage_with_hint(&key).unwrap(), (None, StorageReadHint::Cache));
+	}
+
 	#[test]
 	fn should_track_used_size_correctly() {
 		let root_parent = H256::random();
@@ -81,6 +81,18 @@ pub enum Error {
 	/// Execution of a host function failed.
 	#[display(fmt="Host function {} execution failed with: {}", _0, _1)]
 	FunctionExecution(String, String),
+	/// The execution of the wasm code trapped.
+	#[display(fmt="Wasm execution trapped: {}", message)]
+	#[from(ignore)]
+	WasmTrap {
+		/// The message of the trap.
+		message: String,
+		/// The backtrace of the trap, if known.
+		backtrace: Option<String>,
+	},
+	/// The wasm runtime could not be created.
+	#[display(fmt="{}", _0)]
+	Wasm(WasmError),
 }
 
 impl std::error::Error for Error {
@@ -102,12 +114,6 @@ impl From<&'static str> for Error {
 	}
 }
 
-impl From<WasmError> for Error {
-	fn from(err: WasmError) -> Error {
-		Error::Other(err.to_string())
-	}
-}
-
 /// Type for errors occurring during Wasm runtime construction.
 #[derive(Debug, derive_more::Display)]
 pub enum WasmError {
@@ -657,6 +657,38 @@ fn parallel_execution(wasm_method: WasmExecutionMethod) {
 	}
 }
 
+#[test_case(WasmExecutionMethod::Interpreted)]
+#[cfg_attr(feature = "wasmtime", test_case(WasmExecutionMethod::Compiled))]
+fn runtime_instances_are_reused(wasm_method: WasmExecutionMethod) {
+	let executor = crate::WasmExecutor::new(
+		wasm_method,
+		Some(1024),
+		HostFunctions::host_functions(),
+		8,
+	);
+	let code_hash = blake2_256(wasm_binary_unwrap()).to_vec();
+	for _ in 0..3 {
+		let mut ext = TestExternalities::default();
+		let mut ext = ext.ext();
+		executor.call_in_wasm(
+			&wasm_binary_unwrap()[..],
+			Some(code_hash.clone()),
+			"test_twox_128",
+			&[0],
+			&mut ext,
+			sp_core::traits::MissingHostFunctions::Allow,
+		).unwrap();
+	}
+
+	assert_eq!(executor.runtime_cache_stats(), crate::RuntimeCacheStats {
+		runtime_hits: 2,
+		runtime_misses: 1,
+		runtimes_evicted: 0,
+		instances_reused: 2,
+		instances_created: 1,
+	});
+}
+
 #[test_case(WasmExecutionMethod::Interpreted)]
 fn wasm_tracing_should_work(wasm_method: WasmExecutionMethod) {
 
@@ -45,7 +45,10 @@ pub use codec::Codec;
 pub use sp_core::traits::{Externalities, CallInWasm};
 #[doc(hidden)]
 pub use sp_wasm_interface;
-pub use wasm_runtime::WasmExecutionMethod;
+pub use wasm_runtime::{
+	WasmExecutionMethod, RuntimeCacheStats, RUNTIME_VERSION_SECTION, read_embedded_runtime_version,
+	read_runtime_version,
+};
 
 pub use sc_executor_common::{error, sandbox};
 
@@ -17,17 +17,22 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::{
-	RuntimeInfo, error::{Error, Result},
-	wasm_runtime::{RuntimeCache, WasmExecutionMethod},
+	RuntimeInfo, error::{Error, Result, WasmError},
+	wasm_runtime::{RuntimeCache, RuntimeCacheStats, WasmExecutionMethod},
 };
 use sp_version::{NativeVersion, RuntimeVersion};
 use codec::{Decode, Encode};
 use sp_core::{
-	NativeOrEncoded, traits::{CodeExecutor, Externalities, RuntimeCode, MissingHostFunctions},
+	NativeOrEncoded,
+	traits::{
+		CodeExecutor, CodeExecutorErrorKind, Externalities, RuntimeCode, MissingHostFunctions,
+		ExecutionEnvironmentExt,
+	},
 };
 use log::trace;
 use std::{result, panic::{UnwindSafe, AssertUnwindSafe}, sync::Arc};
 use sp_wasm_interface::{HostFunctions, Function};
+use sp_externalities::ExternalitiesExt;
 use sc_executor_common::wasm_runtime::WasmInstance;
 
 /// Default num of pages for the heap
@@ -115,6 +120,11 @@ impl WasmExecutor {
 		}
 	}
 
+	/// Returns the usage statistics of the runtime cache.
+	pub fn runtime_cache_stats(&self) -> RuntimeCacheStats {
+		self.cache.stats()
+	}
+
 	/// Execute the given closure `f` with the latest runtime (based on `runtime_code`).
 	///
 	/// The closure `f` is expected to return `Err(_)` when there happened a `panic!` in native code
@@ -253,6 +263,11 @@ impl<D: NativeExecutionDispatch> NativeExecutor<D> {
 			wasm: wasm_executor,
 		}
 	}
+
+	/// Returns the usage statistics of the runtime cache of the fallback wasm executor.
+	pub fn runtime_cache_stats(&self) -> RuntimeCacheStats {
+		self.wasm.runtime_cache_stats()
+	}
 }
 
 impl<D: NativeExecutionDispatch> RuntimeInfo for NativeExecutor<D> {
@@ -283,18 +298,26 @@ impl<D: NativeExecutionDispatch + 'static> CodeExecutor for NativeExecutor<D> {
 		NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
 	>(
 		&self,
-		ext: &mut dyn Externalities,
+		mut ext: &mut dyn Externalities,
 		runtime_code: &RuntimeCode,
 		method: &str,
 		data: &[u8],
 		use_native: bool,
 		native_call: Option<NC>,
 	) -> (Result<NativeOrEncoded<R>>, bool) {
+		let environment = ext.extension::<ExecutionEnvironmentExt>()
+			.map(|environment| environment.0.clone())
+			.unwrap_or_default();
+		let runtime_code = RuntimeCode {
+			heap_pages: Some(environment.heap_pages(runtime_code, self.wasm.default_heap_pages)),
+			..runtime_code.clone()
+		};
+
 		let mut used_native = false;
 		let result = self.wasm.with_instance(
-			runtime_code,
+			&runtime_code,
 			ext,
-			false,
+			environment.missing_host_functions().allowed(),
 			|instance, onchain_version, mut ext| {
 				let onchain_version = onchain_version.ok_or_else(
 					|| Error::ApiError("Unknown version".into())
@@ -357,6 +380,19 @@ impl<D: NativeExecutionDispatch + 'static> CodeExecutor for NativeExecutor<D> {
 		);
 		(result, used_native)
 	}
+
+	fn error_kind(error: &Error) -> CodeExecutorErrorKind {
+		match error {
+			Error::Trap(_) | Error::RuntimePanicked(_) =>
+				CodeExecutorErrorKind::Trap { backtrace: None },
+			Error::WasmTrap { backtrace, .. } =>
+				CodeExecutorErrorKind::Trap { backtrace: backtrace.clone() },
+			Error::FunctionExecution(name, _) =>
+				CodeExecutorErrorKind::HostFunction { name: name.clone() },
+			Error::Wasm(WasmError::CodeNotFound) => CodeExecutorErrorKind::MissingCode,
+			_ => CodeExecutorErrorKind::Other,
+		}
+	}
 }
 
 impl<D: NativeExecutionDispatch> Clone for NativeExecutor<D> {
@@ -492,4 +528,44 @@ mod tests {
 
 		my_interface::say_hello_world("hey");
 	}
+
+	#[test]
+	fn execution_environment_overrides_heap_pages() {
+		use sp_core::traits::{ExecutionEnvironment, WrappedRuntimeCode};
+
+		let code = WrappedRuntimeCode(Vec::new().into());
+		let runtime_code = RuntimeCode { code_fetcher: &code, heap_pages: Some(64), hash: Vec::new() };
+
+		let environment = ExecutionEnvironment::default();
+		assert_eq!(environment.heap_pages(&runtime_code, DEFAULT_HEAP_PAGES), 64);
+		assert!(!environment.missing_host_functions().allowed());
+
+		let environment = ExecutionEnvironment {
+			heap_pages: Some(2048),
+			max_heap_pages: Some(1024),
+			allow_missing_imports: true,
+		};
+		assert_eq!(environment.heap_pages(&runtime_code, DEFAULT_HEAP_PAGES), 1024);
+		assert!(environment.missing_host_functions().allowed());
+	}
+
+	#[test]
+	fn errors_are_classified() {
+		let kind = |error| <NativeExecutor<MyExecutor> as CodeExecutor>::error_kind(&error);
+
+		assert_eq!(kind(WasmError::CodeNotFound.into()), CodeExecutorErrorKind::MissingCode);
+		assert_eq!(
+			kind(Error::FunctionExecution("ext_storage_get".into(), "failed".into())),
+			CodeExecutorErrorKind::HostFunction { name: "ext_storage_get".into() },
+		);
+		assert_eq!(
+			kind(Error::RuntimePanicked("oops".into())),
+			CodeExecutorErrorKind::Trap { backtrace: None },
+		);
+		assert_eq!(
+			kind(Error::WasmTrap { message: "unreachable".into(), backtrace: Some("0: f".into()) }),
+			CodeExecutorErrorKind::Trap { backtrace: Some("0: f".into()) },
+		);
+		assert_eq!(kind(Error::ApiError("failed".into())), CodeExecutorErrorKind::Other);
+	}
 }
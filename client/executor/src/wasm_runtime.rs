@@ -19,11 +19,14 @@
 //! The primary means of accessing the runtimes is through a cache which saves the reusable
 //! components of the runtime that are expensive to initialize.
 
-use std::sync::Arc;
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
 use crate::error::{Error, WasmError};
 use parking_lot::Mutex;
 use codec::Decode;
-use sp_core::traits::{Externalities, RuntimeCode, FetchRuntimeCode};
+use sp_core::{
+	storage::well_known_keys,
+	traits::{Externalities, RuntimeCode, FetchRuntimeCode, WrappedRuntimeCode},
+};
 use sp_version::RuntimeVersion;
 use std::panic::AssertUnwindSafe;
 use sc_executor_common::wasm_runtime::{WasmModule, WasmInstance};
@@ -46,6 +49,47 @@ impl Default for WasmExecutionMethod {
 	}
 }
 
+/// Statistics of a [`RuntimeCache`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeCacheStats {
+	/// Number of calls that found their runtime in the cache.
+	pub runtime_hits: u64,
+	/// Number of calls that had to create their runtime.
+	pub runtime_misses: u64,
+	/// Number of runtimes that were evicted from the cache.
+	pub runtimes_evicted: u64,
+	/// Number of calls that reused an instance of the runtime.
+	pub instances_reused: u64,
+	/// Number of instances that were created, including the ones that were not cached.
+	pub instances_created: u64,
+}
+
+/// The counters behind [`RuntimeCacheStats`].
+#[derive(Default)]
+struct CacheCounters {
+	runtime_hits: AtomicU64,
+	runtime_misses: AtomicU64,
+	runtimes_evicted: AtomicU64,
+	instances_reused: AtomicU64,
+	instances_created: AtomicU64,
+}
+
+impl CacheCounters {
+	fn inc(counter: &AtomicU64) {
+		counter.fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn stats(&self) -> RuntimeCacheStats {
+		RuntimeCacheStats {
+			runtime_hits: self.runtime_hits.load(Ordering::Relaxed),
+			runtime_misses: self.runtime_misses.load(Ordering::Relaxed),
+			runtimes_evicted: self.runtimes_evicted.load(Ordering::Relaxed),
+			instances_reused: self.instances_reused.load(Ordering::Relaxed),
+			instances_created: self.instances_created.load(Ordering::Relaxed),
+		}
+	}
+}
+
 /// A Wasm runtime object along with its cached runtime version.
 struct VersionedRuntime {
 	/// Runtime code hash.
@@ -67,6 +111,7 @@ impl VersionedRuntime {
 	fn with_instance<'c, R, F>(
 		&self,
 		ext: &mut dyn Externalities,
+		counters: &CacheCounters,
 		f: F,
 	) -> Result<R, Error>
 		where F: FnOnce(
@@ -86,6 +131,11 @@ impl VersionedRuntime {
 				let (instance, new_inst) = locked.take()
 					.map(|r| Ok((r, false)))
 					.unwrap_or_else(|| self.module.new_instance().map(|i| (i, true)))?;
+				if new_inst {
+					CacheCounters::inc(&counters.instances_created);
+				} else {
+					CacheCounters::inc(&counters.instances_reused);
+				}
 
 				let result = f(&*instance, self.version.as_ref(), ext);
 				if let Err(e) = &result {
@@ -122,6 +172,7 @@ impl VersionedRuntime {
 
 				// Allocate a new instance
 				let instance = self.module.new_instance()?;
+				CacheCounters::inc(&counters.instances_created);
 
 				f(&*instance, self.version.as_ref(), ext)
 			}
@@ -141,7 +192,9 @@ const MAX_RUNTIMES: usize = 2;
 /// the memory reset to the initial memory. So, one runtime instance is reused for every fetch
 /// request.
 ///
-/// The size of cache is equal to `MAX_RUNTIMES`.
+/// The size of cache is equal to `MAX_RUNTIMES`. Runtimes are keyed by the hash of their code,
+/// so a runtime upgrade that changes `:code` never uses the runtime of the old code. The old
+/// runtime is evicted once it is the least recently used one.
 pub struct RuntimeCache {
 	/// A cache of runtimes along with metadata.
 	///
@@ -149,6 +202,8 @@ pub struct RuntimeCache {
 	runtimes: Mutex<[Option<Arc<VersionedRuntime>>; MAX_RUNTIMES]>,
 	/// The size of the instances cache for each runtime.
 	max_runtime_instances: usize,
+	/// Usage statistics of the cache.
+	counters: CacheCounters,
 }
 
 impl RuntimeCache {
@@ -157,9 +212,15 @@ impl RuntimeCache {
 		RuntimeCache {
 			runtimes: Default::default(),
 			max_runtime_instances,
+			counters: Default::default(),
 		}
 	}
 
+	/// Returns the usage statistics of the cache.
+	pub fn stats(&self) -> RuntimeCacheStats {
+		self.counters.stats()
+	}
+
 	/// Prepares a WASM module instance and executes given function for it.
 	///
 	/// This uses internal cache to find avaiable instance or create a new one.
@@ -216,10 +277,14 @@ impl RuntimeCache {
 		));
 
 		let runtime = match pos {
-			Some(n) => runtimes[n]
-				.clone()
-				.expect("`position` only returns `Some` for entries that are `Some`"),
+			Some(n) => {
+				CacheCounters::inc(&self.counters.runtime_hits);
+				runtimes[n]
+					.clone()
+					.expect("`position` only returns `Some` for entries that are `Some`")
+			},
 			None =>  {
+				CacheCounters::inc(&self.counters.runtime_misses);
 				let code = runtime_code.fetch_runtime_code().ok_or(WasmError::CodeNotFound)?;
 
 				let result = create_versioned_wasm_runtime(
@@ -248,6 +313,9 @@ impl RuntimeCache {
 				}
 			}
 			None => {
+				if runtimes[MAX_RUNTIMES-1].is_some() {
+					CacheCounters::inc(&self.counters.runtimes_evicted);
+				}
 				runtimes[MAX_RUNTIMES-1] = Some(runtime.clone());
 				for i in (1 .. MAX_RUNTIMES).rev() {
 					runtimes.swap(i, i - 1);
@@ -256,7 +324,7 @@ impl RuntimeCache {
 		}
 		drop(runtimes);
 
-		Ok(runtime.with_instance(ext, f))
+		Ok(runtime.with_instance(ext, &self.counters, f))
 	}
 }
 
@@ -306,6 +374,50 @@ fn decode_version(version: &[u8]) -> Result<RuntimeVersion, WasmError> {
 	}
 }
 
+/// Name of the custom section a runtime can embed its SCALE encoded [`RuntimeVersion`] in.
+pub const RUNTIME_VERSION_SECTION: &str = "runtime_version";
+
+/// Read the [`RuntimeVersion`] that is embedded in the given wasm `code`.
+///
+/// Returns `Ok(None)` if the code has no [`RUNTIME_VERSION_SECTION`].
+pub fn read_embedded_runtime_version(code: &[u8]) -> Result<Option<RuntimeVersion>, WasmError> {
+	let module = parity_wasm::deserialize_buffer::<parity_wasm::elements::Module>(code)
+		.map_err(|_| WasmError::CantDeserializeWasm)?;
+	module.custom_sections()
+		.find(|section| section.name() == RUNTIME_VERSION_SECTION)
+		.map(|section| decode_version(section.payload()))
+		.transpose()
+}
+
+/// Read the [`RuntimeVersion`] of the runtime that is stored at `:code` in the given `ext`.
+///
+/// The version is taken from the [`RUNTIME_VERSION_SECTION`] of the code if present. Only
+/// otherwise the runtime is instantiated to call `Core_version`, which is a lot slower.
+pub fn read_runtime_version(
+	runtime_info: &dyn crate::RuntimeInfo,
+	ext: &mut dyn Externalities,
+) -> Result<RuntimeVersion, Error> {
+	let code = ext.storage(well_known_keys::CODE).ok_or(WasmError::CodeNotFound)?;
+	match read_embedded_runtime_version(&code) {
+		Ok(Some(version)) => return Ok(version),
+		Ok(None) => {},
+		Err(err) => log::debug!(
+			target: "wasm-runtime",
+			"Cannot read the embedded runtime version: {:?}",
+			err,
+		),
+	}
+
+	let heap_pages = ext.storage(well_known_keys::HEAP_PAGES)
+		.and_then(|pages| u64::decode(&mut &pages[..]).ok());
+	let runtime_code = RuntimeCode {
+		code_fetcher: &WrappedRuntimeCode(code.as_slice().into()),
+		hash: sp_core::blake2_256(&code).to_vec(),
+		heap_pages,
+	};
+	runtime_info.runtime_version(ext, &runtime_code)
+}
+
 fn create_versioned_wasm_runtime(
 	code: &[u8],
 	code_hash: Vec<u8>,
@@ -409,6 +521,60 @@ mod tests {
 		decode_version(&old_runtime_version.encode()).unwrap_err();
 	}
 
+	struct FallbackRuntimeInfo(sp_version::NativeVersion);
+
+	impl crate::RuntimeInfo for FallbackRuntimeInfo {
+		fn native_version(&self) -> &sp_version::NativeVersion {
+			&self.0
+		}
+
+		fn runtime_version(
+			&self,
+			_: &mut dyn Externalities,
+			_: &RuntimeCode,
+		) -> Result<RuntimeVersion, Error> {
+			Ok(self.0.runtime_version.clone())
+		}
+	}
+
+	fn runtime_version(spec_version: u32) -> RuntimeVersion {
+		RuntimeVersion {
+			spec_name: "test".into(),
+			spec_version,
+			transaction_version: 1,
+			.. Default::default()
+		}
+	}
+
+	fn code_with_sections(sections: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+		use parity_wasm::elements::{CustomSection, Section};
+
+		let mut module = parity_wasm::builder::module().build();
+		for (name, payload) in sections {
+			module.sections_mut().push(Section::Custom(CustomSection::new(name.into(), payload)));
+		}
+		parity_wasm::serialize(module).unwrap()
+	}
+
+	#[test]
+	fn embedded_runtime_version_is_preferred() {
+		let fallback = FallbackRuntimeInfo(sp_version::NativeVersion {
+			runtime_version: runtime_version(1),
+			can_author_with: Default::default(),
+		});
+		let mut ext = sp_state_machine::BasicExternalities::default();
+
+		ext.insert(
+			well_known_keys::CODE.to_vec(),
+			code_with_sections(vec![(RUNTIME_VERSION_SECTION, runtime_version(2).encode())]),
+		);
+		assert_eq!(read_runtime_version(&fallback, &mut ext).unwrap(), runtime_version(2));
+
+		ext.insert(well_known_keys::CODE.to_vec(), code_with_sections(vec![("other", vec![1])]));
+		assert_eq!(read_embedded_runtime_version(&code_with_sections(vec![])).unwrap(), None);
+		assert_eq!(read_runtime_version(&fallback, &mut ext).unwrap(), runtime_version(1));
+	}
+
 	#[test]
 	fn new_runtime_version_decodes() {
 		let old_runtime_version = sp_api::RuntimeVersion {
@@ -162,10 +162,15 @@ fn perform_call(
 				Ok(unpack_ptr_and_len(retval))
 			}
 			Err(trap) => {
-				return Err(Error::from(format!(
-					"Wasm execution trapped: {}",
-					trap
-				)));
+				let backtrace = trap.trace().iter().enumerate().map(|(index, frame)| {
+					let function = frame.func_name().map(Into::into)
+						.unwrap_or_else(|| format!("<wasm function {}>", frame.func_index()));
+					format!("{}: {}", index, function)
+				}).collect::<Vec<_>>();
+				return Err(Error::WasmTrap {
+					message: trap.to_string(),
+					backtrace: if backtrace.is_empty() { None } else { Some(backtrace.join("\n")) },
+				});
 			}
 		}
 	});
@@ -477,6 +477,38 @@ impl<H: Hasher> StateBackend<H> for GenesisOrUnavailableState<H>
 		}
 	}
 
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		match *self {
+			GenesisOrUnavailableState::Genesis(ref state) =>
+				state.storage_root_presorted(delta),
+			GenesisOrUnavailableState::Unavailable => Default::default(),
+		}
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: H::Out,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		match *self {
+			GenesisOrUnavailableState::Genesis(ref state) =>
+				state.storage_root_incremental(root, transaction, delta),
+			GenesisOrUnavailableState::Unavailable => None,
+		}
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		match *self {
+			GenesisOrUnavailableState::Genesis(ref state) =>
+				state.supports_incremental_storage_root(),
+			GenesisOrUnavailableState::Unavailable => false,
+		}
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
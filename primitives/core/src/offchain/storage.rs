@@ -294,4 +294,42 @@ mod test {
 		);
 		assert_eq!(iter.next(), None);
 	}
+
+	#[test]
+	fn compare_and_set_works() {
+		let mut storage = InMemOffchainStorage::default();
+		assert!(!storage.compare_and_set(STORAGE_PREFIX, b"lock", Some(b"1"), b"2"));
+		assert!(storage.compare_and_set(STORAGE_PREFIX, b"lock", None, b"1"));
+		assert!(!storage.compare_and_set(STORAGE_PREFIX, b"lock", None, b"2"));
+		assert!(!storage.compare_and_set(STORAGE_PREFIX, b"lock", Some(b"2"), b"3"));
+		assert_eq!(storage.get(STORAGE_PREFIX, b"lock"), Some(b"1".to_vec()));
+		assert!(storage.compare_and_set(STORAGE_PREFIX, b"lock", Some(b"1"), b"2"));
+		assert_eq!(storage.get(STORAGE_PREFIX, b"lock"), Some(b"2".to_vec()));
+	}
+
+	#[test]
+	fn compare_and_set_is_atomic_across_threads() {
+		use codec::{Decode, Encode};
+		use crate::offchain::testing::TestPersistentOffchainDB;
+
+		let db = TestPersistentOffchainDB::new();
+		let threads: Vec<_> = (0..4).map(|_| {
+			let mut db = db.clone();
+			std::thread::spawn(move || {
+				for _ in 0..100 {
+					loop {
+						let old = db.get(STORAGE_PREFIX, b"nonce");
+						let next = old.as_ref()
+							.map_or(0u32, |v| u32::decode(&mut &v[..]).unwrap()) + 1;
+						if db.compare_and_set(STORAGE_PREFIX, b"nonce", old.as_deref(), &next.encode()) {
+							break
+						}
+					}
+				}
+			})
+		}).collect();
+		threads.into_iter().for_each(|t| t.join().unwrap());
+
+		assert_eq!(db.get(STORAGE_PREFIX, b"nonce"), Some(400u32.encode()));
+	}
 }
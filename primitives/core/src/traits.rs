@@ -203,6 +203,25 @@ sp_externalities::decl_extension! {
 	pub struct KeystoreExt(BareCryptoStorePtr);
 }
 
+/// The kind of an error of a [`CodeExecutor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodeExecutorErrorKind {
+	/// The runtime code could not be found.
+	MissingCode,
+	/// The execution of the runtime trapped or panicked.
+	Trap {
+		/// The backtrace of the trap, if known.
+		backtrace: Option<String>,
+	},
+	/// The host function with the given `name` failed.
+	HostFunction {
+		/// The name of the host function.
+		name: String,
+	},
+	/// Any other error.
+	Other,
+}
+
 /// Code execution engine.
 pub trait CodeExecutor: Sized + Send + Sync + CallInWasm + Clone + 'static {
 	/// Externalities error type.
@@ -222,6 +241,11 @@ pub trait CodeExecutor: Sized + Send + Sync + CallInWasm + Clone + 'static {
 		use_native: bool,
 		native_call: Option<NC>,
 	) -> (Result<crate::NativeOrEncoded<R>, Self::Error>, bool);
+
+	/// Classify the given `error` of [`Self::call`].
+	fn error_kind(_error: &Self::Error) -> CodeExecutorErrorKind {
+		CodeExecutorErrorKind::Other
+	}
 }
 
 /// Something that can fetch the runtime `:code`.
@@ -318,6 +342,47 @@ impl MissingHostFunctions {
 	}
 }
 
+/// Options for the wasm execution of a runtime call.
+///
+/// Executors fall back to their own defaults for every option that isn't set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionEnvironment {
+	/// The number of 64KB heap pages, overrides the heap pages of the [`RuntimeCode`].
+	pub heap_pages: Option<u64>,
+	/// The maximum number of 64KB heap pages the runtime may use.
+	///
+	/// Applied after `heap_pages`, so it also caps the heap pages found in the state.
+	pub max_heap_pages: Option<u64>,
+	/// Replace missing host functions by stubs that return an error when they are called,
+	/// instead of failing to instantiate the runtime.
+	pub allow_missing_imports: bool,
+}
+
+impl ExecutionEnvironment {
+	/// Returns the heap pages to use for `runtime_code` if the executor defaults to
+	/// `default_heap_pages`.
+	pub fn heap_pages(&self, runtime_code: &RuntimeCode, default_heap_pages: u64) -> u64 {
+		let heap_pages = self.heap_pages
+			.or(runtime_code.heap_pages)
+			.unwrap_or(default_heap_pages);
+		self.max_heap_pages.map_or(heap_pages, |max| heap_pages.min(max))
+	}
+
+	/// Returns how to handle missing host functions.
+	pub fn missing_host_functions(&self) -> MissingHostFunctions {
+		if self.allow_missing_imports {
+			MissingHostFunctions::Allow
+		} else {
+			MissingHostFunctions::Disallow
+		}
+	}
+}
+
+sp_externalities::decl_extension! {
+	/// The execution environment extension to register/retrieve from the externalities.
+	pub struct ExecutionEnvironmentExt(ExecutionEnvironment);
+}
+
 /// Something that can call a method in a WASM blob.
 pub trait CallInWasm: Send + Sync {
 	/// Call the given `method` in the given `wasm_blob` using `call_data` (SCALE encoded arguments)
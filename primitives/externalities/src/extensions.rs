@@ -141,6 +141,11 @@ impl Extensions {
 	pub fn deregister(&mut self, type_id: TypeId) -> Option<Box<dyn Extension>> {
 		self.extensions.remove(&type_id)
 	}
+
+	/// Returns `true` if no extension is registered.
+	pub fn is_empty(&self) -> bool {
+		self.extensions.is_empty()
+	}
 }
 
 #[cfg(test)]
@@ -33,6 +33,20 @@ pub use extensions::{Extension, Extensions, ExtensionStore};
 mod extensions;
 mod scope_limited;
 
+/// A check the runtime wants to be verified before the changes of a call are committed.
+///
+/// Returns `true` if the check succeeded.
+pub type DeferredCheck = Box<dyn FnOnce() -> bool + Send>;
+
+/// The trie layout a proof passed to [`Externalities::verify_trie_proof`] was created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrieProofLayout {
+	/// The default layout, hashing with `blake2_256`.
+	Blake2,
+	/// The default layout, hashing with `keccak_256`.
+	Keccak,
+}
+
 /// Externalities error.
 #[derive(Debug)]
 pub enum Error {
@@ -124,6 +138,24 @@ pub trait Externalities: ExtensionStore {
 		self.child_storage(child_info, key).is_some()
 	}
 
+	/// Returns the length of the storage value at `key`, if it exists.
+	///
+	/// This may be optimized to not read the full value.
+	fn storage_size(&self, key: &[u8]) -> Option<u32> {
+		self.storage(key).map(|v| v.len() as u32)
+	}
+
+	/// Returns the length of the child storage value at `key`, if it exists.
+	///
+	/// This may be optimized to not read the full value.
+	fn child_storage_size(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Option<u32> {
+		self.child_storage(child_info, key).map(|v| v.len() as u32)
+	}
+
 	/// Returns the key immediately following the given key, if it exists.
 	fn next_storage_key(&self, key: &[u8]) -> Option<Vec<u8>>;
 
@@ -150,6 +182,16 @@ pub trait Externalities: ExtensionStore {
 	/// Set or clear a storage entry (`key`) of current contract being called (effective immediately).
 	fn place_storage(&mut self, key: Vec<u8>, value: Option<Vec<u8>>);
 
+	/// Set or clear a storage entry and account the write to the storage namespace `ns`, e.g.
+	/// the index of the runtime module that writes it.
+	///
+	/// The namespace is only used for IO accounting, the key is stored unchanged. By default
+	/// the namespace is ignored and the entry is stored with `place_storage`.
+	fn place_storage_ns(&mut self, ns: u32, key: Vec<u8>, value: Option<Vec<u8>>) {
+		let _ = ns;
+		self.place_storage(key, value)
+	}
+
 	/// Set or clear a child storage entry.
 	fn place_child_storage(
 		&mut self,
@@ -195,6 +237,58 @@ pub trait Externalities: ExtensionStore {
 	/// The returned hash is defined by the `Block` and is SCALE encoded.
 	fn storage_changes_root(&mut self, parent: &[u8]) -> Result<Option<Vec<u8>>, ()>;
 
+	/// Skip building the changes trie for the current block.
+	///
+	/// `storage_changes_root` returns `None` for the current block afterwards. The changes
+	/// trie configuration is not touched, so changes tries are built for the following blocks.
+	fn disable_changes_trie_for_block(&mut self);
+
+	/// Register a `check` that has to succeed for the current call to succeed.
+	///
+	/// Implementations may run the check right away or defer it to the end of the call, to
+	/// verify it in parallel to the execution. A failed check fails the call.
+	fn register_deferred_check(&mut self, check: DeferredCheck);
+
+	/// Verify a compact trie `proof` of `items` against `root`.
+	///
+	/// An item with a `None` value is proven to not be part of the trie. The root does not need
+	/// to be related to the current storage, this allows the runtime to verify proofs of other
+	/// chains natively. Returns `false` if the root has the wrong length for the `layout`.
+	fn verify_trie_proof(
+		&self,
+		root: &[u8],
+		proof: &[Vec<u8>],
+		items: &[(Vec<u8>, Option<Vec<u8>>)],
+		layout: TrieProofLayout,
+	) -> bool;
+
+	/// Store `value` in a side table next to the trie and return its SCALE encoded handle.
+	///
+	/// The handle consists of the hash and the length of the value. Storing the handle in place
+	/// of a large value keeps the trie small, the value is resolved with `blob_get`.
+	fn blob_put(&mut self, value: Vec<u8>) -> Vec<u8>;
+
+	/// Resolve a `handle` returned by `blob_put`.
+	///
+	/// Returns `None` if the handle is invalid or the value is unknown.
+	fn blob_get(&self, handle: &[u8]) -> Option<Vec<u8>>;
+
+	/// Release a `handle` returned by `blob_put`.
+	///
+	/// The value is deleted once every handle to it is released. Returns `false` if the handle
+	/// is invalid or the value is unknown.
+	fn blob_remove(&mut self, handle: &[u8]) -> bool;
+
+	/// Read the transient value of `key`.
+	///
+	/// Transient values are scratch space, e.g. for caches of the current block. They are
+	/// never read from or written to the state, are not part of the storage root and are
+	/// discarded together with the changes of the block.
+	fn transient_storage(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+	/// Set or clear the transient `value` of `key`, see `transient_storage`.
+	fn set_transient_storage(&mut self, key: Vec<u8>, value: Option<Vec<u8>>);
+
 	/// Start a new nested transaction.
 	///
 	/// This allows to either commit or roll back all changes made after this call to the
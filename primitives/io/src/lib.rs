@@ -56,7 +56,7 @@ use sp_runtime_interface::{runtime_interface, Pointer};
 use codec::{Encode, Decode};
 
 #[cfg(feature = "std")]
-use sp_externalities::{ExternalitiesExt, Externalities};
+use sp_externalities::{ExternalitiesExt, Externalities, TrieProofLayout};
 
 #[cfg(feature = "std")]
 mod batch_verifier;
@@ -108,11 +108,33 @@ pub trait Storage {
 		self.clear_storage(key)
 	}
 
+	/// Set `key` to `value` in the storage and account the write to the namespace `ns`, e.g.
+	/// the index of the runtime module.
+	///
+	/// The namespace is only used for the IO statistics of the client, the key is stored
+	/// unchanged.
+	fn set_in_namespace(&mut self, ns: u32, key: &[u8], value: &[u8]) {
+		self.place_storage_ns(ns, key.to_vec(), Some(value.to_vec()));
+	}
+
+	/// Clear the storage of the given `key` and account the removal to the namespace `ns`.
+	fn clear_in_namespace(&mut self, ns: u32, key: &[u8]) {
+		self.place_storage_ns(ns, key.to_vec(), None);
+	}
+
 	/// Check whether the given `key` exists in storage.
 	fn exists(&self, key: &[u8]) -> bool {
 		self.exists_storage(key)
 	}
 
+	/// Returns the length of the value at `key` in the storage or `None` if the key can not
+	/// be found.
+	///
+	/// Unlike `get` this does not copy the value into the runtime.
+	fn size(&self, key: &[u8]) -> Option<u32> {
+		self.storage_size(key)
+	}
+
 	/// Clear the storage of each key-value pair where the key starts with the given `prefix`.
 	fn clear_prefix(&mut self, prefix: &[u8]) {
 		Externalities::clear_prefix(*self, prefix)
@@ -130,6 +152,46 @@ pub trait Storage {
 		self.storage_append(key.to_vec(), value);
 	}
 
+	/// Store `value` in a side table next to the trie and return its SCALE encoded handle.
+	///
+	/// Storing the handle in place of a large value keeps the trie small.
+	fn blob_put(&mut self, value: &[u8]) -> Vec<u8> {
+		Externalities::blob_put(*self, value.to_vec())
+	}
+
+	/// Resolve a `handle` returned by `blob_put`.
+	///
+	/// Returns `None` if the handle is invalid or the value is unknown.
+	fn blob_get(&self, handle: &[u8]) -> Option<Vec<u8>> {
+		Externalities::blob_get(*self, handle)
+	}
+
+	/// Release a `handle` returned by `blob_put`.
+	///
+	/// The value is deleted once every handle to it is released. Returns `false` if the handle
+	/// is invalid or the value is unknown.
+	fn blob_remove(&mut self, handle: &[u8]) -> bool {
+		Externalities::blob_remove(*self, handle)
+	}
+
+	/// Returns the transient value of `key`, see `transient_set`.
+	fn transient_get(&self, key: &[u8]) -> Option<Vec<u8>> {
+		Externalities::transient_storage(*self, key)
+	}
+
+	/// Set the transient `value` of `key`.
+	///
+	/// Transient values are scratch space of the current block. They are not part of the
+	/// state, so they do not change the storage root and are discarded with the block.
+	fn transient_set(&mut self, key: &[u8], value: &[u8]) {
+		Externalities::set_transient_storage(*self, key.to_vec(), Some(value.to_vec()));
+	}
+
+	/// Clear the transient value of `key`.
+	fn transient_clear(&mut self, key: &[u8]) {
+		Externalities::set_transient_storage(*self, key.to_vec(), None);
+	}
+
 	/// "Commit" all existing operations and compute the resulting storage root.
 	///
 	/// The hashing algorithm is defined by the `Block`.
@@ -151,6 +213,14 @@ pub trait Storage {
 			.expect("Invalid `parent_hash` given to `changes_root`.")
 	}
 
+	/// Skip building the changes trie for the current block.
+	///
+	/// `changes_root` returns `None` for the current block afterwards, while changes tries are
+	/// still built for the following blocks.
+	fn disable_changes_trie_for_block(&mut self) {
+		Externalities::disable_changes_trie_for_block(*self)
+	}
+
 	/// Get the next key in storage after the given one in lexicographic order.
 	fn next_key(&mut self, key: &[u8]) -> Option<Vec<u8>> {
 		self.next_storage_key(&key)
@@ -288,6 +358,19 @@ pub trait DefaultChildStorage {
 		self.exists_child_storage(&child_info, key)
 	}
 
+	/// Child storage value length.
+	///
+	/// Returns the length of the value at `key` in default child defined at `storage_key`
+	/// or `None` if the key can not be found.
+	fn size(
+		&self,
+		storage_key: &[u8],
+		key: &[u8],
+	) -> Option<u32> {
+		let child_info = ChildInfo::new_default(storage_key);
+		self.child_storage_size(&child_info, key)
+	}
+
 	/// Clear child default key by prefix.
 	///
 	/// Clear the child storage of each key-value pair where the key starts with the given `prefix`.
@@ -349,6 +432,30 @@ pub trait Trie {
 	fn keccak_256_ordered_root(input: Vec<Vec<u8>>) -> H256 {
 		Layout::<sp_core::KeccakHasher>::ordered_trie_root(input)
 	}
+
+	/// Verify a compact `blake2_256` trie `proof` of `items` against `root`.
+	///
+	/// Items with a `None` value are checked for non-inclusion.
+	fn blake2_256_verify_proof(
+		&self,
+		root: H256,
+		proof: &[Vec<u8>],
+		items: &[(Vec<u8>, Option<Vec<u8>>)],
+	) -> bool {
+		self.verify_trie_proof(root.as_ref(), proof, items, TrieProofLayout::Blake2)
+	}
+
+	/// Verify a compact `keccak_256` trie `proof` of `items` against `root`.
+	///
+	/// Items with a `None` value are checked for non-inclusion.
+	fn keccak_256_verify_proof(
+		&self,
+		root: H256,
+		proof: &[Vec<u8>],
+		items: &[(Vec<u8>, Option<Vec<u8>>)],
+	) -> bool {
+		self.verify_trie_proof(root.as_ref(), proof, items, TrieProofLayout::Keccak)
+	}
 }
 
 /// Interface that provides miscellaneous functions for communicating between the runtime and the node.
@@ -513,6 +620,34 @@ pub trait Crypto {
 		).unwrap_or_else(|| sr25519_verify(sig, msg, pub_key))
 	}
 
+	/// Register a `ed25519` signature check that is verified before the current call returns.
+	///
+	/// The host may verify the signature in parallel to the execution, a failed
+	/// verification fails the whole call.
+	fn ed25519_deferred_verify(
+		&mut self,
+		sig: &ed25519::Signature,
+		msg: &[u8],
+		pub_key: &ed25519::Public,
+	) {
+		let (sig, msg, pub_key) = (sig.clone(), msg.to_vec(), pub_key.clone());
+		self.register_deferred_check(Box::new(move || ed25519::Pair::verify(&sig, &msg, &pub_key)));
+	}
+
+	/// Register a `sr25519` signature check that is verified before the current call returns.
+	///
+	/// The host may verify the signature in parallel to the execution, a failed
+	/// verification fails the whole call.
+	fn sr25519_deferred_verify(
+		&mut self,
+		sig: &sr25519::Signature,
+		msg: &[u8],
+		pub_key: &sr25519::Public,
+	) {
+		let (sig, msg, pub_key) = (sig.clone(), msg.to_vec(), pub_key.clone());
+		self.register_deferred_check(Box::new(move || sr25519::Pair::verify(&sig, &msg, &pub_key)));
+	}
+
 	/// Start verification extension.
 	fn start_batch_verify(&mut self) {
 		let scheduler = self.extension::<TaskExecutorExt>()
@@ -1404,4 +1539,69 @@ mod tests {
 			assert!(!crypto::finish_batch_verify());
 		});
 	}
+
+	#[test]
+	fn blob_store_works() {
+		BasicExternalities::default().execute_with(|| {
+			let value = vec![42u8; 1024];
+			let handle = storage::blob_put(&value);
+			assert_eq!(handle.len(), 36);
+			assert_eq!(storage::blob_get(&handle), Some(value));
+			assert_eq!(storage::blob_get(&handle[1..]), None);
+			assert!(storage::blob_remove(&handle));
+			assert_eq!(storage::blob_get(&handle), None);
+			assert!(!storage::blob_remove(&handle));
+		});
+	}
+
+	#[test]
+	fn namespaced_storage_works() {
+		BasicExternalities::default().execute_with(|| {
+			storage::set_in_namespace(1, b"key", b"value");
+			assert_eq!(storage::get(b"key"), Some(b"value".to_vec()));
+			storage::clear_in_namespace(1, b"key");
+			assert_eq!(storage::get(b"key"), None);
+		});
+	}
+
+	#[test]
+	fn transient_storage_is_not_part_of_the_state() {
+		BasicExternalities::default().execute_with(|| {
+			let root = storage::root();
+			storage::transient_set(b"cache", b"1");
+			assert_eq!(storage::transient_get(b"cache"), Some(b"1".to_vec()));
+			assert_eq!(storage::get(b"cache"), None);
+			assert_eq!(storage::root(), root);
+
+			storage::transient_clear(b"cache");
+			assert_eq!(storage::transient_get(b"cache"), None);
+		});
+	}
+
+	#[test]
+	fn verify_trie_proof_works() {
+		use sp_trie::{MemoryDB, TrieMut, trie_types::TrieDBMut};
+
+		let mut db = MemoryDB::<sp_core::Blake2Hasher>::default();
+		let mut root = H256::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			trie.insert(b"foo", b"bar").unwrap();
+			trie.insert(b"fob", b"baz").unwrap();
+		}
+		let proof = sp_trie::generate_trie_proof::<Layout<sp_core::Blake2Hasher>, _, _, _>(
+			&db,
+			root,
+			&[&b"foo"[..], &b"fox"[..]],
+		).unwrap();
+
+		BasicExternalities::default().execute_with(|| {
+			let items = vec![(b"foo".to_vec(), Some(b"bar".to_vec())), (b"fox".to_vec(), None)];
+			assert!(trie::blake2_256_verify_proof(root, &proof, &items));
+			assert!(!trie::keccak_256_verify_proof(root, &proof, &items));
+
+			let items = vec![(b"foo".to_vec(), Some(b"baz".to_vec()))];
+			assert!(!trie::blake2_256_verify_proof(root, &proof, &items));
+		});
+	}
 }
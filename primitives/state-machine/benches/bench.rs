@@ -0,0 +1,242 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use codec::Encode;
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use sp_core::{Blake2Hasher, ChangesTrieConfiguration, H256, traits::Externalities};
+use sp_core::offchain::storage::OffchainOverlayedChanges;
+use sp_core::storage::{Storage, well_known_keys::{CHANGES_TRIE_CONFIG, EXTRINSIC_INDEX}};
+use sp_state_machine::{
+	Ext, InMemoryBackend, InMemoryChangesTrieStorage, OverlayedChanges, SharedCacheBackend,
+	StorageTransactionCache, TestExternalities,
+};
+
+criterion_group!(
+	benches,
+	set_storage,
+	nested_transactions,
+	commit_all,
+	clear_prefix,
+	storage_root,
+	overlay_misses,
+	cached_reads,
+	changes_trie_block_import,
+);
+criterion_main!(benches);
+
+type TestExt = TestExternalities<Blake2Hasher, u64>;
+
+fn key(i: u32) -> Vec<u8> {
+	let mut key = b"key".to_vec();
+	key.extend_from_slice(&i.to_be_bytes());
+	key
+}
+
+fn ext_with_backend_keys(n: u32) -> TestExt {
+	let mut ext = TestExt::default();
+	{
+		let mut ext = ext.ext();
+		for i in 0..n {
+			ext.set_storage(key(i), vec![1; 32]);
+		}
+	}
+	ext.commit_all().expect("No transaction is open; qed");
+	ext
+}
+
+fn set_storage(c: &mut Criterion) {
+	c.bench_function("set_storage 10k keys", |b| b.iter_batched(
+		TestExt::default,
+		|mut ext| {
+			let mut ext = ext.ext();
+			for i in 0..10_000 {
+				ext.set_storage(key(i), vec![1; 32]);
+			}
+		},
+		BatchSize::SmallInput,
+	));
+}
+
+fn nested_transactions(c: &mut Criterion) {
+	c.bench_function("nested transactions depth 100", |b| b.iter_batched(
+		TestExt::default,
+		|mut ext| {
+			let mut ext = ext.ext();
+			for i in 0..100 {
+				ext.storage_start_transaction();
+				for j in 0..10 {
+					ext.set_storage(key(j), vec![i as u8; 32]);
+				}
+			}
+			for i in 0..100 {
+				if i % 2 == 0 {
+					ext.storage_commit_transaction().unwrap();
+				} else {
+					ext.storage_rollback_transaction().unwrap();
+				}
+			}
+		},
+		BatchSize::SmallInput,
+	));
+}
+
+fn commit_all(c: &mut Criterion) {
+	c.bench_function("commit_all 10k keys", |b| b.iter_batched(
+		|| {
+			let mut ext = TestExt::default();
+			{
+				let mut ext = ext.ext();
+				for i in 0..10_000 {
+					ext.set_storage(key(i), vec![1; 32]);
+				}
+			}
+			ext
+		},
+		|mut ext| ext.commit_all().unwrap(),
+		BatchSize::SmallInput,
+	));
+}
+
+fn clear_prefix(c: &mut Criterion) {
+	c.bench_function("clear_prefix 100k keys", |b| b.iter_batched(
+		|| {
+			let mut ext = TestExt::default();
+			{
+				let mut ext = ext.ext();
+				for i in 0..100_000 {
+					ext.set_storage(key(i), vec![1; 32]);
+				}
+			}
+			ext
+		},
+		|mut ext| ext.ext().clear_prefix(b"key"),
+		BatchSize::LargeInput,
+	));
+}
+
+fn storage_root(c: &mut Criterion) {
+	let mut group = c.benchmark_group("storage_root");
+	for delta in [10u32, 100, 1_000, 10_000].iter() {
+		group.bench_with_input(BenchmarkId::from_parameter(delta), delta, |b, &delta| b.iter_batched(
+			|| {
+				let mut ext = ext_with_backend_keys(10_000);
+				{
+					let mut ext = ext.ext();
+					for i in 0..delta {
+						ext.set_storage(key(i * 7), vec![2; 32]);
+					}
+				}
+				ext
+			},
+			|mut ext| ext.ext().storage_root(),
+			BatchSize::LargeInput,
+		));
+	}
+	group.finish();
+}
+
+fn overlay_misses(c: &mut Criterion) {
+	let backend = InMemoryBackend::<Blake2Hasher>::default();
+	let mut group = c.benchmark_group("overlay_misses");
+	for with_filter in [false, true].iter() {
+		let mut overlay = OverlayedChanges::default();
+		if *with_filter {
+			overlay.enable_key_filter();
+		}
+		let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+		let mut cache = StorageTransactionCache::default();
+		let mut ext = Ext::<_, u64, _>::new(
+			&mut overlay,
+			&mut offchain_overlay,
+			&mut cache,
+			&backend,
+			None,
+			None,
+		);
+		for i in 0..10_000 {
+			ext.set_storage(key(i), vec![1; 32]);
+		}
+
+		let name = if *with_filter { "with key filter" } else { "without key filter" };
+		group.bench_function(name, |b| b.iter(|| {
+			for i in 10_000..20_000 {
+				assert!(ext.storage(&key(i)).is_none());
+			}
+		}));
+	}
+	group.finish();
+}
+
+fn cached_reads(c: &mut Criterion) {
+	let backend: InMemoryBackend<Blake2Hasher> = vec![
+		(None, (0..10_000).map(|i| (key(i), Some(vec![1; 32]))).collect()),
+	].into();
+	let backend = SharedCacheBackend::<_, Blake2Hasher>::new(backend, 64 * 1024 * 1024);
+	let mut overlay = OverlayedChanges::default();
+	overlay.enable_key_filter();
+	let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+	let mut cache = StorageTransactionCache::default();
+	let mut ext = Ext::<_, u64, _>::new(
+		&mut overlay,
+		&mut offchain_overlay,
+		&mut cache,
+		&backend,
+		None,
+		None,
+	);
+	for i in 0..100 {
+		ext.set_storage(key(i * 100), vec![2; 32]);
+	}
+	let keys: Vec<_> = (0..10_000).map(key).collect();
+	for key in &keys {
+		ext.storage(key);
+	}
+
+	c.bench_function("cached reads 10k keys", |b| b.iter(|| {
+		for key in &keys {
+			assert!(ext.storage(key).is_some());
+		}
+	}));
+}
+
+fn changes_trie_block_import(c: &mut Criterion) {
+	let parent_hash = H256::repeat_byte(1);
+	c.bench_function("block import with changes trie 100 extrinsics", |b| b.iter_batched(
+		|| {
+			let mut storage = Storage::default();
+			storage.top.insert(
+				CHANGES_TRIE_CONFIG.to_vec(),
+				ChangesTrieConfiguration { digest_interval: 4, digest_levels: 2 }.encode(),
+			);
+			let mut ext = TestExt::new(storage);
+			*ext.changes_trie_storage() = InMemoryChangesTrieStorage::with_blocks(vec![(0, parent_hash)]);
+			ext
+		},
+		|mut ext| {
+			let mut ext = ext.ext();
+			for extrinsic in 0..100u32 {
+				ext.set_storage(EXTRINSIC_INDEX.to_vec(), extrinsic.encode());
+				for i in 0..10 {
+					ext.set_storage(key(extrinsic * 10 + i), vec![1; 32]);
+				}
+				ext.set_storage(b"shared".to_vec(), extrinsic.encode());
+			}
+			ext.storage_changes_root(&parent_hash.encode()).unwrap()
+		},
+		BatchSize::SmallInput,
+	));
+}
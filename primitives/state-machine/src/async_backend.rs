@@ -0,0 +1,435 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Asynchronous state backends, e.g. backends that fetch the state from a remote node.
+
+use std::{fmt, marker::PhantomData, sync::mpsc};
+use futures::{future::BoxFuture, FutureExt};
+use log::debug;
+use hash_db::Hasher;
+use parking_lot::Mutex;
+use sp_core::{storage::ChildInfo, traits::SpawnNamed};
+use crate::{Backend, Error, StorageKey, StorageValue, UsageInfo, MemoryDB};
+
+/// The future returned by the methods of [`AsyncBackend`].
+pub type AsyncBackendResult<T, E> = BoxFuture<'static, Result<T, E>>;
+
+/// A state backend that answers queries asynchronously.
+///
+/// Implementations are expected to verify the data they receive, e.g. by checking read proofs
+/// against a trusted state root.
+pub trait AsyncBackend<H: Hasher>: Send + Sync {
+	/// An error type when fetching data is not possible.
+	type Error: Error;
+
+	/// Get keyed storage or None if there is nothing associated.
+	fn storage(&self, key: &[u8]) -> AsyncBackendResult<Option<StorageValue>, Self::Error>;
+
+	/// Get keyed child storage or None if there is nothing associated.
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> AsyncBackendResult<Option<StorageValue>, Self::Error>;
+
+	/// Return the next key in storage in lexicographic order or `None` if there is no value.
+	fn next_storage_key(&self, key: &[u8]) -> AsyncBackendResult<Option<StorageKey>, Self::Error>;
+
+	/// Return the next key in child storage in lexicographic order or `None` if there is no value.
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> AsyncBackendResult<Option<StorageKey>, Self::Error>;
+
+	/// Get all keys which start with the given prefix.
+	fn for_keys_with_prefix(&self, prefix: &[u8]) -> AsyncBackendResult<Vec<StorageKey>, Self::Error>;
+
+	/// Get all keys of the given child storage which start with the given prefix.
+	fn for_child_keys_with_prefix(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+	) -> AsyncBackendResult<Vec<StorageKey>, Self::Error>;
+}
+
+/// Error of an [`AsyncBackendAdapter`].
+#[derive(Debug)]
+pub enum AsyncBackendError<E> {
+	/// The wrapped backend failed.
+	Backend(E),
+	/// The query was dropped by the executor before it resolved, e.g. on shutdown.
+	Canceled,
+}
+
+impl<E: fmt::Display> fmt::Display for AsyncBackendError<E> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			AsyncBackendError::Backend(e) => write!(f, "{}", e),
+			AsyncBackendError::Canceled => write!(f, "Query of the async backend was canceled"),
+		}
+	}
+}
+
+/// Adapter that turns an [`AsyncBackend`] into a [`Backend`].
+///
+/// The futures returned by the wrapped backend are run by the given executor, every query
+/// blocks the calling thread until the result is sent back. The executor must therefore not
+/// run the futures on the calling thread. Iterating over the key values fetches every value
+/// separately.
+///
+/// The `for_*` iteration methods can not return errors. If fetching the keys or values fails,
+/// the iteration stops and the error is returned by the next call to
+/// [`Backend::check_limits`].
+///
+/// Like the state of the light client, this adapter can not calculate storage roots. The
+/// returned roots are always the default value and the transactions are empty.
+pub struct AsyncBackendAdapter<A: AsyncBackend<H>, H: Hasher> {
+	backend: A,
+	spawner: Box<dyn SpawnNamed>,
+	/// The first error of a key fetch that was not reported yet.
+	fetch_failure: Mutex<Option<AsyncBackendError<A::Error>>>,
+	_marker: PhantomData<H>,
+}
+
+impl<A: AsyncBackend<H>, H: Hasher> AsyncBackendAdapter<A, H> {
+	/// Create a new adapter for the given `backend`, whose futures are run by `spawner`.
+	pub fn new(backend: A, spawner: impl SpawnNamed + 'static) -> Self {
+		AsyncBackendAdapter {
+			backend,
+			spawner: Box::new(spawner),
+			fetch_failure: Mutex::new(None),
+			_marker: PhantomData,
+		}
+	}
+
+	/// Returns a reference to the wrapped backend.
+	pub fn inner(&self) -> &A {
+		&self.backend
+	}
+
+	/// Run the given query with the executor and wait for its result.
+	fn run<T: Send + 'static>(
+		&self,
+		query: AsyncBackendResult<T, A::Error>,
+	) -> Result<T, AsyncBackendError<A::Error>> {
+		let (sender, receiver) = mpsc::sync_channel(1);
+		self.spawner.spawn("async-backend-query", async move {
+			let _ = sender.send(query.await);
+		}.boxed());
+		match receiver.recv() {
+			Ok(result) => result.map_err(AsyncBackendError::Backend),
+			Err(_) => Err(AsyncBackendError::Canceled),
+		}
+	}
+
+	fn fetch_keys(&self, child_info: Option<&ChildInfo>, prefix: &[u8]) -> Vec<StorageKey> {
+		let keys = match child_info {
+			Some(child_info) =>
+				self.run(self.backend.for_child_keys_with_prefix(child_info, prefix)),
+			None => self.run(self.backend.for_keys_with_prefix(prefix)),
+		};
+		keys.unwrap_or_else(|e| {
+			debug!(target: "state", "Error while fetching keys by prefix: {}", e);
+			self.fetch_failure.lock().get_or_insert(e);
+			Vec::new()
+		})
+	}
+}
+
+impl<A: AsyncBackend<H>, H: Hasher> fmt::Debug for AsyncBackendAdapter<A, H> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "AsyncBackendAdapter")
+	}
+}
+
+impl<A: AsyncBackend<H>, H: Hasher> Backend<H> for AsyncBackendAdapter<A, H> {
+	type Error = AsyncBackendError<A::Error>;
+	type Transaction = MemoryDB<H>;
+	type TrieBackendStorage = MemoryDB<H>;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error> {
+		self.run(self.backend.storage(key))
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, Self::Error> {
+		self.run(self.backend.child_storage(child_info, key))
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+		self.run(self.backend.next_storage_key(key))
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageKey>, Self::Error> {
+		self.run(self.backend.next_child_storage_key(child_info, key))
+	}
+
+	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		f: F,
+	) {
+		self.fetch_keys(Some(child_info), &[]).iter().map(|k| &k[..]).for_each(f)
+	}
+
+	fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], f: F) {
+		self.fetch_keys(None, prefix).iter().map(|k| &k[..]).for_each(f)
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], mut f: F) {
+		for key in self.fetch_keys(None, prefix) {
+			match self.storage(&key) {
+				Ok(Some(value)) => f(&key, &value),
+				Ok(None) => {},
+				Err(e) => {
+					debug!(target: "state", "Error while fetching value by prefix: {}", e);
+					self.fetch_failure.lock().get_or_insert(e);
+					return
+				},
+			}
+		}
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		f: F,
+	) {
+		self.fetch_keys(Some(child_info), prefix).iter().map(|k| &k[..]).for_each(f)
+	}
+
+	fn storage_root<'a>(
+		&self,
+		_delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		Default::default()
+	}
+
+	fn child_storage_root<'a>(
+		&self,
+		_child_info: &ChildInfo,
+		_delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord {
+		(H::Out::default(), true, Default::default())
+	}
+
+	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
+		let mut pairs = Vec::new();
+		self.for_key_values_with_prefix(&[], |k, v| pairs.push((k.to_vec(), v.to_vec())));
+		pairs
+	}
+
+	fn register_overlay_stats(&mut self, _stats: &crate::stats::StateMachineStats) { }
+
+	fn usage_info(&self) -> UsageInfo {
+		UsageInfo::empty()
+	}
+
+	fn check_limits(&self) -> Result<(), Self::Error> {
+		match self.fetch_failure.lock().take() {
+			Some(e) => Err(e),
+			None => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::InMemoryBackend;
+	use sp_runtime::traits::BlakeTwo256;
+
+	/// Runs every future on a new thread.
+	#[derive(Clone)]
+	struct ThreadSpawner;
+
+	impl SpawnNamed for ThreadSpawner {
+		fn spawn_blocking(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+			self.spawn(name, future)
+		}
+
+		fn spawn(&self, _: &'static str, future: BoxFuture<'static, ()>) {
+			std::thread::spawn(move || futures::executor::block_on(future));
+		}
+	}
+
+	/// Answers queries from an in memory backend on a separate thread.
+	struct ThreadedBackend(std::sync::Arc<InMemoryBackend<BlakeTwo256>>);
+
+	impl ThreadedBackend {
+		fn spawn<T: Send + 'static>(
+			&self,
+			f: impl FnOnce(&InMemoryBackend<BlakeTwo256>) -> T + Send + 'static,
+		) -> AsyncBackendResult<T, String> {
+			let (tx, rx) = futures::channel::oneshot::channel();
+			let backend = self.0.clone();
+			std::thread::spawn(move || { let _ = tx.send(f(&backend)); });
+			rx.map(|r| r.map_err(|e| e.to_string())).boxed()
+		}
+	}
+
+	impl AsyncBackend<BlakeTwo256> for ThreadedBackend {
+		type Error = String;
+
+		fn storage(&self, key: &[u8]) -> AsyncBackendResult<Option<StorageValue>, String> {
+			let key = key.to_vec();
+			self.spawn(move |b| b.storage(&key).unwrap())
+		}
+
+		fn child_storage(
+			&self,
+			child_info: &ChildInfo,
+			key: &[u8],
+		) -> AsyncBackendResult<Option<StorageValue>, String> {
+			let (child_info, key) = (child_info.clone(), key.to_vec());
+			self.spawn(move |b| b.child_storage(&child_info, &key).unwrap())
+		}
+
+		fn next_storage_key(&self, key: &[u8]) -> AsyncBackendResult<Option<StorageKey>, String> {
+			let key = key.to_vec();
+			self.spawn(move |b| b.next_storage_key(&key).unwrap())
+		}
+
+		fn next_child_storage_key(
+			&self,
+			child_info: &ChildInfo,
+			key: &[u8],
+		) -> AsyncBackendResult<Option<StorageKey>, String> {
+			let (child_info, key) = (child_info.clone(), key.to_vec());
+			self.spawn(move |b| b.next_child_storage_key(&child_info, &key).unwrap())
+		}
+
+		fn for_keys_with_prefix(&self, prefix: &[u8]) -> AsyncBackendResult<Vec<StorageKey>, String> {
+			let prefix = prefix.to_vec();
+			self.spawn(move |b| b.keys(&prefix))
+		}
+
+		fn for_child_keys_with_prefix(
+			&self,
+			child_info: &ChildInfo,
+			prefix: &[u8],
+		) -> AsyncBackendResult<Vec<StorageKey>, String> {
+			let (child_info, prefix) = (child_info.clone(), prefix.to_vec());
+			self.spawn(move |b| b.child_keys(&child_info, &prefix))
+		}
+	}
+
+	/// Fails to fetch the keys with the prefix `b"fail"`.
+	struct FailingBackend(ThreadedBackend);
+
+	impl AsyncBackend<BlakeTwo256> for FailingBackend {
+		type Error = String;
+
+		fn storage(&self, key: &[u8]) -> AsyncBackendResult<Option<StorageValue>, String> {
+			self.0.storage(key)
+		}
+
+		fn child_storage(
+			&self,
+			child_info: &ChildInfo,
+			key: &[u8],
+		) -> AsyncBackendResult<Option<StorageValue>, String> {
+			self.0.child_storage(child_info, key)
+		}
+
+		fn next_storage_key(&self, key: &[u8]) -> AsyncBackendResult<Option<StorageKey>, String> {
+			self.0.next_storage_key(key)
+		}
+
+		fn next_child_storage_key(
+			&self,
+			child_info: &ChildInfo,
+			key: &[u8],
+		) -> AsyncBackendResult<Option<StorageKey>, String> {
+			self.0.next_child_storage_key(child_info, key)
+		}
+
+		fn for_keys_with_prefix(&self, prefix: &[u8]) -> AsyncBackendResult<Vec<StorageKey>, String> {
+			match prefix {
+				b"fail" => futures::future::ready(Err("keys unavailable".into())).boxed(),
+				_ => self.0.for_keys_with_prefix(prefix),
+			}
+		}
+
+		fn for_child_keys_with_prefix(
+			&self,
+			child_info: &ChildInfo,
+			prefix: &[u8],
+		) -> AsyncBackendResult<Vec<StorageKey>, String> {
+			self.0.for_child_keys_with_prefix(child_info, prefix)
+		}
+	}
+
+	#[test]
+	fn adapter_answers_queries() {
+		let child_info = ChildInfo::new_default(b"child");
+		let inner: InMemoryBackend<BlakeTwo256> = vec![
+			(None, vec![
+				(b"a".to_vec(), Some(b"1".to_vec())),
+				(b"ab".to_vec(), Some(b"2".to_vec())),
+			]),
+			(Some(child_info.clone()), vec![(b"c".to_vec(), Some(b"3".to_vec()))]),
+		].into();
+		let adapter = AsyncBackendAdapter::new(
+			ThreadedBackend(std::sync::Arc::new(inner)),
+			ThreadSpawner,
+		);
+
+		assert_eq!(adapter.storage(b"ab").unwrap(), Some(b"2".to_vec()));
+		assert_eq!(adapter.child_storage(&child_info, b"c").unwrap(), Some(b"3".to_vec()));
+		assert_eq!(adapter.next_storage_key(b"a").unwrap(), Some(b"ab".to_vec()));
+
+		let mut pairs = Vec::new();
+		adapter.for_key_values_with_prefix(b"a", |k, v| pairs.push((k.to_vec(), v.to_vec())));
+		assert_eq!(pairs, vec![
+			(b"a".to_vec(), b"1".to_vec()),
+			(b"ab".to_vec(), b"2".to_vec()),
+		]);
+		assert_eq!(adapter.child_keys(&child_info, &[]), vec![b"c".to_vec()]);
+	}
+
+	#[test]
+	fn failed_key_fetch_is_reported_by_check_limits() {
+		let inner: InMemoryBackend<BlakeTwo256> = vec![
+			(None, vec![(b"failing".to_vec(), Some(b"1".to_vec()))]),
+		].into();
+		let adapter = AsyncBackendAdapter::new(
+			FailingBackend(ThreadedBackend(std::sync::Arc::new(inner))),
+			ThreadSpawner,
+		);
+
+		assert_eq!(adapter.keys(b"fa"), vec![b"failing".to_vec()]);
+		assert!(adapter.check_limits().is_ok());
+
+		assert!(adapter.keys(b"fail").is_empty());
+		match adapter.check_limits() {
+			Err(AsyncBackendError::Backend(e)) => assert_eq!(e, "keys unavailable"),
+			other => panic!("Unexpected result: {:?}", other),
+		}
+		// The failure is reported once.
+		assert!(adapter.check_limits().is_ok());
+	}
+}
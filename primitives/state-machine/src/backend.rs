@@ -26,9 +26,21 @@ use sp_core::{
 use crate::{
 	trie_backend::TrieBackend,
 	trie_backend_essence::TrieBackendStorage,
+	key_ref::KeyRef,
 	UsageInfo, StorageKey, StorageValue, StorageCollection, ChildStorageCollection,
 };
 
+/// Where a backend found the value of a storage read, see [`Backend::storage_with_hint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageReadHint {
+	/// The value was served from a cache of the backend.
+	Cache,
+	/// The value had to be read from the database.
+	Disk,
+	/// The backend can not tell.
+	Unknown,
+}
+
 /// A state backend is used to read state data and can have changes committed
 /// to it.
 ///
@@ -46,6 +58,28 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 	/// Get keyed storage or None if there is nothing associated.
 	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error>;
 
+	/// Get keyed storage like [`Self::storage`] and report whether the value was cached.
+	///
+	/// Backends with a cache should override this, by default the hint is
+	/// [`StorageReadHint::Unknown`].
+	fn storage_with_hint(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		Ok((self.storage(key)?, StorageReadHint::Unknown))
+	}
+
+	/// Like [`storage_with_hint`](Backend::storage_with_hint), with the hash of the key
+	/// computed in advance.
+	///
+	/// Backends that hash keys for their lookups should override this.
+	fn storage_with_hint_ref(
+		&self,
+		key: KeyRef,
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		self.storage_with_hint(key.key())
+	}
+
 	/// Get keyed storage value hash or None if there is nothing associated.
 	fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, Self::Error> {
 		self.storage(key).map(|v| v.map(|v| H::hash(&v)))
@@ -81,6 +115,24 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 		Ok(self.child_storage(child_info, key)?.is_some())
 	}
 
+	/// Get the length of the value of a keyed storage entry or None if there is nothing
+	/// associated.
+	///
+	/// Backends that know the length without reading the value should override this.
+	fn storage_size(&self, key: &[u8]) -> Result<Option<u32>, Self::Error> {
+		Ok(self.storage(key)?.map(|v| v.len() as u32))
+	}
+
+	/// Get the length of the value of a child storage entry or None if there is nothing
+	/// associated.
+	fn child_storage_size(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<u32>, Self::Error> {
+		Ok(self.child_storage(child_info, key)?.map(|v| v.len() as u32))
+	}
+
 	/// Return the next key in storage in lexicographic order or `None` if there is no value.
 	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error>;
 
@@ -118,6 +170,40 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 		f: F,
 	);
 
+	/// Return at most `count` keys of child storage starting with `prefix` in lexicographic
+	/// order.
+	///
+	/// Only keys that are strictly superior to `start_key` are returned, so a query can be
+	/// resumed by passing the last key returned by the previous one.
+	fn child_keys_paged(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		count: usize,
+		start_key: Option<&[u8]>,
+	) -> Result<Vec<StorageKey>, Self::Error> {
+		let mut keys = Vec::new();
+		let mut current = match start_key {
+			Some(start_key) if start_key >= prefix => start_key.to_vec(),
+			_ => {
+				if count > 0 && self.exists_child_storage(child_info, prefix)? {
+					keys.push(prefix.to_vec());
+				}
+				prefix.to_vec()
+			},
+		};
+		while keys.len() < count {
+			match self.next_child_storage_key(child_info, &current)? {
+				Some(key) if key.starts_with(prefix) => {
+					keys.push(key.clone());
+					current = key;
+				},
+				_ => break,
+			}
+		}
+		Ok(keys)
+	}
+
 	/// Calculate the storage root, with given delta over what is already stored in
 	/// the backend, and produce a "transaction" that can be used to commit.
 	/// Does not include child storage updates.
@@ -126,6 +212,42 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
 	) -> (H::Out, Self::Transaction) where H::Out: Ord;
 
+	/// Same as [`Backend::storage_root`], but the `delta` is already sorted by key.
+	///
+	/// Implementations may skip sorting the delta, so callers that keep their changes
+	/// ordered anyway don't pay for it. Passing an unsorted delta is a bug.
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.storage_root(delta)
+	}
+
+	/// Calculate the storage root of the state `root` with the given sorted `delta` applied.
+	///
+	/// `root` must be the root of the state that is reached by applying `transaction` to this
+	/// backend. The changes of the delta are added to `transaction`, so that it yields the
+	/// new state afterwards. This allows to fold in changes bit by bit instead of calculating
+	/// the root over the whole delta every time.
+	///
+	/// Returns `None` if this is not supported by the backend, which is the default, or if
+	/// the calculation failed. `transaction` must be discarded in the latter case.
+	fn storage_root_incremental<'a>(
+		&self,
+		_root: H::Out,
+		_transaction: &mut Self::Transaction,
+		_delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		None
+	}
+
+	/// Whether [`Self::storage_root_incremental`] is supported by this backend.
+	///
+	/// Allows to skip collecting the delta if the calculation would not be done anyway.
+	fn supports_incremental_storage_root(&self) -> bool {
+		false
+	}
+
 	/// Calculate the child storage root, with given delta over what is already stored in
 	/// the backend, and produce a "transaction" that can be used to commit. The second argument
 	/// is true if child storage root equals default storage root.
@@ -156,6 +278,39 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 		all
 	}
 
+	/// Call `f` for all raw key value pairs of the state whose keys start with `prefix`.
+	///
+	/// The roots of the default child tries are not passed to `f`. Instead all pairs of a child
+	/// trie are passed with its `ChildInfo` if its prefixed storage key starts with `prefix`.
+	/// This allows to rebuild the state with [`TrieBackend::from_raw_iter`], without the
+	/// overhead of a proof, when the source can be trusted.
+	fn export_raw<F: FnMut(Option<&ChildInfo>, &[u8], &[u8])>(
+		&self,
+		prefix: &[u8],
+		mut f: F,
+	) -> Result<(), Self::Error> {
+		let mut child_infos = Vec::new();
+		let child_prefix = well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+		self.for_key_values_with_prefix(prefix, |key, value| {
+			if key.starts_with(child_prefix) {
+				child_infos.push(ChildInfo::new_default(&key[child_prefix.len()..]));
+			} else {
+				f(None, key, value);
+			}
+		});
+
+		for child_info in child_infos {
+			let mut keys = Vec::new();
+			self.for_keys_in_child_storage(&child_info, |key| keys.push(key.to_vec()));
+			for key in keys {
+				if let Some(value) = self.child_storage(&child_info, &key)? {
+					f(Some(&child_info), &key, &value);
+				}
+			}
+		}
+		Ok(())
+	}
+
 	/// Try convert into trie backend.
 	fn as_trie_backend(&mut self) -> Option<&TrieBackend<Self::TrieBackendStorage, H>> {
 		None
@@ -209,6 +364,29 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 	/// case when they don't, empty statistics is returned.
 	fn usage_info(&self) -> UsageInfo;
 
+	/// Returns the size in bytes of the storage proof recorded so far.
+	///
+	/// `None` if the backend does not record a proof.
+	fn proof_size(&self) -> Option<u32> {
+		None
+	}
+
+	/// Returns `true` if `error` reports that the read quota of the backend is exhausted.
+	///
+	/// See [`QuotaBackend`](crate::QuotaBackend).
+	fn is_quota_exceeded(_error: &Self::Error) -> bool {
+		false
+	}
+
+	/// Returns an error if a limit of the backend was hit since it was created.
+	///
+	/// The `for_*` iteration methods can not report errors, a backend that stops an iteration
+	/// early because of a limit must report it here. Callers that rely on complete iterations
+	/// have to check this afterwards.
+	fn check_limits(&self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+
 	/// Wipe the state database.
 	fn wipe(&self) -> Result<(), Self::Error> {
 		unimplemented!()
@@ -253,6 +431,20 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 		(*self).storage(key)
 	}
 
+	fn storage_with_hint(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		(*self).storage_with_hint(key)
+	}
+
+	fn storage_with_hint_ref(
+		&self,
+		key: KeyRef,
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		(*self).storage_with_hint_ref(key)
+	}
+
 	fn child_storage(
 		&self,
 		child_info: &ChildInfo,
@@ -261,6 +453,18 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 		(*self).child_storage(child_info, key)
 	}
 
+	fn storage_size(&self, key: &[u8]) -> Result<Option<u32>, Self::Error> {
+		(*self).storage_size(key)
+	}
+
+	fn child_storage_size(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<u32>, Self::Error> {
+		(*self).child_storage_size(child_info, key)
+	}
+
 	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
 		&self,
 		child_info: &ChildInfo,
@@ -294,6 +498,16 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 		(*self).for_child_keys_with_prefix(child_info, prefix, f)
 	}
 
+	fn child_keys_paged(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		count: usize,
+		start_key: Option<&[u8]>,
+	) -> Result<Vec<StorageKey>, Self::Error> {
+		(*self).child_keys_paged(child_info, prefix, count, start_key)
+	}
+
 	fn storage_root<'b>(
 		&self,
 		delta: impl Iterator<Item=(&'b [u8], Option<&'b [u8]>)>,
@@ -301,6 +515,26 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 		(*self).storage_root(delta)
 	}
 
+	fn storage_root_presorted<'b>(
+		&self,
+		delta: impl Iterator<Item=(&'b [u8], Option<&'b [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		(*self).storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'b>(
+		&self,
+		root: H::Out,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'b [u8], Option<&'b [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		(*self).storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		(*self).supports_incremental_storage_root()
+	}
+
 	fn child_storage_root<'b>(
 		&self,
 		child_info: &ChildInfo,
@@ -322,6 +556,18 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 	fn usage_info(&self) -> UsageInfo {
 		(*self).usage_info()
 	}
+
+	fn proof_size(&self) -> Option<u32> {
+		(*self).proof_size()
+	}
+
+	fn is_quota_exceeded(error: &Self::Error) -> bool {
+		T::is_quota_exceeded(error)
+	}
+
+	fn check_limits(&self) -> Result<(), Self::Error> {
+		(*self).check_limits()
+	}
 }
 
 /// Trait that allows consolidate two transactions together.
@@ -33,19 +33,21 @@ use sp_core::{
 };
 use log::warn;
 use codec::Encode;
-use sp_externalities::{Extensions, Extension};
+use sp_externalities::{Extensions, Extension, DeferredCheck, TrieProofLayout};
 
 /// Simple Map-based Externalities impl.
 #[derive(Debug)]
 pub struct BasicExternalities {
 	inner: Storage,
+	/// Transient values, they are never part of the storages.
+	transient: BTreeMap<StorageKey, StorageValue>,
 	extensions: Extensions,
 }
 
 impl BasicExternalities {
 	/// Create a new instance of `BasicExternalities`
 	pub fn new(inner: Storage) -> Self {
-		BasicExternalities { inner, extensions: Default::default() }
+		BasicExternalities { inner, transient: Default::default(), extensions: Default::default() }
 	}
 
 	/// New basic externalities with empty storage.
@@ -75,6 +77,7 @@ impl BasicExternalities {
 				top: std::mem::take(&mut storage.top),
 				children_default: std::mem::take(&mut storage.children_default),
 			},
+			transient: Default::default(),
 			extensions: Default::default(),
 		};
 
@@ -129,6 +132,7 @@ impl From<BTreeMap<StorageKey, StorageValue>> for BasicExternalities {
 				top: hashmap,
 				children_default: Default::default(),
 			},
+			transient: Default::default(),
 			extensions: Default::default(),
 		}
 	}
@@ -301,6 +305,47 @@ impl Externalities for BasicExternalities {
 		Ok(None)
 	}
 
+	fn disable_changes_trie_for_block(&mut self) {}
+
+	fn register_deferred_check(&mut self, check: DeferredCheck) {
+		if !check() {
+			panic!("Deferred check failed");
+		}
+	}
+
+	fn verify_trie_proof(
+		&self,
+		root: &[u8],
+		proof: &[Vec<u8>],
+		items: &[(Vec<u8>, Option<Vec<u8>>)],
+		layout: TrieProofLayout,
+	) -> bool {
+		crate::verify_trie_proof(root, proof, items, layout)
+	}
+
+	fn blob_put(&mut self, value: Vec<u8>) -> Vec<u8> {
+		crate::blob::put::<Blake2Hasher>(self, value)
+	}
+
+	fn blob_get(&self, handle: &[u8]) -> Option<Vec<u8>> {
+		crate::blob::get::<Blake2Hasher>(self, handle)
+	}
+
+	fn blob_remove(&mut self, handle: &[u8]) -> bool {
+		crate::blob::remove::<Blake2Hasher>(self, handle)
+	}
+
+	fn transient_storage(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.transient.get(key).cloned()
+	}
+
+	fn set_transient_storage(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+		match value {
+			Some(value) => { self.transient.insert(key, value); },
+			None => { self.transient.remove(&key); },
+		}
+	}
+
 	fn storage_start_transaction(&mut self) {
 		unimplemented!("Transactions are not supported by BasicExternalities");
 	}
@@ -355,6 +400,36 @@ impl sp_externalities::ExtensionStore for BasicExternalities {
 	}
 }
 
+/// Calculate the state root of the given storage, e.g. of a genesis state.
+///
+/// The roots of the child tries are calculated in parallel, before they are inserted into
+/// the main trie. This uses the closed form of the trie root and does not build any trie
+/// nodes, so it is considerably faster than going through a backend for large states.
+pub fn parallel_storage_root<H: Hasher>(storage: &Storage) -> H::Out {
+	use rayon::prelude::*;
+
+	let children: Vec<_> = storage.children_default.values().collect();
+	let child_roots: Vec<_> = children.par_iter()
+		.map(|child| (
+			child.child_info.prefixed_storage_key(),
+			sp_trie::child_trie_root::<Layout<H>, _, _, _>(child.data.iter()),
+		))
+		.collect();
+
+	let empty_root = empty_child_trie_root::<Layout<H>>();
+	let mut top: BTreeMap<_, _> = storage.top.iter()
+		.map(|(key, value)| (&key[..], value.clone()))
+		.collect();
+	for (prefixed_storage_key, root) in &child_roots {
+		if *root == empty_root {
+			top.remove(prefixed_storage_key.as_slice());
+		} else {
+			top.insert(prefixed_storage_key.as_slice(), root.encode());
+		}
+	}
+	Layout::<H>::trie_root(top)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -363,6 +438,26 @@ mod tests {
 	use sp_core::storage::well_known_keys::CODE;
 	use hex_literal::hex;
 
+	#[test]
+	fn parallel_storage_root_matches_backend() {
+		let storage = Storage {
+			top: map![b"doe".to_vec() => b"reindeer".to_vec(), b"dog".to_vec() => b"puppy".to_vec()],
+			children_default: map![
+				b"child1".to_vec() => StorageChild {
+					data: map![b"doe".to_vec() => b"reindeer".to_vec()],
+					child_info: ChildInfo::new_default(b"child1"),
+				},
+				b"child2".to_vec() => StorageChild {
+					data: map![],
+					child_info: ChildInfo::new_default(b"child2"),
+				}
+			],
+		};
+		let backend = crate::InMemoryBackend::<Blake2Hasher>::from(storage.clone());
+
+		assert_eq!(parallel_storage_root::<Blake2Hasher>(&storage), *backend.root());
+	}
+
 	#[test]
 	fn commit_should_work() {
 		let mut ext = BasicExternalities::default();
@@ -0,0 +1,281 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Out-of-band storage of large values.
+//!
+//! Large values can be stored in a side table instead of the main trie, see
+//! [`Externalities::blob_put`]. The trie only holds the SCALE encoded [`BlobHandle`] of such a
+//! value, which keeps the nodes of the main trie and its proofs small. The side table is the
+//! default child trie [`BLOB_STORE_KEY`], keyed by the hash of the payload, so the payloads are
+//! persisted and proven like any other child storage.
+//!
+//! Equal values share one payload. Every `blob_put` takes a reference on it and every
+//! `blob_remove` releases one, the payload is deleted when its last reference is released.
+//!
+//! [`LargeValueMigration`] converts existing large values into handles.
+
+use codec::{Decode, Encode};
+use hash_db::Hasher;
+use sp_core::{storage::ChildInfo, traits::Externalities};
+use crate::{MigrationLimits, MigrationProgress, MigrationUsage, StorageMigration};
+
+/// Storage key of the child trie that holds the payloads of the blobs.
+pub const BLOB_STORE_KEY: &[u8] = b":blob_store";
+
+/// The child trie that holds the payloads of the blobs.
+pub fn blob_child_info() -> ChildInfo {
+	ChildInfo::new_default(BLOB_STORE_KEY)
+}
+
+/// Reference to a value in the blob store.
+///
+/// Encoded as the bytes of the hash, followed by the length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct BlobHandle<Hash> {
+	/// The hash of the value.
+	pub hash: Hash,
+	/// The length of the value.
+	pub len: u32,
+}
+
+/// A payload in the blob store, together with the number of handles that refer to it.
+#[derive(Encode, Decode)]
+struct BlobEntry {
+	refs: u32,
+	value: Vec<u8>,
+}
+
+/// Store `value` in the blob store of `ext` and return its encoded handle.
+///
+/// Storing the same value twice returns the same handle and takes another reference on it.
+pub(crate) fn put<H: Hasher>(ext: &mut (impl Externalities + ?Sized), value: Vec<u8>) -> Vec<u8> {
+	let hash = H::hash(&value);
+	let mut handle = hash.as_ref().to_vec();
+	(value.len() as u32).encode_to(&mut handle);
+	let refs = entry(ext, hash.as_ref()).map_or(0, |entry| entry.refs);
+	let entry = BlobEntry { refs: refs.saturating_add(1), value };
+	ext.set_child_storage(&blob_child_info(), hash.as_ref().to_vec(), entry.encode());
+	handle
+}
+
+/// Resolve the encoded `handle` in the blob store of `ext`.
+///
+/// Returns `None` if the handle can not be decoded or the value is not known.
+pub(crate) fn get<H: Hasher>(ext: &(impl Externalities + ?Sized), handle: &[u8]) -> Option<Vec<u8>> {
+	resolve::<H>(ext, handle).map(|(_, entry)| entry.value)
+}
+
+/// Release a reference on the value of the encoded `handle` in the blob store of `ext`.
+///
+/// The value is deleted with its last reference. Returns `false` if the handle can not be
+/// decoded or the value is not known.
+pub(crate) fn remove<H: Hasher>(ext: &mut (impl Externalities + ?Sized), handle: &[u8]) -> bool {
+	let (hash, entry) = match resolve::<H>(ext, handle) {
+		Some(resolved) => resolved,
+		None => return false,
+	};
+	if entry.refs > 1 {
+		let entry = BlobEntry { refs: entry.refs - 1, value: entry.value };
+		ext.set_child_storage(&blob_child_info(), hash, entry.encode());
+	} else {
+		ext.clear_child_storage(&blob_child_info(), &hash);
+	}
+	true
+}
+
+fn entry(ext: &(impl Externalities + ?Sized), hash: &[u8]) -> Option<BlobEntry> {
+	ext.child_storage(&blob_child_info(), hash)
+		.and_then(|entry| BlobEntry::decode(&mut &entry[..]).ok())
+}
+
+fn resolve<H: Hasher>(
+	ext: &(impl Externalities + ?Sized),
+	handle: &[u8],
+) -> Option<(Vec<u8>, BlobEntry)> {
+	if handle.len() != H::LENGTH + 4 {
+		return None
+	}
+	let (hash, len) = handle.split_at(H::LENGTH);
+	let len = u32::decode(&mut &len[..]).ok()?;
+	entry(ext, hash)
+		.filter(|entry| entry.value.len() == len as usize)
+		.map(|entry| (hash.to_vec(), entry))
+}
+
+/// Moves the values under a prefix that are larger than a threshold into the blob store and
+/// replaces them with their handles.
+///
+/// Values that already resolve as a handle are left alone, so running the migration again
+/// does not wrap handles into further handles.
+///
+/// Every migrated key costs one unit of weight. The cursor is the last migrated key.
+pub struct LargeValueMigration {
+	/// Only values under this prefix are migrated.
+	pub prefix: Vec<u8>,
+	/// Values larger than this many bytes are migrated.
+	pub threshold: u32,
+}
+
+impl StorageMigration for LargeValueMigration {
+	fn migrate(
+		&self,
+		ext: &mut dyn Externalities,
+		cursor: Option<&[u8]>,
+		limits: MigrationLimits,
+	) -> Result<MigrationProgress, String> {
+		let mut usage = MigrationUsage::default();
+		// `next_storage_key` skips the given key, so the prefix itself is checked first.
+		let (mut last, mut first) = match cursor {
+			Some(cursor) => (cursor.to_vec(), None),
+			None => (
+				self.prefix.clone(),
+				Some(self.prefix.clone()).filter(|prefix| ext.exists_storage(prefix)),
+			),
+		};
+
+		while usage.weight < limits.weight {
+			let key = match first.take().or_else(|| ext.next_storage_key(&last)) {
+				Some(key) if key.starts_with(&self.prefix) => key,
+				_ => return Ok(MigrationProgress::Complete { usage }),
+			};
+			let value = ext.storage(&key).unwrap_or_default();
+			if value.len() > self.threshold as usize && ext.blob_get(&value).is_none() {
+				let bytes = usage.bytes + value.len() as u64;
+				if bytes > limits.bytes {
+					if usage.weight == 0 {
+						return Err(format!("Value of {:?} exceeds the byte limit of a step", key))
+					}
+					break
+				}
+				usage.bytes = bytes;
+				let handle = ext.blob_put(value);
+				ext.set_storage(key.clone(), handle);
+			}
+			usage.weight += 1;
+			last = key;
+		}
+		Ok(MigrationProgress::Continue { cursor: last, usage })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{TestExternalities, MigrationDriver, MigrationStatus};
+	use sp_core::{Blake2Hasher, H256};
+
+	#[test]
+	fn blobs_are_resolved_by_handle() {
+		let mut ext = TestExternalities::<Blake2Hasher>::default();
+		let mut ext = ext.ext();
+
+		let value = vec![7u8; 500];
+		let handle = ext.blob_put(value.clone());
+		assert_eq!(
+			BlobHandle::<H256>::decode(&mut &handle[..]).unwrap(),
+			BlobHandle { hash: Blake2Hasher::hash(&value), len: 500 },
+		);
+		assert_eq!(ext.blob_put(value.clone()), handle);
+		assert_eq!(ext.blob_get(&handle), Some(value));
+
+		let unknown = BlobHandle { hash: H256::repeat_byte(1), len: 500 }.encode();
+		assert_eq!(ext.blob_get(&unknown), None);
+		let wrong_len = BlobHandle { hash: Blake2Hasher::hash(&[7u8; 500]), len: 10 }.encode();
+		assert_eq!(ext.blob_get(&wrong_len), None);
+	}
+
+	#[test]
+	fn blobs_are_deleted_with_their_last_handle() {
+		let mut ext = TestExternalities::<Blake2Hasher>::default();
+		let mut ext = ext.ext();
+
+		let value = vec![7u8; 500];
+		let handle = ext.blob_put(value.clone());
+		assert_eq!(ext.blob_put(value.clone()), handle);
+
+		assert!(ext.blob_remove(&handle));
+		assert_eq!(ext.blob_get(&handle), Some(value));
+		assert!(ext.blob_remove(&handle));
+		assert_eq!(ext.blob_get(&handle), None);
+		assert!(!ext.blob_remove(&handle));
+		assert_eq!(ext.next_child_storage_key(&blob_child_info(), &[]), None);
+	}
+
+	#[test]
+	fn large_values_are_migrated() {
+		let mut ext = TestExternalities::<Blake2Hasher>::default();
+		let mut ext = ext.ext();
+		ext.set_storage(b"big".to_vec(), vec![1; 100]);
+		for i in 0u8..4 {
+			ext.set_storage(vec![b'b', b'i', b'g', i], vec![i; 10 + 50 * i as usize]);
+		}
+		ext.set_storage(b"other".to_vec(), vec![2; 100]);
+
+		let migration = LargeValueMigration { prefix: b"big".to_vec(), threshold: 32 };
+		let driver = MigrationDriver::new(vec![&migration], MigrationLimits { weight: 2, bytes: 1000 });
+		while driver.step(&mut ext).unwrap() != MigrationStatus::Complete {}
+
+		let handle = ext.storage(b"big").unwrap();
+		assert_eq!(ext.blob_get(&handle), Some(vec![1; 100]));
+		assert_eq!(ext.storage(&[b'b', b'i', b'g', 0]), Some(vec![0; 10]));
+		for i in 1u8..4 {
+			let handle = ext.storage(&[b'b', b'i', b'g', i]).unwrap();
+			assert_eq!(ext.blob_get(&handle), Some(vec![i; 10 + 50 * i as usize]));
+		}
+		assert_eq!(ext.storage(b"other"), Some(vec![2; 100]));
+	}
+
+	#[test]
+	fn value_that_overruns_a_step_is_migrated_in_the_next_one() {
+		let mut ext = TestExternalities::<Blake2Hasher>::default();
+		let mut ext = ext.ext();
+		for i in 0u8..3 {
+			ext.set_storage(vec![b'b', b'i', b'g', i], vec![i; 60]);
+		}
+
+		let migration = LargeValueMigration { prefix: b"big".to_vec(), threshold: 32 };
+		let driver = MigrationDriver::new(
+			vec![&migration],
+			MigrationLimits { weight: 10, bytes: 100 },
+		);
+		assert_eq!(driver.step(&mut ext), Ok(MigrationStatus::InProgress));
+		assert_eq!(driver.step(&mut ext), Ok(MigrationStatus::InProgress));
+		assert_eq!(driver.step(&mut ext), Ok(MigrationStatus::Complete));
+
+		for i in 0u8..3 {
+			let handle = ext.storage(&[b'b', b'i', b'g', i]).unwrap();
+			assert_eq!(ext.blob_get(&handle), Some(vec![i; 60]));
+		}
+	}
+
+	#[test]
+	fn handles_are_not_migrated_again() {
+		let mut ext = TestExternalities::<Blake2Hasher>::default();
+		let mut ext = ext.ext();
+		ext.set_storage(b"big".to_vec(), vec![1; 100]);
+
+		// Handles are larger than the threshold.
+		let migration = LargeValueMigration { prefix: b"big".to_vec(), threshold: 8 };
+		let limits = MigrationLimits { weight: 10, bytes: 1000 };
+		migration.migrate(&mut ext, None, limits).unwrap();
+		let handle = ext.storage(b"big").unwrap();
+		migration.migrate(&mut ext, None, limits).unwrap();
+
+		assert_eq!(ext.storage(b"big"), Some(handle.clone()));
+		assert_eq!(ext.blob_get(&handle), Some(vec![1; 100]));
+	}
+}
@@ -0,0 +1,243 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Execution of a whole block on top of the state of its parent.
+//!
+//! Bundles what every client does when importing a block: executing the block, optionally
+//! recording a storage proof and building the storage and changes trie transactions.
+
+use hash_db::Hasher;
+use codec::Codec;
+use sp_core::{
+	offchain::storage::OffchainOverlayedChanges,
+	traits::{CodeExecutor, RuntimeCode, SpawnNamed},
+};
+use sp_externalities::Extensions;
+use crate::{
+	Backend, Error, ExecutionError, ExecutionStrategy, OverlayedChanges, StateMachine,
+	StorageChanges, StorageProof, StorageTransactionCache, TrieBackend, UsageInfo,
+	changes_trie::{BlockNumber, State as ChangesTrieState},
+	proving_backend::ProvingBackend,
+	trie_backend_essence::TrieBackendStorage,
+};
+
+/// The runtime method that executes a block.
+pub const EXECUTE_BLOCK_METHOD: &str = "Core_execute_block";
+
+/// Options of [`execute_block_with_state`].
+pub struct ExecuteBlockOptions<'a, H: Hasher, N: BlockNumber> {
+	strategy: ExecutionStrategy,
+	record_proof: bool,
+	changes_trie_state: Option<ChangesTrieState<'a, H, N>>,
+	parent_hash: H::Out,
+}
+
+impl<'a, H: Hasher, N: BlockNumber> ExecuteBlockOptions<'a, H, N> {
+	/// Create the options for a block with the given `parent_hash`.
+	///
+	/// By default the block is executed with [`ExecutionStrategy::NativeElseWasm`], no proof
+	/// is recorded and no changes trie is built.
+	pub fn new(parent_hash: H::Out) -> Self {
+		ExecuteBlockOptions {
+			strategy: ExecutionStrategy::NativeElseWasm,
+			record_proof: false,
+			changes_trie_state: None,
+			parent_hash,
+		}
+	}
+
+	/// Execute the block with the given `strategy`.
+	pub fn with_strategy(mut self, strategy: ExecutionStrategy) -> Self {
+		self.strategy = strategy;
+		self
+	}
+
+	/// Record a proof of all trie nodes of the parent state that are accessed.
+	pub fn with_proof_recording(mut self) -> Self {
+		self.record_proof = true;
+		self
+	}
+
+	/// Build the changes trie of the block using the given state.
+	pub fn with_changes_trie_state(mut self, state: Option<ChangesTrieState<'a, H, N>>) -> Self {
+		self.changes_trie_state = state;
+		self
+	}
+}
+
+/// Everything needed to import an executed block.
+pub struct BlockImportOutcome<Transaction, H: Hasher, N: BlockNumber> {
+	/// The storage changes of the block, including the storage root, the backend transaction
+	/// and the changes trie transaction.
+	pub storage_changes: StorageChanges<Transaction, H, N>,
+	/// Proof of the accessed trie nodes, if recording was requested.
+	pub proof: Option<StorageProof>,
+	/// The usage statistics of the backend and the overlay.
+	pub stats: UsageInfo,
+}
+
+/// Execute the encoded `block` on top of `trie_backend`, the state of its parent.
+///
+/// The changes of the block are collected in `overlay`, which is drained afterwards. If the
+/// overlay already contains changes, they are part of the returned storage changes.
+pub fn execute_block_with_state<S, H, N, Exec, Spawn>(
+	trie_backend: &TrieBackend<S, H>,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	block: &[u8],
+	runtime_code: &RuntimeCode,
+	options: ExecuteBlockOptions<H, N>,
+) -> Result<BlockImportOutcome<S::Overlay, H, N>, Box<dyn Error>>
+where
+	S: TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Ord + 'static + Codec,
+	Exec: CodeExecutor + Clone + 'static,
+	N: BlockNumber,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	if !options.record_proof {
+		let (storage_changes, stats) = execute_block_on_backend(
+			trie_backend,
+			overlay,
+			exec,
+			spawn_handle,
+			block,
+			runtime_code,
+			&options,
+		)?;
+		return Ok(BlockImportOutcome { storage_changes, proof: None, stats })
+	}
+
+	let proving_backend = ProvingBackend::new(trie_backend);
+	let (storage_changes, stats) = execute_block_on_backend(
+		&proving_backend,
+		overlay,
+		exec,
+		spawn_handle,
+		block,
+		runtime_code,
+		&options,
+	)?;
+
+	Ok(BlockImportOutcome {
+		storage_changes,
+		proof: Some(proving_backend.extract_proof()),
+		stats,
+	})
+}
+
+fn execute_block_on_backend<B, H, N, Exec, Spawn>(
+	backend: &B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	block: &[u8],
+	runtime_code: &RuntimeCode,
+	options: &ExecuteBlockOptions<H, N>,
+) -> Result<(StorageChanges<B::Transaction, H, N>, UsageInfo), Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + 'static + Codec,
+	Exec: CodeExecutor + Clone + 'static,
+	N: BlockNumber,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let mut offchain_overlay = OffchainOverlayedChanges::default();
+	let mut cache = StorageTransactionCache::<_, H, N>::default();
+
+	StateMachine::new(
+		backend,
+		options.changes_trie_state.clone(),
+		overlay,
+		&mut offchain_overlay,
+		exec,
+		EXECUTE_BLOCK_METHOD,
+		block,
+		Extensions::default(),
+		runtime_code,
+		spawn_handle,
+	)
+		.with_storage_transaction_cache(Some(&mut cache))
+		.execute(options.strategy)?;
+
+	let mut stats = backend.usage_info();
+	stats.include_state_machine_states(overlay.stats());
+
+	let storage_changes = overlay.drain_storage_changes(
+		backend,
+		options.changes_trie_state.as_ref(),
+		options.parent_hash,
+		&mut cache,
+	).map_err(|e| Box::new(ExecutionError::Backend(e)) as Box<dyn Error>)?;
+
+	Ok((storage_changes, stats))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::testing::TaskExecutor;
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::{tests::DummyCodeExecutor, trie_backend::tests::test_trie};
+
+	#[test]
+	fn execute_block_with_state_works() {
+		let backend = test_trie();
+		let exec = DummyCodeExecutor {
+			change_changes_trie_config: true,
+			native_available: true,
+			native_succeeds: false,
+			fallback_succeeds: true,
+		};
+		let runtime_code = RuntimeCode::empty();
+
+		let outcome = execute_block_with_state::<_, BlakeTwo256, u64, _, _>(
+			&backend,
+			&mut OverlayedChanges::default(),
+			&exec,
+			TaskExecutor::new(),
+			&[0],
+			&runtime_code,
+			ExecuteBlockOptions::new(Default::default()).with_proof_recording(),
+		).unwrap();
+
+		let changes = &outcome.storage_changes;
+		assert_eq!(changes.main_storage_changes.len(), 1);
+		assert!(changes.changes_trie_transaction.is_none());
+		let delta = changes.main_storage_changes.iter()
+			.map(|(k, v)| (&k[..], v.as_ref().map(|v| &v[..])));
+		assert_eq!(changes.transaction_storage_root, backend.storage_root(delta).0);
+		assert!(!outcome.proof.unwrap().is_empty());
+		assert!(outcome.stats.overlay_writes.ops > 0);
+
+		let outcome = execute_block_with_state::<_, BlakeTwo256, u64, _, _>(
+			&backend,
+			&mut OverlayedChanges::default(),
+			&exec,
+			TaskExecutor::new(),
+			&[0],
+			&runtime_code,
+			ExecuteBlockOptions::new(Default::default())
+				.with_strategy(ExecutionStrategy::AlwaysWasm),
+		).unwrap();
+		assert!(outcome.proof.is_none());
+		assert_eq!(outcome.storage_changes.transaction_storage_root, changes.transaction_storage_root);
+	}
+}
@@ -0,0 +1,194 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memoization of runtime calls.
+
+use std::collections::{BTreeMap, HashMap};
+use parking_lot::Mutex;
+
+/// Identifies a runtime call whose result is cached.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CallKey {
+	/// Hash of the runtime code.
+	pub(crate) code_hash: Vec<u8>,
+	/// Number of heap pages the runtime is executed with.
+	pub(crate) heap_pages: Option<u64>,
+	/// The called method.
+	pub(crate) method: String,
+	/// The call data.
+	pub(crate) call_data: Vec<u8>,
+	/// The state root the call was executed on.
+	pub(crate) state_root: Vec<u8>,
+}
+
+impl CallKey {
+	fn size(&self) -> usize {
+		self.code_hash.len() + self.method.len() + self.call_data.len() + self.state_root.len()
+	}
+}
+
+struct CachedCall {
+	output: Vec<u8>,
+	last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+	entries: HashMap<CallKey, CachedCall>,
+	/// The keys of `entries` by their last use.
+	by_use: BTreeMap<u64, CallKey>,
+	tick: u64,
+	size: usize,
+}
+
+impl Inner {
+	fn remove(&mut self, key: &CallKey) {
+		if let Some(cached) = self.entries.remove(key) {
+			self.by_use.remove(&cached.last_used);
+			self.size -= key.size() + cached.output.len();
+		}
+	}
+}
+
+/// Cache of the encoded results of runtime calls.
+///
+/// A result is identified by the hash of the runtime code, the method, the call data and the
+/// state root of the backend. Results of another state root are never returned, so the cache
+/// doesn't need to be invalidated when the state changes. Entries of old states are simply not
+/// used anymore and are the first to be evicted when the cache exceeds its size bound.
+///
+/// Only calls that start on empty overlays and don't write to them are cached, see
+/// [`StateMachine::with_call_cache`](crate::StateMachine::with_call_cache).
+pub struct CallCache {
+	max_size: usize,
+	inner: Mutex<Inner>,
+}
+
+impl CallCache {
+	/// Create a new cache that holds at most `max_size` bytes of keys and results.
+	pub fn new(max_size: usize) -> Self {
+		CallCache {
+			max_size,
+			inner: Default::default(),
+		}
+	}
+
+	/// Returns the number of cached results.
+	pub fn len(&self) -> usize {
+		self.inner.lock().entries.len()
+	}
+
+	/// Returns `true` if no result is cached.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Returns the size of the cached keys and results in bytes.
+	pub fn size(&self) -> usize {
+		self.inner.lock().size
+	}
+
+	/// Remove all cached results.
+	pub fn clear(&self) {
+		*self.inner.lock() = Default::default();
+	}
+
+	/// Returns the cached result of the call with the given `key`.
+	pub(crate) fn get(&self, key: &CallKey) -> Option<Vec<u8>> {
+		let mut inner = self.inner.lock();
+		let inner = &mut *inner;
+		let tick = inner.tick;
+		let cached = inner.entries.get_mut(key)?;
+
+		inner.by_use.remove(&cached.last_used);
+		inner.by_use.insert(tick, key.clone());
+		cached.last_used = tick;
+		inner.tick += 1;
+		Some(cached.output.clone())
+	}
+
+	/// Cache the `output` of the call with the given `key`.
+	///
+	/// The least recently used results are evicted to stay in the size bound. Results that are
+	/// bigger than the whole cache are not cached.
+	pub(crate) fn insert(&self, key: CallKey, output: Vec<u8>) {
+		let size = key.size() + output.len();
+		if size > self.max_size {
+			return
+		}
+
+		let mut inner = self.inner.lock();
+		inner.remove(&key);
+		while inner.size + size > self.max_size {
+			let oldest = match inner.by_use.values().next() {
+				Some(oldest) => oldest.clone(),
+				None => break,
+			};
+			inner.remove(&oldest);
+		}
+
+		let tick = inner.tick;
+		inner.tick += 1;
+		inner.size += size;
+		inner.by_use.insert(tick, key.clone());
+		inner.entries.insert(key, CachedCall { output, last_used: tick });
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key(method: &str, state_root: u8) -> CallKey {
+		CallKey {
+			code_hash: vec![1],
+			heap_pages: None,
+			method: method.into(),
+			call_data: Vec::new(),
+			state_root: vec![state_root],
+		}
+	}
+
+	#[test]
+	fn results_are_cached_per_state_root() {
+		let cache = CallCache::new(1024);
+		cache.insert(key("Core_version", 1), vec![1, 2, 3]);
+
+		assert_eq!(cache.get(&key("Core_version", 1)), Some(vec![1, 2, 3]));
+		assert_eq!(cache.get(&key("Core_version", 2)), None);
+		assert_eq!(cache.get(&key("Metadata_metadata", 1)), None);
+	}
+
+	#[test]
+	fn least_recently_used_results_are_evicted() {
+		// Every entry takes 16 bytes, 15 for the key and 1 for the result.
+		let cache = CallCache::new(40);
+		cache.insert(key("method_a_____", 1), vec![0; 1]);
+		cache.insert(key("method_b_____", 1), vec![0; 1]);
+		assert!(cache.get(&key("method_a_____", 1)).is_some());
+
+		cache.insert(key("method_c_____", 1), vec![0; 1]);
+		assert_eq!(cache.len(), 2);
+		assert_eq!(cache.size(), 32);
+		assert!(cache.get(&key("method_a_____", 1)).is_some());
+		assert!(cache.get(&key("method_b_____", 1)).is_none());
+		assert!(cache.get(&key("method_c_____", 1)).is_some());
+
+		cache.insert(key("too_big", 1), vec![0; 40]);
+		assert_eq!(cache.len(), 2);
+	}
+}
@@ -0,0 +1,85 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cancellation of runtime calls.
+
+use std::{
+	sync::{Arc, atomic::{AtomicBool, Ordering}},
+	time::{Duration, Instant},
+};
+
+/// Token that can be used to interrupt a call into the runtime.
+///
+/// The token is checked by [`Ext`](crate::Ext) every time the runtime calls into the host.
+/// Clones of the token share the cancellation state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+	cancelled: Arc<AtomicBool>,
+	deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+	/// Create a new token without a deadline.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Create a new token that is cancelled automatically at `deadline`.
+	pub fn with_deadline(deadline: Instant) -> Self {
+		CancellationToken {
+			cancelled: Default::default(),
+			deadline: Some(deadline),
+		}
+	}
+
+	/// Create a new token that is cancelled automatically after `timeout`.
+	pub fn with_timeout(timeout: Duration) -> Self {
+		Self::with_deadline(Instant::now() + timeout)
+	}
+
+	/// Cancel the call this token belongs to.
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::Relaxed);
+	}
+
+	/// Returns `true` if the token was cancelled or the deadline is reached.
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::Relaxed)
+			|| self.deadline.map_or(false, |deadline| Instant::now() >= deadline)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cancel_is_shared_between_clones() {
+		let token = CancellationToken::new();
+		let clone = token.clone();
+		assert!(!token.is_cancelled());
+
+		clone.cancel();
+		assert!(token.is_cancelled());
+	}
+
+	#[test]
+	fn deadline_cancels_token() {
+		assert!(CancellationToken::with_deadline(Instant::now()).is_cancelled());
+		assert!(!CancellationToken::with_timeout(Duration::from_secs(3600)).is_cancelled());
+	}
+}
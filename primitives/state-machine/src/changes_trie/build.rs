@@ -17,11 +17,12 @@
 
 //! Structures and functions required to build changes trie for given block.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::collections::btree_map::Entry;
 use codec::{Decode, Encode};
 use hash_db::Hasher;
 use num_traits::One;
+use rayon::{ThreadPool, prelude::*};
 use crate::{
 	StorageKey,
 	backend::Backend,
@@ -45,6 +46,7 @@ pub(crate) fn prepare_input<'a, B, H, Number>(
 	config: ConfigurationRange<'a, Number>,
 	overlay: &'a OverlayedChanges,
 	parent: &'a AnchorBlockId<H::Out, Number>,
+	thread_pool: Option<&ThreadPool>,
 ) -> Result<(
 		impl Iterator<Item=InputPair<Number>> + 'a,
 		Vec<(ChildIndex<Number>, impl Iterator<Item=InputPair<Number>> + 'a)>,
@@ -67,6 +69,7 @@ pub(crate) fn prepare_input<'a, B, H, Number>(
 		config,
 		number,
 		storage,
+		thread_pool,
 	)?;
 
 	let mut children_digest = Vec::with_capacity(children_extrinsics_input.len());
@@ -184,12 +187,19 @@ fn prepare_extrinsics_input_inner<'a, B, H, Number>(
 }
 
 
+/// The keys changed in a single block, for the main trie and every child trie.
+type BlockChangedKeys = (BTreeSet<StorageKey>, BTreeMap<PrefixedStorageKey, BTreeSet<StorageKey>>);
+
 /// Prepare DigestIndex input pairs.
+///
+/// The changed keys of the covered blocks are collected on the given `thread_pool`, if any.
+/// The resulting pairs don't depend on it.
 fn prepare_digest_input<'a, H, Number>(
 	parent: &'a AnchorBlockId<H::Out, Number>,
 	config: ConfigurationRange<Number>,
 	block: Number,
 	storage: &'a dyn Storage<H, Number>,
+	thread_pool: Option<&ThreadPool>,
 ) -> Result<(
 		impl Iterator<Item=InputPair<Number>> + 'a,
 		BTreeMap<ChildIndex<Number>, impl Iterator<Item=InputPair<Number>> + 'a>,
@@ -210,117 +220,137 @@ fn prepare_digest_input<'a, H, Number>(
 	};
 
 	let digest_input_blocks = digest_build_iterator(config, block_for_digest).collect::<Vec<_>>();
-	digest_input_blocks.clone().into_iter()
-		.try_fold(
-			(BTreeMap::new(), BTreeMap::new()), move |(mut map, mut child_map), digest_build_block| {
-			let extrinsic_prefix = ExtrinsicIndex::key_neutral_prefix(digest_build_block.clone());
-			let digest_prefix = DigestIndex::key_neutral_prefix(digest_build_block.clone());
-			let child_prefix = ChildIndex::key_neutral_prefix(digest_build_block.clone());
-			let trie_root = storage.root(parent, digest_build_block.clone())?;
-			let trie_root = trie_root.ok_or_else(|| format!("No changes trie root for block {}", digest_build_block.clone()))?;
-
-			let insert_to_map = |map: &mut BTreeMap<_,_>, key: StorageKey| {
-				match map.entry(key.clone()) {
-					Entry::Vacant(entry) => {
-						entry.insert((DigestIndex {
-							block: block.clone(),
-							key,
-						}, vec![digest_build_block.clone()]));
-					},
-					Entry::Occupied(mut entry) => {
-						// DigestIndexValue must be sorted. Here we are relying on the fact that digest_build_iterator()
-						// returns blocks in ascending order => we only need to check for duplicates
-						//
-						// is_dup_block could be true when key has been changed in both digest block
-						// AND other blocks that it covers
-						let is_dup_block = entry.get().1.last() == Some(&digest_build_block);
-						if !is_dup_block {
-							entry.get_mut().1.push(digest_build_block.clone());
-						}
-					},
-				}
-			};
+	let changed_keys = |digest_build_block: &Number|
+		block_changed_keys::<H, Number>(parent, storage, digest_build_block.clone());
+	let blocks_changed_keys = match thread_pool {
+		Some(thread_pool) => thread_pool.install(||
+			digest_input_blocks.par_iter().map(changed_keys).collect::<Result<Vec<_>, _>>()
+		)?,
+		None => digest_input_blocks.iter().map(changed_keys).collect::<Result<Vec<_>, _>>()?,
+	};
 
-			// try to get all updated keys from cache
-			let populated_from_cache = storage.with_cached_changed_keys(
-				&trie_root,
-				&mut |changed_keys| {
-					for (storage_key, changed_keys) in changed_keys {
-						let map = match storage_key {
-							Some(storage_key) => child_map
-								.entry(ChildIndex::<Number> {
-									block: block.clone(),
-									storage_key: storage_key.clone(),
-								})
-								.or_default(),
-							None => &mut map,
-						};
-						for changed_key in changed_keys.iter().cloned() {
-							insert_to_map(map, changed_key);
-						}
-					}
-				}
-			);
-			if populated_from_cache {
-				return Ok((map, child_map));
+	let mut map = BTreeMap::new();
+	let mut child_map = BTreeMap::new();
+	let insert_to_map = |map: &mut BTreeMap<_, _>, key: StorageKey, digest_build_block: &Number| {
+		map.entry(key.clone())
+			.or_insert_with(|| (DigestIndex { block: block.clone(), key }, Vec::new()))
+			.1.push(digest_build_block.clone());
+	};
+	// DigestIndexValue must be sorted. Here we are relying on the fact that digest_build_iterator()
+	// returns blocks in ascending order and that the changed keys of every block are unique.
+	for (digest_build_block, (keys, child_keys)) in digest_input_blocks.iter().zip(blocks_changed_keys) {
+		for key in keys {
+			insert_to_map(&mut map, key, digest_build_block);
+		}
+		for (storage_key, keys) in child_keys {
+			let child_index = ChildIndex::<Number> {
+				block: block.clone(),
+				storage_key,
+			};
+			let map = child_map.entry(child_index).or_insert_with(BTreeMap::new);
+			for key in keys {
+				insert_to_map(map, key, digest_build_block);
 			}
+		}
+	}
 
-			let mut children_roots = BTreeMap::<PrefixedStorageKey, _>::new();
-			{
-				let trie_storage = TrieBackendEssence::<_, H>::new(
-					crate::changes_trie::TrieBackendStorageAdapter(storage),
-					trie_root,
-				);
-
-				trie_storage.for_key_values_with_prefix(&child_prefix, |key, value|
-					if let Ok(InputKey::ChildIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
-						if let Ok(value) = <Vec<u8>>::decode(&mut &value[..]) {
-							let mut trie_root = <H as Hasher>::Out::default();
-							trie_root.as_mut().copy_from_slice(&value[..]);
-							children_roots.insert(trie_key.storage_key, trie_root);
-						}
-					});
+	Ok((
+		map.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)),
+		child_map.into_iter().map(|(sk, pairs)|
+			(sk, pairs.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)))).collect(),
+		digest_input_blocks,
+	))
+}
 
-				trie_storage.for_keys_with_prefix(&extrinsic_prefix, |key|
-					if let Ok(InputKey::ExtrinsicIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
-						insert_to_map(&mut map, trie_key.key);
-					});
+/// Collect the keys changed in the given `digest_build_block`.
+fn block_changed_keys<H, Number>(
+	parent: &AnchorBlockId<H::Out, Number>,
+	storage: &dyn Storage<H, Number>,
+	digest_build_block: Number,
+) -> Result<BlockChangedKeys, String>
+	where
+		H: Hasher,
+		H::Out: Encode,
+		Number: BlockNumber,
+{
+	let extrinsic_prefix = ExtrinsicIndex::key_neutral_prefix(digest_build_block.clone());
+	let digest_prefix = DigestIndex::key_neutral_prefix(digest_build_block.clone());
+	let child_prefix = ChildIndex::key_neutral_prefix(digest_build_block.clone());
+	let cached_root = storage.roots_cache()
+		.and_then(|cache| cache.get(parent, &digest_build_block));
+	let trie_root = match cached_root {
+		Some(trie_root) => trie_root,
+		None => {
+			let trie_root = storage.root(parent, digest_build_block.clone())?;
+			trie_root.ok_or_else(|| format!("No changes trie root for block {}", digest_build_block.clone()))?
+		},
+	};
 
-				trie_storage.for_keys_with_prefix(&digest_prefix, |key|
-					if let Ok(InputKey::DigestIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
-						insert_to_map(&mut map, trie_key.key);
-					});
+	let mut keys = BTreeSet::new();
+	let mut child_keys = BTreeMap::<PrefixedStorageKey, BTreeSet<StorageKey>>::new();
+
+	// try to get all updated keys from cache
+	let populated_from_cache = storage.with_cached_changed_keys(
+		&trie_root,
+		&mut |changed_keys| {
+			for (storage_key, changed_keys) in changed_keys {
+				let keys = match storage_key {
+					Some(storage_key) => child_keys.entry(storage_key.clone()).or_default(),
+					None => &mut keys,
+				};
+				keys.extend(changed_keys.iter().cloned());
 			}
+		}
+	);
+	if populated_from_cache {
+		return Ok((keys, child_keys));
+	}
 
-			for (storage_key, trie_root) in children_roots.into_iter() {
-				let child_index = ChildIndex::<Number> {
-					block: block.clone(),
-					storage_key,
-				};
+	let mut children_roots = BTreeMap::<PrefixedStorageKey, _>::new();
+	{
+		let trie_storage = TrieBackendEssence::<_, H>::new(
+			crate::changes_trie::TrieBackendStorageAdapter(storage),
+			trie_root,
+		);
 
-				let mut map = child_map.entry(child_index).or_default();
-				let trie_storage = TrieBackendEssence::<_, H>::new(
-					crate::changes_trie::TrieBackendStorageAdapter(storage),
-					trie_root,
-				);
-				trie_storage.for_keys_with_prefix(&extrinsic_prefix, |key|
-					if let Ok(InputKey::ExtrinsicIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
-						insert_to_map(&mut map, trie_key.key);
-					});
-
-				trie_storage.for_keys_with_prefix(&digest_prefix, |key|
-					if let Ok(InputKey::DigestIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
-						insert_to_map(&mut map, trie_key.key);
-					});
-			}
-			Ok((map, child_map))
-		})
-		.map(|(pairs, child_pairs)| (
-			pairs.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)),
-			child_pairs.into_iter().map(|(sk, pairs)|
-				(sk, pairs.into_iter().map(|(_, (k, v))| InputPair::DigestIndex(k, v)))).collect(),
-			digest_input_blocks,
-		))
+		trie_storage.for_key_values_with_prefix(&child_prefix, |key, value|
+			if let Ok(InputKey::ChildIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
+				if let Ok(value) = <Vec<u8>>::decode(&mut &value[..]) {
+					let mut trie_root = <H as Hasher>::Out::default();
+					trie_root.as_mut().copy_from_slice(&value[..]);
+					children_roots.insert(trie_key.storage_key, trie_root);
+				}
+			});
+
+		trie_storage.for_keys_with_prefix(&extrinsic_prefix, |key|
+			if let Ok(InputKey::ExtrinsicIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
+				keys.insert(trie_key.key);
+			});
+
+		trie_storage.for_keys_with_prefix(&digest_prefix, |key|
+			if let Ok(InputKey::DigestIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
+				keys.insert(trie_key.key);
+			});
+	}
+
+	for (storage_key, trie_root) in children_roots.into_iter() {
+		let keys = child_keys.entry(storage_key).or_default();
+		let trie_storage = TrieBackendEssence::<_, H>::new(
+			crate::changes_trie::TrieBackendStorageAdapter(storage),
+			trie_root,
+		);
+		trie_storage.for_keys_with_prefix(&extrinsic_prefix, |key|
+			if let Ok(InputKey::ExtrinsicIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
+				keys.insert(trie_key.key);
+			});
+
+		trie_storage.for_keys_with_prefix(&digest_prefix, |key|
+			if let Ok(InputKey::DigestIndex::<Number>(trie_key)) = Decode::decode(&mut &key[..]) {
+				keys.insert(trie_key.key);
+			});
+	}
+
+	Ok((keys, child_keys))
 }
 
 #[cfg(test)]
@@ -460,6 +490,7 @@ mod test {
 				configuration_range(&config, zero),
 				&changes,
 				&parent,
+				None,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
 				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 5, key: vec![100] }, vec![0, 2, 3]),
@@ -498,6 +529,7 @@ mod test {
 				configuration_range(&config, zero),
 				&changes,
 				&parent,
+				None,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
 				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2, 3]),
@@ -532,6 +564,30 @@ mod test {
 		test_with_zero(17);
 	}
 
+	#[test]
+	fn digest_input_is_the_same_with_thread_pool() {
+		let (backend, storage, changes, config) = prepare_for_build(0);
+		let parent = AnchorBlockId { hash: Default::default(), number: 15 };
+		let thread_pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+		let input = |thread_pool| {
+			let (top, children, blocks) = prepare_input(
+				&backend,
+				&storage,
+				configuration_range(&config, 0),
+				&changes,
+				&parent,
+				thread_pool,
+			).unwrap();
+			(
+				top.collect::<Vec<InputPair<u64>>>(),
+				children.into_iter().map(|(k, v)| (k, v.collect::<Vec<_>>())).collect::<Vec<_>>(),
+				blocks,
+			)
+		};
+
+		assert_eq!(input(Some(&thread_pool)), input(None));
+	}
+
 	#[test]
 	fn build_changes_trie_nodes_on_digest_block_l2() {
 		fn test_with_zero(zero: u64) {
@@ -545,6 +601,7 @@ mod test {
 				configuration_range(&config, zero),
 				&changes,
 				&parent,
+				None,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
 				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 16, key: vec![100] }, vec![0, 2, 3]),
@@ -590,6 +647,7 @@ mod test {
 				configuration_range.clone(),
 				&changes,
 				&parent,
+				None,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
 				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![100] }, vec![0, 2, 3]),
@@ -604,6 +662,7 @@ mod test {
 				configuration_range,
 				&changes,
 				&parent,
+				None,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
 				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 11, key: vec![100] }, vec![0, 2, 3]),
@@ -640,6 +699,7 @@ mod test {
 				configuration_range(&config, zero),
 				&changes,
 				&parent,
+				None,
 			).unwrap();
 			assert_eq!(changes_trie_nodes.0.collect::<Vec<InputPair<u64>>>(), vec![
 				InputPair::ExtrinsicIndex(ExtrinsicIndex { block: zero + 4, key: vec![100] }, vec![0, 2, 3]),
@@ -709,6 +769,7 @@ mod test {
 			configuration_range(&config, 0),
 			&changes,
 			&parent,
+			None,
 		).unwrap();
 		assert_eq!(root_changes_trie_nodes.collect::<Vec<InputPair<u64>>>(), vec![
 			InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 16, key: vec![100] }, vec![0, 2, 3]),
@@ -95,6 +95,14 @@ impl<H, N> BuildCache<H, N>
 		self.changed_keys.get(&root)
 	}
 
+	/// Get cached changed keys for changes trie of the block with given number.
+	///
+	/// Only the most recently cached changes trie of the given block number is known, so on forks
+	/// the entry may belong to a block of another branch. Use `get` if the root is known.
+	pub fn get_at_block(&self, block: &N) -> Option<&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>> {
+		self.roots_by_number.get(block).and_then(|root| self.changed_keys.get(root))
+	}
+
 	/// Execute given functor with cached entry for given block.
 	/// Returns true if the functor has been called and false otherwise.
 	pub fn with_changed_keys(
@@ -229,6 +237,23 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn changed_keys_are_found_by_block() {
+		let mut cache = BuildCache::<u32, u32>::new();
+		cache.perform(CacheAction::CacheBuildData(IncompleteCachedBuildData::new()
+			.insert(None, vec![vec![1]].into_iter().collect())
+			.complete(1, 10)));
+
+		assert_eq!(cache.get_at_block(&1), cache.get(&10));
+		assert!(cache.get_at_block(&1).is_some());
+		assert!(cache.get_at_block(&10).is_none());
+
+		cache.perform(CacheAction::CacheBuildData(IncompleteCachedBuildData::new()
+			.set_digest_input_blocks(vec![1])
+			.complete(4, 40)));
+		assert!(cache.get_at_block(&1).is_none());
+	}
+
 	#[test]
 	fn obsolete_entries_are_purged_when_new_ct_is_built() {
 		let mut cache = BuildCache::<u32, u32>::new();
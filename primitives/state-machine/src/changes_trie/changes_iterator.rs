@@ -22,7 +22,7 @@ use std::cell::RefCell;
 use std::collections::VecDeque;
 use codec::{Decode, Encode, Codec};
 use hash_db::Hasher;
-use num_traits::Zero;
+use num_traits::{One, Zero};
 use sp_core::storage::PrefixedStorageKey;
 use sp_trie::Recorder;
 use crate::changes_trie::{AnchorBlockId, ConfigurationRange, RootsStorage, Storage, BlockNumber};
@@ -66,6 +66,7 @@ pub fn key_changes<'a, H: Hasher, Number: BlockNumber>(
 
 			extrinsics: Default::default(),
 			blocks: Default::default(),
+			missing: None,
 
 			_hasher: ::std::marker::PhantomData::<H>::default(),
 		},
@@ -105,6 +106,7 @@ pub fn key_changes_proof<'a, H: Hasher, Number: BlockNumber>(
 
 			extrinsics: Default::default(),
 			blocks: Default::default(),
+			missing: None,
 
 			_hasher: ::std::marker::PhantomData::<H>::default(),
 		},
@@ -176,12 +178,45 @@ pub fn key_changes_proof_check_with_db<'a, H: Hasher, Number: BlockNumber>(
 
 			extrinsics: Default::default(),
 			blocks: Default::default(),
+			missing: None,
 
 			_hasher: ::std::marker::PhantomData::<H>::default(),
 		},
 	}.collect()
 }
 
+/// Range of blocks (inclusive) where the changes of a key are unknown, because the changes
+/// tries covering this range are missing from the storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingRange<Number> {
+	/// First block of the range.
+	pub begin: Number,
+	/// Last block of the range.
+	pub end: Number,
+}
+
+/// Similar to the `key_changes` function, but tolerates missing (e.g. pruned) changes tries.
+///
+/// Returns changes of given key at given blocks range that could be found, and the block
+/// ranges covered by the changes tries that are missing from the storage. Both are returned
+/// in descending order (i.e. last block comes first). Only fails if the range is invalid.
+pub fn key_changes_tolerating_missing<'a, H: Hasher, Number: BlockNumber>(
+	config: ConfigurationRange<'a, Number>,
+	storage: &'a dyn Storage<H, Number>,
+	begin: Number,
+	end: &'a AnchorBlockId<H::Out, Number>,
+	max: Number,
+	storage_key: Option<&'a PrefixedStorageKey>,
+	key: &'a [u8],
+) -> Result<(Vec<(Number, u32)>, Vec<MissingRange<Number>>), String> where H::Out: Encode {
+	let mut iter = key_changes(config, storage, begin, end, max, storage_key, key)?;
+	iter.essence.missing = Some(Vec::new());
+
+	let changes = iter.by_ref().collect::<Result<Vec<_>, _>>()?;
+	let missing = iter.essence.missing.take().unwrap_or_default();
+	Ok((changes, missing))
+}
+
 /// Drilldown iterator - receives 'digest points' from surface iterator and explores
 /// every point until extrinsic is found.
 pub struct DrilldownIteratorEssence<'a, H, Number>
@@ -201,6 +236,8 @@ pub struct DrilldownIteratorEssence<'a, H, Number>
 
 	extrinsics: VecDeque<(Number, u32)>,
 	blocks: VecDeque<(Number, Option<u32>)>,
+	/// Ranges of the missing changes tries. `None` if missing changes tries are an error.
+	missing: Option<Vec<MissingRange<Number>>>,
 
 	_hasher: ::std::marker::PhantomData<H>,
 }
@@ -235,14 +272,29 @@ impl<'a, H, Number> DrilldownIteratorEssence<'a, H, Number>
 				// not having a changes trie root is an error because:
 				// we never query roots for future blocks
 				// AND trie roots for old blocks are known (both on full + light node)
-				let trie_root = self.roots_storage.root(&self.end, block.clone())?
-					.ok_or_else(|| format!("Changes trie root for block {} is not found", block.clone()))?;
+				let trie_root = match self.roots_storage.root(&self.end, block.clone())
+					.and_then(|root| root.ok_or_else(||
+						format!("Changes trie root for block {} is not found", block.clone())))
+				{
+					Ok(trie_root) => trie_root,
+					Err(err) => {
+						self.mark_missing(block, level, err)?;
+						continue;
+					},
+				};
 				let trie_root = if let Some(storage_key) = self.storage_key {
 					let child_key = ChildIndex {
 						block: block.clone(),
 						storage_key: storage_key.clone(),
 					}.encode();
-					if let Some(trie_root) = trie_reader(self.storage, trie_root, &child_key)?
+					let child_root = match trie_reader(self.storage, trie_root, &child_key) {
+						Ok(child_root) => child_root,
+						Err(err) => {
+							self.mark_missing(block, level, err)?;
+							continue;
+						},
+					};
+					if let Some(trie_root) = child_root
 						.and_then(|v| <Vec<u8>>::decode(&mut &v[..]).ok())
 						.map(|v| {
 							let mut hash = H::Out::default();
@@ -261,19 +313,30 @@ impl<'a, H, Number> DrilldownIteratorEssence<'a, H, Number>
 				// most of blocks will be filtered out before pushing to `self.blocks`
 				// here we just throwing away changes at digest blocks we're processing
 				debug_assert!(block >= self.begin, "We shall not touch digests earlier than a range' begin");
-				if block <= self.end.number {
+				let extrinsics = if block <= self.end.number {
 					let extrinsics_key = ExtrinsicIndex { block: block.clone(), key: self.key.to_vec() }.encode();
-					let extrinsics = trie_reader(self.storage, trie_root, &extrinsics_key);
-					if let Some(extrinsics) = extrinsics? {
-						if let Ok(extrinsics) = ExtrinsicIndexValue::decode(&mut &extrinsics[..]) {
-							self.extrinsics.extend(extrinsics.into_iter().rev().map(|e| (block.clone(), e)));
-						}
+					trie_reader(self.storage, trie_root, &extrinsics_key)
+				} else {
+					Ok(None)
+				};
+				let blocks_key = DigestIndex { block: block.clone(), key: self.key.to_vec() }.encode();
+				let blocks = extrinsics.and_then(|extrinsics|
+					trie_reader(self.storage, trie_root, &blocks_key).map(|blocks| (extrinsics, blocks)));
+				let (extrinsics, blocks) = match blocks {
+					Ok(values) => values,
+					Err(err) => {
+						self.mark_missing(block, level, err)?;
+						continue;
+					},
+				};
+
+				if let Some(extrinsics) = extrinsics {
+					if let Ok(extrinsics) = ExtrinsicIndexValue::decode(&mut &extrinsics[..]) {
+						self.extrinsics.extend(extrinsics.into_iter().rev().map(|e| (block.clone(), e)));
 					}
 				}
 
-				let blocks_key = DigestIndex { block: block.clone(), key: self.key.to_vec() }.encode();
-				let blocks = trie_reader(self.storage, trie_root, &blocks_key);
-				if let Some(blocks) = blocks? {
+				if let Some(blocks) = blocks {
 					if let Ok(blocks) = <DigestIndexValue<Number>>::decode(&mut &blocks[..]) {
 						// filter level0 blocks here because we tend to use digest blocks,
 						// AND digest block changes could also include changes for out-of-range blocks
@@ -306,6 +369,41 @@ impl<'a, H, Number> DrilldownIteratorEssence<'a, H, Number>
 			}
 		}
 	}
+
+	/// Remember that the changes trie of given block is missing.
+	///
+	/// Returns the error back if missing changes tries are not tolerated.
+	fn mark_missing(&mut self, block: Number, level: Option<u32>, err: String) -> Result<(), String> {
+		let config = &self.config;
+		let begin = match level {
+			Some(0) => block.clone(),
+			Some(level) => {
+				let digest_interval = (0..level)
+					.fold(1u32, |interval, _| interval.saturating_mul(config.config.digest_interval));
+				let digest_interval = Number::from(digest_interval);
+				if block > digest_interval {
+					block.clone() - digest_interval + One::one()
+				} else {
+					One::one()
+				}
+			},
+			// skewed digest covers all blocks since the previous max-level digest
+			None => config.config.prev_max_level_digest_block(config.zero.clone(), block.clone())
+				.filter(|prev_digest| *prev_digest != block)
+				.unwrap_or_else(|| config.zero.clone()) + One::one(),
+		};
+
+		match self.missing {
+			Some(ref mut missing) => {
+				missing.push(MissingRange {
+					begin: std::cmp::max(begin, self.begin.clone()),
+					end: std::cmp::min(block, self.end.number.clone()),
+				});
+				Ok(())
+			},
+			None => Err(err),
+		}
+	}
 }
 
 /// Exploring drilldown operator.
@@ -547,6 +645,54 @@ mod tests {
 		).and_then(|i| i.collect::<Result<Vec<_>, _>>()).is_err());
 	}
 
+	#[test]
+	fn drilldown_iterator_tolerates_missing_tries() {
+		let (config, storage) = prepare_for_drilldown();
+		let all_roots = (1..=16).map(|block| {
+			let anchor = AnchorBlockId { hash: Default::default(), number: 16 };
+			storage.root(&anchor, block).unwrap().unwrap()
+		}).collect::<Vec<_>>();
+
+		// nothing is missing
+		let result = key_changes_tolerating_missing::<BlakeTwo256, u64>(
+			configuration_range(&config, 0),
+			&storage,
+			1,
+			&AnchorBlockId { hash: Default::default(), number: 16 },
+			16,
+			None,
+			&[42],
+		);
+		assert_eq!(result, Ok((vec![(8, 2), (8, 1), (6, 3), (3, 0)], vec![])));
+
+		// prune the level 1 digest trie of block 4
+		storage.remove_from_storage(&vec![all_roots[3]].into_iter().collect());
+		let result = key_changes_tolerating_missing::<BlakeTwo256, u64>(
+			configuration_range(&config, 0),
+			&storage,
+			1,
+			&AnchorBlockId { hash: Default::default(), number: 16 },
+			16,
+			None,
+			&[42],
+		);
+		assert_eq!(result, Ok((
+			vec![(8, 2), (8, 1), (6, 3)],
+			vec![MissingRange { begin: 1, end: 4 }],
+		)));
+
+		// the regular iterator still fails
+		assert!(key_changes::<BlakeTwo256, u64>(
+			configuration_range(&config, 0),
+			&storage,
+			1,
+			&AnchorBlockId { hash: Default::default(), number: 16 },
+			16,
+			None,
+			&[42],
+		).and_then(|i| i.collect::<Result<Vec<_>, _>>()).is_err());
+	}
+
 	#[test]
 	fn drilldown_iterator_fails_when_range_is_invalid() {
 		let (config, storage) = prepare_for_drilldown();
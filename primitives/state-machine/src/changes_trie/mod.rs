@@ -55,14 +55,17 @@ mod build_iterator;
 mod changes_iterator;
 mod input;
 mod prune;
+mod roots_cache;
 mod storage;
 mod surface_iterator;
 
 pub use self::build_cache::{BuildCache, CachedBuildData, CacheAction};
 pub use self::storage::InMemoryStorage;
+pub use self::roots_cache::{RootsCache, DEFAULT_ROOTS_CACHE_SIZE};
 pub use self::changes_iterator::{
 	key_changes, key_changes_proof,
 	key_changes_proof_check, key_changes_proof_check_with_db,
+	key_changes_tolerating_missing, MissingRange,
 };
 pub use self::prune::prune;
 
@@ -162,6 +165,38 @@ pub trait Storage<H: Hasher, Number: BlockNumber>: RootsStorage<H, Number> {
 	) -> bool;
 	/// Get a trie node.
 	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String>;
+	/// Get the cache of the changes trie roots of recent blocks, if any.
+	fn roots_cache(&self) -> Option<&RootsCache<H::Out, Number>> {
+		None
+	}
+}
+
+/// Changes trie storage that maintains the changes tries of imported blocks.
+///
+/// [`Storage`] only gives read access to the changes tries. This is the contract a
+/// persistent storage, e.g. the one of the client database, has to implement to also
+/// insert and prune them.
+pub trait PersistentStorage<H: Hasher, Number: BlockNumber>: Storage<H, Number> {
+	/// Insert the changes trie of the given `block` with the given `root`.
+	///
+	/// The `transaction` holds the nodes of the trie, as built by [`build_changes_trie`].
+	fn insert_transaction(
+		&self,
+		block: AnchorBlockId<H::Out, Number>,
+		root: H::Out,
+		transaction: MemoryDB<H>,
+	) -> Result<(), String>;
+	/// Get the changes trie root of the given block of the canonical chain.
+	fn root_by_block(&self, block: &Number) -> Result<Option<H::Out>, String>;
+	/// Remove the changes tries of the blocks in the range `first..=last`.
+	///
+	/// See [`prune`] for the meaning of the arguments.
+	fn prune(
+		&self,
+		first: Number,
+		last: Number,
+		current_block: &AnchorBlockId<H::Out, Number>,
+	) -> Result<(), String>;
 }
 
 /// Changes trie storage -> trie backend essence adapter.
@@ -223,12 +258,16 @@ pub fn disabled_state<'a, H, Number>() -> Option<State<'a, H, Number>> {
 /// Returns Err(()) if unknown `parent_hash` has been passed.
 /// Returns Ok(None) if there's no data to perform computation.
 /// Panics if background storage returns an error OR if insert to MemoryDB fails.
+///
+/// If a `thread_pool` is given, the input of digest tries is collected from the covered
+/// blocks in parallel. The resulting trie is the same in both cases.
 pub fn build_changes_trie<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
 	backend: &B,
 	state: Option<&'a State<'a, H, Number>>,
 	changes: &OverlayedChanges,
 	parent_hash: H::Out,
 	panic_on_storage_error: bool,
+	thread_pool: Option<&rayon::ThreadPool>,
 ) -> Result<Option<(MemoryDB<H>, H::Out, CacheAction<H::Out, Number>)>, ()>
 	where
 		H::Out: Ord + 'static + Encode,
@@ -277,6 +316,7 @@ pub fn build_changes_trie<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
 			config_range.clone(),
 			changes,
 			&parent,
+			thread_pool,
 		),
 		panic_on_storage_error,
 	)?;
@@ -344,6 +384,27 @@ pub fn build_changes_trie<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
 	Ok(Some((mdb, root, cache_action)))
 }
 
+/// Compute the changes trie transaction for the block built on top of `parent_hash`.
+///
+/// Unlike `build_changes_trie`, this never panics on storage errors and does not require
+/// a changes trie [`State`], so the changes trie may be built outside of block execution and
+/// committed later. Returns Err(()) if the changes trie could not be built, e.g. because an
+/// unknown `parent_hash` has been passed or the storage failed.
+pub fn build_changes_trie_transaction<B: Backend<H>, H: Hasher, Number: BlockNumber>(
+	backend: &B,
+	storage: &dyn Storage<H, Number>,
+	changes: &OverlayedChanges,
+	parent_hash: H::Out,
+	config: Configuration,
+	zero: Number,
+) -> Result<Option<(MemoryDB<H>, H::Out, CacheAction<H::Out, Number>)>, ()>
+	where
+		H::Out: Ord + 'static + Encode,
+{
+	let state = State::new(config, zero, storage);
+	build_changes_trie::<_, H, Number>(backend, Some(&state), changes, parent_hash, false, None)
+}
+
 /// Prepare empty cached build data for given block.
 fn prepare_cached_build_data<Number: BlockNumber>(
 	config: ConfigurationRange<Number>,
@@ -375,6 +436,51 @@ fn prepare_cached_build_data<Number: BlockNumber>(
 mod tests {
 	use super::*;
 
+	#[test]
+	fn build_changes_trie_transaction_works() {
+		use hash_db::HashDB;
+		use sp_core::Blake2Hasher;
+		use crate::InMemoryBackend;
+
+		let parent_hash = Blake2Hasher::hash(b"parent");
+		let storage = InMemoryStorage::<Blake2Hasher, u64>::with_blocks(vec![(0, parent_hash)]);
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let config = Configuration { digest_interval: 4, digest_levels: 2 };
+		let mut changes = OverlayedChanges::default();
+		changes.set_collect_extrinsics(true);
+		changes.set_storage(b"key".to_vec(), Some(b"value".to_vec()));
+
+		let (mdb, root, _) = build_changes_trie_transaction::<_, Blake2Hasher, u64>(
+			&backend,
+			&storage,
+			&changes,
+			parent_hash,
+			config.clone(),
+			0,
+		).unwrap().unwrap();
+		assert!(mdb.contains(&root, hash_db::EMPTY_PREFIX));
+
+		let state = State::new(config.clone(), 0, &storage);
+		let expected = build_changes_trie::<_, Blake2Hasher, u64>(
+			&backend,
+			Some(&state),
+			&changes,
+			parent_hash,
+			true,
+			None,
+		).unwrap().unwrap();
+		assert_eq!(root, expected.1);
+
+		assert!(build_changes_trie_transaction::<_, Blake2Hasher, u64>(
+			&backend,
+			&storage,
+			&changes,
+			Default::default(),
+			config,
+			0,
+		).is_err());
+	}
+
 	#[test]
 	fn cache_is_cleared_when_digests_are_disabled() {
 		let config = Configuration { digest_interval: 0, digest_levels: 0 };
@@ -0,0 +1,189 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of the changes trie roots of recent blocks.
+
+use std::collections::HashMap;
+use parking_lot::Mutex;
+use crate::changes_trie::{AnchorBlockId, BlockNumber};
+
+/// The default number of roots kept by a [`RootsCache`].
+pub const DEFAULT_ROOTS_CACHE_SIZE: usize = 64;
+
+/// Least recently used cache of the changes trie roots of imported blocks.
+///
+/// The roots are inserted with the hash of their block and of its parent when the blocks are
+/// imported, and consulted when digests are built, instead of resolving them from the storage
+/// every time. A root is looked up by walking from the anchor of the digest back to the
+/// requested block number through the cached parents, so roots of other forks are never
+/// returned. Roots of reverted blocks have to be removed with [`RootsCache::remove`].
+pub struct RootsCache<Hash, Number> {
+	capacity: usize,
+	entries: Mutex<Entries<Hash, Number>>,
+}
+
+struct Entry<Hash, Number> {
+	number: Number,
+	parent: Hash,
+	root: Hash,
+	/// The moment the entry was last used.
+	used: u64,
+}
+
+struct Entries<Hash, Number> {
+	/// The entries by the hash of their block.
+	blocks: HashMap<Hash, Entry<Hash, Number>>,
+	/// Incremented on every use.
+	clock: u64,
+}
+
+impl<Hash, Number> RootsCache<Hash, Number> where
+	Hash: Clone + Eq + std::hash::Hash + std::fmt::Debug,
+	Number: BlockNumber,
+{
+	/// Create a cache that keeps at most `capacity` roots.
+	pub fn new(capacity: usize) -> Self {
+		RootsCache {
+			capacity,
+			entries: Mutex::new(Entries { blocks: HashMap::new(), clock: 0 }),
+		}
+	}
+
+	/// Get the cached changes trie root of the block with the given `number` on the chain
+	/// ending at `anchor`.
+	///
+	/// Returns `None` unless all blocks from the anchor back to the requested one are cached.
+	pub fn get(&self, anchor: &AnchorBlockId<Hash, Number>, number: &Number) -> Option<Hash> {
+		if *number > anchor.number {
+			return None
+		}
+
+		let mut entries = self.entries.lock();
+		entries.clock += 1;
+		let clock = entries.clock;
+		let mut hash = anchor.hash.clone();
+		loop {
+			let entry = entries.blocks.get_mut(&hash)?;
+			if entry.number == *number {
+				entry.used = clock;
+				return Some(entry.root.clone())
+			}
+			if entry.number < *number {
+				return None
+			}
+			hash = entry.parent.clone();
+		}
+	}
+
+	/// Insert the changes trie `root` of the imported `block` with the given `parent` hash.
+	///
+	/// If the cache is full, the least recently used root is evicted.
+	pub fn insert(&self, block: AnchorBlockId<Hash, Number>, parent: Hash, root: Hash) {
+		if self.capacity == 0 {
+			return
+		}
+
+		let mut entries = self.entries.lock();
+		entries.clock += 1;
+		let clock = entries.clock;
+		if !entries.blocks.contains_key(&block.hash) && entries.blocks.len() >= self.capacity {
+			let evicted = entries.blocks.iter()
+				.min_by_key(|(_, entry)| entry.used)
+				.map(|(hash, _)| hash.clone());
+			if let Some(evicted) = evicted {
+				entries.blocks.remove(&evicted);
+			}
+		}
+		let entry = Entry { number: block.number, parent, root, used: clock };
+		entries.blocks.insert(block.hash, entry);
+	}
+
+	/// Remove the root of the block with the given `hash`, e.g. when the block is reverted.
+	pub fn remove(&self, hash: &Hash) {
+		self.entries.lock().blocks.remove(hash);
+	}
+
+	/// Remove all roots.
+	pub fn clear(&self) {
+		self.entries.lock().blocks.clear();
+	}
+}
+
+impl<Hash, Number> Default for RootsCache<Hash, Number> where
+	Hash: Clone + Eq + std::hash::Hash + std::fmt::Debug,
+	Number: BlockNumber,
+{
+	fn default() -> Self {
+		Self::new(DEFAULT_ROOTS_CACHE_SIZE)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn anchor(hash: u8, number: u64) -> AnchorBlockId<u8, u64> {
+		AnchorBlockId { hash, number }
+	}
+
+	/// Inserts the blocks `first..=last` on top of `parent`. The hash of a block is
+	/// `fork + number`, its root is the complement of the hash.
+	fn insert_chain(cache: &RootsCache<u8, u64>, fork: u8, parent: u8, first: u64, last: u64) {
+		let mut parent = parent;
+		for number in first..=last {
+			let hash = fork + number as u8;
+			cache.insert(anchor(hash, number), parent, !hash);
+			parent = hash;
+		}
+	}
+
+	#[test]
+	fn roots_are_resolved_through_the_chain_of_the_anchor() {
+		let cache = RootsCache::<u8, u64>::default();
+		insert_chain(&cache, 0, 0, 1, 4);
+		// A fork of blocks 3 and 4 on top of block 2.
+		insert_chain(&cache, 100, 2, 3, 4);
+
+		assert_eq!(cache.get(&anchor(4, 4), &3), Some(!3));
+		assert_eq!(cache.get(&anchor(104, 4), &3), Some(!103));
+		assert_eq!(cache.get(&anchor(104, 4), &1), Some(!1));
+		assert_eq!(cache.get(&anchor(104, 4), &5), None);
+		assert_eq!(cache.get(&anchor(0, 0), &0), None);
+
+		// A gap in the chain is a miss.
+		cache.remove(&103);
+		assert_eq!(cache.get(&anchor(104, 4), &3), None);
+		assert_eq!(cache.get(&anchor(104, 4), &1), None);
+		assert_eq!(cache.get(&anchor(4, 4), &1), Some(!1));
+
+		cache.clear();
+		assert_eq!(cache.get(&anchor(4, 4), &4), None);
+	}
+
+	#[test]
+	fn least_recently_used_root_is_evicted() {
+		let cache = RootsCache::<u8, u64>::new(2);
+		cache.insert(anchor(1, 1), 0, 10);
+		cache.insert(anchor(2, 1), 0, 20);
+		assert_eq!(cache.get(&anchor(1, 1), &1), Some(10));
+
+		cache.insert(anchor(3, 1), 0, 30);
+		assert_eq!(cache.get(&anchor(2, 1), &1), None);
+		assert_eq!(cache.get(&anchor(1, 1), &1), Some(10));
+		assert_eq!(cache.get(&anchor(3, 1), &1), Some(30));
+	}
+}
@@ -18,6 +18,7 @@
 //! Changes trie storage utilities.
 
 use std::collections::{BTreeMap, HashSet, HashMap};
+use codec::Codec;
 use hash_db::{Hasher, Prefix, EMPTY_PREFIX};
 use sp_core::storage::PrefixedStorageKey;
 use sp_trie::DBValue;
@@ -26,7 +27,9 @@ use parking_lot::RwLock;
 use crate::{
 	StorageKey,
 	trie_backend_essence::TrieBackendStorage,
-	changes_trie::{BuildCache, RootsStorage, Storage, AnchorBlockId, BlockNumber},
+	changes_trie::{
+		BuildCache, RootsStorage, Storage, PersistentStorage, AnchorBlockId, BlockNumber,
+	},
 };
 
 #[cfg(test)]
@@ -194,6 +197,47 @@ impl<H: Hasher, Number: BlockNumber> Storage<H, Number> for InMemoryStorage<H, N
 	}
 }
 
+impl<H, Number> PersistentStorage<H, Number> for InMemoryStorage<H, Number>
+	where
+		H: Hasher,
+		H::Out: Codec,
+		Number: BlockNumber,
+{
+	fn insert_transaction(
+		&self,
+		block: AnchorBlockId<H::Out, Number>,
+		root: H::Out,
+		transaction: MemoryDB<H>,
+	) -> Result<(), String> {
+		self.insert(block.number, root, transaction);
+		Ok(())
+	}
+
+	fn root_by_block(&self, block: &Number) -> Result<Option<H::Out>, String> {
+		Ok(self.data.read().roots.get(block).cloned())
+	}
+
+	fn prune(
+		&self,
+		first: Number,
+		last: Number,
+		current_block: &AnchorBlockId<H::Out, Number>,
+	) -> Result<(), String> {
+		let mut nodes = Vec::new();
+		super::prune(self, first.clone(), last.clone(), current_block, |node| nodes.push(node));
+
+		let mut data = self.data.write();
+		for node in nodes {
+			data.mdb.remove_and_purge(&node, EMPTY_PREFIX);
+		}
+		let blocks = data.roots.range(first..=last).map(|(block, _)| block.clone()).collect::<Vec<_>>();
+		for block in blocks {
+			data.roots.remove(&block);
+		}
+		Ok(())
+	}
+}
+
 impl<'a, H: Hasher, Number: BlockNumber> TrieBackendAdapter<'a, H, Number> {
 	pub fn new(storage: &'a dyn Storage<H, Number>) -> Self {
 		Self { storage, _hasher: Default::default() }
@@ -211,3 +255,133 @@ impl<'a, H, Number> TrieBackendStorage<H> for TrieBackendAdapter<'a, H, Number>
 		self.storage.get(key, prefix)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use codec::Encode;
+	use sp_core::{Blake2Hasher, H256};
+	use crate::changes_trie::input::ExtrinsicIndex;
+
+	const ANCHOR_PREFIX: &[u8] = b"anchor";
+	const ROOT_PREFIX: &[u8] = b"root";
+	const NODE_PREFIX: &[u8] = b"node";
+
+	/// Changes trie storage on top of a plain key-value database, like the one of a client.
+	struct KeyValueStorage {
+		db: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+		cache: BuildCache<H256, u64>,
+	}
+
+	impl KeyValueStorage {
+		fn new() -> Self {
+			KeyValueStorage { db: Default::default(), cache: BuildCache::new() }
+		}
+
+		fn read(&self, prefix: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+			self.db.read().get(&[prefix, key].concat()).cloned()
+		}
+	}
+
+	impl RootsStorage<Blake2Hasher, u64> for KeyValueStorage {
+		fn build_anchor(&self, hash: H256) -> Result<AnchorBlockId<H256, u64>, String> {
+			let number = self.read(ANCHOR_PREFIX, hash.as_ref())
+				.ok_or_else(|| format!("Unknown block {:?}", hash))?;
+			let number = codec::Decode::decode(&mut &number[..]).map_err(|e| e.to_string())?;
+			Ok(AnchorBlockId { hash, number })
+		}
+
+		fn root(&self, _anchor: &AnchorBlockId<H256, u64>, block: u64) -> Result<Option<H256>, String> {
+			self.root_by_block(&block)
+		}
+	}
+
+	impl Storage<Blake2Hasher, u64> for KeyValueStorage {
+		fn as_roots_storage(&self) -> &dyn RootsStorage<Blake2Hasher, u64> {
+			self
+		}
+
+		fn with_cached_changed_keys(
+			&self,
+			root: &H256,
+			functor: &mut dyn FnMut(&HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>),
+		) -> bool {
+			self.cache.with_changed_keys(root, functor)
+		}
+
+		fn get(&self, key: &H256, _prefix: Prefix) -> Result<Option<DBValue>, String> {
+			Ok(self.read(NODE_PREFIX, key.as_ref()))
+		}
+	}
+
+	impl PersistentStorage<Blake2Hasher, u64> for KeyValueStorage {
+		fn insert_transaction(
+			&self,
+			block: AnchorBlockId<H256, u64>,
+			root: H256,
+			mut transaction: MemoryDB<Blake2Hasher>,
+		) -> Result<(), String> {
+			let mut db = self.db.write();
+			for (key, (value, rc)) in transaction.drain() {
+				if rc > 0 {
+					db.insert([NODE_PREFIX, key.as_ref()].concat(), value);
+				}
+			}
+			db.insert([ANCHOR_PREFIX, block.hash.as_ref()].concat(), block.number.encode());
+			db.insert([ROOT_PREFIX, &block.number.encode()[..]].concat(), root.as_ref().to_vec());
+			Ok(())
+		}
+
+		fn root_by_block(&self, block: &u64) -> Result<Option<H256>, String> {
+			Ok(self.read(ROOT_PREFIX, &block.encode()).map(|root| H256::from_slice(&root)))
+		}
+
+		fn prune(
+			&self,
+			first: u64,
+			last: u64,
+			current_block: &AnchorBlockId<H256, u64>,
+		) -> Result<(), String> {
+			let mut nodes = Vec::new();
+			crate::changes_trie::prune(self, first, last, current_block, |node| nodes.push(node));
+
+			let mut db = self.db.write();
+			for node in nodes {
+				db.remove(&[NODE_PREFIX, node.as_ref()].concat());
+			}
+			for block in first..=last {
+				db.remove(&[ROOT_PREFIX, &block.encode()[..]].concat());
+			}
+			Ok(())
+		}
+	}
+
+	fn check_persistent_storage(storage: &dyn PersistentStorage<Blake2Hasher, u64>) {
+		let anchor = || AnchorBlockId { hash: H256::repeat_byte(1), number: 1 };
+		let mut transaction = MemoryDB::default();
+		let input = InputPair::ExtrinsicIndex(ExtrinsicIndex { block: 1u64, key: vec![42] }, vec![0]);
+		let root = insert_into_memory_db::<Blake2Hasher, _>(&mut transaction, vec![input.into()])
+			.unwrap();
+
+		storage.insert_transaction(anchor(), root, transaction).unwrap();
+		assert_eq!(storage.root_by_block(&1).unwrap(), Some(root));
+		assert_eq!(storage.root(&anchor(), 1).unwrap(), Some(root));
+		assert!(storage.get(&root, EMPTY_PREFIX).unwrap().is_some());
+
+		storage.prune(1, 1, &anchor()).unwrap();
+		assert_eq!(storage.root_by_block(&1).unwrap(), None);
+		assert!(storage.get(&root, EMPTY_PREFIX).unwrap().is_none());
+	}
+
+	#[test]
+	fn in_memory_storage_is_persistent_storage() {
+		check_persistent_storage(&InMemoryStorage::<Blake2Hasher, u64>::new());
+	}
+
+	#[test]
+	fn key_value_storage_is_persistent_storage() {
+		let storage = KeyValueStorage::new();
+		check_persistent_storage(&storage);
+		assert_eq!(storage.build_anchor(H256::repeat_byte(1)).unwrap().number, 1);
+	}
+}
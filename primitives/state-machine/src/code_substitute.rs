@@ -0,0 +1,140 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime code substitutes.
+//!
+//! A substitute replaces the runtime code stored under `:code` for a given set of states. This
+//! is an escape hatch for chains that got bricked by a broken runtime.
+
+use std::{collections::HashMap, sync::Arc};
+use hash_db::Hasher;
+use codec::Encode;
+use sp_core::traits::{RuntimeCode, WrappedRuntimeCode};
+use crate::changes_trie::BlockNumber;
+
+/// Runtime code that should be used instead of the code found in the state.
+pub struct RuntimeCodeSubstitute {
+	code: WrappedRuntimeCode<'static>,
+	hash: Vec<u8>,
+	heap_pages: Option<u64>,
+}
+
+impl RuntimeCodeSubstitute {
+	/// Create a new substitute for the given wasm `code`.
+	///
+	/// The code hash is calculated using the hasher `H`.
+	pub fn new<H: Hasher>(code: Vec<u8>, heap_pages: Option<u64>) -> Self where H::Out: Encode {
+		let hash = H::hash(&code).encode();
+		RuntimeCodeSubstitute {
+			code: WrappedRuntimeCode(code.into()),
+			hash,
+			heap_pages,
+		}
+	}
+
+	/// Returns the [`RuntimeCode`] of this substitute.
+	pub fn runtime_code(&self) -> RuntimeCode {
+		RuntimeCode {
+			code_fetcher: &self.code,
+			hash: self.hash.clone(),
+			heap_pages: self.heap_pages,
+		}
+	}
+}
+
+/// Collection of runtime code substitutes, keyed by state root or by block range.
+pub struct RuntimeCodeSubstitutes<H: Hasher, N> {
+	by_state_root: HashMap<H::Out, Arc<RuntimeCodeSubstitute>>,
+	by_block_range: Vec<(N, Option<N>, Arc<RuntimeCodeSubstitute>)>,
+}
+
+impl<H: Hasher, N> Default for RuntimeCodeSubstitutes<H, N> {
+	fn default() -> Self {
+		RuntimeCodeSubstitutes {
+			by_state_root: HashMap::new(),
+			by_block_range: Vec::new(),
+		}
+	}
+}
+
+impl<H: Hasher, N: BlockNumber> RuntimeCodeSubstitutes<H, N> {
+	/// Use `substitute` for any execution on top of the state with the given `root`.
+	pub fn insert_for_state_root(&mut self, root: H::Out, substitute: Arc<RuntimeCodeSubstitute>) {
+		self.by_state_root.insert(root, substitute);
+	}
+
+	/// Use `substitute` for any execution in the blocks `from..=to`.
+	///
+	/// If `to` is `None`, the substitute is used for all blocks starting at `from`.
+	pub fn insert_for_block_range(
+		&mut self,
+		from: N,
+		to: Option<N>,
+		substitute: Arc<RuntimeCodeSubstitute>,
+	) {
+		self.by_block_range.push((from, to, substitute));
+	}
+
+	/// Returns `true` if no substitute is registered.
+	pub fn is_empty(&self) -> bool {
+		self.by_state_root.is_empty() && self.by_block_range.is_empty()
+	}
+
+	/// Find the substitute for the state with the given `root` at the given `block`.
+	///
+	/// Substitutes registered for a state root take precedence over those registered for
+	/// a block range.
+	pub fn get(&self, root: &H::Out, block: Option<&N>) -> Option<&RuntimeCodeSubstitute> {
+		if let Some(substitute) = self.by_state_root.get(root) {
+			return Some(&**substitute)
+		}
+
+		let block = block?;
+		self.by_block_range.iter()
+			.find(|(from, to, _)| from <= block && to.as_ref().map_or(true, |to| block <= to))
+			.map(|(_, _, substitute)| &**substitute)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::{Blake2Hasher, traits::FetchRuntimeCode};
+
+	#[test]
+	fn substitute_lookup_works() {
+		let by_root = Arc::new(RuntimeCodeSubstitute::new::<Blake2Hasher>(vec![1], None));
+		let by_range = Arc::new(RuntimeCodeSubstitute::new::<Blake2Hasher>(vec![2], Some(8)));
+		let root = Blake2Hasher::hash(b"root");
+		let other_root = Blake2Hasher::hash(b"other");
+
+		let mut substitutes = RuntimeCodeSubstitutes::<Blake2Hasher, u64>::default();
+		assert!(substitutes.is_empty());
+		substitutes.insert_for_state_root(root, by_root);
+		substitutes.insert_for_block_range(10, Some(20), by_range);
+
+		let code = |s: Option<&RuntimeCodeSubstitute>| s.map(|s| {
+			s.runtime_code().fetch_runtime_code().unwrap().into_owned()
+		});
+		assert_eq!(code(substitutes.get(&root, Some(&15))), Some(vec![1]));
+		assert_eq!(code(substitutes.get(&other_root, Some(&15))), Some(vec![2]));
+		assert_eq!(code(substitutes.get(&other_root, Some(&20))), Some(vec![2]));
+		assert_eq!(code(substitutes.get(&other_root, Some(&21))), None);
+		assert_eq!(code(substitutes.get(&other_root, None)), None);
+		assert_eq!(substitutes.get(&other_root, Some(&10)).unwrap().runtime_code().heap_pages, Some(8));
+	}
+}
@@ -0,0 +1,289 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Consistency checks of committed changes against the state they were applied to.
+//!
+//! The checks replay the changes independently on an in-memory copy of the old state and
+//! compare the result with what the backend produces. This walks the whole state, so it is
+//! meant for tests of the block import and for the forensics of faults, not for production.
+
+use std::collections::{BTreeMap, BTreeSet};
+use hash_db::{HashDB, Hasher, Prefix};
+use codec::Codec;
+use sp_core::storage::ChildInfo;
+use sp_trie::DBValue;
+use crate::{
+	Backend, CommittedChanges, InMemoryBackend, StorageKey, StorageValue, TrieBackend,
+	TrieBackendStorage,
+};
+
+/// A key whose value differs between the backend and the independent replay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueMismatch {
+	/// The storage key of the child trie of the key, `None` for the top trie.
+	pub child: Option<StorageKey>,
+	/// The key.
+	pub key: StorageKey,
+	/// The value read from the state the backend produced for the changes.
+	pub backend: Option<StorageValue>,
+	/// The value read from the replayed state.
+	pub replay: Option<StorageValue>,
+}
+
+/// The result of [`verify_committed_against_backend`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsistencyReport<Hash> {
+	/// The root the changes are expected to lead to.
+	pub new_root: Hash,
+	/// The root calculated by the old backend from the changes.
+	pub delta_root: Hash,
+	/// The root of the old state with the changes replayed independently.
+	pub replay_root: Hash,
+	/// The keys whose values differ between the backend and the replay.
+	pub mismatches: Vec<ValueMismatch>,
+}
+
+impl<Hash: PartialEq> ConsistencyReport<Hash> {
+	/// True if all roots match and no key was flagged.
+	pub fn is_consistent(&self) -> bool {
+		self.delta_root == self.new_root &&
+			self.replay_root == self.new_root &&
+			self.mismatches.is_empty()
+	}
+}
+
+/// The values of a state, by child trie and key.
+type State = BTreeMap<Option<ChildInfo>, BTreeMap<StorageKey, StorageValue>>;
+
+/// Trie node storage that serves the nodes of `transaction` on top of the nodes of `storage`.
+struct TransactionOverlay<'a, S: TrieBackendStorage<H>, H: Hasher> {
+	storage: &'a S,
+	transaction: S::Overlay,
+}
+
+impl<'a, S: TrieBackendStorage<H>, H: Hasher> TrieBackendStorage<H>
+	for TransactionOverlay<'a, S, H>
+{
+	type Overlay = S::Overlay;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		match HashDB::get(&self.transaction, key, prefix) {
+			Some(value) => Ok(Some(value)),
+			None => self.storage.get(key, prefix),
+		}
+	}
+}
+
+/// Verify that applying `committed` to `old_backend` leads to `new_root`.
+///
+/// The backend calculates the root and the trie nodes of the new state from the changes.
+/// Independently, the changes are replayed on an in-memory copy of the old state. The values
+/// of all keys of the state produced by the backend are then compared with the replayed
+/// state. Returns an error if a state can not be read.
+pub fn verify_committed_against_backend<S, H>(
+	committed: &CommittedChanges,
+	old_backend: &TrieBackend<S, H>,
+	new_root: H::Out,
+) -> Result<ConsistencyReport<H::Out>, String>
+where
+	S: TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Codec + Ord,
+{
+	let child_infos = child_infos(committed);
+	let (delta_root, transaction) = old_backend.full_storage_root(
+		committed.top.iter().map(|(k, v)| (&k[..], v.as_deref())),
+		child_infos.iter().zip(committed.children.iter()).map(|(child_info, (_, changes))| {
+			(child_info, changes.iter().map(|(k, v)| (&k[..], v.as_deref())))
+		}),
+	);
+	verify_delta(committed, old_backend, delta_root, transaction, new_root)
+}
+
+fn child_infos(committed: &CommittedChanges) -> Vec<ChildInfo> {
+	committed.children.iter()
+		.map(|(storage_key, _)| ChildInfo::new_default(storage_key))
+		.collect()
+}
+
+fn export<H: Hasher, B: Backend<H>>(backend: &B, name: &str) -> Result<State, String> {
+	let mut state = State::new();
+	backend.export_raw(&[], |child_info, key, value| {
+		state.entry(child_info.cloned()).or_default().insert(key.to_vec(), value.to_vec());
+	}).map_err(|e| format!("Failed to export the {} state: {}", name, e))?;
+	Ok(state)
+}
+
+/// Compare the state the backend produced for `committed`, given by the `delta_root` and
+/// its `transaction`, with the independent replay of the changes.
+fn verify_delta<S, H>(
+	committed: &CommittedChanges,
+	old_backend: &TrieBackend<S, H>,
+	delta_root: H::Out,
+	transaction: S::Overlay,
+	new_root: H::Out,
+) -> Result<ConsistencyReport<H::Out>, String>
+where
+	S: TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Codec + Ord,
+{
+	let mut replay = export(old_backend, "old")?;
+	let changes = child_infos(committed).into_iter().map(Some)
+		.zip(committed.children.iter().map(|(_, changes)| changes))
+		.chain(std::iter::once((None, &committed.top)));
+	for (child_info, changes) in changes {
+		let entries = replay.entry(child_info).or_default();
+		for (key, value) in changes {
+			match value {
+				Some(value) => entries.insert(key.clone(), value.clone()),
+				None => entries.remove(key),
+			};
+		}
+	}
+
+	let post_backend = TrieBackend::new(
+		TransactionOverlay { storage: old_backend.backend_storage(), transaction },
+		delta_root,
+	);
+	let post = export(&post_backend, "new")?;
+
+	let mut keys = BTreeSet::new();
+	for state in &[&replay, &post] {
+		for (child_info, entries) in state.iter() {
+			keys.extend(entries.keys().map(|key| (child_info.clone(), key.clone())));
+		}
+	}
+	let value = |state: &State, child_info: &Option<ChildInfo>, key: &StorageKey| {
+		state.get(child_info).and_then(|entries| entries.get(key)).cloned()
+	};
+	let mismatches = keys.into_iter().filter_map(|(child_info, key)| {
+		let backend = value(&post, &child_info, &key);
+		let replay = value(&replay, &child_info, &key);
+		if backend == replay {
+			return None
+		}
+		Some(ValueMismatch {
+			child: child_info.map(|info| info.storage_key().to_vec()),
+			key,
+			backend,
+			replay,
+		})
+	}).collect();
+
+	let replayed = InMemoryBackend::<H>::from_raw_iter(
+		replay.into_iter().flat_map(|(child_info, entries)| {
+			entries.into_iter().map(move |(key, value)| (child_info.clone(), key, value))
+		}),
+	);
+	Ok(ConsistencyReport {
+		new_root,
+		delta_root,
+		replay_root: *replayed.root(),
+		mismatches,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::{Blake2Hasher, H256};
+
+	fn old_backend(child_info: &ChildInfo) -> InMemoryBackend<Blake2Hasher> {
+		InMemoryBackend::<Blake2Hasher>::default().update(vec![
+			(Some(child_info.clone()), vec![(b"c1".to_vec(), Some(b"v1".to_vec()))]),
+			(None, vec![
+				(b"a".to_vec(), Some(b"v2".to_vec())),
+				(b"b".to_vec(), Some(b"v3".to_vec())),
+			]),
+		])
+	}
+
+	#[test]
+	fn consistent_changes_pass() {
+		let child_info = ChildInfo::new_default(b"child");
+		let backend = old_backend(&child_info);
+		let committed = CommittedChanges {
+			top: vec![(b"a".to_vec(), None), (b"c".to_vec(), Some(b"v4".to_vec()))],
+			children: vec![
+				(b"child".to_vec(), vec![(b"c2".to_vec(), Some(b"v5".to_vec()))]),
+			],
+		};
+		let new_root = *backend.update(vec![
+			(Some(child_info.clone()), vec![(b"c2".to_vec(), Some(b"v5".to_vec()))]),
+			(None, committed.top.clone()),
+		]).root();
+
+		let report = verify_committed_against_backend(&committed, &backend, new_root).unwrap();
+		assert!(report.is_consistent(), "{:?}", report);
+	}
+
+	#[test]
+	fn wrong_root_is_flagged() {
+		let child_info = ChildInfo::new_default(b"child");
+		let backend = old_backend(&child_info);
+		let committed = CommittedChanges {
+			top: vec![(b"b".to_vec(), Some(b"v6".to_vec()))],
+			children: vec![(b"child".to_vec(), vec![(b"c1".to_vec(), None)])],
+		};
+
+		let report = verify_committed_against_backend(&committed, &backend, H256::repeat_byte(1))
+			.unwrap();
+		assert!(!report.is_consistent());
+		assert_eq!(report.delta_root, report.replay_root);
+		assert!(report.mismatches.is_empty());
+	}
+
+	#[test]
+	fn corrupted_delta_is_flagged() {
+		let child_info = ChildInfo::new_default(b"child");
+		let backend = old_backend(&child_info);
+		let committed = CommittedChanges {
+			top: vec![(b"b".to_vec(), Some(b"v6".to_vec()))],
+			children: vec![(b"child".to_vec(), vec![(b"c1".to_vec(), None)])],
+		};
+		let new_root = *backend.update(vec![
+			(Some(child_info.clone()), vec![(b"c1".to_vec(), None)]),
+			(None, committed.top.clone()),
+		]).root();
+
+		// A faulty backend that drops the change of `b` and writes `a` instead.
+		let (delta_root, transaction) = backend.full_storage_root(
+			vec![(&b"a"[..], Some(&b"v7"[..]))].into_iter(),
+			vec![(&child_info, vec![(&b"c1"[..], None)].into_iter())].into_iter(),
+		);
+		let report = verify_delta(&committed, &backend, delta_root, transaction, new_root)
+			.unwrap();
+
+		assert!(!report.is_consistent());
+		assert_eq!(report.replay_root, new_root);
+		assert_eq!(report.mismatches, vec![
+			ValueMismatch {
+				child: None,
+				key: b"a".to_vec(),
+				backend: Some(b"v7".to_vec()),
+				replay: Some(b"v2".to_vec()),
+			},
+			ValueMismatch {
+				child: None,
+				key: b"b".to_vec(),
+				backend: Some(b"v3".to_vec()),
+				replay: Some(b"v6".to_vec()),
+			},
+		]);
+	}
+}
@@ -0,0 +1,72 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of the checks the runtime deferred to the end of a call.
+
+use futures::{future::{FutureExt, join_all}, channel::oneshot, executor::block_on};
+use sp_core::traits::SpawnNamed;
+use sp_externalities::DeferredCheck;
+
+/// Verify the given `checks`, returns `true` if all of them succeeded.
+///
+/// With a `spawner` every check is verified in its own blocking task, otherwise the checks
+/// are verified one after another on the current thread.
+pub(crate) fn verify_deferred_checks(
+	checks: Vec<DeferredCheck>,
+	spawner: Option<&dyn SpawnNamed>,
+) -> bool {
+	let spawner = match spawner {
+		Some(spawner) if checks.len() > 1 => spawner,
+		_ => return checks.into_iter().all(|check| check()),
+	};
+
+	let pending = checks.into_iter().map(|check| {
+		let (sender, receiver) = oneshot::channel();
+		spawner.spawn_blocking(
+			"substrate_deferred_check",
+			async move {
+				// The receiver only goes away if the verification was abandoned.
+				let _ = sender.send(check());
+			}.boxed(),
+		);
+		receiver
+	}).collect::<Vec<_>>();
+
+	// A check that was dropped without reporting a result counts as failed.
+	block_on(join_all(pending)).into_iter().all(|result| result.unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::testing::TaskExecutor;
+
+	fn checks(results: &[bool]) -> Vec<DeferredCheck> {
+		results.iter().map(|&result| Box::new(move || result) as DeferredCheck).collect()
+	}
+
+	#[test]
+	fn deferred_checks_are_verified() {
+		let spawner = TaskExecutor::new();
+
+		assert!(verify_deferred_checks(Vec::new(), Some(&spawner)));
+		assert!(verify_deferred_checks(checks(&[true, true, true]), Some(&spawner)));
+		assert!(!verify_deferred_checks(checks(&[true, false, true]), Some(&spawner)));
+		assert!(verify_deferred_checks(checks(&[true, true]), None));
+		assert!(!verify_deferred_checks(checks(&[false, true]), None));
+	}
+}
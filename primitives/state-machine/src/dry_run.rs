@@ -0,0 +1,105 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Estimation of the cost of an extrinsic without applying it.
+
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use crate::{OpCostHandler, StorageOp};
+
+/// The storage IO of an extrinsic applied by [`StateMachine::dry_run_extrinsic`].
+///
+/// [`StateMachine::dry_run_extrinsic`]: crate::StateMachine::dry_run_extrinsic
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DryRunReport {
+	/// The SCALE encoded `ApplyExtrinsicResult` returned by the runtime.
+	pub result: Vec<u8>,
+	/// Number of values, hashes or keys that were read.
+	pub reads: u64,
+	/// Number of values that were written, appended to or removed.
+	pub writes: u64,
+	/// Size of the read values and their keys in bytes.
+	pub bytes_read: u64,
+	/// Size of the written values and their keys in bytes.
+	pub bytes_written: u64,
+	/// Number of bytes the extrinsic added to the storage proof.
+	///
+	/// `None` if the backend does not record a proof, see [`Backend::proof_size`].
+	///
+	/// [`Backend::proof_size`]: crate::Backend::proof_size
+	pub proof_size_delta: Option<u32>,
+}
+
+impl DryRunReport {
+	/// Returns true if the extrinsic was dispatched successfully.
+	pub fn succeeded(&self) -> bool {
+		crate::apply_extrinsic_succeeded(&self.result)
+	}
+}
+
+/// Counts the storage operations of a dry run and forwards them to the handler of the
+/// state machine, if any.
+pub(crate) struct DryRunCounter {
+	inner: Option<Arc<dyn OpCostHandler>>,
+	reads: AtomicU64,
+	writes: AtomicU64,
+	bytes_read: AtomicU64,
+	bytes_written: AtomicU64,
+}
+
+impl DryRunCounter {
+	pub(crate) fn new(inner: Option<Arc<dyn OpCostHandler>>) -> Self {
+		DryRunCounter {
+			inner,
+			reads: AtomicU64::new(0),
+			writes: AtomicU64::new(0),
+			bytes_read: AtomicU64::new(0),
+			bytes_written: AtomicU64::new(0),
+		}
+	}
+
+	pub(crate) fn report(&self, result: Vec<u8>, proof_size_delta: Option<u32>) -> DryRunReport {
+		DryRunReport {
+			result,
+			reads: self.reads.load(Ordering::Relaxed),
+			writes: self.writes.load(Ordering::Relaxed),
+			bytes_read: self.bytes_read.load(Ordering::Relaxed),
+			bytes_written: self.bytes_written.load(Ordering::Relaxed),
+			proof_size_delta,
+		}
+	}
+}
+
+impl OpCostHandler for DryRunCounter {
+	fn on_storage_op(&self, op: StorageOp, key_len: usize, value_len: usize) {
+		let bytes = (key_len + value_len) as u64;
+		match op {
+			StorageOp::Get => {
+				self.reads.fetch_add(1, Ordering::Relaxed);
+				self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+			},
+			StorageOp::Set | StorageOp::Remove => {
+				self.writes.fetch_add(1, Ordering::Relaxed);
+				self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+			},
+			StorageOp::Root => (),
+		}
+
+		if let Some(inner) = self.inner.as_ref() {
+			inner.on_storage_op(op, key_len, value_len);
+		}
+	}
+}
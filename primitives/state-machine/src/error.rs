@@ -17,32 +17,124 @@
 
 /// State Machine Errors
 
-use std::fmt;
+use std::{any::Any, fmt};
+use sp_core::traits::{CodeExecutor, CodeExecutorErrorKind};
+use crate::quota_backend::QUOTA_EXCEEDED;
 
 /// State Machine Error bound.
 ///
 /// This should reflect Wasm error type bound for future compatibility.
-pub trait Error: 'static + fmt::Debug + fmt::Display + Send {}
+pub trait Error: 'static + fmt::Debug + fmt::Display + Send {
+	/// Returns the error as [`Any`], so it can be downcast to its concrete type.
+	fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: 'static + fmt::Debug + fmt::Display + Send> Error for T {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
 
-impl<T: 'static + fmt::Debug + fmt::Display + Send> Error for T {}
+impl dyn Error {
+	/// Returns the error as `T` if it is of that type.
+	///
+	/// The errors returned by [`StateMachine`](crate::StateMachine) can be downcast to
+	/// [`ExecutionError`].
+	pub fn downcast_ref<T: Error>(&self) -> Option<&T> {
+		self.as_any().downcast_ref()
+	}
+}
 
-/// Externalities Error.
+/// Error of an execution of the state machine.
 ///
-/// Externalities are not really allowed to have errors, since it's assumed that dependent code
-/// would not be executed unless externalities were available. This is included for completeness,
-/// and as a transition away from the pre-existing framework.
+/// The variants allow embedders to react to a failed execution, e.g. to retry a call that was
+/// aborted by a failing backend or to report a runtime that traps.
 #[derive(Debug, Eq, PartialEq)]
 pub enum ExecutionError {
 	/// Backend error.
 	Backend(String),
+	/// The read quota of the backend was exceeded, see [`QuotaBackend`](crate::QuotaBackend).
+	QuotaExceeded,
+	/// The execution of the runtime trapped or panicked.
+	Trap {
+		/// The error reported by the executor.
+		message: String,
+		/// The backtrace of the trap, if known.
+		backtrace: Option<String>,
+	},
+	/// A host function failed.
+	HostFunction {
+		/// The name of the host function.
+		name: String,
+		/// The error reported by the executor.
+		message: String,
+	},
+	/// The executor failed for another reason.
+	Executor(String),
 	/// The entry `:code` doesn't exist in storage so there's no way we can execute anything.
 	CodeEntryDoesNotExist,
 	/// Backend is incompatible with execution proof generation process.
 	UnableToGenerateProof,
 	/// Invalid execution proof.
 	InvalidProof,
+	/// The execution was interrupted by a cancellation token.
+	Interrupted,
+	/// A check the runtime registered with `register_deferred_check` failed.
+	DeferredCheckFailed,
+	/// The runtime tried to write to the storage in [`ExecutionMode::ReadOnly`].
+	///
+	/// [`ExecutionMode::ReadOnly`]: crate::ExecutionMode::ReadOnly
+	WriteInReadOnlyMode {
+		/// The name of the refused operation.
+		operation: String,
+	},
+	/// The storage root after the execution differs from the expected one.
+	StorageRootMismatch {
+		/// The encoded expected storage root.
+		expected: Vec<u8>,
+		/// The encoded storage root calculated after the execution.
+		calculated: Vec<u8>,
+	},
+}
+
+impl ExecutionError {
+	/// Classify an `error` of the executor `Exec`, see [`CodeExecutor::error_kind`].
+	pub(crate) fn from_executor_error<Exec: CodeExecutor>(error: Exec::Error) -> Self {
+		let kind = Exec::error_kind(&error);
+		let message = error.to_string();
+		match kind {
+			CodeExecutorErrorKind::MissingCode => ExecutionError::CodeEntryDoesNotExist,
+			CodeExecutorErrorKind::Trap { backtrace } => ExecutionError::Trap { message, backtrace },
+			CodeExecutorErrorKind::HostFunction { name } =>
+				ExecutionError::HostFunction { name, message },
+			CodeExecutorErrorKind::Other => ExecutionError::Executor(message),
+		}
+	}
 }
 
 impl fmt::Display for ExecutionError {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Externalities Error") }
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ExecutionError::Backend(e) => write!(f, "Backend error: {}", e),
+			ExecutionError::QuotaExceeded => write!(f, "{}", QUOTA_EXCEEDED),
+			ExecutionError::Trap { message, .. } => write!(f, "Runtime trapped: {}", message),
+			ExecutionError::HostFunction { name, message } =>
+				write!(f, "Host function {} failed: {}", name, message),
+			ExecutionError::Executor(e) => write!(f, "{}", e),
+			ExecutionError::CodeEntryDoesNotExist => write!(f, "The `:code` entry does not exist"),
+			ExecutionError::UnableToGenerateProof =>
+				write!(f, "The backend can not generate execution proofs"),
+			ExecutionError::InvalidProof => write!(f, "Invalid execution proof"),
+			ExecutionError::Interrupted => write!(f, "Execution was interrupted"),
+			ExecutionError::DeferredCheckFailed => write!(f, "A deferred check failed"),
+			ExecutionError::WriteInReadOnlyMode { operation } =>
+				write!(f, "Storage write `{}` in read-only execution", operation),
+			ExecutionError::StorageRootMismatch { expected, calculated } => write!(
+				f,
+				"Storage root mismatch, expected {:?}, calculated {:?}",
+				expected,
+				calculated,
+			),
+		}
+	}
 }
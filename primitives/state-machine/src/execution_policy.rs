@@ -0,0 +1,70 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selection of the execution strategy per call.
+
+use std::collections::HashMap;
+use crate::{CallContext, ExecutionStrategy};
+
+/// Which result to use if the native and the wasm execution of a call with
+/// [`ExecutionStrategy::Both`] disagree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsensusFailureResolution {
+	/// Use the result of the wasm execution.
+	UseWasm,
+	/// Use the result of the native execution.
+	UseNative,
+}
+
+/// Decides how a call into the runtime is executed.
+///
+/// This allows a node to use different strategies for different calls, e.g. wasm only for
+/// `validate_transaction`, but native else wasm for block import. The policy overrides the
+/// strategy the [`StateMachine`](crate::StateMachine) is executed with.
+pub trait ExecutionPolicy: Send + Sync {
+	/// Returns the strategy for calling `method` in the given `context`.
+	///
+	/// `None` keeps the strategy the call was executed with.
+	fn strategy(&self, method: &str, context: CallContext) -> Option<ExecutionStrategy>;
+
+	/// Returns the result to use if the native and the wasm execution of `method` disagree.
+	///
+	/// Only used if the policy selected [`ExecutionStrategy::Both`].
+	fn on_consensus_failure(&self, _method: &str, _context: CallContext) -> ConsensusFailureResolution {
+		ConsensusFailureResolution::UseWasm
+	}
+}
+
+/// An [`ExecutionPolicy`] that selects the strategy by the called method.
+#[derive(Debug, Clone, Default)]
+pub struct MethodExecutionPolicy {
+	methods: HashMap<String, ExecutionStrategy>,
+}
+
+impl MethodExecutionPolicy {
+	/// Execute all calls of `method` with the given `strategy`.
+	pub fn with_method(mut self, method: impl Into<String>, strategy: ExecutionStrategy) -> Self {
+		self.methods.insert(method.into(), strategy);
+		self
+	}
+}
+
+impl ExecutionPolicy for MethodExecutionPolicy {
+	fn strategy(&self, method: &str, _context: CallContext) -> Option<ExecutionStrategy> {
+		self.methods.get(method).cloned()
+	}
+}
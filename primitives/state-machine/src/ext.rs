@@ -18,7 +18,9 @@
 //! Concrete externalities implementation.
 
 use crate::{
-	StorageKey, StorageValue, OverlayedChanges, StorageTransactionCache,
+	StorageKey, StorageValue, OverlayedChanges, StorageTransactionCache, CallContext, ExecutionMode,
+	CancellationToken, OpCostHandler, StorageOp, StateTracer, TraceOp, ValueSource,
+	NoChildChanges, RecordingControl, StorageNamespace, KeyRef, ExecutionError,
 	backend::Backend,
 	changes_trie::State as ChangesTrieState,
 };
@@ -26,17 +28,25 @@ use crate::{
 use hash_db::Hasher;
 use sp_core::{
 	offchain::storage::OffchainOverlayedChanges,
-	storage::{well_known_keys::is_child_storage_key, ChildInfo, TrackedStorageKey},
+	storage::{
+		well_known_keys::{self, is_child_storage_key}, ChildInfo, PrefixedStorageKey,
+		TrackedStorageKey,
+	},
 	traits::Externalities, hexdisplay::HexDisplay,
 };
 use sp_trie::{trie_types::Layout, empty_child_trie_root};
-use sp_externalities::{Extensions, Extension};
+use sp_externalities::{Extensions, Extension, DeferredCheck, TrieProofLayout};
 use codec::{Decode, Encode, EncodeAppend};
 
-use std::{error, fmt, any::{Any, TypeId}};
+use std::{
+	error, fmt, any::{Any, TypeId}, cell::RefCell, collections::BTreeSet, sync::Arc, time::Instant,
+};
 use log::{warn, trace};
 
 const EXT_NOT_ALLOWED_TO_FAIL: &str = "Externalities not allowed to fail within runtime";
+
+const EXT_INTERRUPTED: &str = "Execution interrupted by cancellation token";
+const EXT_READ_ONLY: &str = "Storage write in read-only execution";
 const BENCHMARKING_FN: &str = "\
 	This is a special fn only for benchmarking where a database commit happens from the runtime.
 	For that reason client started transactions before calling into runtime are not allowed.
@@ -71,6 +81,15 @@ impl<B: error::Error, E: error::Error> error::Error for Error<B, E> {
 	}
 }
 
+/// Returns where a value was found, given the lookup result of the overlay.
+fn value_source<T>(overlay_value: &Option<T>) -> ValueSource {
+	if overlay_value.is_some() {
+		ValueSource::Overlay
+	} else {
+		ValueSource::Backend
+	}
+}
+
 /// Wraps a read-only backend, call executor, and current overlayed changes.
 pub struct Ext<'a, H, N, B>
 	where
@@ -94,6 +113,24 @@ pub struct Ext<'a, H, N, B>
 	_phantom: std::marker::PhantomData<N>,
 	/// Extensions registered with this instance.
 	extensions: Option<&'a mut Extensions>,
+	/// The context the current call is executed in.
+	call_context: CallContext,
+	/// Whether the current call may write to the storage.
+	execution_mode: ExecutionMode,
+	/// The write that was refused in [`ExecutionMode::ReadOnly`].
+	refused_write: Option<&'static str>,
+	/// Token that is checked on every call into the externalities.
+	cancellation_token: Option<CancellationToken>,
+	/// Handler that is informed about every storage operation.
+	op_cost_handler: Option<Arc<dyn OpCostHandler>>,
+	/// Tracer that records every storage operation.
+	tracer: Option<Arc<StateTracer>>,
+	/// Checks registered by the runtime, `None` if checks are run right away.
+	deferred_checks: Option<Vec<DeferredCheck>>,
+	/// Handle to pause the proof recording of the backend.
+	recording_control: Option<RecordingControl>,
+	/// The first error of the backend, it aborts the runtime call.
+	backend_failure: RefCell<Option<ExecutionError>>,
 }
 
 impl<'a, H, N, B> Ext<'a, H, N, B>
@@ -122,6 +159,332 @@ where
 			id: rand::random(),
 			_phantom: Default::default(),
 			extensions,
+			call_context: CallContext::default(),
+			execution_mode: ExecutionMode::default(),
+			refused_write: None,
+			cancellation_token: None,
+			op_cost_handler: None,
+			tracer: None,
+			deferred_checks: None,
+			recording_control: None,
+			backend_failure: RefCell::new(None),
+		}
+	}
+
+	/// Set the context the current call is executed in.
+	pub fn with_call_context(mut self, call_context: CallContext) -> Self {
+		self.call_context = call_context;
+		self
+	}
+
+	/// Returns the context the current call is executed in.
+	pub fn call_context(&self) -> CallContext {
+		self.call_context
+	}
+
+	/// Set the mode the current call is executed in.
+	pub fn with_execution_mode(mut self, execution_mode: ExecutionMode) -> Self {
+		self.execution_mode = execution_mode;
+		self
+	}
+
+	/// Returns the name of the write that was refused in [`ExecutionMode::ReadOnly`], if any.
+	pub fn take_refused_write(&mut self) -> Option<&'static str> {
+		self.refused_write.take()
+	}
+
+	/// Panics if the current call is not allowed to write to the storage.
+	fn check_writable(&mut self, operation: &'static str) {
+		if self.execution_mode == ExecutionMode::ReadOnly {
+			trace!(target: "state", "{:04x}: Refused {} in read-only execution", self.id, operation);
+			self.refused_write.get_or_insert(operation);
+			panic!("{}: {}", EXT_READ_ONLY, operation);
+		}
+	}
+
+	/// Aborts the call if the last iteration over the backend was incomplete.
+	///
+	/// See [`Backend::check_limits`].
+	fn check_backend_limits(&self) {
+		if let Err(e) = self.backend.check_limits() {
+			Self::backend_failed(&self.backend_failure, e)
+		}
+	}
+
+	/// Interrupt the current call when the given `token` is cancelled.
+	///
+	/// The token is checked every time the runtime calls into the externalities. When it is
+	/// cancelled, the call panics, which unwinds the runtime execution.
+	pub fn with_cancellation_token(mut self, token: Option<CancellationToken>) -> Self {
+		self.cancellation_token = token;
+		self
+	}
+
+	/// Panics if the cancellation token was cancelled.
+	fn check_cancelled(&self) {
+		if self.cancellation_token.as_ref().map_or(false, |t| t.is_cancelled()) {
+			trace!(target: "state", "{:04x}: Interrupted", self.id);
+			panic!("{}", EXT_INTERRUPTED);
+		}
+	}
+
+	/// Report every storage operation to the given `handler`.
+	pub fn with_op_cost_handler(mut self, handler: Option<Arc<dyn OpCostHandler>>) -> Self {
+		self.op_cost_handler = handler;
+		self
+	}
+
+	/// Reports a storage operation to the op cost handler.
+	fn on_storage_op(&self, op: StorageOp, key_len: usize, value_len: usize) {
+		if let Some(handler) = self.op_cost_handler.as_ref() {
+			handler.on_storage_op(op, key_len, value_len);
+		}
+	}
+
+	/// Record every storage operation with the given `tracer`.
+	pub fn with_tracer(mut self, tracer: Option<Arc<StateTracer>>) -> Self {
+		self.tracer = tracer;
+		self
+	}
+
+	/// Returns the start time of an operation if a tracer is installed.
+	fn trace_start(&self) -> Option<Instant> {
+		self.tracer.as_ref().map(|_| Instant::now())
+	}
+
+	/// Records an operation that was started at `started` with the tracer.
+	fn trace_op(
+		&self,
+		started: Option<Instant>,
+		op: TraceOp,
+		child_info: Option<&ChildInfo>,
+		key: &[u8],
+		value: Option<&[u8]>,
+		source: Option<ValueSource>,
+	) {
+		if let (Some(tracer), Some(started)) = (self.tracer.as_ref(), started) {
+			tracer.record(op, child_info, key, value, source, started.elapsed());
+		}
+	}
+
+	/// Use the given `control` to pause and resume the proof recording of the backend.
+	pub fn with_recording_control(mut self, control: Option<RecordingControl>) -> Self {
+		self.recording_control = control;
+		self
+	}
+
+	/// Stop recording the trie nodes that are read into the proof.
+	///
+	/// Does nothing if no recording control was given. Everything that is read until
+	/// [`Ext::resume_proof_recording`] is called must be derivable by the verifier of the
+	/// proof on its own, see [`RecordingControl`].
+	pub fn pause_proof_recording(&self) {
+		if let Some(control) = self.recording_control.as_ref() {
+			trace!(target: "state", "{:04x}: PauseRecording", self.id);
+			control.pause();
+		}
+	}
+
+	/// Record the trie nodes that are read into the proof again.
+	pub fn resume_proof_recording(&self) {
+		if let Some(control) = self.recording_control.as_ref() {
+			trace!(target: "state", "{:04x}: ResumeRecording", self.id);
+			control.resume();
+		}
+	}
+
+	/// Collect the checks registered by the runtime instead of running them right away.
+	///
+	/// The collected checks need to be taken with [`Ext::take_deferred_checks`] after the
+	/// call and verified before its changes are committed.
+	pub fn with_deferred_checks(mut self) -> Self {
+		self.deferred_checks = Some(Vec::new());
+		self
+	}
+
+	/// Returns the checks that were registered since the last call.
+	pub fn take_deferred_checks(&mut self) -> Vec<DeferredCheck> {
+		self.deferred_checks.as_mut().map(std::mem::take).unwrap_or_default()
+	}
+
+	/// Returns the error of the backend that aborted the last call, if any.
+	///
+	/// This is either [`ExecutionError::QuotaExceeded`] or [`ExecutionError::Backend`].
+	pub fn take_backend_failure(&mut self) -> Option<ExecutionError> {
+		self.backend_failure.get_mut().take()
+	}
+
+	/// Remember the first error of the backend in `failure` and abort the runtime call.
+	///
+	/// The call is unwound even if the caller holds a guard that aborts on panics, so the
+	/// error reaches the caller of the state machine.
+	fn backend_failed(failure: &RefCell<Option<ExecutionError>>, error: B::Error) -> ! {
+		failure.borrow_mut().get_or_insert_with(|| if B::is_quota_exceeded(&error) {
+			ExecutionError::QuotaExceeded
+		} else {
+			ExecutionError::Backend(error.to_string())
+		});
+		let _guard = sp_panic_handler::AbortGuard::force_unwind();
+		panic!("{}: {:?}", EXT_NOT_ALLOWED_TO_FAIL, error)
+	}
+
+	/// Use the given precalculated `root` of the child trie with the given `storage_key`.
+	///
+	/// This allows to calculate child roots outside of the state machine, e.g. in parallel.
+	/// `transaction` has to contain the changed nodes of the child trie. The hint is only used
+	/// while the overlayed changes of the child trie are the ones it was set for, see
+	/// [`OverlayedChanges::set_child_storage_root_hint`].
+	pub fn set_child_storage_root_hint(
+		&mut self,
+		storage_key: &[u8],
+		root: H::Out,
+		transaction: B::Transaction,
+	) -> Result<(), NoChildChanges> {
+		trace!(target: "state", "{:04x}: ChildRootHint({}) {}",
+			self.id,
+			HexDisplay::from(&storage_key),
+			HexDisplay::from(&root.as_ref()),
+		);
+		self.mark_dirty();
+		self.overlay.set_child_storage_root_hint(
+			storage_key,
+			root,
+			transaction,
+			self.storage_transaction_cache,
+		)
+	}
+
+	/// Calculate the storage root and return it together with the transaction that contains
+	/// the changed trie nodes of the top trie and of all child tries.
+	///
+	/// The root is calculated only once, the result of a previous [`Externalities::storage_root`]
+	/// is reused. The transaction is moved out of the cache, so it is recalculated if the
+	/// storage changes are drained afterwards.
+	pub fn storage_root_with_transaction(&mut self) -> (H::Out, B::Transaction) {
+		self.check_cancelled();
+		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		if self.storage_transaction_cache.transaction.is_none() ||
+			self.storage_transaction_cache.transaction_storage_root.is_none()
+		{
+			let started = self.trace_start();
+			let root = self.overlay.storage_root(self.backend, self.storage_transaction_cache);
+			let encoded_root = root.encode();
+			self.on_storage_op(StorageOp::Root, 0, encoded_root.len());
+			self.trace_op(started, TraceOp::Root, None, &[], Some(&encoded_root), None);
+		}
+
+		let root = self.storage_transaction_cache.transaction_storage_root
+			.expect("Root is calculated together with the transaction; qed");
+		let transaction = self.storage_transaction_cache.transaction.take()
+			.expect("Transaction was calculated above if it didn't exist; qed");
+		trace!(target: "state", "{:04x}: RootWithTransaction {}",
+			self.id,
+			HexDisplay::from(&root.as_ref()),
+		);
+		(root, transaction)
+	}
+
+	/// Return at most `count` keys of child storage starting with `prefix` in lexicographic
+	/// order, as seen with the overlayed changes applied.
+	///
+	/// Only keys that are strictly superior to `start_key` are returned. See
+	/// [`Backend::child_keys_paged`].
+	pub fn child_keys_paged(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		count: usize,
+		start_key: Option<&[u8]>,
+	) -> Vec<StorageKey> {
+		self.check_cancelled();
+		let mut keys = Vec::with_capacity(count);
+		let mut start_key = start_key.map(|k| k.to_vec());
+		while keys.len() < count {
+			let backend_keys = self.backend
+				.child_keys_paged(child_info, prefix, count, start_key.as_deref())
+				.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e));
+			// Every key up to the last backend key of a full page is known, after a partial
+			// page every remaining key is known.
+			let end_key = if backend_keys.len() == count { backend_keys.last().cloned() } else { None };
+			let in_range = |key: &[u8]| key.starts_with(prefix) &&
+				start_key.as_ref().map_or(true, |start| key > &start[..]) &&
+				end_key.as_ref().map_or(true, |end| key <= &end[..]);
+
+			let mut page: BTreeSet<StorageKey> = backend_keys.into_iter().collect();
+			if let Some((changes, _)) = self.overlay.child_changes(child_info.storage_key()) {
+				for (key, value) in changes.filter(|(key, _)| in_range(key)) {
+					if value.value().is_some() {
+						page.insert(key.clone());
+					} else {
+						page.remove(key);
+					}
+				}
+			}
+			keys.extend(page.into_iter().take(count - keys.len()));
+
+			match end_key {
+				Some(end_key) => start_key = Some(end_key),
+				None => break,
+			}
+		}
+		keys
+	}
+
+	/// Returns the prefixed storage keys of all child tries that exist with the overlayed
+	/// changes applied, in lexicographic order.
+	///
+	/// Child tries of the backend are only reported if the overlay does not remove all of
+	/// their keys. Child tries that are only created by the overlay are reported as well.
+	pub fn child_storage_keys_iter(&self) -> impl Iterator<Item=PrefixedStorageKey> {
+		self.check_cancelled();
+		let mut keys: BTreeSet<StorageKey> = self.backend
+			.keys(well_known_keys::CHILD_STORAGE_KEY_PREFIX)
+			.into_iter()
+			.collect();
+		for (_, child_info) in self.overlay.children() {
+			let prefixed_storage_key = child_info.prefixed_storage_key().into_inner();
+			let exists = self.exists_child_storage(child_info, &[]) ||
+				self.next_child_storage_key(child_info, &[]).is_some();
+			if exists {
+				keys.insert(prefixed_storage_key);
+			} else {
+				keys.remove(&prefixed_storage_key);
+			}
+		}
+		keys.into_iter().map(PrefixedStorageKey::new)
+	}
+
+	fn place_storage_in(
+		&mut self,
+		ns: Option<StorageNamespace>,
+		key: StorageKey,
+		value: Option<StorageValue>,
+	) {
+		trace!(target: "state", "{:04x}: Put {}={:?}",
+			self.id,
+			HexDisplay::from(&key),
+			value.as_ref().map(HexDisplay::from)
+		);
+		self.check_cancelled();
+		self.check_writable("set_storage");
+		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		if is_child_storage_key(&key) {
+			warn!(target: "trie", "Refuse to directly set child storage key");
+			return;
+		}
+
+		self.on_storage_op(
+			if value.is_some() { StorageOp::Set } else { StorageOp::Remove },
+			key.len(),
+			value.as_ref().map_or(0, |v| v.len()),
+		);
+		let started = self.trace_start();
+		self.trace_op(started, TraceOp::Set, None, &key, value.as_deref(), None);
+		self.mark_dirty();
+		self.note_original_value(&key);
+		match ns {
+			Some(ns) => self.overlay.set_storage_ns(ns, key, value),
+			None => self.overlay.set_storage(key, value),
 		}
 	}
 
@@ -132,6 +495,27 @@ where
 		self.storage_transaction_cache.reset();
 	}
 
+	/// Note the backend value of `key` in the overlay before it is changed.
+	fn note_original_value(&self, key: &[u8]) {
+		if self.overlay.tracks_original_values() {
+			let backend = &self.backend;
+			let failure = &self.backend_failure;
+			self.overlay.note_original_value(
+				key,
+				|| backend.storage(key).unwrap_or_else(|e| Self::backend_failed(failure, e)),
+			);
+		}
+	}
+
+	/// The share of the backend reads of the overlay that were served from a cache.
+	///
+	/// The reads are accumulated in the statistics of the overlay, so this covers all calls
+	/// executed on the overlay, usually the whole block. `None` if the backend did not
+	/// classify any read, see [`Backend::storage_with_hint`].
+	pub fn warm_cache_ratio(&self) -> Option<f64> {
+		self.overlay.stats().warm_cache_ratio()
+	}
+
 	/// Read only accessor for the scheduled overlay changes.
 	pub fn get_offchain_storage_changes(&self) -> &OffchainOverlayedChanges {
 		&*self.offchain_overlay
@@ -169,16 +553,31 @@ where
 
 	fn set_offchain_storage(&mut self, key: &[u8], value: Option<&[u8]>) {
 		use ::sp_core::offchain::STORAGE_PREFIX;
+		self.check_writable("set_offchain_storage");
 		match value {
 			Some(value) => self.offchain_overlay.set(STORAGE_PREFIX, key, value),
 			None => self.offchain_overlay.remove(STORAGE_PREFIX, key),
 		}
+		self.overlay.set_offchain_storage(key, value);
 	}
 
 	fn storage(&self, key: &[u8]) -> Option<StorageValue> {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
-		let result = self.overlay.storage(key).map(|x| x.map(|x| x.to_vec())).unwrap_or_else(||
-			self.backend.storage(key).expect(EXT_NOT_ALLOWED_TO_FAIL));
+		let started = self.trace_start();
+		// The key is hashed once for the overlay and the backend.
+		let key_ref = KeyRef::new(key);
+		let overlay_value = self.overlay.storage_ref(key_ref);
+		let source = value_source(&overlay_value);
+		let result = overlay_value.map(|x| x.map(|x| x.to_vec())).unwrap_or_else(|| {
+			let (value, hint) = self.backend.storage_with_hint_ref(key_ref)
+				.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e));
+			self.overlay.stats().tally_backend_read(hint);
+			self.overlay.note_original_value(key, || value.clone());
+			value
+		});
+		self.on_storage_op(StorageOp::Get, key.len(), result.as_ref().map_or(0, |v| v.len()));
+		self.trace_op(started, TraceOp::Get, None, key, result.as_deref(), Some(source));
 		trace!(target: "state", "{:04x}: Get {}={:?}",
 			self.id,
 			HexDisplay::from(&key),
@@ -188,11 +587,19 @@ where
 	}
 
 	fn storage_hash(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
-		let result = self.overlay
-			.storage(key)
+		let started = self.trace_start();
+		let overlay_value = self.overlay.storage(key);
+		let source = value_source(&overlay_value);
+		let result = overlay_value
 			.map(|x| x.map(|x| H::hash(x)))
-			.unwrap_or_else(|| self.backend.storage_hash(key).expect(EXT_NOT_ALLOWED_TO_FAIL));
+			.unwrap_or_else(||
+				self.backend.storage_hash(key)
+					.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e))
+			);
+		self.on_storage_op(StorageOp::Get, key.len(), 0);
+		self.trace_op(started, TraceOp::Get, None, key, result.as_ref().map(AsRef::as_ref), Some(source));
 
 		trace!(target: "state", "{:04x}: Hash {}={:?}",
 			self.id,
@@ -207,14 +614,19 @@ where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> Option<StorageValue> {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
-		let result = self.overlay
-			.child_storage(child_info, key)
+		let started = self.trace_start();
+		let overlay_value = self.overlay.child_storage(child_info, key);
+		let source = value_source(&overlay_value);
+		let result = overlay_value
 			.map(|x| x.map(|x| x.to_vec()))
 			.unwrap_or_else(||
 				self.backend.child_storage(child_info, key)
-					.expect(EXT_NOT_ALLOWED_TO_FAIL)
+					.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e))
 			);
+		self.on_storage_op(StorageOp::Get, key.len(), result.as_ref().map_or(0, |v| v.len()));
+		self.trace_op(started, TraceOp::Get, Some(child_info), key, result.as_deref(), Some(source));
 
 		trace!(target: "state", "{:04x}: GetChild({}) {}={:?}",
 			self.id,
@@ -231,14 +643,26 @@ where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> Option<Vec<u8>> {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
-		let result = self.overlay
-			.child_storage(child_info, key)
+		let started = self.trace_start();
+		let overlay_value = self.overlay.child_storage(child_info, key);
+		let source = value_source(&overlay_value);
+		let result = overlay_value
 			.map(|x| x.map(|x| H::hash(x)))
 			.unwrap_or_else(||
 				self.backend.child_storage_hash(child_info, key)
-					.expect(EXT_NOT_ALLOWED_TO_FAIL)
+					.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e))
 			);
+		self.on_storage_op(StorageOp::Get, key.len(), 0);
+		self.trace_op(
+			started,
+			TraceOp::Get,
+			Some(child_info),
+			key,
+			result.as_ref().map(AsRef::as_ref),
+			Some(source),
+		);
 
 		trace!(target: "state", "{:04x}: ChildHash({}) {}={:?}",
 			self.id,
@@ -251,11 +675,14 @@ where
 	}
 
 	fn exists_storage(&self, key: &[u8]) -> bool {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let result = match self.overlay.storage(key) {
 			Some(x) => x.is_some(),
-			_ => self.backend.exists_storage(key).expect(EXT_NOT_ALLOWED_TO_FAIL),
+			_ => self.backend.exists_storage(key)
+				.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e)),
 		};
+		self.on_storage_op(StorageOp::Get, key.len(), 0);
 
 		trace!(target: "state", "{:04x}: Exists {}={:?}",
 			self.id,
@@ -271,14 +698,16 @@ where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> bool {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 
 		let result = match self.overlay.child_storage(child_info, key) {
 			Some(x) => x.is_some(),
 			_ => self.backend
 				.exists_child_storage(child_info, key)
-				.expect(EXT_NOT_ALLOWED_TO_FAIL),
+				.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e)),
 		};
+		self.on_storage_op(StorageOp::Get, key.len(), 0);
 
 		trace!(target: "state", "{:04x}: ChildExists({}) {}={:?}",
 			self.id,
@@ -289,8 +718,54 @@ where
 		result
 	}
 
+	fn storage_size(&self, key: &[u8]) -> Option<u32> {
+		self.check_cancelled();
+		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		let result = match self.overlay.storage(key) {
+			Some(x) => x.map(|x| x.len() as u32),
+			_ => self.backend.storage_size(key)
+				.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e)),
+		};
+		self.on_storage_op(StorageOp::Get, key.len(), 0);
+
+		trace!(target: "state", "{:04x}: Size {}={:?}",
+			self.id,
+			HexDisplay::from(&key),
+			result,
+		);
+
+		result
+	}
+
+	fn child_storage_size(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Option<u32> {
+		self.check_cancelled();
+		let _guard = sp_panic_handler::AbortGuard::force_abort();
+
+		let result = match self.overlay.child_storage(child_info, key) {
+			Some(x) => x.map(|x| x.len() as u32),
+			_ => self.backend
+				.child_storage_size(child_info, key)
+				.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e)),
+		};
+		self.on_storage_op(StorageOp::Get, key.len(), 0);
+
+		trace!(target: "state", "{:04x}: ChildSize({}) {}={:?}",
+			self.id,
+			HexDisplay::from(&child_info.storage_key()),
+			HexDisplay::from(&key),
+			result,
+		);
+		result
+	}
+
 	fn next_storage_key(&self, key: &[u8]) -> Option<StorageKey> {
-		let next_backend_key = self.backend.next_storage_key(key).expect(EXT_NOT_ALLOWED_TO_FAIL);
+		self.check_cancelled();
+		let next_backend_key = self.backend.next_storage_key(key)
+			.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e));
 		let next_overlay_key_change = self.overlay.next_storage_key_change(key);
 
 		match (next_backend_key, next_overlay_key_change) {
@@ -309,9 +784,10 @@ where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> Option<StorageKey> {
+		self.check_cancelled();
 		let next_backend_key = self.backend
 			.next_child_storage_key(child_info, key)
-			.expect(EXT_NOT_ALLOWED_TO_FAIL);
+			.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e));
 		let next_overlay_key_change = self.overlay.next_child_storage_key_change(
 			child_info.storage_key(),
 			key
@@ -332,19 +808,16 @@ where
 	}
 
 	fn place_storage(&mut self, key: StorageKey, value: Option<StorageValue>) {
-		trace!(target: "state", "{:04x}: Put {}={:?}",
-			self.id,
-			HexDisplay::from(&key),
-			value.as_ref().map(HexDisplay::from)
-		);
-		let _guard = sp_panic_handler::AbortGuard::force_abort();
-		if is_child_storage_key(&key) {
-			warn!(target: "trie", "Refuse to directly set child storage key");
-			return;
-		}
+		self.place_storage_in(None, key, value)
+	}
 
-		self.mark_dirty();
-		self.overlay.set_storage(key, value);
+	fn place_storage_ns(
+		&mut self,
+		ns: StorageNamespace,
+		key: StorageKey,
+		value: Option<StorageValue>,
+	) {
+		self.place_storage_in(Some(ns), key, value)
 	}
 
 	fn place_child_storage(
@@ -359,8 +832,21 @@ where
 			HexDisplay::from(&key),
 			value.as_ref().map(HexDisplay::from)
 		);
+		self.check_cancelled();
+		self.check_writable("set_child_storage");
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		if is_child_storage_key(child_info.storage_key()) {
+			warn!(target: "trie", "Refuse to use a prefixed storage key as child storage key");
+			return;
+		}
 
+		self.on_storage_op(
+			if value.is_some() { StorageOp::Set } else { StorageOp::Remove },
+			key.len(),
+			value.as_ref().map_or(0, |v| v.len()),
+		);
+		let started = self.trace_start();
+		self.trace_op(started, TraceOp::Set, Some(child_info), &key, value.as_deref(), None);
 		self.mark_dirty();
 		self.overlay.set_child_storage(child_info, key, value);
 	}
@@ -373,13 +859,23 @@ where
 			self.id,
 			HexDisplay::from(&child_info.storage_key()),
 		);
+		self.check_cancelled();
+		self.check_writable("kill_child_storage");
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		if is_child_storage_key(child_info.storage_key()) {
+			warn!(target: "trie", "Refuse to use a prefixed storage key as child storage key");
+			return;
+		}
 
+		self.on_storage_op(StorageOp::Remove, child_info.storage_key().len(), 0);
+		let started = self.trace_start();
 		self.mark_dirty();
 		self.overlay.clear_child_storage(child_info);
 		self.backend.for_keys_in_child_storage(child_info, |key| {
 			self.overlay.set_child_storage(child_info, key.to_vec(), None);
 		});
+		self.check_backend_limits();
+		self.trace_op(started, TraceOp::HostCall("kill_child_storage"), Some(child_info), &[], None, None);
 	}
 
 	fn clear_prefix(&mut self, prefix: &[u8]) {
@@ -387,17 +883,29 @@ where
 			self.id,
 			HexDisplay::from(&prefix),
 		);
+		self.check_cancelled();
+		self.check_writable("clear_prefix");
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		if is_child_storage_key(prefix) {
 			warn!(target: "trie", "Refuse to directly clear prefix that is part of child storage key");
 			return;
 		}
 
+		self.on_storage_op(StorageOp::Remove, prefix.len(), 0);
+		let started = self.trace_start();
 		self.mark_dirty();
 		self.overlay.clear_prefix(prefix);
+		let tracks_original_values = self.overlay.tracks_original_values();
 		self.backend.for_keys_with_prefix(prefix, |key| {
+			if tracks_original_values {
+				let value = self.backend.storage(key)
+					.unwrap_or_else(|e| Self::backend_failed(&self.backend_failure, e));
+				self.overlay.note_original_value(key, || value);
+			}
 			self.overlay.set_storage(key.to_vec(), None);
 		});
+		self.check_backend_limits();
+		self.trace_op(started, TraceOp::HostCall("clear_prefix"), None, prefix, None, None);
 	}
 
 	fn clear_child_prefix(
@@ -410,13 +918,30 @@ where
 			HexDisplay::from(&child_info.storage_key()),
 			HexDisplay::from(&prefix),
 		);
+		self.check_cancelled();
+		self.check_writable("clear_child_prefix");
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		if is_child_storage_key(child_info.storage_key()) {
+			warn!(target: "trie", "Refuse to use a prefixed storage key as child storage key");
+			return;
+		}
 
+		self.on_storage_op(StorageOp::Remove, prefix.len(), 0);
+		let started = self.trace_start();
 		self.mark_dirty();
 		self.overlay.clear_child_prefix(child_info, prefix);
 		self.backend.for_child_keys_with_prefix(child_info, prefix, |key| {
 			self.overlay.set_child_storage(child_info, key.to_vec(), None);
 		});
+		self.check_backend_limits();
+		self.trace_op(
+			started,
+			TraceOp::HostCall("clear_child_prefix"),
+			Some(child_info),
+			prefix,
+			None,
+			None,
+		);
 	}
 
 	fn storage_append(
@@ -430,13 +955,22 @@ where
 			HexDisplay::from(&value),
 		);
 
+		self.check_cancelled();
+		self.check_writable("append");
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		self.on_storage_op(StorageOp::Set, key.len(), value.len());
+		let started = self.trace_start();
+		self.trace_op(started, TraceOp::HostCall("append"), None, &key, Some(&value), None);
 		self.mark_dirty();
+		self.note_original_value(&key);
 
 		let backend = &mut self.backend;
+		let failure = &self.backend_failure;
 		let current_value = self.overlay.value_mut_or_insert_with(
 			&key,
-			|| backend.storage(&key).expect(EXT_NOT_ALLOWED_TO_FAIL).unwrap_or_default()
+			|| backend.storage(&key)
+				.unwrap_or_else(|e| Self::backend_failed(failure, e))
+				.unwrap_or_default()
 		);
 		StorageAppend::new(current_value).append(value);
 	}
@@ -446,6 +980,7 @@ where
 	}
 
 	fn storage_root(&mut self) -> Vec<u8> {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		if let Some(ref root) = self.storage_transaction_cache.transaction_storage_root {
 			trace!(target: "state", "{:04x}: Root(cached) {}",
@@ -455,15 +990,19 @@ where
 			return root.encode();
 		}
 
-		let root = self.overlay.storage_root(self.backend, self.storage_transaction_cache);
-		trace!(target: "state", "{:04x}: Root {}", self.id, HexDisplay::from(&root.as_ref()));
-		root.encode()
+		let started = self.trace_start();
+		let root = self.overlay.storage_root(self.backend, self.storage_transaction_cache).encode();
+		self.on_storage_op(StorageOp::Root, 0, root.len());
+		self.trace_op(started, TraceOp::Root, None, &[], Some(&root), None);
+		trace!(target: "state", "{:04x}: Root {}", self.id, HexDisplay::from(&root));
+		root
 	}
 
 	fn child_storage_root(
 		&mut self,
 		child_info: &ChildInfo,
 	) -> Vec<u8> {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let storage_key = child_info.storage_key();
 		let prefixed_storage_key = child_info.prefixed_storage_key();
@@ -481,15 +1020,22 @@ where
 			);
 			root.encode()
 		} else {
-			let root = if let Some((changes, info)) = self.overlay.child_changes(storage_key) {
+			let started = self.trace_start();
+			let hint = self.overlay.child_storage_root_hint(storage_key, self.storage_transaction_cache);
+			let root = if let Some(root) = hint {
+				Some((root, root == empty_child_trie_root::<Layout<H>>()))
+			} else if let Some((changes, info)) = self.overlay.child_changes(storage_key) {
 				let delta = changes.map(|(k, v)| (k.as_ref(), v.value().map(AsRef::as_ref)));
-				Some(self.backend.child_storage_root(info, delta))
+				let (root, is_empty, _) = self.backend.child_storage_root(info, delta);
+				Some((root, is_empty))
 			} else {
 				None
 			};
 
-			if let Some((root, is_empty, _)) = root {
+			if let Some((root, is_empty)) = root {
 				let root = root.encode();
+				self.on_storage_op(StorageOp::Root, storage_key.len(), root.len());
+				self.trace_op(started, TraceOp::Root, Some(child_info), &[], Some(&root), None);
 				// We store update in the overlay in order to be able to use 'self.storage_transaction'
 				// cache. This is brittle as it rely on Ext only querying the trie backend for
 				// storage root.
@@ -526,6 +1072,7 @@ where
 	}
 
 	fn storage_changes_root(&mut self, parent_hash: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+		self.check_cancelled();
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let root = self.overlay.changes_trie_root(
 			self.backend,
@@ -550,17 +1097,106 @@ where
 		root.map(|r| r.map(|o| o.encode()))
 	}
 
+	fn disable_changes_trie_for_block(&mut self) {
+		trace!(target: "state", "{:04x}: DisableChangesTrie", self.id);
+		self.overlay.disable_changes_trie_for_block();
+	}
+
+	fn register_deferred_check(&mut self, check: DeferredCheck) {
+		self.check_cancelled();
+		match self.deferred_checks.as_mut() {
+			Some(checks) => {
+				trace!(target: "state", "{:04x}: DeferCheck #{}", self.id, checks.len());
+				checks.push(check);
+			},
+			None => if !check() {
+				panic!("Deferred check failed");
+			},
+		}
+	}
+
+	fn verify_trie_proof(
+		&self,
+		root: &[u8],
+		proof: &[Vec<u8>],
+		items: &[(Vec<u8>, Option<Vec<u8>>)],
+		layout: TrieProofLayout,
+	) -> bool {
+		self.check_cancelled();
+		let started = self.trace_start();
+		let result = crate::verify_trie_proof(root, proof, items, layout);
+		trace!(target: "state", "{:04x}: VerifyTrieProof {} items={} valid={}",
+			self.id,
+			HexDisplay::from(&root),
+			items.len(),
+			result,
+		);
+		self.trace_op(started, TraceOp::HostCall("verify_trie_proof"), None, &[], None, None);
+		result
+	}
+
+	fn blob_put(&mut self, value: Vec<u8>) -> Vec<u8> {
+		trace!(target: "state", "{:04x}: BlobPut len={}", self.id, value.len());
+		crate::blob::put::<H>(self, value)
+	}
+
+	fn blob_get(&self, handle: &[u8]) -> Option<Vec<u8>> {
+		let result = crate::blob::get::<H>(self, handle);
+		trace!(target: "state", "{:04x}: BlobGet {} len={:?}",
+			self.id,
+			HexDisplay::from(&handle),
+			result.as_ref().map(|v| v.len()),
+		);
+		result
+	}
+
+	fn blob_remove(&mut self, handle: &[u8]) -> bool {
+		trace!(target: "state", "{:04x}: BlobRemove {}", self.id, HexDisplay::from(&handle));
+		crate::blob::remove::<H>(self, handle)
+	}
+
+	fn transient_storage(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.check_cancelled();
+		let result = self.overlay.transient_storage(key).map(<[u8]>::to_vec);
+		trace!(target: "state", "{:04x}: GetTransient {}={:?}",
+			self.id,
+			HexDisplay::from(&key),
+			result.as_ref().map(HexDisplay::from)
+		);
+		result
+	}
+
+	fn set_transient_storage(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+		trace!(target: "state", "{:04x}: PutTransient {}={:?}",
+			self.id,
+			HexDisplay::from(&key),
+			value.as_ref().map(HexDisplay::from)
+		);
+		// Transient values never reach the state, so they are allowed in read-only execution.
+		self.check_cancelled();
+		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		self.overlay.set_transient_storage(key, value);
+	}
+
 	fn storage_start_transaction(&mut self) {
-		self.overlay.start_transaction()
+		let started = self.trace_start();
+		self.overlay.start_transaction_labeled("runtime");
+		self.trace_op(started, TraceOp::HostCall("start_transaction"), None, &[], None, None);
 	}
 
 	fn storage_rollback_transaction(&mut self) -> Result<(), ()> {
+		let started = self.trace_start();
 		self.mark_dirty();
-		self.overlay.rollback_transaction().map_err(|_| ())
+		let result = self.overlay.rollback_transaction().map_err(|_| ());
+		self.trace_op(started, TraceOp::HostCall("rollback_transaction"), None, &[], None, None);
+		result
 	}
 
 	fn storage_commit_transaction(&mut self) -> Result<(), ()> {
-		self.overlay.commit_transaction().map_err(|_| ())
+		let started = self.trace_start();
+		let result = self.overlay.commit_transaction().map_err(|_| ());
+		self.trace_op(started, TraceOp::HostCall("commit_transaction"), None, &[], None, None);
+		result
 	}
 
 	fn wipe(&mut self) {
@@ -795,6 +1431,100 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn storage_changes_root_is_none_when_disabled_for_block() {
+		let mut overlay = prepare_overlay_with_changes();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let mut cache = StorageTransactionCache::default();
+		let storage = TestChangesTrieStorage::with_blocks(vec![(99, Default::default())]);
+		let state = Some(ChangesTrieState::new(changes_trie_config(), Zero::zero(), &storage));
+		let backend = TestBackend::default();
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, state, None);
+		ext.disable_changes_trie_for_block();
+		assert_eq!(ext.storage_changes_root(&H256::default().encode()).unwrap(), None);
+	}
+
+	#[test]
+	fn namespaced_writes_are_accounted() {
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = TestBackend::default();
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		ext.place_storage_ns(7, b"key".to_vec(), Some(b"value".to_vec()));
+		ext.place_storage_ns(7, b"other".to_vec(), None);
+		assert_eq!(ext.storage(b"key"), Some(b"value".to_vec()));
+		drop(ext);
+
+		let stats = overlay.namespace_stats(7).unwrap();
+		assert_eq!((stats.writes, stats.removals, stats.bytes_written), (1, 1, 13));
+		assert!(overlay.namespace_stats(0).is_none());
+	}
+
+	#[test]
+	fn storage_ops_are_reported_to_op_cost_handler() {
+		#[derive(Default)]
+		struct Recorder(parking_lot::Mutex<Vec<(StorageOp, usize, usize)>>);
+
+		impl OpCostHandler for Recorder {
+			fn on_storage_op(&self, op: StorageOp, key_len: usize, value_len: usize) {
+				self.0.lock().push((op, key_len, value_len));
+			}
+		}
+
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = Storage {
+			top: map![b"doe".to_vec() => b"reindeer".to_vec()],
+			children_default: map![],
+		}.into();
+		let recorder = Arc::new(Recorder::default());
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None)
+			.with_op_cost_handler(Some(recorder.clone()));
+		assert_eq!(ext.storage(b"doe"), Some(b"reindeer".to_vec()));
+		ext.set_storage(b"dog".to_vec(), b"puppy".to_vec());
+		ext.clear_storage(b"doe");
+		ext.storage_root();
+
+		assert_eq!(*recorder.0.lock(), vec![
+			(StorageOp::Get, 3, 8),
+			(StorageOp::Set, 3, 5),
+			(StorageOp::Remove, 3, 0),
+			(StorageOp::Root, 0, 32),
+		]);
+	}
+
+	#[test]
+	fn storage_ops_are_traced() {
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = Storage {
+			top: map![b"doe".to_vec() => b"reindeer".to_vec()],
+			children_default: map![],
+		}.into();
+		let tracer = Arc::new(StateTracer::new(4));
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None)
+			.with_tracer(Some(tracer.clone()));
+		ext.storage(b"doe");
+		ext.set_storage(b"doe".to_vec(), b"deer".to_vec());
+		ext.storage(b"doe");
+		ext.storage_start_transaction();
+
+		let entries: Vec<_> = tracer.entries().into_iter()
+			.map(|e| (e.op, e.key, e.value, e.value_len, e.source))
+			.collect();
+		assert_eq!(entries, vec![
+			(TraceOp::Get, b"doe".to_vec(), Some(b"rein".to_vec()), 8, Some(ValueSource::Backend)),
+			(TraceOp::Set, b"doe".to_vec(), Some(b"deer".to_vec()), 4, None),
+			(TraceOp::Get, b"doe".to_vec(), Some(b"deer".to_vec()), 4, Some(ValueSource::Overlay)),
+			(TraceOp::HostCall("start_transaction"), vec![], None, 0, None),
+		]);
+	}
+
 	#[test]
 	fn next_storage_key_works() {
 		let mut cache = StorageTransactionCache::default();
@@ -925,6 +1655,275 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn storage_size_works() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let child_info = &child_info;
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![20], None);
+		overlay.set_storage(vec![30], Some(vec![1, 2, 3]));
+		overlay.set_child_storage(child_info, vec![30], Some(vec![31]));
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = Storage {
+			top: map![
+				vec![10] => vec![10, 11],
+				vec![20] => vec![20]
+			],
+			children_default: map![
+				child_info.storage_key().to_vec() => StorageChild {
+					data: map![
+						vec![10] => vec![10; 5],
+						vec![30] => vec![40]
+					],
+					child_info: child_info.to_owned(),
+				}
+			],
+		}.into();
+
+		let ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+
+		assert_eq!(ext.storage_size(&[10]), Some(2));
+		assert_eq!(ext.storage_size(&[20]), None);
+		assert_eq!(ext.storage_size(&[30]), Some(3));
+		assert_eq!(ext.storage_size(&[40]), None);
+		assert_eq!(ext.child_storage_size(child_info, &[10]), Some(5));
+		assert_eq!(ext.child_storage_size(child_info, &[30]), Some(1));
+		assert_eq!(ext.child_storage_size(child_info, &[40]), None);
+	}
+
+	#[test]
+	fn child_storage_keys_iter_works() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let child3 = ChildInfo::new_default(b"Child3");
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = Storage {
+			top: map![],
+			children_default: map![
+				child1.storage_key().to_vec() => StorageChild {
+					data: map![vec![10] => vec![10]],
+					child_info: child1.to_owned(),
+				},
+				child2.storage_key().to_vec() => StorageChild {
+					data: map![vec![20] => vec![20]],
+					child_info: child2.to_owned(),
+				}
+			],
+		}.into();
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		ext.kill_child_storage(&child1);
+		ext.set_child_storage(&child2, vec![21], vec![21]);
+		ext.set_child_storage(&child3, vec![30], vec![30]);
+
+		assert_eq!(
+			ext.child_storage_keys_iter().collect::<Vec<_>>(),
+			vec![child2.prefixed_storage_key(), child3.prefixed_storage_key()],
+		);
+
+		ext.set_child_storage(&child1, vec![11], vec![11]);
+		ext.clear_child_storage(&child3, &[30]);
+		assert_eq!(
+			ext.child_storage_keys_iter().collect::<Vec<_>>(),
+			vec![child1.prefixed_storage_key(), child2.prefixed_storage_key()],
+		);
+	}
+
+	#[test]
+	fn child_storage_root_hints_are_used() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let child_info = &child_info;
+		let backend: TestBackend = Storage {
+			top: map![],
+			children_default: map![
+				child_info.storage_key().to_vec() => StorageChild {
+					data: map![vec![30] => vec![40]],
+					child_info: child_info.to_owned(),
+				}
+			],
+		}.into();
+		let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(child_info, vec![30], Some(vec![31]));
+
+		let expected_root = {
+			let mut overlay = overlay.clone();
+			let mut cache = StorageTransactionCache::default();
+			let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+			ext.storage_root()
+		};
+
+		let (child_root, _, child_transaction) = backend.child_storage_root(
+			child_info,
+			vec![(&[30][..], Some(&[31][..]))].into_iter(),
+		);
+		let mut cache = StorageTransactionCache::default();
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		assert_eq!(
+			ext.set_child_storage_root_hint(b"Other", child_root, Default::default()),
+			Err(NoChildChanges),
+		);
+		ext.set_child_storage_root_hint(child_info.storage_key(), child_root, child_transaction)
+			.unwrap();
+		assert_eq!(ext.storage_root(), expected_root);
+
+		// The hint is used as long as the child changes are not modified.
+		ext.set_child_storage_root_hint(child_info.storage_key(), H256::zero(), Default::default())
+			.unwrap();
+		assert_eq!(ext.child_storage_root(child_info), H256::zero().encode());
+		ext.set_child_storage(child_info, vec![40], vec![41]);
+		assert_ne!(ext.child_storage_root(child_info), H256::zero().encode());
+	}
+
+	#[test]
+	fn proof_recording_can_be_paused() {
+		let data: std::collections::BTreeMap<_, _> = map![vec![10] => vec![10], vec![20] => vec![20]];
+		let backend: TestBackend = data.into();
+		let proving_backend = crate::ProvingBackend::new(&backend);
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+		let ext = Ext::<Blake2Hasher, u64, _>::new(
+			&mut overlay,
+			&mut offchain_overlay,
+			&mut cache,
+			&proving_backend,
+			None,
+			None,
+		).with_recording_control(Some(proving_backend.recording_control().clone()));
+
+		ext.pause_proof_recording();
+		assert_eq!(ext.storage(&[10]), Some(vec![10]));
+		assert!(proving_backend.extract_proof().is_empty());
+
+		ext.resume_proof_recording();
+		assert_eq!(ext.storage(&[20]), Some(vec![20]));
+		assert!(!proving_backend.extract_proof().is_empty());
+	}
+
+	#[test]
+	fn child_keys_paged_works() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let child_info = &child_info;
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(child_info, vec![1, 2], None);
+		overlay.set_child_storage(child_info, vec![1, 3], Some(vec![13]));
+		overlay.set_child_storage(child_info, vec![2, 1], Some(vec![21]));
+		let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+		let backend: TestBackend = Storage {
+			top: map![],
+			children_default: map![
+				child_info.storage_key().to_vec() => StorageChild {
+					data: map![
+						vec![1] => vec![1],
+						vec![1, 1] => vec![11],
+						vec![1, 2] => vec![12],
+						vec![1, 4] => vec![14],
+						vec![2] => vec![2]
+					],
+					child_info: child_info.to_owned(),
+				}
+			],
+		}.into();
+
+		assert_eq!(
+			backend.child_keys_paged(child_info, &[1], 2, None).unwrap(),
+			vec![vec![1], vec![1, 1]],
+		);
+		assert_eq!(
+			backend.child_keys_paged(child_info, &[1], 10, Some(&[1, 1])).unwrap(),
+			vec![vec![1, 2], vec![1, 4]],
+		);
+
+		let ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		assert_eq!(
+			ext.child_keys_paged(child_info, &[1], 3, None),
+			vec![vec![1], vec![1, 1], vec![1, 3]],
+		);
+		assert_eq!(
+			ext.child_keys_paged(child_info, &[1], 3, Some(&[1, 1])),
+			vec![vec![1, 3], vec![1, 4]],
+		);
+		assert_eq!(
+			ext.child_keys_paged(child_info, &[], 10, Some(&[1, 4])),
+			vec![vec![2], vec![2, 1]],
+		);
+	}
+
+	#[test]
+	fn storage_root_with_transaction_works() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+		let backend = TestBackend::default();
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		let encoded_root = ext.storage_root();
+		let (root, transaction) = ext.storage_root_with_transaction();
+		assert_eq!(root.encode(), encoded_root);
+		assert_eq!(ext.storage_root(), encoded_root);
+
+		use crate::backend::Consolidate;
+		let mut db = backend.into_storage();
+		db.consolidate(transaction);
+		let committed = crate::TrieBackend::new(db, root);
+		assert_eq!(committed.storage(&[1]).unwrap(), Some(vec![1]));
+		assert_eq!(committed.child_storage(&child_info, &[2]).unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn original_values_are_noted() {
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.enable_original_values();
+		let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+		let backend = Storage {
+			top: map![
+				vec![10] => vec![10],
+				vec![20] => vec![20]
+			],
+			children_default: map![],
+		}.into();
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		assert_eq!(ext.storage(&[10]), Some(vec![10]));
+		ext.place_storage(vec![10], Some(vec![11]));
+		ext.place_storage(vec![10], Some(vec![10]));
+		ext.place_storage(vec![20], None);
+		ext.place_storage(vec![30], Some(vec![30]));
+		drop(ext);
+
+		assert_eq!(overlay.original_value(&[10]), Some(Some(vec![10])));
+		assert_eq!(overlay.is_original_value_restored(&[10]), Some(true));
+		assert_eq!(overlay.original_value(&[20]), Some(Some(vec![20])));
+		assert_eq!(overlay.is_original_value_restored(&[20]), Some(false));
+		assert_eq!(overlay.original_value(&[30]), Some(None));
+		assert_eq!(overlay.is_original_value_restored(&[30]), Some(false));
+		assert_eq!(overlay.original_value(&[40]), None);
+	}
+
+	#[test]
+	fn prefixed_child_storage_key_is_refused() {
+		let child_info = ChildInfo::new_default(b":child_storage:default:Child1");
+		let child_info = &child_info;
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = Storage::default().into();
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		ext.place_child_storage(child_info, vec![10], Some(vec![10]));
+
+		assert_eq!(ext.child_storage(child_info, &[10]), None);
+	}
+
 	#[test]
 	fn storage_append_works() {
 		let mut data = Vec::new();
@@ -944,4 +1943,65 @@ mod tests {
 
 		assert_eq!(Vec::<u32>::decode(&mut &data[..]).unwrap(), vec![1, 2]);
 	}
+
+	#[test]
+	fn writes_are_refused_in_read_only_mode() {
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = OffchainOverlayedChanges::enabled();
+		let mut cache = StorageTransactionCache::default();
+		let backend: TestBackend = vec![(None, vec![(b"a".to_vec(), Some(vec![1]))])].into();
+
+		{
+			let mut ext = TestExt::new(
+				&mut overlay,
+				&mut offchain_overlay,
+				&mut cache,
+				&backend,
+				None,
+				None,
+			).with_execution_mode(ExecutionMode::ReadOnly);
+
+			assert_eq!(ext.storage(b"a"), Some(vec![1]));
+			let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+				|| ext.set_storage(b"a".to_vec(), vec![2]),
+			));
+			assert!(result.is_err());
+			assert_eq!(ext.take_refused_write(), Some("set_storage"));
+
+			let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+				|| ext.set_offchain_storage(b"k", Some(b"v")),
+			));
+			assert!(result.is_err());
+			assert_eq!(ext.take_refused_write(), Some("set_offchain_storage"));
+			assert_eq!(ext.storage(b"a"), Some(vec![1]));
+		}
+
+		assert!(overlay.is_empty());
+		assert!(offchain_overlay.iter().next().is_none());
+	}
+
+	#[test]
+	fn incomplete_iteration_fails_the_call() {
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = OffchainOverlayedChanges::default();
+		let mut cache = StorageTransactionCache::default();
+		let backend: TestBackend = vec![
+			(None, vec![(b"a1".to_vec(), Some(vec![1])), (b"a2".to_vec(), Some(vec![2]))]),
+		].into();
+		let backend = crate::QuotaBackend::new(backend, crate::ReadQuota { reads: 1, bytes: 100 });
+		let mut ext = Ext::<Blake2Hasher, u64, _>::new(
+			&mut overlay,
+			&mut offchain_overlay,
+			&mut cache,
+			&backend,
+			None,
+			None,
+		);
+
+		let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+			|| ext.clear_prefix(b"a"),
+		));
+		assert!(result.is_err());
+		assert_eq!(ext.take_backend_failure(), Some(ExecutionError::QuotaExceeded));
+	}
 }
@@ -0,0 +1,218 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Conformance checks for [`Externalities`] implementations.
+//!
+//! Every check expects externalities whose backend holds [`initial_storage`] and no pending
+//! changes, and panics if the behavior differs from the reference implementation, [`Ext`].
+//! Custom externalities, e.g. for simulators, can be validated with [`run_all`]:
+//!
+//! ```
+//! use sp_state_machine::{TestExternalities, externalities_conformance::{run_all, initial_storage}};
+//!
+//! run_all(|check| {
+//! 	let mut ext = TestExternalities::<sp_core::Blake2Hasher>::new(initial_storage());
+//! 	check(&mut ext.ext());
+//! });
+//! ```
+//!
+//! [`Ext`]: crate::Ext
+
+use std::collections::{BTreeMap, HashMap};
+use sp_core::storage::{ChildInfo, Storage, StorageChild};
+use sp_externalities::Externalities;
+
+/// A single conformance check.
+pub type Check = fn(&mut dyn Externalities);
+
+/// All conformance checks, by name.
+pub const CHECKS: &[(&str, Check)] = &[
+	("storage_double_option", storage_double_option),
+	("overlay_precedence", overlay_precedence),
+	("child_isolation", child_isolation),
+	("prefix_clearing", prefix_clearing),
+	("transaction_rollback", transaction_rollback),
+];
+
+const CHILD_1: &[u8] = b"child1";
+const CHILD_2: &[u8] = b"child2";
+
+/// The backend state the checks expect.
+pub fn initial_storage() -> Storage {
+	let top: BTreeMap<_, _> = vec![
+		(b"a".to_vec(), b"1".to_vec()),
+		(b"b".to_vec(), b"2".to_vec()),
+		(b"c".to_vec(), b"3".to_vec()),
+		(b"prefix:x".to_vec(), b"4".to_vec()),
+		(b"prefix:y".to_vec(), b"5".to_vec()),
+		(b"prefiy".to_vec(), b"6".to_vec()),
+	].into_iter().collect();
+
+	let mut children_default = HashMap::new();
+	for (storage_key, value) in &[(CHILD_1, b"c1"), (CHILD_2, b"c2")] {
+		children_default.insert(storage_key.to_vec(), StorageChild {
+			data: vec![
+				(b"a".to_vec(), value.to_vec()),
+				(b"prefix:x".to_vec(), value.to_vec()),
+			].into_iter().collect(),
+			child_info: ChildInfo::new_default(storage_key),
+		});
+	}
+
+	Storage { top, children_default }
+}
+
+/// Runs every check with externalities provided by `with_ext`.
+///
+/// `with_ext` is called once per check and must pass fresh externalities to it.
+pub fn run_all(mut with_ext: impl FnMut(Check)) {
+	for (name, check) in CHECKS {
+		log::debug!(target: "state", "Running externalities conformance check {}", name);
+		with_ext(*check);
+	}
+}
+
+/// A removed value is absent, which is different from an empty value.
+pub fn storage_double_option(ext: &mut dyn Externalities) {
+	assert_eq!(ext.storage(b"missing"), None);
+	assert!(!ext.exists_storage(b"missing"));
+
+	ext.set_storage(b"empty".to_vec(), Vec::new());
+	assert_eq!(ext.storage(b"empty"), Some(Vec::new()));
+	assert!(ext.exists_storage(b"empty"));
+	assert_eq!(ext.storage_size(b"empty"), Some(0));
+
+	ext.clear_storage(b"empty");
+	assert_eq!(ext.storage(b"empty"), None);
+	assert!(!ext.exists_storage(b"empty"));
+	assert_eq!(ext.storage_size(b"empty"), None);
+
+	ext.place_storage(b"a".to_vec(), None);
+	assert_eq!(ext.storage(b"a"), None);
+	assert_eq!(ext.storage_hash(b"a"), None);
+}
+
+/// Pending changes shadow the backend, including for key iteration.
+pub fn overlay_precedence(ext: &mut dyn Externalities) {
+	assert_eq!(ext.storage(b"a"), Some(b"1".to_vec()));
+	ext.set_storage(b"a".to_vec(), b"10".to_vec());
+	assert_eq!(ext.storage(b"a"), Some(b"10".to_vec()));
+	assert_eq!(ext.storage_size(b"a"), Some(2));
+
+	ext.set_storage(b"aa".to_vec(), b"11".to_vec());
+	ext.clear_storage(b"b");
+	assert_eq!(ext.next_storage_key(b"a"), Some(b"aa".to_vec()));
+	assert_eq!(ext.next_storage_key(b"aa"), Some(b"c".to_vec()));
+
+	ext.set_storage(b"b".to_vec(), b"20".to_vec());
+	assert_eq!(ext.next_storage_key(b"aa"), Some(b"b".to_vec()));
+	assert_eq!(ext.storage(b"b"), Some(b"20".to_vec()));
+}
+
+/// Child tries are independent of each other and of the main trie.
+pub fn child_isolation(ext: &mut dyn Externalities) {
+	let child_1 = ChildInfo::new_default(CHILD_1);
+	let child_2 = ChildInfo::new_default(CHILD_2);
+
+	ext.set_child_storage(&child_1, b"a".to_vec(), b"c1x".to_vec());
+	assert_eq!(ext.child_storage(&child_1, b"a"), Some(b"c1x".to_vec()));
+	assert_eq!(ext.child_storage(&child_2, b"a"), Some(b"c2".to_vec()));
+	assert_eq!(ext.storage(b"a"), Some(b"1".to_vec()));
+
+	ext.clear_storage(b"a");
+	assert_eq!(ext.child_storage(&child_1, b"a"), Some(b"c1x".to_vec()));
+
+	ext.kill_child_storage(&child_1);
+	assert_eq!(ext.child_storage(&child_1, b"a"), None);
+	assert_eq!(ext.child_storage(&child_1, b"prefix:x"), None);
+	assert_eq!(ext.next_child_storage_key(&child_1, b""), None);
+	assert_eq!(ext.child_storage(&child_2, b"a"), Some(b"c2".to_vec()));
+	assert_eq!(ext.storage(b"b"), Some(b"2".to_vec()));
+}
+
+/// Clearing a prefix removes pending and backend keys, and only those.
+pub fn prefix_clearing(ext: &mut dyn Externalities) {
+	ext.set_storage(b"prefix:z".to_vec(), b"7".to_vec());
+	ext.clear_prefix(b"prefix:");
+	for key in &[&b"prefix:x"[..], b"prefix:y", b"prefix:z"] {
+		assert_eq!(ext.storage(key), None);
+	}
+	assert_eq!(ext.storage(b"prefiy"), Some(b"6".to_vec()));
+	assert_eq!(ext.next_storage_key(b"c"), Some(b"prefiy".to_vec()));
+
+	ext.set_storage(b"prefix:x".to_vec(), b"8".to_vec());
+	assert_eq!(ext.storage(b"prefix:x"), Some(b"8".to_vec()));
+
+	let child_1 = ChildInfo::new_default(CHILD_1);
+	let child_2 = ChildInfo::new_default(CHILD_2);
+	ext.clear_child_prefix(&child_1, b"prefix:");
+	assert_eq!(ext.child_storage(&child_1, b"prefix:x"), None);
+	assert_eq!(ext.child_storage(&child_1, b"a"), Some(b"c1".to_vec()));
+	assert_eq!(ext.child_storage(&child_2, b"prefix:x"), Some(b"c2".to_vec()));
+}
+
+/// Rolled back transactions leave no trace, committed ones are kept.
+pub fn transaction_rollback(ext: &mut dyn Externalities) {
+	let child_1 = ChildInfo::new_default(CHILD_1);
+
+	ext.storage_start_transaction();
+	ext.set_storage(b"a".to_vec(), b"10".to_vec());
+	ext.clear_storage(b"b");
+	ext.clear_prefix(b"prefix:");
+	ext.set_child_storage(&child_1, b"a".to_vec(), b"c1x".to_vec());
+	ext.storage_rollback_transaction().unwrap();
+	assert_eq!(ext.storage(b"a"), Some(b"1".to_vec()));
+	assert_eq!(ext.storage(b"b"), Some(b"2".to_vec()));
+	assert_eq!(ext.storage(b"prefix:x"), Some(b"4".to_vec()));
+	assert_eq!(ext.child_storage(&child_1, b"a"), Some(b"c1".to_vec()));
+
+	ext.storage_start_transaction();
+	ext.set_storage(b"a".to_vec(), b"10".to_vec());
+	ext.storage_start_transaction();
+	ext.set_storage(b"a".to_vec(), b"11".to_vec());
+	ext.clear_storage(b"c");
+	ext.storage_rollback_transaction().unwrap();
+	assert_eq!(ext.storage(b"a"), Some(b"10".to_vec()));
+	assert_eq!(ext.storage(b"c"), Some(b"3".to_vec()));
+	ext.storage_commit_transaction().unwrap();
+	assert_eq!(ext.storage(b"a"), Some(b"10".to_vec()));
+
+	assert_eq!(ext.storage_rollback_transaction(), Err(()));
+	assert_eq!(ext.storage_commit_transaction(), Err(()));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::{BasicExternalities, TestExternalities};
+
+	#[test]
+	fn ext_conforms() {
+		run_all(|check| {
+			let mut ext = TestExternalities::<BlakeTwo256, u64>::new(initial_storage());
+			check(&mut ext.ext());
+		});
+	}
+
+	#[test]
+	fn basic_externalities_conform() {
+		// Transactions are not supported by `BasicExternalities`.
+		for (_, check) in CHECKS.iter().filter(|(name, _)| *name != "transaction_rollback") {
+			check(&mut BasicExternalities::new(initial_storage()));
+		}
+	}
+}
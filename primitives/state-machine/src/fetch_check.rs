@@ -0,0 +1,191 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of the proofs a light client fetches from full nodes.
+//!
+//! The requests only describe what is checked, retries and peer selection are up to the
+//! caller.
+
+use std::collections::HashMap;
+use hash_db::Hasher;
+use codec::Codec;
+use sp_core::{
+	storage::{ChildInfo, PrefixedStorageKey},
+	traits::{CodeExecutor, SpawnNamed},
+};
+use crate::{
+	Error, OverlayedChanges, StorageKey, StorageValue, StorageProof,
+	backend::BackendRuntimeCode,
+	changes_trie::{
+		AnchorBlockId, BlockNumber, ConfigurationRange, RootsStorage, key_changes_proof_check,
+	},
+	create_proof_check_backend, execution_proof_check_on_trie_backend,
+	read_proof_check, read_child_proof_check,
+};
+
+/// Request to read keys from the state with the given root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadProofRequest<Hash> {
+	/// Root of the state the keys are read from.
+	pub root: Hash,
+	/// The child trie to read from, `None` for the main trie.
+	pub child_info: Option<ChildInfo>,
+	/// The keys to read.
+	pub keys: Vec<StorageKey>,
+}
+
+/// Request to call into the runtime of the state with the given root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionProofRequest<Hash> {
+	/// Root of the state the call is executed on.
+	pub root: Hash,
+	/// The method to call.
+	pub method: String,
+	/// The encoded arguments of the call.
+	pub call_data: Vec<u8>,
+}
+
+/// Request to find the blocks in which a key was changed.
+///
+/// A request covers a single changes trie configuration, queries over ranges with
+/// multiple configurations need to be split by the caller.
+pub struct ChangesProofRequest<'a, Hash: std::fmt::Debug, Number: BlockNumber> {
+	/// The changes trie configuration of the queried range.
+	pub config: ConfigurationRange<'a, Number>,
+	/// The first block of the range.
+	pub begin: Number,
+	/// The last block of the range.
+	pub end: AnchorBlockId<Hash, Number>,
+	/// Only digests up to this block are used.
+	pub max: Number,
+	/// The child trie the key belongs to, `None` for the main trie.
+	pub storage_key: Option<PrefixedStorageKey>,
+	/// The key to look up.
+	pub key: StorageKey,
+}
+
+/// Check a proof generated for the given read `request`.
+///
+/// Returns the value of every requested key.
+pub fn check_read_proof<H>(
+	request: &ReadProofRequest<H::Out>,
+	proof: StorageProof,
+) -> Result<HashMap<StorageKey, Option<StorageValue>>, Box<dyn Error>>
+where
+	H: Hasher,
+	H::Out: Ord + Codec,
+{
+	match request.child_info {
+		Some(ref child_info) =>
+			read_child_proof_check::<H, _>(request.root, proof, child_info, &request.keys),
+		None => read_proof_check::<H, _>(request.root, proof, &request.keys),
+	}
+}
+
+/// Check a proof generated for the given execution `request`.
+///
+/// The runtime code is taken from the proof, so the proof has to contain `:code`.
+/// Returns the output of the call.
+pub fn check_execution_proof<H, N, Exec, Spawn>(
+	request: &ExecutionProofRequest<H::Out>,
+	proof: StorageProof,
+	exec: &Exec,
+	spawn_handle: Spawn,
+) -> Result<Vec<u8>, Box<dyn Error>>
+where
+	H: Hasher,
+	H::Out: Ord + 'static + Codec,
+	N: BlockNumber,
+	Exec: CodeExecutor + Clone + 'static,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let trie_backend = create_proof_check_backend::<H>(request.root, proof)?;
+	let backend_runtime_code = BackendRuntimeCode::new(&trie_backend);
+	let runtime_code = backend_runtime_code.runtime_code()
+		.map_err(|e| Box::new(e) as Box<dyn Error>)?;
+
+	execution_proof_check_on_trie_backend::<_, N, _, _>(
+		&trie_backend,
+		&mut OverlayedChanges::default(),
+		exec,
+		spawn_handle,
+		&request.method,
+		&request.call_data,
+		&runtime_code,
+	)
+}
+
+/// Check a proof generated for the given changes `request`.
+///
+/// `roots_storage` has to provide the changes trie roots of the queried range. Returns the
+/// block numbers and extrinsic indices of all changes to the key.
+pub fn check_changes_proof<H, N>(
+	request: &ChangesProofRequest<H::Out, N>,
+	roots_storage: &dyn RootsStorage<H, N>,
+	proof: Vec<Vec<u8>>,
+) -> Result<Vec<(N, u32)>, String>
+where
+	H: Hasher,
+	H::Out: Codec,
+	N: BlockNumber,
+{
+	key_changes_proof_check::<H, N>(
+		request.config.clone(),
+		roots_storage,
+		proof,
+		request.begin.clone(),
+		&request.end,
+		request.max.clone(),
+		request.storage_key.as_ref(),
+		&request.key,
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{Backend, prove_read, prove_child_read, trie_backend::tests::test_trie};
+	use sp_runtime::traits::BlakeTwo256;
+
+	#[test]
+	fn check_read_proof_works() {
+		let child_info = ChildInfo::new_default(b"sub1");
+		let root = test_trie().storage_root(std::iter::empty()).0;
+
+		let proof = prove_read(test_trie(), &[&b"value2"[..], &b"missing"[..]]).unwrap();
+		let request = ReadProofRequest {
+			root,
+			child_info: None,
+			keys: vec![b"value2".to_vec(), b"missing".to_vec()],
+		};
+		let values = check_read_proof::<BlakeTwo256>(&request, proof).unwrap();
+		assert_eq!(values.get(&b"value2".to_vec()), Some(&Some(vec![24])));
+		assert_eq!(values.get(&b"missing".to_vec()), Some(&None));
+
+		let proof = prove_child_read(test_trie(), &child_info, &[b"value3"]).unwrap();
+		let request = ReadProofRequest {
+			root,
+			child_info: Some(child_info),
+			keys: vec![b"value3".to_vec()],
+		};
+		let values = check_read_proof::<BlakeTwo256>(&request, proof.clone()).unwrap();
+		assert_eq!(values.get(&b"value3".to_vec()), Some(&Some(vec![142])));
+
+		let request = ReadProofRequest { root: Default::default(), ..request };
+		assert!(check_read_proof::<BlakeTwo256>(&request, proof).is_err());
+	}
+}
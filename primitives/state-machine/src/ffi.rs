@@ -0,0 +1,269 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! C compatible interface to [`OverlayedChanges`] for embedders.
+//!
+//! An overlay is represented by an opaque [`overlay_t`] handle that is created with
+//! [`overlay_create`] and has to be released with [`overlay_destroy`]. All other functions
+//! return one of the `OVERLAY_*` status codes, which are stable across releases.
+//!
+//! Storage roots are calculated on top of an empty state using `Blake2-256`.
+
+#![allow(non_camel_case_types)]
+
+use std::{ptr, slice};
+use sp_core::Blake2Hasher;
+use crate::{OverlayedChanges, StorageTransactionCache, new_in_mem};
+
+/// The call succeeded.
+pub const OVERLAY_OK: i32 = 0;
+/// The key is not changed by the overlay.
+pub const OVERLAY_NOT_FOUND: i32 = 1;
+/// The key is deleted by the overlay.
+pub const OVERLAY_DELETED: i32 = 2;
+/// The output buffer is too small, the required length was written to `out_len`.
+pub const OVERLAY_BUFFER_TOO_SMALL: i32 = 3;
+/// There is no open transaction that could be closed.
+pub const OVERLAY_NO_OPEN_TRANSACTION: i32 = 4;
+/// A required pointer argument was null.
+pub const OVERLAY_NULL_POINTER: i32 = -1;
+
+/// Length of the storage root written by [`overlay_root`].
+pub const OVERLAY_ROOT_LEN: usize = 32;
+
+/// Opaque handle to an overlay.
+pub struct overlay_t(OverlayedChanges);
+
+/// Interpret the given pointer and length as a byte slice.
+///
+/// A null pointer is only allowed for an empty slice.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+	if len == 0 {
+		Some(&[])
+	} else if data.is_null() {
+		None
+	} else {
+		Some(slice::from_raw_parts(data, len))
+	}
+}
+
+/// Create a new empty overlay.
+#[no_mangle]
+pub extern "C" fn overlay_create() -> *mut overlay_t {
+	Box::into_raw(Box::new(overlay_t(OverlayedChanges::default())))
+}
+
+/// Release the given overlay. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `overlay` has to be null or a handle returned by [`overlay_create`] that was not
+/// released before.
+#[no_mangle]
+pub unsafe extern "C" fn overlay_destroy(overlay: *mut overlay_t) {
+	if !overlay.is_null() {
+		drop(Box::from_raw(overlay));
+	}
+}
+
+/// Set the given key to the given value, or delete it if `value` is null.
+///
+/// # Safety
+///
+/// `overlay` has to be a valid handle, `key` and `value` have to point to at least
+/// `key_len` and `value_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn overlay_set(
+	overlay: *mut overlay_t,
+	key: *const u8,
+	key_len: usize,
+	value: *const u8,
+	value_len: usize,
+) -> i32 {
+	let overlay = match overlay.as_mut() {
+		Some(overlay) => overlay,
+		None => return OVERLAY_NULL_POINTER,
+	};
+	let key = match bytes(key, key_len) {
+		Some(key) => key,
+		None => return OVERLAY_NULL_POINTER,
+	};
+	let value = if value.is_null() {
+		None
+	} else {
+		Some(slice::from_raw_parts(value, value_len).to_vec())
+	};
+	overlay.0.set_storage(key.to_vec(), value);
+	OVERLAY_OK
+}
+
+/// Read the value of the given key as seen by the current transaction.
+///
+/// The value is copied into `out` if it fits into `out_cap` bytes. Its length is always
+/// written to `out_len` when the key is set.
+///
+/// # Safety
+///
+/// `overlay` has to be a valid handle, `key` has to point to `key_len` readable bytes,
+/// `out` to `out_cap` writable bytes and `out_len` to a writable `usize`.
+#[no_mangle]
+pub unsafe extern "C" fn overlay_get(
+	overlay: *const overlay_t,
+	key: *const u8,
+	key_len: usize,
+	out: *mut u8,
+	out_cap: usize,
+	out_len: *mut usize,
+) -> i32 {
+	let (overlay, key) = match (overlay.as_ref(), bytes(key, key_len)) {
+		(Some(overlay), Some(key)) if !out_len.is_null() => (overlay, key),
+		_ => return OVERLAY_NULL_POINTER,
+	};
+	let value = match overlay.0.storage(key) {
+		None => return OVERLAY_NOT_FOUND,
+		Some(None) => return OVERLAY_DELETED,
+		Some(Some(value)) => value,
+	};
+
+	*out_len = value.len();
+	if value.len() > out_cap {
+		return OVERLAY_BUFFER_TOO_SMALL
+	}
+	if !value.is_empty() {
+		if out.is_null() {
+			return OVERLAY_NULL_POINTER
+		}
+		ptr::copy_nonoverlapping(value.as_ptr(), out, value.len());
+	}
+	OVERLAY_OK
+}
+
+/// Start a new nested transaction.
+///
+/// # Safety
+///
+/// `overlay` has to be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn overlay_start_transaction(overlay: *mut overlay_t) -> i32 {
+	match overlay.as_mut() {
+		Some(overlay) => {
+			overlay.0.start_transaction();
+			OVERLAY_OK
+		},
+		None => OVERLAY_NULL_POINTER,
+	}
+}
+
+/// Commit the last transaction.
+///
+/// # Safety
+///
+/// `overlay` has to be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn overlay_commit(overlay: *mut overlay_t) -> i32 {
+	match overlay.as_mut() {
+		Some(overlay) => match overlay.0.commit_transaction() {
+			Ok(()) => OVERLAY_OK,
+			Err(_) => OVERLAY_NO_OPEN_TRANSACTION,
+		},
+		None => OVERLAY_NULL_POINTER,
+	}
+}
+
+/// Roll back the last transaction.
+///
+/// # Safety
+///
+/// `overlay` has to be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn overlay_rollback(overlay: *mut overlay_t) -> i32 {
+	match overlay.as_mut() {
+		Some(overlay) => match overlay.0.rollback_transaction() {
+			Ok(()) => OVERLAY_OK,
+			Err(_) => OVERLAY_NO_OPEN_TRANSACTION,
+		},
+		None => OVERLAY_NULL_POINTER,
+	}
+}
+
+/// Write the storage root of all changes as seen by the current transaction to `out`.
+///
+/// # Safety
+///
+/// `overlay` has to be a valid handle and `out` has to point to [`OVERLAY_ROOT_LEN`]
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn overlay_root(overlay: *const overlay_t, out: *mut u8) -> i32 {
+	let overlay = match overlay.as_ref() {
+		Some(overlay) if !out.is_null() => overlay,
+		_ => return OVERLAY_NULL_POINTER,
+	};
+	let backend = new_in_mem::<Blake2Hasher>();
+	let mut cache = StorageTransactionCache::<_, Blake2Hasher, u64>::default();
+	let root = overlay.0.storage_root(&backend, &mut cache);
+	ptr::copy_nonoverlapping(root.as_ref().as_ptr(), out, OVERLAY_ROOT_LEN);
+	OVERLAY_OK
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::Backend;
+
+	#[test]
+	fn overlay_handle_works() {
+		unsafe {
+			let overlay = overlay_create();
+			let mut out = [0u8; 8];
+			let mut out_len = 0;
+
+			assert_eq!(overlay_set(overlay, b"key".as_ptr(), 3, b"value".as_ptr(), 5), OVERLAY_OK);
+			assert_eq!(overlay_start_transaction(overlay), OVERLAY_OK);
+			assert_eq!(overlay_set(overlay, b"key".as_ptr(), 3, ptr::null(), 0), OVERLAY_OK);
+			assert_eq!(
+				overlay_get(overlay, b"key".as_ptr(), 3, out.as_mut_ptr(), 8, &mut out_len),
+				OVERLAY_DELETED,
+			);
+			assert_eq!(overlay_rollback(overlay), OVERLAY_OK);
+			assert_eq!(overlay_commit(overlay), OVERLAY_NO_OPEN_TRANSACTION);
+
+			assert_eq!(
+				overlay_get(overlay, b"key".as_ptr(), 3, out.as_mut_ptr(), 2, &mut out_len),
+				OVERLAY_BUFFER_TOO_SMALL,
+			);
+			assert_eq!(
+				overlay_get(overlay, b"key".as_ptr(), 3, out.as_mut_ptr(), 8, &mut out_len),
+				OVERLAY_OK,
+			);
+			assert_eq!(&out[..out_len], b"value");
+			assert_eq!(
+				overlay_get(overlay, b"other".as_ptr(), 5, out.as_mut_ptr(), 8, &mut out_len),
+				OVERLAY_NOT_FOUND,
+			);
+
+			let mut root = [0u8; OVERLAY_ROOT_LEN];
+			assert_eq!(overlay_root(overlay, root.as_mut_ptr()), OVERLAY_OK);
+			let expected = new_in_mem::<Blake2Hasher>()
+				.storage_root(vec![(&b"key"[..], Some(&b"value"[..]))].into_iter())
+				.0;
+			assert_eq!(&root[..], expected.as_ref());
+
+			assert_eq!(overlay_root(ptr::null(), root.as_mut_ptr()), OVERLAY_NULL_POINTER);
+			overlay_destroy(overlay);
+		}
+	}
+}
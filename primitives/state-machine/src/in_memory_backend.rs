@@ -120,6 +120,15 @@ where
 		self.essence.set_root(root);
 	}
 
+	/// Apply the given transaction to this backend and set the root to the given value.
+	///
+	/// In contrast to [`update_backend`](Self::update_backend) this doesn't copy the
+	/// underlying storage.
+	pub fn apply_transaction(&mut self, root: H::Out, transaction: MemoryDB<H>) {
+		self.backend_storage_mut().consolidate(transaction);
+		self.essence.set_root(root);
+	}
+
 	/// Merge trie nodes into this backend.
 	pub fn update_backend(&self, root: H::Out, changes: MemoryDB<H>) -> Self {
 		let mut clone = self.backend_storage().clone();
@@ -127,6 +136,21 @@ where
 		Self::new(clone, root)
 	}
 
+	/// Build a backend from raw key value pairs, as exported by
+	/// [`Backend::export_raw`](crate::Backend::export_raw).
+	///
+	/// Pairs with a `ChildInfo` are inserted into the respective child trie, whose root is
+	/// inserted into the top trie.
+	pub fn from_raw_iter<I>(pairs: I) -> Self
+		where I: IntoIterator<Item=(Option<ChildInfo>, StorageKey, StorageValue)>,
+	{
+		let mut tries: HashMap<Option<ChildInfo>, BTreeMap<StorageKey, StorageValue>> = HashMap::new();
+		for (child_info, key, value) in pairs {
+			tries.entry(child_info).or_default().insert(key, value);
+		}
+		tries.into()
+	}
+
 	/// Compare with another in-memory backend.
 	pub fn eq(&self, other: &Self) -> bool {
 		self.root() == other.root()
@@ -232,4 +256,52 @@ mod tests {
 		let storage_key = child_info.prefixed_storage_key();
 		assert!(trie_backend.storage(storage_key.as_slice()).unwrap().is_some());
 	}
+
+	#[test]
+	fn apply_transaction_works() {
+		let mut storage = new_in_mem::<BlakeTwo256>();
+		let child_info = ChildInfo::new_default(b"1");
+		let child_delta = vec![(&b"2"[..], Some(&b"3"[..]))];
+
+		let child_root = storage.child_storage_root(&child_info, child_delta.clone().into_iter()).0;
+		let (root, transaction) = storage.full_storage_root(
+			vec![(&b"4"[..], Some(&b"5"[..]))].into_iter(),
+			vec![(&child_info, child_delta.into_iter())].into_iter(),
+		);
+		storage.apply_transaction(root, transaction);
+
+		assert_eq!(*storage.root(), root);
+		assert_eq!(storage.storage(b"4").unwrap(), Some(b"5".to_vec()));
+		assert_eq!(storage.child_storage(&child_info, b"2").unwrap(), Some(b"3".to_vec()));
+		assert_eq!(
+			storage.storage(child_info.prefixed_storage_key().as_slice()).unwrap(),
+			Some(child_root.as_ref().to_vec()),
+		);
+	}
+
+	#[test]
+	fn export_raw_round_trips() {
+		let child_info = ChildInfo::new_default(b"child");
+		let storage = new_in_mem::<BlakeTwo256>().update(vec![
+			(Some(child_info.clone()), vec![(b"c1".to_vec(), Some(b"v1".to_vec()))]),
+			(None, vec![
+				(b"a1".to_vec(), Some(b"v2".to_vec())),
+				(b"b1".to_vec(), Some(b"v3".to_vec())),
+			]),
+		]);
+
+		let mut pairs = Vec::new();
+		storage.export_raw(&[], |child_info, key, value| {
+			pairs.push((child_info.cloned(), key.to_vec(), value.to_vec()));
+		}).unwrap();
+		assert_eq!(pairs.len(), 3);
+		assert!(pairs.contains(&(Some(child_info.clone()), b"c1".to_vec(), b"v1".to_vec())));
+
+		let imported = TrieBackend::<MemoryDB<BlakeTwo256>, _>::from_raw_iter(pairs);
+		assert_eq!(imported.root(), storage.root());
+
+		let mut keys = Vec::new();
+		storage.export_raw(b"a", |_, key, _| keys.push(key.to_vec())).unwrap();
+		assert_eq!(keys, vec![b"a1".to_vec()]);
+	}
 }
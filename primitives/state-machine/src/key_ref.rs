@@ -0,0 +1,102 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Storage keys with a precomputed hash.
+
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// A storage key together with its hash.
+///
+/// The hash is computed once per host call and then used by every layer the lookup passes
+/// through, e.g. the key filter of the overlay and the cache of the backend. It is not
+/// collision resistant, users must always compare the full key as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRef<'a> {
+	key: &'a [u8],
+	hash: u64,
+}
+
+impl<'a> KeyRef<'a> {
+	/// Hash the given key.
+	pub fn new(key: &'a [u8]) -> Self {
+		KeyRef { key, hash: fnv1a(key) }
+	}
+
+	/// Returns the key.
+	pub fn key(&self) -> &'a [u8] {
+		self.key
+	}
+
+	/// Returns the hash of the key.
+	pub fn hash(&self) -> u64 {
+		self.hash
+	}
+}
+
+impl<'a> AsRef<[u8]> for KeyRef<'a> {
+	fn as_ref(&self) -> &[u8] {
+		self.key
+	}
+}
+
+/// FNV-1a, cheap for the short keys of the storage.
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+	let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+	for byte in data {
+		hash ^= *byte as u64;
+		hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+	}
+	hash
+}
+
+/// Hasher for maps that are keyed by [`KeyRef::hash`], the hash is used as is.
+#[derive(Default)]
+pub(crate) struct PrehashedHasher(u64);
+
+impl Hasher for PrehashedHasher {
+	fn write(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			self.0 = self.0.rotate_left(8) ^ *byte as u64;
+		}
+	}
+
+	fn write_u64(&mut self, hash: u64) {
+		self.0 = hash;
+	}
+
+	fn finish(&self) -> u64 {
+		self.0
+	}
+}
+
+/// Builder of [`PrehashedHasher`].
+pub(crate) type BuildPrehashed = BuildHasherDefault<PrehashedHasher>;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	#[test]
+	fn prehashed_map_uses_key_hash() {
+		let key = KeyRef::new(b"key");
+		let mut map: HashMap<u64, &[u8], BuildPrehashed> = Default::default();
+		map.insert(key.hash(), key.key());
+
+		assert_eq!(map.get(&KeyRef::new(b"key").hash()), Some(&&b"key"[..]));
+		assert_ne!(KeyRef::new(b"key").hash(), KeyRef::new(b"kez").hash());
+	}
+}
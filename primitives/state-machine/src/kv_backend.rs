@@ -0,0 +1,436 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Backend adapter over a generic ordered key-value store.
+//!
+//! The store holds three kinds of entries, distinguished by their first byte:
+//!
+//! - the trie nodes of every committed state, used to compute storage roots;
+//! - a flat copy of the values of the latest committed state, used to serve reads and
+//!   iterations with `get` and `next_key` instead of walking the trie;
+//! - the root of the latest committed state.
+//!
+//! Nodes of previous states are never removed, pruning is left to the embedder.
+
+use std::{collections::HashMap, sync::Arc};
+use hash_db::{Hasher, Prefix};
+use codec::{Codec, Decode, Encode};
+use log::warn;
+use parking_lot::RwLock;
+use sp_core::storage::ChildInfo;
+use sp_trie::{PrefixedMemoryDB, PrefixedKey, KeyFunction, DBValue, empty_child_trie_root};
+use sp_trie::trie_types::Layout;
+use crate::{
+	Backend, StorageKey, StorageValue, StorageCollection, ChildStorageCollection, UsageInfo,
+	trie_backend::TrieBackend,
+	trie_backend_essence::TrieBackendStorage,
+};
+
+/// Key of the root of the latest committed state.
+const ROOT_KEY: &[u8] = b"R";
+/// Prefix of trie nodes.
+const NODE_PREFIX: u8 = b'N';
+/// Prefix of the flat values of the main trie.
+const TOP_PREFIX: u8 = b'T';
+/// Prefix of the flat values of child tries, followed by the encoded child storage key.
+const CHILD_PREFIX: u8 = b'C';
+
+/// Ordered key-value store that a [`KvBackend`] is built upon.
+///
+/// This is the only thing an embedder has to provide, e.g. on top of sled, LMDB or
+/// IndexedDB.
+pub trait OrderedKv: Send + Sync {
+	/// Get the value stored at `key`.
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+	/// Returns the smallest key that is strictly greater than `key`.
+	fn next_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+	/// Atomically apply the given changes, `None` removes the key.
+	fn write_batch(&self, batch: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<(), String>;
+}
+
+/// Trie node storage of a [`KvBackend`].
+///
+/// Nodes of a commit that is being prepared are served from `pending` before they reach the
+/// store.
+pub struct KvNodeStorage<K> {
+	kv: Arc<K>,
+	pending: Option<Arc<HashMap<Vec<u8>, DBValue>>>,
+}
+
+impl<K: OrderedKv, H: Hasher> TrieBackendStorage<H> for KvNodeStorage<K> {
+	type Overlay = PrefixedMemoryDB<H>;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		let key = node_key(&PrefixedKey::<H>::key(key, prefix));
+		if let Some(value) = self.pending.as_ref().and_then(|pending| pending.get(&key)) {
+			return Ok(Some(value.clone()))
+		}
+		self.kv.get(&key)
+	}
+}
+
+fn node_key(key: &[u8]) -> Vec<u8> {
+	let mut result = Vec::with_capacity(key.len() + 1);
+	result.push(NODE_PREFIX);
+	result.extend_from_slice(key);
+	result
+}
+
+fn top_key(key: &[u8]) -> Vec<u8> {
+	let mut result = Vec::with_capacity(key.len() + 1);
+	result.push(TOP_PREFIX);
+	result.extend_from_slice(key);
+	result
+}
+
+fn child_prefix(storage_key: &[u8]) -> Vec<u8> {
+	let mut result = vec![CHILD_PREFIX];
+	storage_key.encode_to(&mut result);
+	result
+}
+
+fn child_key(child_prefix: &[u8], key: &[u8]) -> Vec<u8> {
+	let mut result = Vec::with_capacity(child_prefix.len() + key.len());
+	result.extend_from_slice(child_prefix);
+	result.extend_from_slice(key);
+	result
+}
+
+/// [`Backend`] implementation on top of an [`OrderedKv`] store.
+///
+/// Reads are served from a flat copy of the latest committed state, while storage roots are
+/// computed with a trie whose nodes are kept in the same store.
+pub struct KvBackend<K, H: Hasher> {
+	kv: Arc<K>,
+	root: RwLock<H::Out>,
+}
+
+impl<K: OrderedKv, H: Hasher> KvBackend<K, H> where H::Out: Codec {
+	/// Open the state stored in `kv`, an empty store is an empty state.
+	pub fn new(kv: Arc<K>) -> Result<Self, String> {
+		let root = match kv.get(ROOT_KEY)? {
+			Some(root) => Decode::decode(&mut &root[..])
+				.map_err(|e| format!("Invalid state root: {:?}", e))?,
+			None => empty_child_trie_root::<Layout<H>>(),
+		};
+		Ok(KvBackend {
+			kv,
+			root: RwLock::new(root),
+		})
+	}
+
+	/// Returns the root of the latest committed state.
+	pub fn root(&self) -> H::Out {
+		*self.root.read()
+	}
+
+	/// Returns a reference to the underlying store.
+	pub fn kv(&self) -> &Arc<K> {
+		&self.kv
+	}
+
+	fn trie(&self) -> TrieBackend<KvNodeStorage<K>, H> {
+		self.trie_at(self.root(), None)
+	}
+
+	fn trie_at(
+		&self,
+		root: H::Out,
+		pending: Option<Arc<HashMap<Vec<u8>, DBValue>>>,
+	) -> TrieBackend<KvNodeStorage<K>, H> {
+		TrieBackend::new(KvNodeStorage { kv: self.kv.clone(), pending }, root)
+	}
+
+	/// Calls `f` with every stored key that starts with `prefix`, in order.
+	fn scan(
+		&self,
+		prefix: &[u8],
+		mut f: impl FnMut(&[u8]) -> Result<(), String>,
+	) -> Result<(), String> {
+		if self.kv.get(prefix)?.is_some() {
+			f(prefix)?;
+		}
+		let mut current = prefix.to_vec();
+		while let Some(next) = self.kv.next_key(&current)? {
+			if !next.starts_with(prefix) {
+				break
+			}
+			f(&next)?;
+			current = next;
+		}
+		Ok(())
+	}
+
+	fn scan_keys(&self, namespace_len: usize, prefix: &[u8], mut f: impl FnMut(&[u8])) {
+		if let Err(e) = self.scan(prefix, |key| {
+			f(&key[namespace_len..]);
+			Ok(())
+		}) {
+			warn!(target: "state", "Failed to iterate over the key-value store: {}", e);
+		}
+	}
+
+	fn next_key_in(&self, namespace: &[u8], key: &[u8]) -> Result<Option<StorageKey>, String> {
+		let next = self.kv.next_key(&child_key(namespace, key))?;
+		Ok(next.filter(|next| next.starts_with(namespace)).map(|next| next[namespace.len()..].to_vec()))
+	}
+}
+
+impl<K, H: Hasher> std::fmt::Debug for KvBackend<K, H> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "KvBackend")
+	}
+}
+
+impl<K: OrderedKv, H: Hasher> Backend<H> for KvBackend<K, H> where
+	H::Out: Ord + Codec,
+{
+	type Error = String;
+	type Transaction = PrefixedMemoryDB<H>;
+	type TrieBackendStorage = KvNodeStorage<K>;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error> {
+		self.kv.get(&top_key(key))
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, Self::Error> {
+		self.kv.get(&child_key(&child_prefix(child_info.storage_key()), key))
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+		self.next_key_in(&[TOP_PREFIX], key)
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageKey>, Self::Error> {
+		self.next_key_in(&child_prefix(child_info.storage_key()), key)
+	}
+
+	fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], f: F) {
+		self.scan_keys(1, &top_key(prefix), f)
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], mut f: F) {
+		let kv = &self.kv;
+		if let Err(e) = self.scan(&top_key(prefix), |key| {
+			if let Some(value) = kv.get(key)? {
+				f(&key[1..], &value);
+			}
+			Ok(())
+		}) {
+			warn!(target: "state", "Failed to iterate over the key-value store: {}", e);
+		}
+	}
+
+	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		f: F,
+	) {
+		let prefix = child_prefix(child_info.storage_key());
+		self.scan_keys(prefix.len(), &prefix, f)
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		f: F,
+	) {
+		let namespace = child_prefix(child_info.storage_key());
+		self.scan_keys(namespace.len(), &child_key(&namespace, prefix), f)
+	}
+
+	fn storage_root<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.trie().storage_root(delta)
+	}
+
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.trie().storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: H::Out,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		self.trie().storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		true
+	}
+
+	fn child_storage_root<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord {
+		self.trie().child_storage_root(child_info, delta)
+	}
+
+	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
+		let mut pairs = Vec::new();
+		self.for_key_values_with_prefix(&[], |key, value| pairs.push((key.to_vec(), value.to_vec())));
+		pairs
+	}
+
+	fn register_overlay_stats(&mut self, _stats: &crate::stats::StateMachineStats) { }
+
+	fn usage_info(&self) -> UsageInfo {
+		UsageInfo::empty()
+	}
+
+	fn commit(
+		&self,
+		root: H::Out,
+		mut transaction: Self::Transaction,
+		main_storage_changes: StorageCollection,
+		child_storage_changes: ChildStorageCollection,
+	) -> Result<(), Self::Error> {
+		let mut current_root = self.root.write();
+
+		let nodes: HashMap<_, _> = transaction.drain().into_iter()
+			.filter(|(_, (_, rc))| *rc > 0)
+			.map(|(key, (value, _))| (node_key(&key), value))
+			.collect();
+		let nodes = Arc::new(nodes);
+
+		let mut batch: Vec<_> = nodes.iter()
+			.map(|(key, value)| (key.clone(), Some(value.clone())))
+			.collect();
+		batch.extend(main_storage_changes.into_iter().map(|(key, value)| (top_key(&key), value)));
+
+		// The main trie holds the roots of the child tries, keep the flat copy in sync with it.
+		let trie = self.trie_at(root, Some(nodes.clone()));
+		for (storage_key, changes) in child_storage_changes {
+			let prefixed_storage_key = ChildInfo::new_default(&storage_key).prefixed_storage_key();
+			let child_root = trie.storage(prefixed_storage_key.as_slice())?;
+			batch.push((top_key(prefixed_storage_key.as_slice()), child_root));
+
+			let prefix = child_prefix(&storage_key);
+			batch.extend(changes.into_iter().map(|(key, value)| (child_key(&prefix, &key), value)));
+		}
+		batch.push((ROOT_KEY.to_vec(), Some(root.encode())));
+
+		self.kv.write_batch(batch)?;
+		*current_root = root;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::BTreeMap;
+	use std::ops::Bound;
+	use parking_lot::Mutex;
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::InMemoryBackend;
+
+	#[derive(Default)]
+	struct BTreeKv(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+	impl OrderedKv for BTreeKv {
+		fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+			Ok(self.0.lock().get(key).cloned())
+		}
+
+		fn next_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+			let map = self.0.lock();
+			let next = map.range::<[u8], _>((Bound::Excluded(key), Bound::Unbounded)).next();
+			Ok(next.map(|(key, _)| key.clone()))
+		}
+
+		fn write_batch(&self, batch: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<(), String> {
+			let mut map = self.0.lock();
+			for (key, value) in batch {
+				match value {
+					Some(value) => { map.insert(key, value); },
+					None => { map.remove(&key); },
+				}
+			}
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn empty_store_has_empty_root() {
+		let backend = KvBackend::<_, BlakeTwo256>::new(Arc::new(BTreeKv::default())).unwrap();
+		let in_memory = InMemoryBackend::<BlakeTwo256>::default();
+		assert_eq!(backend.root(), *in_memory.root());
+		assert!(backend.pairs().is_empty());
+	}
+
+	#[test]
+	fn commit_matches_in_memory_backend() {
+		let kv = Arc::new(BTreeKv::default());
+		let backend = KvBackend::<_, BlakeTwo256>::new(kv.clone()).unwrap();
+		let child_info = ChildInfo::new_default(b"child");
+
+		let top = vec![(b"a".to_vec(), Some(b"1".to_vec())), (b"b".to_vec(), Some(b"2".to_vec()))];
+		let child = vec![(b"c".to_vec(), Some(b"3".to_vec()))];
+		let (root, transaction) = backend.full_storage_root(
+			top.iter().map(|(k, v)| (&k[..], v.as_deref())),
+			std::iter::once((&child_info, child.iter().map(|(k, v)| (&k[..], v.as_deref())))),
+		);
+		backend.commit(
+			root,
+			transaction,
+			top.clone(),
+			vec![(b"child".to_vec(), child.clone())],
+		).unwrap();
+
+		let in_memory: InMemoryBackend<BlakeTwo256> = vec![
+			(None, top),
+			(Some(child_info.clone()), child),
+		].into();
+		assert_eq!(backend.root(), *in_memory.root());
+		assert_eq!(backend.pairs(), in_memory.pairs());
+
+		assert_eq!(backend.storage(b"a").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(backend.next_storage_key(b"a").unwrap(), Some(b"b".to_vec()));
+		assert_eq!(backend.child_storage(&child_info, b"c").unwrap(), Some(b"3".to_vec()));
+		assert_eq!(backend.next_child_storage_key(&child_info, b"c").unwrap(), None);
+
+		// Updates are computed against the committed trie.
+		let (root, transaction) = backend.storage_root(vec![(&b"a"[..], None)].into_iter());
+		backend.commit(root, transaction, vec![(b"a".to_vec(), None)], Vec::new()).unwrap();
+		let in_memory = in_memory.update(vec![(None, vec![(b"a".to_vec(), None)])]);
+		assert_eq!(backend.root(), *in_memory.root());
+		assert_eq!(backend.storage(b"a").unwrap(), None);
+		assert_eq!(backend.keys(b""), in_memory.keys(b""));
+
+		let reopened = KvBackend::<_, BlakeTwo256>::new(kv).unwrap();
+		assert_eq!(reopened.root(), backend.root());
+	}
+}
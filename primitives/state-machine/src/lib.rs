@@ -19,64 +19,135 @@
 
 #![warn(missing_docs)]
 
-use std::{fmt, result, collections::HashMap, panic::UnwindSafe};
+use std::{fmt, result, collections::HashMap, panic::UnwindSafe, sync::Arc, any::TypeId};
 use log::{warn, trace};
 use hash_db::Hasher;
 use codec::{Decode, Encode, Codec};
 use sp_core::{
-	offchain::storage::OffchainOverlayedChanges,
-	storage::ChildInfo, NativeOrEncoded, NeverNativeValue, hexdisplay::HexDisplay,
-	traits::{CodeExecutor, CallInWasmExt, RuntimeCode, SpawnNamed},
+	offchain::{storage::OffchainOverlayedChanges, Capabilities},
+	storage::ChildInfo, ExecutionContext, NativeOrEncoded, NeverNativeValue, hexdisplay::HexDisplay,
+	traits::{
+		CodeExecutor, CallInWasmExt, RuntimeCode, SpawnNamed, ExecutionEnvironment,
+		ExecutionEnvironmentExt, TaskExecutorExt,
+	},
 };
 use sp_externalities::Extensions;
 
 pub mod backend;
+mod async_backend;
 mod in_memory_backend;
 mod changes_trie;
 mod error;
+mod fetch_check;
 mod ext;
 mod testing;
 mod basic;
 mod overlayed_changes;
 mod proving_backend;
+mod quota_backend;
+mod recording_backend;
 mod trie_backend;
 mod trie_backend_essence;
 mod stats;
 mod read_only;
+mod migrations;
+mod code_substitute;
+mod cancellation;
+mod op_cost;
+mod tracer;
+mod replay;
+mod block_import;
+mod overlay_set;
+mod deferred_checks;
+mod execution_policy;
+mod tape;
+mod dry_run;
+mod key_ref;
+mod kv_backend;
+mod trie_proof;
+mod trie_statistics;
+mod consistency;
+mod blob;
+mod call_cache;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod overlay_fuzz;
+pub mod externalities_conformance;
+#[cfg(any(test, feature = "ffi"))]
+pub mod ffi;
 
 pub use sp_trie::{trie_types::{Layout, TrieDBMut}, StorageProof, TrieMut, DBValue, MemoryDB};
 pub use testing::TestExternalities;
-pub use basic::BasicExternalities;
+pub use basic::{BasicExternalities, parallel_storage_root};
 pub use read_only::{ReadOnlyExternalities, InspectState};
 pub use ext::Ext;
-pub use backend::Backend;
+pub use backend::{Backend, StorageReadHint};
+pub use key_ref::KeyRef;
+pub use async_backend::{AsyncBackend, AsyncBackendAdapter, AsyncBackendError, AsyncBackendResult};
 pub use changes_trie::{
 	AnchorBlockId as ChangesTrieAnchorBlockId,
 	State as ChangesTrieState,
 	Storage as ChangesTrieStorage,
 	RootsStorage as ChangesTrieRootsStorage,
+	PersistentStorage as PersistentChangesTrieStorage,
 	InMemoryStorage as InMemoryChangesTrieStorage,
 	BuildCache as ChangesTrieBuildCache,
+	RootsCache as ChangesTrieRootsCache,
 	CacheAction as ChangesTrieCacheAction,
 	ConfigurationRange as ChangesTrieConfigurationRange,
+	MissingRange as ChangesTrieMissingRange,
 	key_changes, key_changes_proof,
 	key_changes_proof_check, key_changes_proof_check_with_db,
+	key_changes_tolerating_missing, build_changes_trie_transaction,
 	prune as prune_changes_tries,
 	disabled_state as disabled_changes_trie_state,
 	BlockNumber as ChangesTrieBlockNumber,
 };
 pub use overlayed_changes::{
 	OverlayedChanges, StorageChanges, StorageTransactionCache, StorageKey, StorageValue,
-	StorageCollection, ChildStorageCollection,
+	StorageCollection, ChildStorageCollection, MergeConflict, NoChildChanges,
+	OverlayedChangesBuilder, KeyHasher, DirtyKeys, ExtrinsicSet, StorageNamespace, NamespaceStats,
+	JournalEntry, JournalRecord, JournalSink, FileJournal, read_journal, CommittedChanges,
+	PendingLayers,
 };
 pub use proving_backend::{
-	create_proof_check_backend, ProofRecorder, ProvingBackend, ProvingBackendRecorder,
+	create_proof_check_backend, ProofRecorder, ProofRecordingStats, ProvingBackend,
+	ProvingBackendRecorder, RecordingControl,
 };
-pub use trie_backend_essence::{TrieBackendStorage, Storage};
+pub use quota_backend::{QuotaBackend, QuotaError, ReadQuota};
+pub use recording_backend::{RecordingBackend, AccessRecord, AccessKind};
+pub use kv_backend::{KvBackend, KvNodeStorage, OrderedKv};
+pub use trie_proof::verify_trie_proof;
+pub use trie_backend_essence::{TrieBackendStorage, Storage, NodeStorage, KeyFunctionStorage};
 pub use trie_backend::TrieBackend;
+pub use trie_statistics::TrieStatistics;
+pub use blob::{BlobHandle, LargeValueMigration, BLOB_STORE_KEY, blob_child_info};
+pub use consistency::{verify_committed_against_backend, ConsistencyReport, ValueMismatch};
 pub use error::{Error, ExecutionError};
+pub use fetch_check::{
+	ReadProofRequest, ExecutionProofRequest, ChangesProofRequest,
+	check_read_proof, check_execution_proof, check_changes_proof,
+};
 pub use in_memory_backend::new_in_mem;
 pub use stats::{UsageInfo, UsageUnit, StateMachineStats};
+pub use cancellation::CancellationToken;
+pub use call_cache::CallCache;
+pub use op_cost::{OpCostHandler, StorageOp};
+pub use dry_run::DryRunReport;
+pub use execution_policy::{ConsensusFailureResolution, ExecutionPolicy, MethodExecutionPolicy};
+pub use tracer::{StateTracer, TraceOp, TraceEntry, ValueSource};
+pub use tape::{
+	ExternalitiesTape, TapeEntry, RecordingExternalities, ReplayExternalities, TapeDivergence,
+};
+pub use replay::{ReplayBlock, ReplayReport, replay_block};
+pub use block_import::{
+	BlockImportOutcome, ExecuteBlockOptions, execute_block_with_state, EXECUTE_BLOCK_METHOD,
+};
+pub use code_substitute::{RuntimeCodeSubstitute, RuntimeCodeSubstitutes};
+pub use overlay_set::{OverlaySet, SharedCacheBackend};
+pub use migrations::{
+	StorageMigration, MigrationDriver, MigrationLimits, MigrationUsage, MigrationProgress,
+	MigrationStatus, MigrationError, MIGRATION_CURSOR_KEY,
+};
 
 const PROOF_CLOSE_TRANSACTION: &str = "\
 	Closing a transaction that was started in this function. Client initiated transactions
@@ -84,6 +155,18 @@ const PROOF_CLOSE_TRANSACTION: &str = "\
 
 type CallResult<R, E> = Result<NativeOrEncoded<R>, E>;
 
+/// The runtime method that applies a single extrinsic.
+pub const APPLY_EXTRINSIC_METHOD: &str = "BlockBuilder_apply_extrinsic";
+
+/// Returns true if the SCALE encoded `ApplyExtrinsicResult` returned by
+/// [`APPLY_EXTRINSIC_METHOD`] reports a successful dispatch.
+///
+/// The result is a `Result<Result<(), DispatchError>, TransactionValidityError>`, so a
+/// successful dispatch is encoded as `[0, 0]`.
+pub fn apply_extrinsic_succeeded(output: &[u8]) -> bool {
+	output == &[0, 0][..]
+}
+
 /// Default handler of the execution manager.
 pub type DefaultHandler<R, E> = fn(CallResult<R, E>, CallResult<R, E>) -> CallResult<R, E>;
 
@@ -120,6 +203,80 @@ pub enum BackendTrustLevel {
 	Untrusted,
 }
 
+/// The context in which a call into the runtime is executed.
+///
+/// In contrast to [`sp_core::ExecutionContext`] this doesn't carry the offchain externalities,
+/// so it can be copied into the state machine and the [`Ext`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallContext {
+	/// General block import (including locally authored blocks).
+	Importing,
+	/// Block import as part of an initial sync of the blockchain.
+	Syncing,
+	/// Block construction.
+	BlockConstruction,
+	/// Offchain call with the given capabilities.
+	OffchainCall {
+		/// Capabilities that are available to the call.
+		capabilities: Capabilities,
+	},
+}
+
+impl Default for CallContext {
+	fn default() -> Self {
+		CallContext::Importing
+	}
+}
+
+/// Whether a call into the runtime may change the state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionMode {
+	/// The runtime may write to the storage.
+	ReadWrite,
+	/// Every write of the runtime traps and the call fails with
+	/// [`ExecutionError::WriteInReadOnlyMode`], so the overlays stay unchanged.
+	///
+	/// Useful for calls like RPC `state_call`, whose changes are discarded anyway.
+	ReadOnly,
+}
+
+impl Default for ExecutionMode {
+	fn default() -> Self {
+		ExecutionMode::ReadWrite
+	}
+}
+
+impl CallContext {
+	/// Returns the offchain capabilities of this context.
+	pub fn capabilities(&self) -> Capabilities {
+		match self {
+			CallContext::OffchainCall { capabilities } => *capabilities,
+			_ => Capabilities::none(),
+		}
+	}
+
+	/// Returns `true` if this is the context of an offchain call.
+	pub fn is_offchain(&self) -> bool {
+		match self {
+			CallContext::OffchainCall { .. } => true,
+			_ => false,
+		}
+	}
+}
+
+impl<'a> From<&'a ExecutionContext> for CallContext {
+	fn from(context: &'a ExecutionContext) -> Self {
+		match context {
+			ExecutionContext::Importing => CallContext::Importing,
+			ExecutionContext::Syncing => CallContext::Syncing,
+			ExecutionContext::BlockConstruction => CallContext::BlockConstruction,
+			ExecutionContext::OffchainCall(_) => CallContext::OffchainCall {
+				capabilities: context.capabilities(),
+			},
+		}
+	}
+}
+
 /// Like `ExecutionStrategy` only it also stores a handler in case of consensus failure.
 #[derive(Clone)]
 pub enum ExecutionManager<F> {
@@ -201,6 +358,21 @@ pub struct StateMachine<'a, B, H, N, Exec>
 	changes_trie_state: Option<ChangesTrieState<'a, H, N>>,
 	storage_transaction_cache: Option<&'a mut StorageTransactionCache<B::Transaction, H, N>>,
 	runtime_code: &'a RuntimeCode<'a>,
+	runtime_code_substitute: Option<RuntimeCode<'a>>,
+	call_context: CallContext,
+	execution_mode: ExecutionMode,
+	cancellation_token: Option<CancellationToken>,
+	op_cost_handler: Option<Arc<dyn OpCostHandler>>,
+	tracer: Option<Arc<StateTracer>>,
+	recording_control: Option<RecordingControl>,
+	execution_policy: Option<Arc<dyn ExecutionPolicy>>,
+	tape: Option<Arc<ExternalitiesTape>>,
+	call_cache: Option<Arc<CallCache>>,
+	extensions_registered: bool,
+	extrinsic_transactions: bool,
+	deferred_checks_failed: bool,
+	backend_failure: Option<ExecutionError>,
+	refused_write: Option<&'static str>,
 	stats: StateMachineStats,
 }
 
@@ -234,8 +406,9 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 		runtime_code: &'a RuntimeCode,
 		spawn_handle: impl SpawnNamed + Send + 'static,
 	) -> Self {
+		let extensions_registered = !extensions.is_empty();
 		extensions.register(CallInWasmExt::new(exec.clone()));
-		extensions.register(sp_core::traits::TaskExecutorExt::new(spawn_handle));
+		extensions.register(TaskExecutorExt::new(spawn_handle));
 
 		Self {
 			backend,
@@ -248,10 +421,174 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			changes_trie_state,
 			storage_transaction_cache: None,
 			runtime_code,
+			runtime_code_substitute: None,
+			call_context: CallContext::default(),
+			execution_mode: ExecutionMode::default(),
+			cancellation_token: None,
+			op_cost_handler: None,
+			tracer: None,
+			recording_control: None,
+			execution_policy: None,
+			tape: None,
+			call_cache: None,
+			extensions_registered,
+			extrinsic_transactions: false,
+			deferred_checks_failed: false,
+			backend_failure: None,
+			refused_write: None,
 			stats: StateMachineStats::default(),
 		}
 	}
 
+	/// Interrupt the execution when the given `token` is cancelled.
+	///
+	/// The token is checked at every call from the runtime into the host. An interrupted
+	/// execution fails with [`ExecutionError::Interrupted`].
+	pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+		self.cancellation_token = Some(token);
+		self
+	}
+
+	/// Report every storage operation of the call to the given `handler`.
+	///
+	/// See [`OpCostHandler`] for details.
+	pub fn with_op_cost_handler(mut self, handler: Arc<dyn OpCostHandler>) -> Self {
+		self.op_cost_handler = Some(handler);
+		self
+	}
+
+	/// Execute the call in the given wasm `environment`.
+	///
+	/// The environment is registered as [`ExecutionEnvironmentExt`], so the code executor can
+	/// apply it.
+	pub fn with_execution_environment(mut self, environment: ExecutionEnvironment) -> Self {
+		self.extensions.register(ExecutionEnvironmentExt(environment));
+		self.extensions_registered = true;
+		self
+	}
+
+	/// Record every storage operation of the call with the given `tracer`.
+	pub fn with_tracer(mut self, tracer: Arc<StateTracer>) -> Self {
+		self.tracer = Some(tracer);
+		self
+	}
+
+	/// Make the control handle of the proof recording available to the [`Ext`].
+	///
+	/// This should be the handle of the [`ProvingBackend`] the call is executed on, see
+	/// [`ProvingBackend::recording_control`].
+	pub fn with_recording_control(mut self, control: RecordingControl) -> Self {
+		self.recording_control = Some(control);
+		self
+	}
+
+	/// Let the given `policy` decide the execution strategy of the call.
+	///
+	/// If the policy selects a strategy, it is used instead of the one passed to
+	/// [`Self::execute`] or [`Self::execute_using_consensus_failure_handler`].
+	pub fn with_execution_policy(mut self, policy: Arc<dyn ExecutionPolicy>) -> Self {
+		self.execution_policy = Some(policy);
+		self
+	}
+
+	/// Record every host call of the call, including its result, on the given `tape`.
+	///
+	/// The recorded calls can be replayed with [`ReplayExternalities`]. When the call is
+	/// executed natively and in wasm, the host calls of both executions are recorded.
+	pub fn with_tape(mut self, tape: Arc<ExternalitiesTape>) -> Self {
+		self.tape = Some(tape);
+		self
+	}
+
+	/// Wrap calls of [`APPLY_EXTRINSIC_METHOD`] into a storage transaction.
+	///
+	/// The transaction is only committed if the extrinsic was dispatched successfully, see
+	/// [`apply_extrinsic_succeeded`]. Otherwise all storage changes of the extrinsic are
+	/// discarded, including fees or events the runtime wrote for the failed dispatch.
+	pub fn with_extrinsic_transactions(mut self) -> Self {
+		self.extrinsic_transactions = true;
+		self
+	}
+
+	/// Memoize the result of the call in the given `cache`.
+	///
+	/// The call is only looked up and cached if the overlays are empty when it starts and
+	/// stay empty, so it only depends on the runtime code, the heap pages, the method, the
+	/// call data and the state root of the backend. Calls with an unknown code hash are not
+	/// cached. Neither are calls on a backend that records a proof, or calls with extensions
+	/// registered by the caller (including an execution environment), since those may
+	/// change the result or need the runtime to actually run. A cached result is returned
+	/// without executing the runtime, so no storage operations are reported or traced for it.
+	pub fn with_call_cache(mut self, cache: Arc<CallCache>) -> Self {
+		self.call_cache = Some(cache);
+		self
+	}
+
+	fn overlays_are_empty(&self) -> bool {
+		self.overlay.is_empty() && self.offchain_overlay.iter().next().is_none()
+	}
+
+	fn call_cache_key(&self) -> Option<call_cache::CallKey> {
+		let runtime_code = self.runtime_code_substitute.as_ref().unwrap_or(self.runtime_code);
+		if self.call_cache.is_none() || runtime_code.hash.is_empty() || !self.overlays_are_empty() {
+			return None
+		}
+		if self.extensions_registered || self.backend.proof_size().is_some() {
+			return None
+		}
+
+		Some(call_cache::CallKey {
+			code_hash: runtime_code.hash.clone(),
+			heap_pages: runtime_code.heap_pages,
+			method: self.method.into(),
+			call_data: self.call_data.to_vec(),
+			state_root: self.backend.storage_root(std::iter::empty()).0.as_ref().to_vec(),
+		})
+	}
+
+	fn is_cancelled(&self) -> bool {
+		self.cancellation_token.as_ref().map_or(false, |t| t.is_cancelled())
+	}
+
+	/// Set the context the call is executed in.
+	///
+	/// The context is forwarded to the [`Ext`] used for the call. By default
+	/// [`CallContext::Importing`] is used.
+	pub fn with_call_context(mut self, call_context: CallContext) -> Self {
+		self.call_context = call_context;
+		self
+	}
+
+	/// Set the mode the call is executed in.
+	///
+	/// By default the runtime may write to the storage, see [`ExecutionMode`].
+	pub fn with_execution_mode(mut self, execution_mode: ExecutionMode) -> Self {
+		self.execution_mode = execution_mode;
+		self
+	}
+
+	/// Use the matching runtime code of `substitutes` instead of the given `runtime_code`.
+	///
+	/// The substitute is looked up by the state root of the backend and the given `block`.
+	/// If no substitute matches, the runtime code given to [`StateMachine::new`] is used.
+	pub fn with_runtime_code_substitutes(
+		mut self,
+		substitutes: &'a RuntimeCodeSubstitutes<H, N>,
+		block: Option<&N>,
+	) -> Self {
+		if substitutes.is_empty() {
+			return self
+		}
+
+		let root = self.backend.storage_root(std::iter::empty()).0;
+		self.runtime_code_substitute = substitutes.get(&root, block)
+			.map(|substitute| substitute.runtime_code());
+		if self.runtime_code_substitute.is_some() {
+			trace!(target: "state", "Using runtime code substitute at {:?}", root);
+		}
+		self
+	}
+
 	/// Use given `cache` as storage transaction cache.
 	///
 	/// The cache will be used to cache storage transactions that can be build while executing a
@@ -282,6 +619,46 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 		).map(NativeOrEncoded::into_encoded)
 	}
 
+	/// Apply the given `extrinsic` with [`APPLY_EXTRINSIC_METHOD`] and report its storage IO.
+	///
+	/// All changes of the extrinsic are discarded afterwards, the overlays are left as they
+	/// were before. The method and the call data given to [`StateMachine::new`] are not
+	/// affected. The trie nodes read by the extrinsic stay in the proof recorder of the
+	/// backend, if any.
+	pub fn dry_run_extrinsic(
+		&mut self,
+		strategy: ExecutionStrategy,
+		extrinsic: &'a [u8],
+	) -> Result<DryRunReport, Box<dyn Error>> {
+		let counter = Arc::new(dry_run::DryRunCounter::new(self.op_cost_handler.clone()));
+		let op_cost_handler = std::mem::replace(
+			&mut self.op_cost_handler,
+			Some(counter.clone() as Arc<dyn OpCostHandler>),
+		);
+		let method = std::mem::replace(&mut self.method, APPLY_EXTRINSIC_METHOD);
+		let call_data = std::mem::replace(&mut self.call_data, extrinsic);
+		let offchain_overlay = self.offchain_overlay.clone();
+		let proof_size = self.backend.proof_size();
+
+		self.overlay.start_transaction_labeled("dry_run_extrinsic");
+		let result = self.execute(strategy);
+		self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
+
+		*self.offchain_overlay = offchain_overlay;
+		if let Some(cache) = self.storage_transaction_cache.as_mut() {
+			cache.reset();
+		}
+		self.op_cost_handler = op_cost_handler;
+		self.method = method;
+		self.call_data = call_data;
+
+		let proof_size_delta = match (proof_size, self.backend.proof_size()) {
+			(Some(before), Some(after)) => Some(after.saturating_sub(before)),
+			_ => None,
+		};
+		result.map(|result| counter.report(result, proof_size_delta))
+	}
+
 	fn execute_aux<R, NC>(
 		&mut self,
 		use_native: bool,
@@ -300,6 +677,10 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			None => &mut cache,
 		};
 
+		// The deferred checks are only verified after the call, a failed check must discard
+		// the changes of the call before the strategy decides whether they are kept.
+		let depth = self.overlay.transaction_depth();
+		self.overlay.start_transaction_labeled("deferred_checks");
 		self.overlay.enter_runtime().expect("StateMachine is never called from the runtime; qed");
 
 		let mut ext = Ext::new(
@@ -309,7 +690,14 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			self.backend,
 			self.changes_trie_state.clone(),
 			Some(&mut self.extensions),
-		);
+		)
+			.with_call_context(self.call_context)
+			.with_execution_mode(self.execution_mode)
+			.with_cancellation_token(self.cancellation_token.clone())
+			.with_op_cost_handler(self.op_cost_handler.clone())
+			.with_tracer(self.tracer.clone())
+			.with_recording_control(self.recording_control.clone())
+			.with_deferred_checks();
 
 		let id = ext.id;
 		trace!(
@@ -320,18 +708,52 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			HexDisplay::from(&self.call_data),
 		);
 
-		let (result, was_native) = self.exec.call(
-			&mut ext,
-			self.runtime_code,
-			self.method,
-			self.call_data,
-			use_native,
-			native_call,
-		);
+		let runtime_code = self.runtime_code_substitute.as_ref().unwrap_or(self.runtime_code);
+		let (result, was_native) = match self.tape.as_ref() {
+			Some(tape) => self.exec.call(
+				&mut RecordingExternalities::new(&mut ext, tape),
+				runtime_code,
+				self.method,
+				self.call_data,
+				use_native,
+				native_call,
+			),
+			None => self.exec.call(
+				&mut ext,
+				runtime_code,
+				self.method,
+				self.call_data,
+				use_native,
+				native_call,
+			),
+		};
+		let deferred_checks = ext.take_deferred_checks();
+		self.backend_failure = ext.take_backend_failure();
+		self.refused_write = ext.take_refused_write();
 
 		self.overlay.exit_runtime()
 			.expect("Runtime is not able to call this function in the overlay; qed");
 
+		// The checks of the last execution decide, a failed native execution that is
+		// retried in wasm must not fail the call.
+		self.deferred_checks_failed = if deferred_checks.is_empty() {
+			false
+		} else {
+			trace!(target: "state", "{:04x}: Verify {} deferred checks", id, deferred_checks.len());
+			let spawner = self.extensions.get_mut(TypeId::of::<TaskExecutorExt>())
+				.and_then(|ext| ext.downcast_mut::<TaskExecutorExt>())
+				.map(|ext| &**ext as &dyn SpawnNamed);
+			!deferred_checks::verify_deferred_checks(deferred_checks, spawner)
+		};
+		// The benchmarking host functions `wipe` and `commit` close all open transactions.
+		if self.overlay.transaction_depth() > depth {
+			if self.deferred_checks_failed {
+				self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
+			} else {
+				self.overlay.commit_transaction().expect(PROOF_CLOSE_TRANSACTION);
+			}
+		}
+
 		trace!(
 			target: "state", "{:04x}: Return. Native={:?}, Result={:?}",
 			id,
@@ -355,7 +777,7 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 				CallResult<R, Exec::Error>,
 			) -> CallResult<R, Exec::Error>
 	{
-		self.overlay.start_transaction();
+		self.overlay.start_transaction_labeled("execute_call_with_both_strategy");
 		let (result, was_native) = self.execute_aux(true, native_call.take());
 
 		if was_native {
@@ -387,7 +809,7 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			R: Decode + Encode + PartialEq,
 			NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
 	{
-		self.overlay.start_transaction();
+		self.overlay.start_transaction_labeled("execute_call_with_native_else_wasm_strategy");
 		let (result, was_native) = self.execute_aux(
 			true,
 			native_call.take(),
@@ -406,6 +828,44 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 		}
 	}
 
+	fn execute_with_manager<Handler, R, NC>(
+		&mut self,
+		manager: ExecutionManager<Handler>,
+		mut native_call: Option<NC>,
+	) -> CallResult<R, Exec::Error>
+		where
+			R: Decode + Encode + PartialEq,
+			NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
+			Handler: FnOnce(
+				CallResult<R, Exec::Error>,
+				CallResult<R, Exec::Error>,
+			) -> CallResult<R, Exec::Error>
+	{
+		match manager {
+			ExecutionManager::Both(on_consensus_failure) => {
+				self.execute_call_with_both_strategy(
+					native_call.take(),
+					on_consensus_failure,
+				)
+			},
+			ExecutionManager::NativeElseWasm => {
+				self.execute_call_with_native_else_wasm_strategy(
+					native_call.take(),
+				)
+			},
+			ExecutionManager::AlwaysWasm(trust_level) => {
+				let _abort_guard = match trust_level {
+					BackendTrustLevel::Trusted => None,
+					BackendTrustLevel::Untrusted => Some(sp_panic_handler::AbortGuard::never_abort()),
+				};
+				self.execute_aux(false, native_call).0
+			},
+			ExecutionManager::NativeWhenPossible => {
+				self.execute_aux(true, native_call).0
+			},
+		}
+	}
+
 	/// Execute a call using the given state backend, overlayed changes, and call executor.
 	///
 	/// On an error, no prospective changes are written to the overlay.
@@ -418,7 +878,7 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 	pub fn execute_using_consensus_failure_handler<Handler, R, NC>(
 		&mut self,
 		manager: ExecutionManager<Handler>,
-		mut native_call: Option<NC>,
+		native_call: Option<NC>,
 	) -> Result<NativeOrEncoded<R>, Box<dyn Error>>
 		where
 			R: Decode + Encode + PartialEq,
@@ -428,36 +888,95 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 				CallResult<R, Exec::Error>,
 			) -> CallResult<R, Exec::Error>
 	{
-		let changes_tries_enabled = self.changes_trie_state.is_some();
-		self.overlay.set_collect_extrinsics(changes_tries_enabled);
-
-		let result = {
-			match manager {
-				ExecutionManager::Both(on_consensus_failure) => {
-					self.execute_call_with_both_strategy(
-						native_call.take(),
-						on_consensus_failure,
-					)
-				},
-				ExecutionManager::NativeElseWasm => {
-					self.execute_call_with_native_else_wasm_strategy(
-						native_call.take(),
-					)
-				},
-				ExecutionManager::AlwaysWasm(trust_level) => {
-					let _abort_guard = match trust_level {
-						BackendTrustLevel::Trusted => None,
-						BackendTrustLevel::Untrusted => Some(sp_panic_handler::AbortGuard::never_abort()),
-					};
-					self.execute_aux(false, native_call).0
-				},
-				ExecutionManager::NativeWhenPossible => {
-					self.execute_aux(true, native_call).0
-				},
+		if self.is_cancelled() {
+			return Err(Box::new(ExecutionError::Interrupted))
+		}
+
+		let cache_key = self.call_cache_key();
+		if let (Some(cache), Some(key)) = (self.call_cache.as_ref(), cache_key.as_ref()) {
+			if let Some(output) = cache.get(key) {
+				trace!(target: "state", "Using the cached result of {}", self.method);
+				return Ok(NativeOrEncoded::Encoded(output))
 			}
+		}
+
+		// Changes tries require the extrinsic indices. Without changes tries the caller
+		// decides whether they are collected.
+		if self.changes_trie_state.is_some() {
+			self.overlay.set_collect_extrinsics(true);
+		}
+
+		let extrinsic_transaction = self.extrinsic_transactions &&
+			self.method == APPLY_EXTRINSIC_METHOD;
+		if extrinsic_transaction {
+			self.overlay.start_transaction_labeled("extrinsic_transaction");
+		}
+
+		let policy = self.execution_policy.clone();
+		let policy_strategy = policy.as_ref()
+			.and_then(|policy| policy.strategy(self.method, self.call_context));
+		let result = match (policy, policy_strategy) {
+			(Some(policy), Some(strategy)) => {
+				let method = self.method;
+				let resolution = policy.on_consensus_failure(method, self.call_context);
+				// The trust level of the backend does not depend on the strategy.
+				let trust_level = match manager {
+					ExecutionManager::AlwaysWasm(ref trust_level) => trust_level.clone(),
+					_ => BackendTrustLevel::Trusted,
+				};
+				let manager = match strategy {
+					ExecutionStrategy::NativeWhenPossible => ExecutionManager::NativeWhenPossible,
+					ExecutionStrategy::AlwaysWasm => ExecutionManager::AlwaysWasm(trust_level),
+					ExecutionStrategy::NativeElseWasm => ExecutionManager::NativeElseWasm,
+					ExecutionStrategy::Both => ExecutionManager::Both(
+						move |wasm_result: CallResult<R, Exec::Error>, native_result: CallResult<R, Exec::Error>| {
+							warn!(
+								"Consensus error between wasm and native result of {}. Using {:?}.",
+								method,
+								resolution,
+							);
+							match resolution {
+								ConsensusFailureResolution::UseWasm => wasm_result,
+								ConsensusFailureResolution::UseNative => native_result,
+							}
+						}
+					),
+				};
+				self.execute_with_manager(manager, native_call)
+			},
+			_ => self.execute_with_manager(manager, native_call),
 		};
 
-		result.map_err(|e| Box::new(e) as _)
+		if extrinsic_transaction {
+			let succeeded = !self.deferred_checks_failed && result.as_ref()
+				.map_or(false, |output| apply_extrinsic_succeeded(&output.as_encoded()));
+			if succeeded {
+				self.overlay.commit_transaction().expect(PROOF_CLOSE_TRANSACTION);
+			} else {
+				trace!(target: "state", "Discarding the changes of a failed extrinsic");
+				self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
+			}
+		}
+
+		if let (Some(cache), Some(key), Ok(output)) = (&self.call_cache, cache_key, &result) {
+			if !self.deferred_checks_failed && self.overlays_are_empty() {
+				cache.insert(key, output.as_encoded().into_owned());
+			}
+		}
+
+		match result {
+			Err(_) if self.is_cancelled() => Err(Box::new(ExecutionError::Interrupted)),
+			_ if self.deferred_checks_failed => Err(Box::new(ExecutionError::DeferredCheckFailed)),
+			result => result.map_err(|e| {
+				let error = match (self.refused_write.take(), self.backend_failure.take()) {
+					(Some(operation), _) =>
+						ExecutionError::WriteInReadOnlyMode { operation: operation.into() },
+					(None, Some(failure)) => failure,
+					(None, None) => ExecutionError::from_executor_error::<Exec>(e),
+				};
+				Box::new(error) as _
+			}),
+		}
 	}
 }
 
@@ -541,6 +1060,87 @@ where
 	Ok((result.into_encoded(), proof))
 }
 
+/// Like [`prove_execution`], but also returns statistics about the recorded proof.
+pub fn prove_execution_with_stats<B, H, N, Exec, Spawn>(
+	mut backend: B,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	method: &str,
+	call_data: &[u8],
+	runtime_code: &RuntimeCode,
+) -> Result<(Vec<u8>, StorageProof, ProofRecordingStats), Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + 'static + codec::Codec,
+	Exec: CodeExecutor + Clone + 'static,
+	N: crate::changes_trie::BlockNumber,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let trie_backend = backend.as_trie_backend()
+		.ok_or_else(|| Box::new(ExecutionError::UnableToGenerateProof) as Box<dyn Error>)?;
+	prove_execution_on_trie_backend_with_stats::<_, _, N, _, _>(
+		trie_backend,
+		overlay,
+		exec,
+		spawn_handle,
+		method,
+		call_data,
+		runtime_code,
+	)
+}
+
+/// Like [`prove_execution_on_trie_backend`], but also returns statistics about the recorded
+/// proof.
+pub fn prove_execution_on_trie_backend_with_stats<S, H, N, Exec, Spawn>(
+	trie_backend: &TrieBackend<S, H>,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	method: &str,
+	call_data: &[u8],
+	runtime_code: &RuntimeCode,
+) -> Result<(Vec<u8>, StorageProof, ProofRecordingStats), Box<dyn Error>>
+where
+	S: trie_backend_essence::TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Ord + 'static + codec::Codec,
+	Exec: CodeExecutor + 'static + Clone,
+	N: crate::changes_trie::BlockNumber,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let mut offchain_overlay = OffchainOverlayedChanges::default();
+	let proving_backend = proving_backend::ProvingBackend::new(trie_backend);
+	let recording_backend = RecordingBackend::new(&proving_backend, usize::max_value());
+	let mut sm = StateMachine::<_, H, N, Exec>::new(
+		&recording_backend,
+		None,
+		overlay,
+		&mut offchain_overlay,
+		exec,
+		method,
+		call_data,
+		Extensions::default(),
+		runtime_code,
+		spawn_handle,
+	);
+
+	let result = sm.execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
+		always_wasm(),
+		None,
+	)?;
+
+	let keys_touched = recording_backend.take_records().into_iter()
+		.filter(|record| record.kind != AccessKind::Write)
+		.map(|record| (record.child_storage_key, record.key))
+		.collect::<std::collections::HashSet<_>>()
+		.len();
+	let proof = proving_backend.extract_proof();
+	let stats = ProofRecordingStats::new(&proof, keys_touched);
+	Ok((result.into_encoded(), proof, stats))
+}
+
 /// Check execution proof, generated by `prove_execution` call.
 pub fn execution_proof_check<H, N, Exec, Spawn>(
 	root: H::Out,
@@ -571,6 +1171,52 @@ where
 	)
 }
 
+/// Check execution proof and the state root after the execution.
+///
+/// Replays the call like [`execution_proof_check`] and calculates the storage root of the
+/// resulting state. Fails with [`ExecutionError::StorageRootMismatch`] if it differs from
+/// `expected_root`.
+pub fn execution_proof_check_with_root<H, N, Exec, Spawn>(
+	root: H::Out,
+	proof: StorageProof,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	method: &str,
+	call_data: &[u8],
+	runtime_code: &RuntimeCode,
+	expected_root: H::Out,
+) -> Result<Vec<u8>, Box<dyn Error>>
+where
+	H: Hasher,
+	Exec: CodeExecutor + Clone + 'static,
+	H::Out: Ord + 'static + codec::Codec,
+	N: crate::changes_trie::BlockNumber,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let trie_backend = create_proof_check_backend::<H>(root, proof)?;
+	let mut overlay = OverlayedChanges::default();
+	let result = execution_proof_check_on_trie_backend::<_, N, _, _>(
+		&trie_backend,
+		&mut overlay,
+		exec,
+		spawn_handle,
+		method,
+		call_data,
+		runtime_code,
+	)?;
+
+	let mut cache = StorageTransactionCache::<_, H, N>::default();
+	let calculated_root = overlay.storage_root(&trie_backend, &mut cache);
+	if calculated_root != expected_root {
+		return Err(Box::new(ExecutionError::StorageRootMismatch {
+			expected: expected_root.encode(),
+			calculated: calculated_root.encode(),
+		}))
+	}
+
+	Ok(result)
+}
+
 /// Check execution proof on proving backend, generated by `prove_execution` call.
 pub fn execution_proof_check_on_trie_backend<H, N, Exec, Spawn>(
 	trie_backend: &TrieBackend<MemoryDB<H>, H>,
@@ -774,11 +1420,11 @@ mod tests {
 	use sp_runtime::traits::BlakeTwo256;
 
 	#[derive(Clone)]
-	struct DummyCodeExecutor {
-		change_changes_trie_config: bool,
-		native_available: bool,
-		native_succeeds: bool,
-		fallback_succeeds: bool,
+	pub(crate) struct DummyCodeExecutor {
+		pub(crate) change_changes_trie_config: bool,
+		pub(crate) native_available: bool,
+		pub(crate) native_succeeds: bool,
+		pub(crate) fallback_succeeds: bool,
 	}
 
 	impl CodeExecutor for DummyCodeExecutor {
@@ -791,11 +1437,18 @@ mod tests {
 			&self,
 			ext: &mut dyn Externalities,
 			_: &RuntimeCode,
-			_method: &str,
-			_data: &[u8],
+			method: &str,
+			data: &[u8],
 			use_native: bool,
 			_native_call: Option<NC>,
 		) -> (CallResult<R, Self::Error>, bool) {
+			// Every byte of the call data registers a check that succeeds if it is not `0`.
+			if method == "deferred_checks" {
+				for &check in data {
+					ext.register_deferred_check(Box::new(move || check != 0));
+				}
+			}
+
 			if self.change_changes_trie_config {
 				ext.place_storage(
 					sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG.to_vec(),
@@ -811,17 +1464,12 @@ mod tests {
 			let using_native = use_native && self.native_available;
 			match (using_native, self.native_succeeds, self.fallback_succeeds) {
 				(true, true, _) | (false, _, true) => {
-					(
-						Ok(
-							NativeOrEncoded::Encoded(
-								vec![
-									ext.storage(b"value1").unwrap()[0] +
-									ext.storage(b"value2").unwrap()[0]
-								]
-							)
-						),
-						using_native
-					)
+					// Like the real executors, fail the call if the externalities panic.
+					let value = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+						ext.storage(b"value1").unwrap()[0] + ext.storage(b"value2").unwrap()[0]
+					}));
+					let result = value.map(|value| NativeOrEncoded::Encoded(vec![value]));
+					(result.map_err(|_| 1), using_native)
 				},
 				_ => (Err(0), using_native),
 			}
@@ -842,6 +1490,105 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn failed_extrinsics_are_rolled_back() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&DummyCodeExecutor {
+				change_changes_trie_config: true,
+				native_available: true,
+				native_succeeds: true,
+				fallback_succeeds: true,
+			},
+			APPLY_EXTRINSIC_METHOD,
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).with_extrinsic_transactions();
+
+		// The dummy executor doesn't return a successful `ApplyExtrinsicResult`.
+		assert_eq!(
+			state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap(),
+			vec![66],
+		);
+		drop(state_machine);
+		assert_eq!(
+			overlayed_changes.storage(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG),
+			None,
+		);
+
+		assert!(apply_extrinsic_succeeded(&[0, 0]));
+		assert!(!apply_extrinsic_succeeded(&[0, 1, 0]));
+		assert!(!apply_extrinsic_succeeded(&[1, 0]));
+	}
+
+	#[test]
+	fn failed_deferred_checks_fail_the_call() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		// The executor writes to the storage.
+		let exec = DummyCodeExecutor {
+			change_changes_trie_config: true,
+			native_available: true,
+			native_succeeds: true,
+			fallback_succeeds: true,
+		};
+
+		let execute = |
+			overlay: &mut OverlayedChanges,
+			offchain_overlay: &mut OffchainOverlayedChanges,
+			call_data: &[u8],
+			strategy: ExecutionStrategy,
+		| StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			overlay,
+			offchain_overlay,
+			&exec,
+			"deferred_checks",
+			call_data,
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).execute(strategy);
+
+		for strategy in &[
+			ExecutionStrategy::NativeWhenPossible,
+			ExecutionStrategy::NativeElseWasm,
+			ExecutionStrategy::AlwaysWasm,
+			ExecutionStrategy::Both,
+		] {
+			let err = execute(
+				&mut overlayed_changes,
+				&mut offchain_overlayed_changes,
+				&[1, 0, 1],
+				*strategy,
+			).unwrap_err();
+			assert_eq!(err.downcast_ref(), Some(&ExecutionError::DeferredCheckFailed));
+			assert!(overlayed_changes.is_empty(), "{:?} kept the changes", strategy);
+		}
+
+		let result = execute(
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&[1, 1, 1],
+			ExecutionStrategy::NativeElseWasm,
+		);
+		assert_eq!(result.unwrap(), vec![66]);
+		assert!(!overlayed_changes.is_empty());
+	}
+
 	#[test]
 	fn execute_works() {
 		let backend = trie_backend::tests::test_trie();
@@ -873,6 +1620,267 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn dry_run_extrinsic_reports_io_and_discards_changes() {
+		let backend = trie_backend::tests::test_trie();
+		let proving_backend = proving_backend::ProvingBackend::new(&backend);
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		overlayed_changes.set_storage(b"abc".to_vec(), Some(b"1".to_vec()));
+
+		let mut state_machine = StateMachine::new(
+			&proving_backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&DummyCodeExecutor {
+				change_changes_trie_config: true,
+				native_available: false,
+				native_succeeds: false,
+				fallback_succeeds: true,
+			},
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		);
+
+		let report = state_machine.dry_run_extrinsic(ExecutionStrategy::AlwaysWasm, &[1, 2]).unwrap();
+		assert_eq!(report.result, vec![66]);
+		assert!(!report.succeeded());
+		assert_eq!((report.reads, report.writes), (2, 1));
+		assert!(report.proof_size_delta.unwrap() > 0);
+		assert_eq!(state_machine.method, "test");
+		drop(state_machine);
+
+		assert_eq!(
+			overlayed_changes.storage(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG),
+			None,
+		);
+		assert_eq!(overlayed_changes.storage(b"abc"), Some(Some(&b"1"[..])));
+	}
+
+	#[test]
+	fn extrinsics_are_collected_without_changes_trie() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		overlayed_changes.set_collect_extrinsics(true);
+		overlayed_changes.set_extrinsic_index(1);
+
+		StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&DummyCodeExecutor {
+				change_changes_trie_config: true,
+				native_available: true,
+				native_succeeds: true,
+				fallback_succeeds: true,
+			},
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).execute(ExecutionStrategy::NativeWhenPossible).unwrap();
+
+		let (_, value) = overlayed_changes.changes()
+			.find(|(key, _)| &key[..] == sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG)
+			.unwrap();
+		assert_eq!(value.extrinsics().cloned().collect::<Vec<_>>(), vec![1]);
+	}
+
+	#[test]
+	fn execute_is_interrupted_by_cancelled_token() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let token = CancellationToken::new();
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&DummyCodeExecutor {
+				change_changes_trie_config: false,
+				native_available: true,
+				native_succeeds: true,
+				fallback_succeeds: true,
+			},
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).with_cancellation_token(token.clone());
+
+		token.cancel();
+		let err = state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap_err();
+		assert_eq!(err.downcast_ref(), Some(&ExecutionError::Interrupted));
+	}
+
+	#[test]
+	fn call_cache_returns_results_of_read_only_calls() {
+		let backend = trie_backend::tests::test_trie();
+		let code = sp_core::traits::WrappedRuntimeCode(Vec::new().into());
+		let runtime_code = RuntimeCode { code_fetcher: &code, heap_pages: None, hash: vec![1] };
+		let cache = Arc::new(CallCache::new(1024));
+
+		let execute = |succeeds: bool, change_changes_trie_config: bool| {
+			let mut overlayed_changes = Default::default();
+			let mut offchain_overlayed_changes = Default::default();
+			StateMachine::new(
+				&backend,
+				changes_trie::disabled_state::<_, u64>(),
+				&mut overlayed_changes,
+				&mut offchain_overlayed_changes,
+				&DummyCodeExecutor {
+					change_changes_trie_config,
+					native_available: true,
+					native_succeeds: succeeds,
+					fallback_succeeds: succeeds,
+				},
+				"test",
+				&[],
+				Default::default(),
+				&runtime_code,
+				TaskExecutor::new(),
+			)
+				.with_call_cache(cache.clone())
+				.execute(ExecutionStrategy::NativeWhenPossible)
+		};
+
+		// Calls that write are not cached.
+		assert_eq!(execute(true, true).unwrap(), vec![66]);
+		assert!(cache.is_empty());
+
+		assert_eq!(execute(true, false).unwrap(), vec![66]);
+		assert_eq!(cache.len(), 1);
+		// The failing executor is not called anymore.
+		assert_eq!(execute(false, false).unwrap(), vec![66]);
+	}
+
+	#[test]
+	fn call_cache_is_not_used_with_extensions_or_proof_recording() {
+		let backend = trie_backend::tests::test_trie();
+		let code = sp_core::traits::WrappedRuntimeCode(Vec::new().into());
+		let runtime_code = RuntimeCode { code_fetcher: &code, heap_pages: None, hash: vec![1] };
+		let cache = Arc::new(CallCache::new(1024));
+		let exec = DummyCodeExecutor {
+			change_changes_trie_config: false,
+			native_available: true,
+			native_succeeds: true,
+			fallback_succeeds: true,
+		};
+
+		let mut extensions = Extensions::new();
+		extensions.register(ExecutionEnvironmentExt(Default::default()));
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let result = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&exec,
+			"test",
+			&[],
+			extensions,
+			&runtime_code,
+			TaskExecutor::new(),
+		)
+			.with_call_cache(cache.clone())
+			.execute(ExecutionStrategy::NativeWhenPossible);
+		assert_eq!(result.unwrap(), vec![66]);
+		assert!(cache.is_empty());
+
+		let proving_backend = proving_backend::ProvingBackend::new(&backend);
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let result = StateMachine::new(
+			&proving_backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&exec,
+			"test",
+			&[],
+			Default::default(),
+			&runtime_code,
+			TaskExecutor::new(),
+		)
+			.with_call_cache(cache.clone())
+			.execute(ExecutionStrategy::NativeWhenPossible);
+		assert_eq!(result.unwrap(), vec![66]);
+		assert!(cache.is_empty());
+		assert!(proving_backend.proof_size().unwrap() > 0);
+	}
+
+	#[test]
+	fn execution_errors_are_classified() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+
+		let err = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&DummyCodeExecutor {
+				change_changes_trie_config: false,
+				native_available: true,
+				native_succeeds: false,
+				fallback_succeeds: false,
+			},
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).execute(ExecutionStrategy::NativeWhenPossible).unwrap_err();
+		assert_eq!(err.downcast_ref(), Some(&ExecutionError::Executor("0".into())));
+	}
+
+	#[test]
+	fn exceeded_read_quota_fails_the_call() {
+		let backend = QuotaBackend::new(
+			trie_backend::tests::test_trie(),
+			ReadQuota { reads: 1, bytes: u64::max_value() },
+		);
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+
+		let err = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&DummyCodeExecutor {
+				change_changes_trie_config: false,
+				native_available: true,
+				native_succeeds: true,
+				fallback_succeeds: true,
+			},
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).execute(ExecutionStrategy::NativeWhenPossible).unwrap_err();
+		assert_eq!(err.downcast_ref(), Some(&ExecutionError::QuotaExceeded));
+		assert!(backend.is_exceeded());
+	}
+
 
 	#[test]
 	fn execute_works_with_native_else_wasm() {
@@ -902,6 +1910,48 @@ mod tests {
 		assert_eq!(state_machine.execute(ExecutionStrategy::NativeElseWasm).unwrap(), vec![66]);
 	}
 
+	#[test]
+	fn execution_policy_overrides_strategy() {
+		let backend = trie_backend::tests::test_trie();
+		let wasm_code = RuntimeCode::empty();
+		let executor = DummyCodeExecutor {
+			change_changes_trie_config: false,
+			native_available: true,
+			native_succeeds: false,
+			fallback_succeeds: true,
+		};
+		let execute = |policy: MethodExecutionPolicy| {
+			let mut overlayed_changes = Default::default();
+			let mut offchain_overlayed_changes = Default::default();
+			let result = StateMachine::new(
+				&backend,
+				changes_trie::disabled_state::<_, u64>(),
+				&mut overlayed_changes,
+				&mut offchain_overlayed_changes,
+				&executor,
+				"test",
+				&[],
+				Default::default(),
+				&wasm_code,
+				TaskExecutor::new(),
+			)
+				.with_execution_policy(Arc::new(policy))
+				.execute(ExecutionStrategy::NativeWhenPossible);
+			result
+		};
+
+		assert!(execute(MethodExecutionPolicy::default()).is_err());
+		assert!(
+			execute(MethodExecutionPolicy::default().with_method("other", ExecutionStrategy::AlwaysWasm))
+				.is_err()
+		);
+		assert_eq!(
+			execute(MethodExecutionPolicy::default().with_method("test", ExecutionStrategy::AlwaysWasm))
+				.unwrap(),
+			vec![66],
+		);
+	}
+
 	#[test]
 	fn dual_execution_strategy_detects_consensus_failure() {
 		let mut consensus_failed = false;
@@ -979,6 +2029,69 @@ mod tests {
 		assert_eq!(remote_result, local_result);
 	}
 
+	#[test]
+	fn execution_proof_check_with_root_works() {
+		let executor = DummyCodeExecutor {
+			change_changes_trie_config: false,
+			native_available: true,
+			native_succeeds: true,
+			fallback_succeeds: true,
+		};
+
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(std::iter::empty()).0;
+		let (_, remote_proof) = prove_execution::<_, _, u64, _, _>(
+			remote_backend,
+			&mut Default::default(),
+			&executor,
+			TaskExecutor::new(),
+			"test",
+			&[],
+			&RuntimeCode::empty(),
+		).unwrap();
+
+		let check = |expected_root| execution_proof_check_with_root::<BlakeTwo256, u64, _, _>(
+			remote_root,
+			remote_proof.clone(),
+			&executor,
+			TaskExecutor::new(),
+			"test",
+			&[],
+			&RuntimeCode::empty(),
+			expected_root,
+		);
+
+		// the call doesn't change the state
+		assert_eq!(check(remote_root).unwrap(), vec![66]);
+		assert!(check(Default::default()).is_err());
+	}
+
+	#[test]
+	fn prove_execution_with_stats_works() {
+		let executor = DummyCodeExecutor {
+			change_changes_trie_config: false,
+			native_available: true,
+			native_succeeds: true,
+			fallback_succeeds: true,
+		};
+
+		let (result, proof, stats) = prove_execution_with_stats::<_, _, u64, _, _>(
+			trie_backend::tests::test_trie(),
+			&mut Default::default(),
+			&executor,
+			TaskExecutor::new(),
+			"test",
+			&[],
+			&RuntimeCode::empty(),
+		).unwrap();
+
+		assert_eq!(result, vec![66]);
+		assert_eq!(stats, ProofRecordingStats::new(&proof, stats.keys_touched));
+		assert!(stats.keys_touched >= 2);
+		assert!(stats.unique_nodes > 0);
+		assert!(stats.largest_node <= stats.total_bytes);
+	}
+
 	#[test]
 	fn clear_prefix_in_ext_works() {
 		let initial: BTreeMap<_, _> = map![
@@ -1231,6 +2344,29 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn prove_read_and_proof_check_works_with_keccak() {
+		use sp_core::KeccakHasher;
+		use sp_trie::TrieConfiguration;
+
+		let mut state = BTreeMap::new();
+		state.insert(b"key".to_vec(), b"value".to_vec());
+		let remote_backend = InMemoryBackend::<KeccakHasher>::from(state.clone());
+		let remote_root = remote_backend.storage_root(std::iter::empty()).0;
+		assert_eq!(remote_root, Layout::<KeccakHasher>::trie_root(state));
+
+		let remote_proof = prove_read(remote_backend, &[b"key"]).unwrap();
+		let local_result = read_proof_check::<KeccakHasher, _>(
+			remote_root,
+			remote_proof,
+			&[b"key"],
+		).unwrap();
+		assert_eq!(
+			local_result.into_iter().collect::<Vec<_>>(),
+			vec![(b"key".to_vec(), Some(b"value".to_vec()))],
+		);
+	}
+
 	#[test]
 	fn prove_read_and_proof_check_works() {
 		let child_info = ChildInfo::new_default(b"sub1");
@@ -0,0 +1,386 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage migrations that are executed through the externalities.
+//!
+//! A migration is split into steps. Every step is bounded by a weight and a byte budget and
+//! is executed inside its own storage transaction, so a failing step leaves no partial changes
+//! behind. The position of the driver is persisted under [`MIGRATION_CURSOR_KEY`], which allows
+//! a migration to be resumed in a later block. The key is removed once all migrations are
+//! complete.
+
+use std::fmt;
+use codec::{Decode, Encode};
+use sp_core::traits::Externalities;
+
+/// Storage key under which the migration driver persists its cursor.
+pub const MIGRATION_CURSOR_KEY: &[u8] = b":migrations:cursor";
+
+/// Resource budget available to a single migration step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationLimits {
+	/// Maximal weight that can be consumed.
+	pub weight: u64,
+	/// Maximal number of bytes that can be written.
+	pub bytes: u64,
+}
+
+impl MigrationLimits {
+	/// Returns `true` if nothing is left of this budget.
+	pub fn is_exhausted(&self) -> bool {
+		self.weight == 0 || self.bytes == 0
+	}
+
+	/// Subtract the given consumption from the budget.
+	///
+	/// Returns `None` if the consumption exceeds the budget.
+	fn consume(&self, used: &MigrationUsage) -> Option<Self> {
+		Some(MigrationLimits {
+			weight: self.weight.checked_sub(used.weight)?,
+			bytes: self.bytes.checked_sub(used.bytes)?,
+		})
+	}
+}
+
+/// Resources consumed by a migration step.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationUsage {
+	/// Consumed weight.
+	pub weight: u64,
+	/// Number of bytes written.
+	pub bytes: u64,
+}
+
+/// Progress reported by [`StorageMigration::migrate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationProgress {
+	/// The migration ran out of budget and needs to be resumed from the given cursor.
+	Continue {
+		/// Opaque cursor that is handed back to the migration on the next step.
+		cursor: Vec<u8>,
+		/// Resources consumed by this step.
+		usage: MigrationUsage,
+	},
+	/// The migration is finished.
+	Complete {
+		/// Resources consumed by this step.
+		usage: MigrationUsage,
+	},
+}
+
+impl MigrationProgress {
+	fn usage(&self) -> &MigrationUsage {
+		match self {
+			MigrationProgress::Continue { usage, .. } => usage,
+			MigrationProgress::Complete { usage } => usage,
+		}
+	}
+}
+
+/// A storage migration that can be split across multiple steps.
+pub trait StorageMigration {
+	/// Check the state before the first step of the migration is executed.
+	fn pre_check(&self, _ext: &mut dyn Externalities) -> Result<(), String> {
+		Ok(())
+	}
+
+	/// Execute one step of the migration.
+	///
+	/// `cursor` is `None` for the first step and otherwise the cursor returned by the
+	/// previous step. The step must not consume more than `limits`.
+	fn migrate(
+		&self,
+		ext: &mut dyn Externalities,
+		cursor: Option<&[u8]>,
+		limits: MigrationLimits,
+	) -> Result<MigrationProgress, String>;
+
+	/// Check the state after the last step of the migration was executed.
+	fn post_check(&self, _ext: &mut dyn Externalities) -> Result<(), String> {
+		Ok(())
+	}
+}
+
+/// Errors that can occur while driving migrations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationError {
+	/// The migration at the given index failed its pre-check.
+	PreCheck(u32, String),
+	/// A step of the migration at the given index failed.
+	Migrate(u32, String),
+	/// The migration at the given index failed its post-check.
+	PostCheck(u32, String),
+	/// The migration at the given index consumed more than it was allowed to.
+	LimitExceeded(u32),
+	/// The migration at the given index asked to be continued without consuming anything.
+	NoProgress(u32),
+	/// The persisted cursor could not be decoded.
+	InvalidCursor,
+}
+
+impl fmt::Display for MigrationError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MigrationError::PreCheck(i, e) => write!(f, "Pre-check of migration {} failed: {}", i, e),
+			MigrationError::Migrate(i, e) => write!(f, "Migration {} failed: {}", i, e),
+			MigrationError::PostCheck(i, e) => write!(f, "Post-check of migration {} failed: {}", i, e),
+			MigrationError::LimitExceeded(i) => write!(f, "Migration {} exceeded its limits", i),
+			MigrationError::NoProgress(i) => write!(f, "Migration {} made no progress", i),
+			MigrationError::InvalidCursor => write!(f, "Invalid migration cursor in storage"),
+		}
+	}
+}
+
+/// Status of the migrations after a call to [`MigrationDriver::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationStatus {
+	/// Some migrations still need to be executed.
+	InProgress,
+	/// All migrations are executed.
+	Complete,
+}
+
+/// Position of the driver that is persisted in storage.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+struct Cursor {
+	/// Index of the migration that is currently executed.
+	index: u32,
+	/// Cursor of the migration, `None` if it was not started yet.
+	inner: Option<Vec<u8>>,
+}
+
+/// Executes a list of migrations in order, one budgeted step at a time.
+pub struct MigrationDriver<'a> {
+	migrations: Vec<&'a dyn StorageMigration>,
+	limits: MigrationLimits,
+}
+
+impl<'a> MigrationDriver<'a> {
+	/// Create a new driver for the given migrations that uses `limits` per step.
+	pub fn new(migrations: Vec<&'a dyn StorageMigration>, limits: MigrationLimits) -> Self {
+		MigrationDriver { migrations, limits }
+	}
+
+	/// Execute one step of the migrations.
+	///
+	/// Migrations that complete are followed by the next one within the remaining budget. A
+	/// migration that asks to be continued has spent the budget of the step, it is resumed
+	/// with a full budget on the next call.
+	///
+	/// Every migration step is executed inside its own storage transaction that is rolled back
+	/// if the step fails. On error the cursor is left untouched, so the same step is retried
+	/// on the next call.
+	///
+	/// The cursor is removed from storage once all migrations are complete. A driver that is
+	/// stepped again afterwards starts over with the first migration, so the caller has to
+	/// record the completion itself, e.g. in a storage version.
+	pub fn step(&self, ext: &mut dyn Externalities) -> Result<MigrationStatus, MigrationError> {
+		let mut cursor = match ext.storage(MIGRATION_CURSOR_KEY) {
+			Some(raw) => Cursor::decode(&mut &raw[..]).map_err(|_| MigrationError::InvalidCursor)?,
+			None => Cursor { index: 0, inner: None },
+		};
+		let mut limits = self.limits;
+
+		while (cursor.index as usize) < self.migrations.len() && !limits.is_exhausted() {
+			let migration = self.migrations[cursor.index as usize];
+
+			ext.storage_start_transaction();
+			let result = Self::run_step(migration, ext, &cursor, limits);
+			let progress = match result {
+				Ok(progress) => {
+					ext.storage_commit_transaction().expect("Transaction was started above; qed");
+					progress
+				},
+				Err(e) => {
+					ext.storage_rollback_transaction().expect("Transaction was started above; qed");
+					return Err(e)
+				},
+			};
+
+			limits = limits.consume(progress.usage())
+				.expect("Usage was checked against the limits in `run_step`; qed");
+			if let MigrationProgress::Continue { cursor: inner, .. } = progress {
+				let cursor = Cursor { index: cursor.index, inner: Some(inner) };
+				ext.set_storage(MIGRATION_CURSOR_KEY.to_vec(), cursor.encode());
+				return Ok(MigrationStatus::InProgress)
+			}
+
+			cursor = Cursor { index: cursor.index + 1, inner: None };
+			if (cursor.index as usize) < self.migrations.len() {
+				ext.set_storage(MIGRATION_CURSOR_KEY.to_vec(), cursor.encode());
+			} else {
+				ext.clear_storage(MIGRATION_CURSOR_KEY);
+			}
+		}
+
+		if (cursor.index as usize) < self.migrations.len() {
+			Ok(MigrationStatus::InProgress)
+		} else {
+			Ok(MigrationStatus::Complete)
+		}
+	}
+
+	fn run_step(
+		migration: &dyn StorageMigration,
+		ext: &mut dyn Externalities,
+		cursor: &Cursor,
+		limits: MigrationLimits,
+	) -> Result<MigrationProgress, MigrationError> {
+		let index = cursor.index;
+		if cursor.inner.is_none() {
+			migration.pre_check(ext).map_err(|e| MigrationError::PreCheck(index, e))?;
+		}
+
+		let progress = migration.migrate(ext, cursor.inner.as_ref().map(|c| &c[..]), limits)
+			.map_err(|e| MigrationError::Migrate(index, e))?;
+		if limits.consume(progress.usage()).is_none() {
+			return Err(MigrationError::LimitExceeded(index))
+		}
+		if let MigrationProgress::Continue { usage, .. } = &progress {
+			if *usage == MigrationUsage::default() {
+				return Err(MigrationError::NoProgress(index))
+			}
+		}
+
+		if let MigrationProgress::Complete { .. } = progress {
+			migration.post_check(ext).map_err(|e| MigrationError::PostCheck(index, e))?;
+		}
+
+		Ok(progress)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::TestExternalities;
+	use sp_core::Blake2Hasher;
+
+	/// Moves every key under `old:` to `new:`, one key per unit of weight.
+	struct MovePrefix;
+
+	impl StorageMigration for MovePrefix {
+		fn migrate(
+			&self,
+			ext: &mut dyn Externalities,
+			_cursor: Option<&[u8]>,
+			limits: MigrationLimits,
+		) -> Result<MigrationProgress, String> {
+			let mut usage = MigrationUsage::default();
+			while usage.weight < limits.weight {
+				let key = match ext.next_storage_key(b"old:") {
+					Some(key) if key.starts_with(b"old:") => key,
+					_ => return Ok(MigrationProgress::Complete { usage }),
+				};
+				let value = ext.storage(&key).unwrap_or_default();
+				usage.weight += 1;
+				usage.bytes += value.len() as u64;
+				let mut new_key = b"new:".to_vec();
+				new_key.extend_from_slice(&key[4..]);
+				ext.set_storage(new_key, value);
+				ext.clear_storage(&key);
+			}
+			Ok(MigrationProgress::Continue { cursor: Vec::new(), usage })
+		}
+
+		fn post_check(&self, ext: &mut dyn Externalities) -> Result<(), String> {
+			match ext.next_storage_key(b"old:") {
+				Some(key) if key.starts_with(b"old:") => Err("keys left behind".into()),
+				_ => Ok(()),
+			}
+		}
+	}
+
+	/// Asks to be continued without doing anything.
+	struct Stalling;
+
+	impl StorageMigration for Stalling {
+		fn migrate(
+			&self,
+			_ext: &mut dyn Externalities,
+			_cursor: Option<&[u8]>,
+			_limits: MigrationLimits,
+		) -> Result<MigrationProgress, String> {
+			Ok(MigrationProgress::Continue { cursor: Vec::new(), usage: Default::default() })
+		}
+	}
+
+	struct Failing;
+
+	impl StorageMigration for Failing {
+		fn migrate(
+			&self,
+			ext: &mut dyn Externalities,
+			_cursor: Option<&[u8]>,
+			_limits: MigrationLimits,
+		) -> Result<MigrationProgress, String> {
+			ext.set_storage(b"garbage".to_vec(), b"1".to_vec());
+			Err("failed".into())
+		}
+	}
+
+	#[test]
+	fn migration_is_resumed_across_steps() {
+		let mut ext = TestExternalities::<Blake2Hasher>::default();
+		let mut ext = ext.ext();
+		for i in 0u8..5 {
+			ext.set_storage(vec![b'o', b'l', b'd', b':', i], vec![i]);
+		}
+
+		let migration = MovePrefix;
+		let driver = MigrationDriver::new(vec![&migration], MigrationLimits { weight: 2, bytes: 100 });
+
+		assert_eq!(driver.step(&mut ext), Ok(MigrationStatus::InProgress));
+		assert_eq!(driver.step(&mut ext), Ok(MigrationStatus::InProgress));
+		assert!(ext.storage(MIGRATION_CURSOR_KEY).is_some());
+		assert_eq!(driver.step(&mut ext), Ok(MigrationStatus::Complete));
+		assert_eq!(ext.storage(MIGRATION_CURSOR_KEY), None);
+
+		for i in 0u8..5 {
+			assert_eq!(ext.storage(&[b'o', b'l', b'd', b':', i]), None);
+			assert_eq!(ext.storage(&[b'n', b'e', b'w', b':', i]), Some(vec![i]));
+		}
+	}
+
+	#[test]
+	fn failing_step_is_rolled_back() {
+		let mut ext = TestExternalities::<Blake2Hasher>::default();
+		let mut ext = ext.ext();
+
+		let migration = Failing;
+		let driver = MigrationDriver::new(vec![&migration], MigrationLimits { weight: 10, bytes: 10 });
+
+		assert_eq!(driver.step(&mut ext), Err(MigrationError::Migrate(0, "failed".into())));
+		assert_eq!(ext.storage(b"garbage"), None);
+		assert_eq!(ext.storage(MIGRATION_CURSOR_KEY), None);
+	}
+
+	#[test]
+	fn migration_without_progress_fails() {
+		let mut ext = TestExternalities::<Blake2Hasher>::default();
+		let mut ext = ext.ext();
+
+		let migration = Stalling;
+		let driver = MigrationDriver::new(
+			vec![&migration],
+			MigrationLimits { weight: 10, bytes: 10 },
+		);
+
+		assert_eq!(driver.step(&mut ext), Err(MigrationError::NoProgress(0)));
+		assert_eq!(ext.storage(MIGRATION_CURSOR_KEY), None);
+	}
+}
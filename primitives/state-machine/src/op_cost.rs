@@ -0,0 +1,45 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cost accounting of the storage operations done by the runtime.
+
+/// A storage operation reported to an [`OpCostHandler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageOp {
+	/// A value, the hash of a value or the existence of a key was read.
+	Get,
+	/// A value was written or appended to.
+	Set,
+	/// A value, a prefix or a whole child trie was removed.
+	Remove,
+	/// A storage root was calculated.
+	Root,
+}
+
+/// Handler that is informed about every storage operation done through an [`Ext`](crate::Ext).
+///
+/// This allows to meter the weight of a call or to account the IO of a block without
+/// touching every call site. The handler is called before the operation is done, it can't
+/// influence the result.
+pub trait OpCostHandler: Send + Sync {
+	/// Called for every storage operation.
+	///
+	/// `key_len` is the length of the key or prefix, `value_len` the length of the read or
+	/// written value. For roots the key is the storage key of the child trie, if any, and
+	/// the value is the encoded root.
+	fn on_storage_op(&self, op: StorageOp, key_len: usize, value_len: usize);
+}
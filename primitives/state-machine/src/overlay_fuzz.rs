@@ -0,0 +1,196 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Differential testing of the transactional semantics of [`OverlayedChanges`].
+//!
+//! A sequence of [`Operation`]s is applied to an [`OverlayedChanges`] and to a naive reference
+//! model that keeps one map per open transaction. After every operation the observable state
+//! of both is compared. This is meant to be driven by fuzzers or property based tests.
+
+use std::collections::{BTreeMap, BTreeSet};
+use crate::{OverlayedChanges, StorageKey, StorageValue};
+
+/// Operation that is applied to the overlay and the reference model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+	/// Set the given key to the given value.
+	Set(StorageKey, StorageValue),
+	/// Remove the given key.
+	Remove(StorageKey),
+	/// Remove all keys with the given prefix.
+	ClearPrefix(StorageKey),
+	/// Start a new transaction.
+	StartTx,
+	/// Commit the last transaction.
+	CommitTx,
+	/// Roll back the last transaction.
+	RollbackTx,
+}
+
+impl Operation {
+	/// Decode a sequence of operations from arbitrary bytes, e.g. fuzzer input.
+	///
+	/// Keys and values are taken from a small domain, so that operations interact with each
+	/// other.
+	pub fn decode_all(mut data: &[u8]) -> Vec<Operation> {
+		let mut ops = Vec::new();
+		while data.len() >= 2 {
+			let (kind, arg) = (data[0], data[1]);
+			data = &data[2..];
+			let key = vec![arg % 4, (arg >> 2) % 4];
+			ops.push(match kind % 6 {
+				0 => Operation::Set(key, vec![arg >> 4]),
+				1 => Operation::Remove(key),
+				2 => Operation::ClearPrefix(key[..1].to_vec()),
+				3 => Operation::StartTx,
+				4 => Operation::CommitTx,
+				_ => Operation::RollbackTx,
+			});
+		}
+		ops
+	}
+}
+
+/// Naive reference implementation of the transactional overlay.
+#[derive(Debug)]
+struct Reference {
+	/// One map per transaction layer, the first one holds the committed changes.
+	layers: Vec<BTreeMap<StorageKey, Option<StorageValue>>>,
+}
+
+impl Reference {
+	fn new() -> Self {
+		Reference { layers: vec![BTreeMap::new()] }
+	}
+
+	fn storage(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+		self.layers.iter().rev()
+			.find_map(|layer| layer.get(key))
+			.map(|value| value.as_ref().map(AsRef::as_ref))
+	}
+
+	fn keys(&self) -> BTreeSet<StorageKey> {
+		self.layers.iter().flat_map(|layer| layer.keys().cloned()).collect()
+	}
+
+	fn set(&mut self, key: StorageKey, value: Option<StorageValue>) {
+		self.layers.last_mut()
+			.expect("There is always the committed layer; qed")
+			.insert(key, value);
+	}
+
+	fn transaction_depth(&self) -> usize {
+		self.layers.len() - 1
+	}
+}
+
+/// Apply `ops` to a fresh [`OverlayedChanges`] and to the reference model.
+///
+/// Panics if the observable state of both diverges at any point.
+pub fn check_operations(ops: &[Operation]) {
+	let mut overlay = OverlayedChanges::default();
+	let mut reference = Reference::new();
+
+	for op in ops {
+		match op.clone() {
+			Operation::Set(key, value) => {
+				overlay.set_storage(key.clone(), Some(value.clone()));
+				reference.set(key, Some(value));
+			},
+			Operation::Remove(key) => {
+				overlay.set_storage(key.clone(), None);
+				reference.set(key, None);
+			},
+			Operation::ClearPrefix(prefix) => {
+				overlay.clear_prefix(&prefix);
+				for key in reference.keys() {
+					if key.starts_with(&prefix) {
+						reference.set(key, None);
+					}
+				}
+			},
+			Operation::StartTx => {
+				overlay.start_transaction();
+				reference.layers.push(BTreeMap::new());
+			},
+			Operation::CommitTx => {
+				let result = overlay.commit_transaction();
+				assert_eq!(result.is_ok(), reference.transaction_depth() > 0, "{:?}", op);
+				if result.is_ok() {
+					let layer = reference.layers.pop().expect("Depth is checked above; qed");
+					reference.layers.last_mut().expect("Depth is checked above; qed").extend(layer);
+				}
+			},
+			Operation::RollbackTx => {
+				let result = overlay.rollback_transaction();
+				assert_eq!(result.is_ok(), reference.transaction_depth() > 0, "{:?}", op);
+				if result.is_ok() {
+					reference.layers.pop();
+				}
+			},
+		}
+
+		assert_same_state(&overlay, &reference, op);
+	}
+}
+
+fn assert_same_state(overlay: &OverlayedChanges, reference: &Reference, op: &Operation) {
+	overlay.assert_invariants();
+	assert_eq!(overlay.transaction_depth(), reference.transaction_depth(), "after {:?}", op);
+
+	let mut keys = reference.keys();
+	keys.extend(overlay.changes().map(|(key, _)| key.clone()));
+	for key in keys {
+		assert_eq!(overlay.storage(&key), reference.storage(&key), "key {:?} after {:?}", key, op);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn nested_transactions_match_reference() {
+		check_operations(&[
+			Operation::Set(vec![1], vec![1]),
+			Operation::StartTx,
+			Operation::Set(vec![1, 2], vec![2]),
+			Operation::StartTx,
+			Operation::ClearPrefix(vec![1]),
+			Operation::Set(vec![3], vec![3]),
+			Operation::RollbackTx,
+			Operation::Remove(vec![1]),
+			Operation::CommitTx,
+			Operation::CommitTx,
+			Operation::RollbackTx,
+		]);
+	}
+
+	#[test]
+	fn pseudo_random_sequences_match_reference() {
+		let mut seed = 0x2545_f491_4f6c_dd1du64;
+		for _ in 0..200 {
+			let data: Vec<u8> = (0..64).map(|_| {
+				seed ^= seed << 13;
+				seed ^= seed >> 7;
+				seed ^= seed << 17;
+				seed as u8
+			}).collect();
+			check_operations(&Operation::decode_all(&data));
+		}
+	}
+}
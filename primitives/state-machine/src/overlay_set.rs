@@ -0,0 +1,595 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Multiple overlays on top of the same backend.
+//!
+//! Used to execute calls for several candidate blocks or transaction validation lanes in
+//! parallel, while reading every value from the backend only once.
+
+use std::collections::{BTreeMap, HashMap};
+use codec::Encode;
+use hash_db::Hasher;
+use parking_lot::Mutex;
+use sp_core::storage::{ChildInfo, TrackedStorageKey};
+use crate::{
+	Backend, KeyRef, StorageReadHint, OverlayedChanges, StorageKey, StorageValue, StorageCollection,
+	ChildStorageCollection, UsageInfo, key_ref::BuildPrehashed,
+};
+
+/// Identifies a cached value in [`Cache::by_use`].
+enum CachedKey<Out> {
+	/// A key of the main trie with its [`KeyRef::hash`].
+	Top(u64, StorageKey),
+	/// The hash of the storage key of a child trie and the key.
+	Child(Out),
+}
+
+struct CachedValue {
+	value: Option<StorageValue>,
+	last_used: u64,
+}
+
+impl CachedValue {
+	fn size(&self) -> usize {
+		self.value.as_ref().map_or(0, Vec::len)
+	}
+}
+
+struct Cache<Out> {
+	top: HashMap<u64, Vec<(StorageKey, CachedValue)>, BuildPrehashed>,
+	children: HashMap<Out, CachedValue>,
+	/// The keys of the cached values by their last use.
+	by_use: BTreeMap<u64, CachedKey<Out>>,
+	tick: u64,
+	size: usize,
+}
+
+impl<Out: std::hash::Hash + Eq + Clone + AsRef<[u8]>> Cache<Out> {
+	fn new() -> Self {
+		Cache {
+			top: Default::default(),
+			children: Default::default(),
+			by_use: BTreeMap::new(),
+			tick: 0,
+			size: 0,
+		}
+	}
+
+	fn len(&self) -> usize {
+		self.by_use.len()
+	}
+
+	fn next_tick(&mut self) -> u64 {
+		self.tick += 1;
+		self.tick
+	}
+
+	/// Mark the value that was last used at `last_used` as used at `tick`.
+	fn touch(&mut self, last_used: u64, tick: u64) {
+		if let Some(key) = self.by_use.remove(&last_used) {
+			self.by_use.insert(tick, key);
+		}
+	}
+
+	fn get_top(&mut self, key: KeyRef) -> Option<Option<StorageValue>> {
+		let tick = self.next_tick();
+		let (_, cached) = self.top.get_mut(&key.hash())?
+			.iter_mut()
+			.find(|(cached, _)| &cached[..] == key.key())?;
+		let last_used = std::mem::replace(&mut cached.last_used, tick);
+		let value = cached.value.clone();
+		self.touch(last_used, tick);
+		Some(value)
+	}
+
+	fn get_child(&mut self, hash: &Out) -> Option<Option<StorageValue>> {
+		let tick = self.next_tick();
+		let cached = self.children.get_mut(hash)?;
+		let last_used = std::mem::replace(&mut cached.last_used, tick);
+		let value = cached.value.clone();
+		self.touch(last_used, tick);
+		Some(value)
+	}
+
+	fn insert_top(&mut self, key: KeyRef, value: Option<StorageValue>, max_size: usize) {
+		let size = key.key().len() + value.as_ref().map_or(0, Vec::len);
+		let cached = self.top.get(&key.hash())
+			.map_or(false, |bucket| bucket.iter().any(|(cached, _)| &cached[..] == key.key()));
+		if cached || !self.make_room(size, max_size) {
+			return
+		}
+
+		let tick = self.next_tick();
+		self.size += size;
+		self.by_use.insert(tick, CachedKey::Top(key.hash(), key.key().to_vec()));
+		self.top.entry(key.hash()).or_default()
+			.push((key.key().to_vec(), CachedValue { value, last_used: tick }));
+	}
+
+	fn insert_child(&mut self, hash: Out, value: Option<StorageValue>, max_size: usize) {
+		let size = hash.as_ref().len() + value.as_ref().map_or(0, Vec::len);
+		if self.children.contains_key(&hash) || !self.make_room(size, max_size) {
+			return
+		}
+
+		let tick = self.next_tick();
+		self.size += size;
+		self.by_use.insert(tick, CachedKey::Child(hash.clone()));
+		self.children.insert(hash, CachedValue { value, last_used: tick });
+	}
+
+	/// Evict the least recently used values until `size` more bytes fit into `max_size`.
+	///
+	/// Returns `false` if the value is bigger than the whole cache.
+	fn make_room(&mut self, size: usize, max_size: usize) -> bool {
+		if size > max_size {
+			return false
+		}
+		while self.size + size > max_size {
+			let oldest = match self.by_use.keys().next() {
+				Some(oldest) => *oldest,
+				None => break,
+			};
+			if let Some(key) = self.by_use.remove(&oldest) {
+				self.remove(key);
+			}
+		}
+		true
+	}
+
+	fn remove(&mut self, key: CachedKey<Out>) {
+		match key {
+			CachedKey::Top(hash, key) => {
+				let bucket = match self.top.get_mut(&hash) {
+					Some(bucket) => bucket,
+					None => return,
+				};
+				if let Some(index) = bucket.iter().position(|(cached, _)| *cached == key) {
+					let (_, cached) = bucket.swap_remove(index);
+					self.size -= key.len() + cached.size();
+				}
+				if bucket.is_empty() {
+					self.top.remove(&hash);
+				}
+			},
+			CachedKey::Child(hash) => {
+				if let Some(cached) = self.children.remove(&hash) {
+					self.size -= hash.as_ref().len() + cached.size();
+				}
+			},
+		}
+	}
+}
+
+/// Wraps a [`Backend`] and caches the values read from it.
+///
+/// The cache is shared by all users of the backend. Values of the main trie are looked up by
+/// [`KeyRef::hash`], so a key that was hashed for the overlay is not hashed again. It is
+/// cleared when changes are committed to the backend, otherwise the wrapped backend must not
+/// change while the cache is in use. The least recently used values are evicted when the
+/// cached keys and values exceed the size bound.
+pub struct SharedCacheBackend<B, H: Hasher> {
+	backend: B,
+	max_size: usize,
+	cache: Mutex<Cache<H::Out>>,
+}
+
+impl<B, H: Hasher> SharedCacheBackend<B, H> {
+	/// Create a new instance with an empty cache that holds at most `max_size` bytes of keys
+	/// and values.
+	pub fn new(backend: B, max_size: usize) -> Self {
+		SharedCacheBackend {
+			backend,
+			max_size,
+			cache: Mutex::new(Cache::new()),
+		}
+	}
+
+	/// Returns a reference to the wrapped backend.
+	pub fn inner(&self) -> &B {
+		&self.backend
+	}
+
+	/// Returns the number of cached values.
+	pub fn cached_values(&self) -> usize {
+		self.cache.lock().len()
+	}
+
+	/// Returns the size of the cached keys and values in bytes.
+	pub fn cache_size(&self) -> usize {
+		self.cache.lock().size
+	}
+
+	/// Remove all cached values.
+	pub fn clear_cache(&self) {
+		*self.cache.lock() = Cache::new();
+	}
+
+	fn cached(&self, key: KeyRef) -> Option<Option<StorageValue>> {
+		self.cache.lock().get_top(key)
+	}
+
+	fn cache(&self, key: KeyRef, value: Option<StorageValue>) {
+		self.cache.lock().insert_top(key, value, self.max_size)
+	}
+
+	fn child_key_hash(child_info: &ChildInfo, key: &[u8]) -> H::Out {
+		H::hash(&(child_info.storage_key(), key).encode())
+	}
+}
+
+impl<B, H: Hasher> std::fmt::Debug for SharedCacheBackend<B, H> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "SharedCacheBackend")
+	}
+}
+
+impl<H: Hasher, B: Backend<H>> Backend<H> for SharedCacheBackend<B, H> {
+	type Error = B::Error;
+	type Transaction = B::Transaction;
+	type TrieBackendStorage = B::TrieBackendStorage;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error> {
+		self.storage_with_hint_ref(KeyRef::new(key)).map(|(value, _)| value)
+	}
+
+	fn storage_with_hint(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		self.storage_with_hint_ref(KeyRef::new(key))
+	}
+
+	fn storage_with_hint_ref(
+		&self,
+		key: KeyRef,
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		if let Some(value) = self.cached(key) {
+			return Ok((value, StorageReadHint::Cache))
+		}
+		let (value, hint) = self.backend.storage_with_hint_ref(key)?;
+		self.cache(key, value.clone());
+		Ok((value, hint))
+	}
+
+	fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, Self::Error> {
+		if let Some(value) = self.cached(KeyRef::new(key)) {
+			return Ok(value.as_ref().map(|v| H::hash(v)))
+		}
+		self.backend.storage_hash(key)
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, Self::Error> {
+		let hash = Self::child_key_hash(child_info, key);
+		if let Some(value) = self.cache.lock().get_child(&hash) {
+			return Ok(value)
+		}
+		let value = self.backend.child_storage(child_info, key)?;
+		self.cache.lock().insert_child(hash, value.clone(), self.max_size);
+		Ok(value)
+	}
+
+	fn child_storage_hash(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<H::Out>, Self::Error> {
+		let hash = Self::child_key_hash(child_info, key);
+		if let Some(value) = self.cache.lock().get_child(&hash) {
+			return Ok(value.as_ref().map(|v| H::hash(v)))
+		}
+		self.backend.child_storage_hash(child_info, key)
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+		self.backend.next_storage_key(key)
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageKey>, Self::Error> {
+		self.backend.next_child_storage_key(child_info, key)
+	}
+
+	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		f: F,
+	) {
+		self.backend.for_keys_in_child_storage(child_info, f)
+	}
+
+	fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], f: F) {
+		self.backend.for_keys_with_prefix(prefix, f)
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
+		self.backend.for_key_values_with_prefix(prefix, f)
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		f: F,
+	) {
+		self.backend.for_child_keys_with_prefix(child_info, prefix, f)
+	}
+
+	fn child_keys_paged(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		count: usize,
+		start_key: Option<&[u8]>,
+	) -> Result<Vec<StorageKey>, Self::Error> {
+		self.backend.child_keys_paged(child_info, prefix, count, start_key)
+	}
+
+	fn storage_root<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.backend.storage_root(delta)
+	}
+
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.backend.storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: H::Out,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		self.backend.storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		self.backend.supports_incremental_storage_root()
+	}
+
+	fn child_storage_root<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord {
+		self.backend.child_storage_root(child_info, delta)
+	}
+
+	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
+		self.backend.pairs()
+	}
+
+	fn keys(&self, prefix: &[u8]) -> Vec<StorageKey> {
+		self.backend.keys(prefix)
+	}
+
+	fn child_keys(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+	) -> Vec<StorageKey> {
+		self.backend.child_keys(child_info, prefix)
+	}
+
+	fn register_overlay_stats(&mut self, stats: &crate::stats::StateMachineStats) {
+		self.backend.register_overlay_stats(stats)
+	}
+
+	fn usage_info(&self) -> UsageInfo {
+		self.backend.usage_info()
+	}
+
+	fn proof_size(&self) -> Option<u32> {
+		self.backend.proof_size()
+	}
+
+	fn is_quota_exceeded(error: &Self::Error) -> bool {
+		B::is_quota_exceeded(error)
+	}
+
+	fn check_limits(&self) -> Result<(), Self::Error> {
+		self.backend.check_limits()
+	}
+
+	fn wipe(&self) -> Result<(), Self::Error> {
+		self.clear_cache();
+		self.backend.wipe()
+	}
+
+	fn commit(
+		&self,
+		root: H::Out,
+		transaction: Self::Transaction,
+		main_storage_changes: StorageCollection,
+		child_storage_changes: ChildStorageCollection,
+	) -> Result<(), Self::Error> {
+		self.clear_cache();
+		self.backend.commit(root, transaction, main_storage_changes, child_storage_changes)
+	}
+
+	fn read_write_count(&self) -> (u32, u32, u32, u32) {
+		self.backend.read_write_count()
+	}
+
+	fn reset_read_write_count(&self) {
+		self.backend.reset_read_write_count()
+	}
+
+	fn get_whitelist(&self) -> Vec<TrackedStorageKey> {
+		self.backend.get_whitelist()
+	}
+
+	fn set_whitelist(&self, whitelist: Vec<TrackedStorageKey>) {
+		self.backend.set_whitelist(whitelist)
+	}
+}
+
+/// A set of overlays on top of the same backend.
+///
+/// Every overlay, called a lane, holds the changes of one candidate block or validation lane.
+/// The lanes share a [`SharedCacheBackend`], so identical reads of different lanes only hit
+/// the backend once. Use [`OverlaySet::lanes_mut`] to execute calls on the lanes in parallel.
+pub struct OverlaySet<B, H: Hasher> {
+	backend: SharedCacheBackend<B, H>,
+	overlays: Vec<OverlayedChanges>,
+}
+
+impl<B, H: Hasher> OverlaySet<B, H> {
+	/// Create a set with `lanes` empty overlays on top of `backend`.
+	///
+	/// The shared cache holds at most `cache_size` bytes, see [`SharedCacheBackend::new`].
+	pub fn new(backend: B, lanes: usize, cache_size: usize) -> Self {
+		OverlaySet {
+			backend: SharedCacheBackend::new(backend, cache_size),
+			overlays: (0..lanes).map(|_| OverlayedChanges::default()).collect(),
+		}
+	}
+
+	/// Returns the shared backend.
+	pub fn backend(&self) -> &SharedCacheBackend<B, H> {
+		&self.backend
+	}
+
+	/// Returns the number of lanes.
+	pub fn len(&self) -> usize {
+		self.overlays.len()
+	}
+
+	/// Returns true if there are no lanes.
+	pub fn is_empty(&self) -> bool {
+		self.overlays.is_empty()
+	}
+
+	/// Add a lane with an empty overlay and return its index.
+	pub fn add_lane(&mut self) -> usize {
+		self.overlays.push(OverlayedChanges::default());
+		self.overlays.len() - 1
+	}
+
+	/// Returns the overlay of the given `lane`.
+	pub fn overlay(&self, lane: usize) -> Option<&OverlayedChanges> {
+		self.overlays.get(lane)
+	}
+
+	/// Returns the shared backend and the overlay of the given `lane`.
+	pub fn lane_mut(
+		&mut self,
+		lane: usize,
+	) -> Option<(&SharedCacheBackend<B, H>, &mut OverlayedChanges)> {
+		let backend = &self.backend;
+		self.overlays.get_mut(lane).map(|overlay| (backend, overlay))
+	}
+
+	/// Returns the shared backend and the overlays of all lanes.
+	pub fn lanes_mut(&mut self) -> (&SharedCacheBackend<B, H>, &mut [OverlayedChanges]) {
+		(&self.backend, &mut self.overlays)
+	}
+
+	/// Take the overlay of the given `lane`, leaving an empty overlay in its place.
+	///
+	/// Panics if the lane does not exist.
+	pub fn take_overlay(&mut self, lane: usize) -> OverlayedChanges {
+		std::mem::take(&mut self.overlays[lane])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::{offchain::storage::OffchainOverlayedChanges, traits::Externalities};
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::{
+		Ext, InMemoryBackend, RecordingBackend, StorageTransactionCache, changes_trie::disabled_state,
+	};
+
+	#[test]
+	fn lanes_share_backend_reads() {
+		let child_info = ChildInfo::new_default(b"child");
+		let backend: InMemoryBackend<BlakeTwo256> = vec![
+			(None, vec![(b"a".to_vec(), Some(b"1".to_vec()))]),
+			(Some(child_info.clone()), vec![(b"b".to_vec(), Some(b"2".to_vec()))]),
+		].into();
+		let mut set = OverlaySet::new(RecordingBackend::new(backend, 16), 2, 1024);
+
+		let (backend, overlays) = set.lanes_mut();
+		for (lane, overlay) in overlays.iter_mut().enumerate() {
+			let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+			let mut cache = StorageTransactionCache::default();
+			let mut ext = Ext::new(
+				overlay,
+				&mut offchain_overlay,
+				&mut cache,
+				backend,
+				disabled_state::<_, u64>(),
+				None,
+			);
+			assert_eq!(ext.storage(b"a"), Some(b"1".to_vec()));
+			assert_eq!(ext.child_storage(&child_info, b"b"), Some(b"2".to_vec()));
+			ext.set_storage(b"lane".to_vec(), vec![lane as u8]);
+		}
+
+		assert_eq!(set.backend().inner().records().len(), 2);
+		assert_eq!(set.backend().cached_values(), 2);
+		assert_eq!(set.overlay(1).unwrap().storage(b"lane"), Some(Some(&[1][..])));
+		assert_eq!(set.take_overlay(0).storage(b"lane"), Some(Some(&[0][..])));
+		assert!(set.overlay(0).unwrap().is_empty());
+	}
+
+	#[test]
+	fn least_recently_used_values_are_evicted() {
+		let child_info = ChildInfo::new_default(b"child");
+		let backend: InMemoryBackend<BlakeTwo256> = vec![
+			(None, (0u8..4).map(|i| (vec![i], Some(vec![i; 9]))).collect()),
+			(Some(child_info.clone()), vec![(b"b".to_vec(), Some(vec![2; 8]))]),
+		].into();
+		// Room for three top values of 10 bytes each.
+		let backend = RecordingBackend::new(backend, 16);
+		let backend = SharedCacheBackend::<_, BlakeTwo256>::new(backend, 30);
+
+		for i in 0u8..3 {
+			assert_eq!(backend.storage(&[i]).unwrap(), Some(vec![i; 9]));
+		}
+		assert_eq!(backend.cache_size(), 30);
+		// Key 1 is used again, so key 0 is evicted first.
+		backend.storage(&[1]).unwrap();
+		backend.storage(&[3]).unwrap();
+		assert_eq!(backend.cached_values(), 3);
+		assert_eq!(backend.inner().records().len(), 4);
+		backend.storage(&[1]).unwrap();
+		backend.storage(&[2]).unwrap();
+		assert_eq!(backend.inner().records().len(), 4);
+		backend.storage(&[0]).unwrap();
+		assert_eq!(backend.inner().records().len(), 5);
+
+		// A child value of 40 bytes, including the hashed key, doesn't fit at all.
+		assert_eq!(backend.child_storage(&child_info, b"b").unwrap(), Some(vec![2; 8]));
+		assert_eq!(backend.cached_values(), 3);
+		assert!(backend.cache_size() <= 30);
+	}
+}
@@ -17,7 +17,7 @@
 
 //! Houses the code that implements the transactional overlay storage.
 
-use super::{StorageKey, StorageValue};
+use super::{StorageKey, StorageValue, extrinsics_set::ExtrinsicSet, key_hasher::KeyHashBuilder};
 
 use itertools::Itertools;
 use std::collections::{HashSet, BTreeMap, BTreeSet};
@@ -28,7 +28,7 @@ const PROOF_OVERLAY_NON_EMPTY: &str = "\
 	An OverlayValue is always created with at least one transaction and dropped as soon
 	as the last transaction is removed; qed";
 
-type DirtyKeysSets = SmallVec<[HashSet<StorageKey>; 5]>;
+type DirtyKeysSets = SmallVec<[HashSet<StorageKey, KeyHashBuilder>; 5]>;
 type Transactions = SmallVec<[InnerValue; 5]>;
 
 /// Error returned when trying to commit or rollback while no transaction is open or
@@ -63,7 +63,7 @@ struct InnerValue {
 	value: Option<StorageValue>,
 	/// The set of extrinsic indices where the values has been changed.
 	/// Is filled only if runtime has announced changes trie support.
-	extrinsics: BTreeSet<u32>,
+	extrinsics: ExtrinsicSet,
 }
 
 /// An overlay that contains all versions of a value for a specific key.
@@ -90,6 +90,15 @@ pub struct OverlayedChangeSet {
 	num_client_transactions: usize,
 	/// Determines whether the node is using the overlay from the client or the runtime.
 	execution_mode: ExecutionMode,
+	/// The number of keys the outermost transaction is expected to change.
+	keys_capacity: usize,
+	/// The expected maximum number of nested transactions.
+	history_depth: usize,
+	/// Hasher of the dirty key sets.
+	key_hasher: KeyHashBuilder,
+	/// The keys changed without any open transaction since the last call to
+	/// `take_unjournaled`, if tracked.
+	unjournaled: Option<BTreeSet<StorageKey>>,
 }
 
 impl Default for ExecutionMode {
@@ -99,6 +108,11 @@ impl Default for ExecutionMode {
 }
 
 impl OverlayedValue {
+	/// Create an empty value with room for `history_depth` versions.
+	fn with_capacity(history_depth: usize) -> Self {
+		OverlayedValue { transactions: Transactions::with_capacity(history_depth) }
+	}
+
 	/// The value as seen by the current transaction.
 	pub fn value(&self) -> Option<&StorageValue> {
 		self.transactions.last().expect(PROOF_OVERLAY_NON_EMPTY).value.as_ref()
@@ -109,6 +123,11 @@ impl OverlayedValue {
 		self.transactions.iter().flat_map(|t| t.extrinsics.iter()).unique()
 	}
 
+	/// Set of extrinsic indices which modified the value.
+	pub fn extrinsics_set(&self) -> BTreeSet<u32> {
+		self.transactions.iter().flat_map(|t| t.extrinsics.iter().cloned()).collect()
+	}
+
 	/// Mutable reference to the most recent version.
 	fn value_mut(&mut self) -> &mut Option<StorageValue> {
 		&mut self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY).value
@@ -120,7 +139,7 @@ impl OverlayedValue {
 	}
 
 	/// Mutable reference to the set which holds the indices for the **current transaction only**.
-	fn transaction_extrinsics_mut(&mut self) -> &mut BTreeSet<u32> {
+	fn transaction_extrinsics_mut(&mut self) -> &mut ExtrinsicSet {
 		&mut self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY).extrinsics
 	}
 
@@ -152,12 +171,53 @@ impl OverlayedValue {
 /// Inserts a key into the dirty set.
 ///
 /// Returns true iff we are currently have at least one open transaction and if this
-/// is the first write to the given key that transaction.
-fn insert_dirty(set: &mut DirtyKeysSets, key: StorageKey) -> bool {
-	set.last_mut().map(|dk| dk.insert(key)).unwrap_or_default()
+/// is the first write to the given key that transaction. Without an open transaction the
+/// key is noted in `unjournaled` instead, if tracked.
+fn insert_dirty(
+	set: &mut DirtyKeysSets,
+	unjournaled: &mut Option<BTreeSet<StorageKey>>,
+	key: StorageKey,
+) -> bool {
+	match set.last_mut() {
+		Some(dk) => dk.insert(key),
+		None => {
+			if let Some(unjournaled) = unjournaled {
+				unjournaled.insert(key);
+			}
+			false
+		},
+	}
 }
 
 impl OverlayedChangeSet {
+	/// Create an empty changeset that is pre-sized for the expected usage.
+	///
+	/// `keys` is the number of keys the outermost transaction is expected to change and
+	/// `history_depth` the expected maximum number of nested transactions.
+	pub fn with_capacity(keys: usize, history_depth: usize) -> Self {
+		Self {
+			dirty_keys: DirtyKeysSets::with_capacity(history_depth),
+			keys_capacity: keys,
+			history_depth,
+			.. Default::default()
+		}
+	}
+
+	/// Use the given hasher for the sets of dirty keys.
+	///
+	/// Must be called before any transaction is started.
+	pub fn with_key_hasher(mut self, key_hasher: KeyHashBuilder) -> Self {
+		debug_assert!(self.dirty_keys.is_empty());
+		self.key_hasher = key_hasher;
+		self
+	}
+
+	/// Create an empty changeset with the same capacities and hasher as this one.
+	pub fn empty_with_same_capacity(&self) -> Self {
+		Self::with_capacity(self.keys_capacity, self.history_depth)
+			.with_key_hasher(self.key_hasher.clone())
+	}
+
 	/// Create a new changeset at the same transaction state but without any contents.
 	///
 	/// This changeset might be created when there are already open transactions.
@@ -165,9 +225,14 @@ impl OverlayedChangeSet {
 	pub fn spawn_child(&self) -> Self {
 		use std::iter::repeat;
 		Self {
-			dirty_keys: repeat(HashSet::new()).take(self.transaction_depth()).collect(),
+			dirty_keys: repeat(HashSet::with_hasher(self.key_hasher.clone()))
+				.take(self.transaction_depth())
+				.collect(),
 			num_client_transactions: self.num_client_transactions,
 			execution_mode: self.execution_mode,
+			history_depth: self.history_depth,
+			key_hasher: self.key_hasher.clone(),
+			unjournaled: self.unjournaled.as_ref().map(|_| Default::default()),
 			.. Default::default()
 		}
 	}
@@ -177,11 +242,68 @@ impl OverlayedChangeSet {
 		self.changes.is_empty()
 	}
 
+	/// Returns the number of changed keys as seen by the current transaction.
+	pub fn len(&self) -> usize {
+		self.changes.len()
+	}
+
 	/// Get an optional reference to the value stored for the specified key.
 	pub fn get(&self, key: &[u8]) -> Option<&OverlayedValue> {
 		self.changes.get(key)
 	}
 
+	/// Get the value of the specified key as seen by the current transaction.
+	///
+	/// Returns `None` if the key was not changed and `Some(None)` if the key was deleted.
+	pub fn prospective_value(&self, key: &[u8]) -> Option<Option<&StorageValue>> {
+		self.changes.get(key).map(|overlayed| overlayed.value())
+	}
+
+	/// Get the value of the specified key as of the last change made without any open
+	/// transaction.
+	///
+	/// Changes made in open transactions are ignored, as they can still be rolled back.
+	/// Returns `None` if there is no such change and `Some(None)` if the key was deleted.
+	pub fn committed_value(&self, key: &[u8]) -> Option<Option<&StorageValue>> {
+		self.changes.get(key).and_then(|overlayed| self.committed(key, overlayed))
+	}
+
+	/// Get a list of all changes made without any open transaction.
+	pub fn committed_changes(&self) -> impl Iterator<Item=(&StorageKey, Option<&StorageValue>)> {
+		self.changes.iter().filter_map(move |(key, overlayed)| {
+			self.committed(key, overlayed).map(|value| (key, value))
+		})
+	}
+
+	/// Get the keys that are deleted as seen by the current transaction.
+	pub fn deleted_keys_iter(&self) -> impl Iterator<Item=&StorageKey> {
+		self.changes.iter().filter(|(_, overlayed)| overlayed.value().is_none()).map(|(key, _)| key)
+	}
+
+	/// Get the keys that are deleted by changes made without any open transaction.
+	pub fn committed_deleted_keys_iter(&self) -> impl Iterator<Item=&StorageKey> {
+		self.committed_changes().filter(|(_, value)| value.is_none()).map(|(key, _)| key)
+	}
+
+	/// The committed version of the given value, if any.
+	///
+	/// Every open transaction that wrote to the key pushed its own version, so the value
+	/// has a committed version iff it has more versions than writing transactions.
+	fn committed<'a>(
+		&self,
+		key: &[u8],
+		overlayed: &'a OverlayedValue,
+	) -> Option<Option<&'a StorageValue>> {
+		let writing_transactions = self.dirty_keys.iter()
+			.filter(|dirty_keys| dirty_keys.contains(key))
+			.count();
+		if overlayed.transactions.len() > writing_transactions {
+			Some(overlayed.transactions[0].value.as_ref())
+		} else {
+			None
+		}
+	}
+
 	/// Set a new value for the specified key.
 	///
 	/// Can be rolled back or committed when called inside a transaction.
@@ -191,8 +313,36 @@ impl OverlayedChangeSet {
 		value: Option<StorageValue>,
 		at_extrinsic: Option<u32>,
 	) {
-		let overlayed = self.changes.entry(key.clone()).or_default();
-		overlayed.set(value, insert_dirty(&mut self.dirty_keys, key), at_extrinsic);
+		let history_depth = self.history_depth;
+		let overlayed = self.changes.entry(key.clone())
+			.or_insert_with(|| OverlayedValue::with_capacity(history_depth));
+		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, &mut self.unjournaled, key);
+		overlayed.set(value, first_write_in_tx, at_extrinsic);
+	}
+
+	/// Set the values of the given keys without attributing them to an extrinsic.
+	///
+	/// Keys are expected in order. If the changeset is empty and no transaction is open, the
+	/// values are collected into it at once instead of being set one by one.
+	pub fn extend_sorted(&mut self, changes: impl Iterator<Item=(StorageKey, Option<StorageValue>)>) {
+		if self.changes.is_empty() && self.dirty_keys.is_empty() && self.unjournaled.is_none() {
+			let history_depth = self.history_depth;
+			self.changes = changes
+				.map(|(key, value)| {
+					let mut overlayed = OverlayedValue::with_capacity(history_depth);
+					overlayed.set(value, false, None);
+					(key, overlayed)
+				})
+				.collect();
+			return
+		}
+
+		if let Some(dirty_keys) = self.dirty_keys.last_mut() {
+			dirty_keys.reserve(changes.size_hint().0);
+		}
+		for (key, value) in changes {
+			self.set(key, value, None);
+		}
 	}
 
 	/// Get a mutable reference for a value.
@@ -205,8 +355,10 @@ impl OverlayedChangeSet {
 		init: impl Fn() -> StorageValue,
 		at_extrinsic: Option<u32>,
 	) -> &mut Option<StorageValue> {
-		let overlayed = self.changes.entry(key.clone()).or_default();
-		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, key);
+		let history_depth = self.history_depth;
+		let overlayed = self.changes.entry(key.clone())
+			.or_insert_with(|| OverlayedValue::with_capacity(history_depth));
+		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, &mut self.unjournaled, key);
 		let clone_into_new_tx = if let Some(tx) = overlayed.transactions.last() {
 			if first_write_in_tx {
 				Some(tx.value.clone())
@@ -232,7 +384,12 @@ impl OverlayedChangeSet {
 		at_extrinsic: Option<u32>,
 	) {
 		for (key, val) in self.changes.iter_mut().filter(|(k, v)| predicate(k, v)) {
-			val.set(None, insert_dirty(&mut self.dirty_keys, key.to_owned()), at_extrinsic);
+			let first_write_in_tx = insert_dirty(
+				&mut self.dirty_keys,
+				&mut self.unjournaled,
+				key.to_owned(),
+			);
+			val.set(None, first_write_in_tx, at_extrinsic);
 		}
 	}
 
@@ -241,6 +398,43 @@ impl OverlayedChangeSet {
 		self.changes.iter()
 	}
 
+	/// Get the changes made since the transaction depth was `layer`, ordered by key.
+	///
+	/// These are the changes of all transactions that were opened at a depth of `layer` or
+	/// deeper and are still open. Each key is reported with its value as seen by the current
+	/// transaction.
+	pub fn changes_since(&self, layer: usize) -> impl Iterator<Item=(&[u8], Option<&[u8]>)> {
+		let keys: BTreeSet<&StorageKey> = self.dirty_keys.iter().skip(layer).flatten().collect();
+		keys.into_iter().filter_map(move |key| self.changes.get(key).map(|overlayed| {
+			(&key[..], overlayed.value().map(|v| &v[..]))
+		}))
+	}
+
+	/// Track the keys changed without any open transaction, see `take_unjournaled`.
+	///
+	/// Changesets spawned from this one are tracked as well.
+	pub fn track_unjournaled(&mut self) {
+		if self.unjournaled.is_none() {
+			self.unjournaled = Some(Default::default());
+		}
+	}
+
+	/// Take the keys changed without any open transaction since the last call, with their
+	/// current values.
+	pub fn take_unjournaled(&mut self) -> Vec<(StorageKey, Option<StorageValue>)> {
+		let keys = match &mut self.unjournaled {
+			Some(keys) => std::mem::take(keys),
+			None => return Vec::new(),
+		};
+		let changes = &self.changes;
+		keys.into_iter()
+			.filter_map(|key| {
+				let value = changes.get(&key)?.value().cloned();
+				Some((key, value))
+			})
+			.collect()
+	}
+
 	/// Get the change that is next to the supplied key.
 	pub fn next_change(&self, key: &[u8]) -> Option<(&[u8], &OverlayedValue)> {
 		use std::ops::Bound;
@@ -248,6 +442,25 @@ impl OverlayedChangeSet {
 		self.changes.range::<[u8], _>(range).next().map(|(k, v)| (&k[..], v))
 	}
 
+	/// Returns the first key that was changed in this and in the `other` changeset.
+	pub fn first_conflict(&self, other: &Self) -> Option<&StorageKey> {
+		other.changes.keys().find(|key| self.changes.contains_key(*key))
+	}
+
+	/// Move all changes of `other` into this changeset.
+	///
+	/// Changes of `other` overwrite changes to the same key in this changeset.
+	///
+	/// Panics:
+	/// Panics if any of both changesets has open transactions.
+	pub fn merge(&mut self, other: Self) {
+		assert!(
+			self.transaction_depth() == 0 && other.transaction_depth() == 0,
+			"Merge is not allowed with open transactions.",
+		);
+		self.changes.extend(other.changes);
+	}
+
 	/// Consume this changeset and return all committed changes.
 	///
 	/// Panics:
@@ -257,6 +470,79 @@ impl OverlayedChangeSet {
 		self.changes.into_iter().map(|(k, mut v)| (k, v.pop_transaction().value))
 	}
 
+	/// Release the memory that is no longer needed to track closed transactions.
+	///
+	/// Committing a transaction merges the versions of a value, but keeps the memory that was
+	/// allocated for deeply nested transactions. This shrinks the version list of every value
+	/// and the list of dirty key sets to what is actually in use. Values written without any
+	/// open transaction are left with their single committed version.
+	pub fn optimize(&mut self) {
+		for overlayed in self.changes.values_mut() {
+			overlayed.transactions.shrink_to_fit();
+		}
+		self.dirty_keys.shrink_to_fit();
+	}
+
+	/// Returns all versions of the value of `key`, oldest first, each with the transaction
+	/// depth at which it was written.
+	///
+	/// Depth zero is the version committed without any open transaction. Returns an empty
+	/// list if the key was not changed. This is meant for debugging.
+	pub fn value_history(&self, key: &[u8]) -> Vec<(usize, Option<&StorageValue>)> {
+		let overlayed = match self.changes.get(key) {
+			Some(overlayed) => overlayed,
+			None => return Vec::new(),
+		};
+		let committed = self.committed(key, overlayed).map(|_| 0);
+		let depths = committed.into_iter().chain(
+			self.dirty_keys.iter()
+				.enumerate()
+				.filter(|(_, dirty_keys)| dirty_keys.contains(key))
+				.map(|(layer, _)| layer + 1)
+		);
+		depths.zip(overlayed.transactions.iter().map(|tx| tx.value.as_ref())).collect()
+	}
+
+	/// Check the internal consistency of the changeset and panic if it is violated.
+	///
+	/// Every version of a value must belong to either the committed state or to an open
+	/// transaction that wrote the key, and every key in a dirty set must have a value. This
+	/// is expensive and meant for debug builds and fuzzers.
+	pub fn assert_invariants(&self) {
+		assert!(
+			self.num_client_transactions <= self.transaction_depth(),
+			"{} client transactions, but only {} open transactions",
+			self.num_client_transactions,
+			self.transaction_depth(),
+		);
+
+		for (layer, dirty_keys) in self.dirty_keys.iter().enumerate() {
+			for key in dirty_keys {
+				assert!(
+					self.changes.contains_key(key),
+					"Dirty key {:?} of transaction {} has no value",
+					key,
+					layer + 1,
+				);
+			}
+		}
+
+		for (key, overlayed) in &self.changes {
+			let writing_transactions = self.dirty_keys.iter()
+				.filter(|dirty_keys| dirty_keys.contains(key))
+				.count();
+			let versions = overlayed.transactions.len();
+			assert!(versions > 0, "Value of {:?} has no version", key);
+			assert!(
+				versions == writing_transactions || versions == writing_transactions + 1,
+				"Value of {:?} has {} versions, but was written by {} open transactions",
+				key,
+				versions,
+				writing_transactions,
+			);
+		}
+	}
+
 	/// Returns the current nesting depth of the transaction stack.
 	///
 	/// A value of zero means that no transaction is open and changes are committed on write.
@@ -307,7 +593,9 @@ impl OverlayedChangeSet {
 	///
 	/// Changes made without any open transaction are committed immediately.
 	pub fn start_transaction(&mut self) {
-		self.dirty_keys.push(Default::default());
+		// Only the outermost transaction is expected to cover all changed keys.
+		let keys_capacity = if self.dirty_keys.is_empty() { self.keys_capacity } else { 0 };
+		self.dirty_keys.push(HashSet::with_capacity_and_hasher(keys_capacity, self.key_hasher.clone()));
 	}
 
 	/// Rollback the last transaction started by `start_transaction`.
@@ -677,6 +965,62 @@ mod test {
 
 	}
 
+	#[test]
+	fn optimize_releases_closed_transactions() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), Some(1));
+
+		for i in 0..10 {
+			changeset.start_transaction();
+			changeset.set(b"key0".to_vec(), Some(vec![i]), Some(i.into()));
+		}
+		assert!(changeset.changes[&b"key0".to_vec()].transactions.spilled());
+		assert!(changeset.dirty_keys.spilled());
+
+		for _ in 0..10 {
+			changeset.commit_transaction().unwrap();
+		}
+		changeset.optimize();
+
+		let overlayed = &changeset.changes[&b"key0".to_vec()];
+		assert_eq!(overlayed.transactions.len(), 1);
+		assert!(!overlayed.transactions.spilled());
+		assert!(!changeset.dirty_keys.spilled());
+		assert_changes(&changeset, &vec![
+			(b"key0", (Some(&[9][..]), (0..10).collect())),
+		]);
+	}
+
+	#[test]
+	fn committed_and_prospective_values_work() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), None);
+
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), None, None);
+		changeset.set(b"key2".to_vec(), Some(b"val2".to_vec()), None);
+
+		changeset.start_transaction();
+		changeset.set(b"key2".to_vec(), Some(b"val2-1".to_vec()), None);
+
+		assert_eq!(changeset.prospective_value(b"key0"), Some(None));
+		assert_eq!(changeset.committed_value(b"key0"), Some(Some(&b"val0".to_vec())));
+		assert_eq!(changeset.prospective_value(b"key1"), Some(Some(&b"val1".to_vec())));
+		assert_eq!(changeset.committed_value(b"key1"), Some(Some(&b"val1".to_vec())));
+		assert_eq!(changeset.prospective_value(b"key2"), Some(Some(&b"val2-1".to_vec())));
+		assert_eq!(changeset.committed_value(b"key2"), None);
+		assert_eq!(changeset.prospective_value(b"key3"), None);
+		assert_eq!(changeset.committed_value(b"key3"), None);
+		assert_eq!(changeset.committed_changes().count(), 2);
+
+		changeset.commit_transaction().unwrap();
+		changeset.commit_transaction().unwrap();
+		assert_eq!(changeset.committed_value(b"key0"), Some(None));
+		assert_eq!(changeset.committed_value(b"key2"), Some(Some(&b"val2-1".to_vec())));
+		assert_eq!(changeset.committed_changes().count(), 3);
+	}
+
 	#[test]
 	fn no_open_tx_commit_errors() {
 		let mut changeset = OverlayedChangeSet::default();
@@ -739,6 +1083,37 @@ mod test {
 		]);
 	}
 
+	#[test]
+	fn changes_since_works() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), None);
+
+		changeset.start_transaction();
+		changeset.set(b"key2".to_vec(), None, None);
+		changeset.set(b"key0".to_vec(), Some(b"val0-1".to_vec()), None);
+
+		changeset.start_transaction();
+		changeset.set(b"key3".to_vec(), Some(b"val3".to_vec()), None);
+		changeset.rollback_transaction().unwrap();
+
+		let since = |layer| changeset.changes_since(layer)
+			.map(|(k, v)| (k.to_vec(), v.map(|v| v.to_vec())))
+			.collect::<Vec<_>>();
+		assert_eq!(since(0), vec![
+			(b"key0".to_vec(), Some(b"val0-1".to_vec())),
+			(b"key1".to_vec(), Some(b"val1".to_vec())),
+			(b"key2".to_vec(), None),
+		]);
+		assert_eq!(since(1), vec![
+			(b"key0".to_vec(), Some(b"val0-1".to_vec())),
+			(b"key2".to_vec(), None),
+		]);
+		assert!(since(2).is_empty());
+	}
+
 	#[test]
 	fn enter_exit_runtime_fails_when_already_in_requested_mode() {
 		let mut changeset = OverlayedChangeSet::default();
@@ -749,4 +1124,66 @@ mod test {
 		assert_eq!(changeset.exit_runtime(), Ok(()));
 		assert_eq!(changeset.exit_runtime(), Err(NotInRuntime));
 	}
+
+	#[test]
+	fn value_history_reports_versions_per_depth() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), None);
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), None, None);
+		changeset.set(b"key1".to_vec(), Some(b"val1-2".to_vec()), None);
+		changeset.assert_invariants();
+
+		let val0 = b"val0".to_vec();
+		let val1 = b"val1".to_vec();
+		let val1_2 = b"val1-2".to_vec();
+		assert_eq!(changeset.value_history(b"key0"), vec![(0, Some(&val0)), (2, None)]);
+		assert_eq!(changeset.value_history(b"key1"), vec![(1, Some(&val1)), (2, Some(&val1_2))]);
+		assert!(changeset.value_history(b"key2").is_empty());
+
+		changeset.commit_transaction().unwrap();
+		changeset.assert_invariants();
+		assert_eq!(changeset.value_history(b"key0"), vec![(0, Some(&val0)), (1, None)]);
+		assert_eq!(changeset.value_history(b"key1"), vec![(1, Some(&val1_2))]);
+
+		changeset.rollback_transaction().unwrap();
+		changeset.assert_invariants();
+		assert_eq!(changeset.value_history(b"key0"), vec![(0, Some(&val0))]);
+		assert!(changeset.value_history(b"key1").is_empty());
+	}
+
+	#[test]
+	#[should_panic(expected = "has no value")]
+	fn assert_invariants_detects_dangling_dirty_key() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		changeset.changes.clear();
+		changeset.assert_invariants();
+	}
+
+	#[test]
+	fn repeated_transactions_do_not_accumulate_versions() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		for i in 0..100u8 {
+			changeset.start_transaction();
+			changeset.set(b"key0".to_vec(), Some(vec![i]), None);
+			changeset.set(b"key1".to_vec(), Some(vec![i]), None);
+			if i % 2 == 0 {
+				changeset.commit_transaction().unwrap();
+			} else {
+				changeset.rollback_transaction().unwrap();
+			}
+		}
+		changeset.assert_invariants();
+
+		assert_eq!(changeset.transaction_depth(), 0);
+		for (_, overlayed) in changeset.changes() {
+			assert_eq!(overlayed.transactions.len(), 1);
+		}
+		assert_eq!(changeset.value_history(b"key0"), vec![(0, Some(&vec![98]))]);
+	}
 }
@@ -0,0 +1,124 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sorted set of the extrinsic indices that changed a value.
+
+use std::collections::BTreeSet;
+use smallvec::SmallVec;
+
+/// Number of indices that are stored without allocating.
+///
+/// Most keys are only changed by one or two extrinsics of a block.
+const INLINE_INDICES: usize = 4;
+
+/// A sorted set of extrinsic indices.
+///
+/// Keeps up to four indices inline and only allocates for keys that are changed by more
+/// extrinsics.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtrinsicSet(SmallVec<[u32; INLINE_INDICES]>);
+
+impl ExtrinsicSet {
+	/// Add an index to the set. Returns false if it was already present.
+	pub fn insert(&mut self, index: u32) -> bool {
+		match self.0.binary_search(&index) {
+			Ok(_) => false,
+			Err(pos) => {
+				self.0.insert(pos, index);
+				true
+			},
+		}
+	}
+
+	/// Returns true if the set contains `index`.
+	pub fn contains(&self, index: u32) -> bool {
+		self.0.binary_search(&index).is_ok()
+	}
+
+	/// Iterate over the indices in ascending order.
+	pub fn iter(&self) -> std::slice::Iter<u32> {
+		self.0.iter()
+	}
+
+	/// Returns the number of indices.
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	/// Returns true if the set contains no indices.
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// Returns the indices as a `BTreeSet`.
+	pub fn to_btree_set(&self) -> BTreeSet<u32> {
+		self.0.iter().cloned().collect()
+	}
+}
+
+impl Extend<u32> for ExtrinsicSet {
+	fn extend<I: IntoIterator<Item = u32>>(&mut self, iter: I) {
+		for index in iter {
+			self.insert(index);
+		}
+	}
+}
+
+impl<'a> IntoIterator for &'a ExtrinsicSet {
+	type Item = &'a u32;
+	type IntoIter = std::slice::Iter<'a, u32>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.0.iter()
+	}
+}
+
+impl IntoIterator for ExtrinsicSet {
+	type Item = u32;
+	type IntoIter = smallvec::IntoIter<[u32; INLINE_INDICES]>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.0.into_iter()
+	}
+}
+
+impl From<ExtrinsicSet> for BTreeSet<u32> {
+	fn from(set: ExtrinsicSet) -> Self {
+		set.0.into_iter().collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn indices_are_sorted_and_unique() {
+		let mut set = ExtrinsicSet::default();
+		assert!(set.insert(5));
+		assert!(set.insert(1));
+		assert!(!set.insert(5));
+		set.extend(vec![3, 1, 9, 7, 2]);
+
+		assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 3, 5, 7, 9]);
+		assert_eq!(set.len(), 6);
+		assert!(set.contains(7));
+		assert!(!set.contains(4));
+		assert_eq!(set.to_btree_set(), BTreeSet::from(set.clone()));
+		assert_eq!(set.into_iter().collect::<BTreeSet<_>>(), vec![1, 2, 3, 5, 7, 9].into_iter().collect());
+	}
+}
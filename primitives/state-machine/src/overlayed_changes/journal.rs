@@ -0,0 +1,131 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Write-ahead journal of the committed changes of an overlay.
+
+use std::{fmt, fs::{File, OpenOptions}, io::{self, Write}, path::Path, sync::Arc};
+use codec::{Decode, Encode};
+use log::warn;
+use parking_lot::Mutex;
+use super::{StorageCollection, ChildStorageCollection};
+
+/// Changes committed to an overlay, see [`OverlayedChanges::enable_journal`].
+///
+/// [`OverlayedChanges::enable_journal`]: super::OverlayedChanges::enable_journal
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct JournalEntry {
+	/// The changed top keys with their new values, `None` for removed keys.
+	pub top: StorageCollection,
+	/// The changed keys of the default child tries, by the storage key of the child trie.
+	pub children: ChildStorageCollection,
+}
+
+/// A record of the journal of an overlay.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum JournalRecord {
+	/// Changes committed to the overlay.
+	Changes(JournalEntry),
+	/// The changes of all earlier records were drained from the overlay, e.g. because the
+	/// block was built. Only the records after the last boundary are needed to rebuild it.
+	Boundary,
+}
+
+impl From<JournalEntry> for JournalRecord {
+	fn from(entry: JournalEntry) -> Self {
+		JournalRecord::Changes(entry)
+	}
+}
+
+/// Receiver of the journal records of an overlay.
+pub trait JournalSink: Send + Sync {
+	/// Append the given record to the journal.
+	///
+	/// The changes of the record are already committed to the overlay. An error stops the
+	/// journal of the overlay and makes draining its changes fail.
+	fn append(&self, record: &JournalRecord) -> Result<(), String>;
+}
+
+impl<F: Fn(&JournalRecord) -> Result<(), String> + Send + Sync> JournalSink for F {
+	fn append(&self, record: &JournalRecord) -> Result<(), String> {
+		self(record)
+	}
+}
+
+/// A [`JournalSink`] that appends the SCALE encoded records to a file.
+///
+/// The file is truncated at every [`JournalRecord::Boundary`], so it only holds the records
+/// of the changes that are not drained yet. It can be read back with [`read_journal`].
+pub struct FileJournal {
+	file: Mutex<File>,
+}
+
+impl FileJournal {
+	/// Open the journal at `path`, records are appended to an existing file.
+	pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		Ok(FileJournal { file: Mutex::new(file) })
+	}
+}
+
+impl JournalSink for FileJournal {
+	fn append(&self, record: &JournalRecord) -> Result<(), String> {
+		let mut file = self.file.lock();
+		let written = match record {
+			JournalRecord::Changes(_) => file.write_all(&record.encode()),
+			JournalRecord::Boundary => file.set_len(0),
+		};
+		written.and_then(|_| file.sync_data()).map_err(|e| e.to_string())
+	}
+}
+
+impl fmt::Debug for FileJournal {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "FileJournal")
+	}
+}
+
+/// Decode the records of a journal written by [`FileJournal`] and return the entries after
+/// the last [`JournalRecord::Boundary`].
+///
+/// Decoding stops at the first incomplete record, which is what a crash while appending
+/// leaves behind.
+pub fn read_journal(mut data: &[u8]) -> Vec<JournalEntry> {
+	let mut entries = Vec::new();
+	while !data.is_empty() {
+		let mut input = data;
+		match JournalRecord::decode(&mut input) {
+			Ok(JournalRecord::Changes(entry)) => entries.push(entry),
+			Ok(JournalRecord::Boundary) => entries.clear(),
+			Err(_) => {
+				warn!(target: "state", "Ignoring {} bytes of an incomplete journal record", data.len());
+				break
+			},
+		}
+		data = input;
+	}
+	entries
+}
+
+/// The sink of an overlay, wrapped to allow deriving `Clone` and `Debug` on the overlay.
+#[derive(Clone)]
+pub(super) struct Journal(pub(super) Arc<dyn JournalSink>);
+
+impl fmt::Debug for Journal {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Journal")
+	}
+}
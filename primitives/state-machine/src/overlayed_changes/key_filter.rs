@@ -0,0 +1,102 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bloom filter for fast negative lookups in the overlay.
+
+use std::fmt;
+use crate::key_ref::{KeyRef, fnv1a};
+
+/// Number of bits in the filter.
+const FILTER_BITS: usize = 1 << 16;
+
+/// Bloom filter with two hash functions over the keys inserted into the overlay.
+///
+/// Keys can not be removed from the filter. A key that was rolled back is therefore
+/// still reported as possibly contained, which only costs a regular lookup.
+#[derive(Clone)]
+pub struct KeyFilter {
+	bits: Vec<u64>,
+}
+
+impl Default for KeyFilter {
+	fn default() -> Self {
+		KeyFilter { bits: vec![0; FILTER_BITS / 64] }
+	}
+}
+
+impl fmt::Debug for KeyFilter {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let set: u32 = self.bits.iter().map(|word| word.count_ones()).sum();
+		write!(f, "KeyFilter {{ set_bits: {} }}", set)
+	}
+}
+
+impl KeyFilter {
+	/// Returns the two bit positions for the given key hash.
+	fn positions(hash: u64) -> (usize, usize) {
+		(hash as usize % FILTER_BITS, (hash >> 32) as usize % FILTER_BITS)
+	}
+
+	fn is_set(&self, pos: usize) -> bool {
+		self.bits[pos / 64] & (1 << (pos % 64)) != 0
+	}
+
+	/// Insert the given key.
+	pub fn insert(&mut self, key: &[u8]) {
+		let (a, b) = Self::positions(fnv1a(key));
+		self.bits[a / 64] |= 1 << (a % 64);
+		self.bits[b / 64] |= 1 << (b % 64);
+	}
+
+	/// Returns `false` if the given key was never inserted.
+	pub fn may_contain(&self, key: &[u8]) -> bool {
+		self.may_contain_ref(KeyRef::new(key))
+	}
+
+	/// Like [`may_contain`](Self::may_contain), without hashing the key again.
+	pub fn may_contain_ref(&self, key: KeyRef) -> bool {
+		let (a, b) = Self::positions(key.hash());
+		self.is_set(a) && self.is_set(b)
+	}
+
+	/// Remove all keys from the filter.
+	pub fn clear(&mut self) {
+		for word in self.bits.iter_mut() {
+			*word = 0;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn inserted_keys_are_contained() {
+		let mut filter = KeyFilter::default();
+		for i in 0u32..1000 {
+			filter.insert(&i.to_le_bytes());
+		}
+
+		assert!((0u32..1000).all(|i| filter.may_contain(&i.to_le_bytes())));
+		let false_positives = (1000u32..11000).filter(|i| filter.may_contain(&i.to_le_bytes())).count();
+		assert!(false_positives < 100);
+
+		filter.clear();
+		assert!(!filter.may_contain(&0u32.to_le_bytes()));
+	}
+}
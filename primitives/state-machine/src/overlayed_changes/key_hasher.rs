@@ -0,0 +1,101 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configurable hasher for the hash maps of the overlay.
+
+use std::{
+	collections::hash_map::{DefaultHasher, RandomState},
+	hash::{BuildHasher, Hasher},
+};
+
+/// The hash function the overlay uses for its hash maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyHasher {
+	/// Randomly keyed SipHash, the default of the standard library.
+	///
+	/// Resistant against keys that are chosen to collide, so this is the right choice
+	/// whenever the keys can be influenced by untrusted parties.
+	Sip,
+	/// FNV-1a.
+	///
+	/// Considerably faster for the short keys of the storage, but keys can easily be crafted
+	/// to collide. Only use this if all executed code is trusted.
+	Fnv,
+}
+
+impl Default for KeyHasher {
+	fn default() -> Self {
+		KeyHasher::Sip
+	}
+}
+
+/// [`BuildHasher`] for the selected [`KeyHasher`].
+#[derive(Debug, Clone, Default)]
+pub struct KeyHashBuilder {
+	kind: KeyHasher,
+	sip: RandomState,
+}
+
+impl KeyHashBuilder {
+	/// Create a builder for the given hash function.
+	pub fn new(kind: KeyHasher) -> Self {
+		KeyHashBuilder { kind, sip: RandomState::new() }
+	}
+
+	/// Returns the hash function of this builder.
+	pub fn kind(&self) -> KeyHasher {
+		self.kind
+	}
+}
+
+impl BuildHasher for KeyHashBuilder {
+	type Hasher = KeyHasherState;
+
+	fn build_hasher(&self) -> KeyHasherState {
+		match self.kind {
+			KeyHasher::Sip => KeyHasherState::Sip(self.sip.build_hasher()),
+			KeyHasher::Fnv => KeyHasherState::Fnv(0xcbf2_9ce4_8422_2325),
+		}
+	}
+}
+
+/// State of a hasher created by [`KeyHashBuilder`].
+pub enum KeyHasherState {
+	/// SipHash state.
+	Sip(DefaultHasher),
+	/// FNV-1a state.
+	Fnv(u64),
+}
+
+impl Hasher for KeyHasherState {
+	fn write(&mut self, bytes: &[u8]) {
+		match self {
+			KeyHasherState::Sip(hasher) => hasher.write(bytes),
+			KeyHasherState::Fnv(hash) => for byte in bytes {
+				*hash ^= *byte as u64;
+				*hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+			},
+		}
+	}
+
+	fn finish(&self) -> u64 {
+		match self {
+			KeyHasherState::Sip(hasher) => hasher.finish(),
+			KeyHasherState::Fnv(hash) => *hash,
+		}
+	}
+}
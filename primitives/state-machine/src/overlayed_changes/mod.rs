@@ -18,24 +18,37 @@
 //! The overlayed changes to state.
 
 mod changeset;
+mod extrinsics_set;
+mod journal;
+mod key_filter;
+mod key_hasher;
 
 use crate::{
-	backend::Backend, ChangesTrieTransaction,
+	backend::{Backend, Consolidate}, ChangesTrieTransaction,
 	changes_trie::{
 		NO_EXTRINSIC_INDEX, BlockNumber, build_changes_trie,
 		State as ChangesTrieState,
 	},
 	stats::StateMachineStats,
+	key_ref::KeyRef,
 };
 use self::changeset::OverlayedChangeSet;
+use self::key_filter::KeyFilter;
+use self::key_hasher::KeyHashBuilder;
 
-use std::collections::HashMap;
+use std::{
+	cell::RefCell, collections::{BTreeMap, BTreeSet, HashMap, btree_map::Entry}, sync::Arc,
+};
 use codec::{Decode, Encode};
-use sp_core::storage::{well_known_keys::EXTRINSIC_INDEX, ChildInfo};
-use sp_core::offchain::storage::OffchainOverlayedChanges;
+use sp_core::{hexdisplay::HexDisplay, storage::{well_known_keys::EXTRINSIC_INDEX, ChildInfo}};
+use sp_core::offchain::storage::{OffchainOverlayedChanges, OffchainOverlayedChange};
 use hash_db::Hasher;
+use sp_trie::{empty_child_trie_root, trie_types::Layout};
 
 pub use self::changeset::{OverlayedValue, NoOpenTransaction, AlreadyInRuntime, NotInRuntime};
+pub use self::extrinsics_set::ExtrinsicSet;
+pub use self::journal::{JournalEntry, JournalRecord, JournalSink, FileJournal, read_journal};
+pub use self::key_hasher::KeyHasher;
 
 /// Storage key.
 pub type StorageKey = Vec<u8>;
@@ -49,6 +62,12 @@ pub type StorageCollection = Vec<(StorageKey, Option<StorageValue>)>;
 /// In memory arrays of storage values for multiple child tries.
 pub type ChildStorageCollection = Vec<(StorageKey, StorageCollection)>;
 
+/// Identifier of a storage namespace, e.g. the index of a runtime module.
+pub type StorageNamespace = u32;
+
+/// Reported for transactions that were started without a label.
+const UNLABELED: &str = "<unlabeled>";
+
 /// The set of changes that are overlaid onto the backend.
 ///
 /// It allows changes to be modified using nestable transactions.
@@ -57,13 +76,140 @@ pub struct OverlayedChanges {
 	/// Top level storage changes.
 	top: OverlayedChangeSet,
 	/// Child storage changes. The map key is the child storage key without the common prefix.
-	children: HashMap<StorageKey, (OverlayedChangeSet, ChildInfo)>,
+	///
+	/// This is an ordered map, so that iterating the child changes is deterministic.
+	children: BTreeMap<StorageKey, (OverlayedChangeSet, ChildInfo)>,
+	/// Transactions that only cover a single child trie. The map key is the child storage key
+	/// and the value holds the top transaction depth at which each of them was started.
+	child_transactions: HashMap<StorageKey, Vec<usize>, KeyHashBuilder>,
+	/// Filter over the keys of `top` that allows to skip lookups of keys that were never set.
+	key_filter: Option<KeyFilter>,
+	/// Changes destined for the offchain database.
+	///
+	/// These are never part of the state root and are only recorded if offchain indexing
+	/// was enabled.
+	offchain: OffchainOverlayedChanges,
+	/// Transient storage changes.
+	///
+	/// These are a scratch space for the current block. They are never part of the state
+	/// root, changes tries or proofs and are discarded when the changes are drained.
+	transient: OverlayedChangeSet,
+	/// The backend values of the top keys at their first access, if tracking was enabled.
+	original_values: Option<RefCell<BTreeMap<StorageKey, Option<StorageValue>>>>,
+	/// The keys written since the last call to `take_dirty_keys`.
+	written_keys: DirtyKeys,
+	/// The top keys written since the storage root was last calculated, if the root is
+	/// calculated incrementally.
+	root_dirty_keys: Option<RefCell<BTreeSet<StorageKey>>>,
+	/// The writes tagged with a namespace since the last call to `take_namespace_stats`.
+	namespaces: BTreeMap<StorageNamespace, NamespaceStats>,
+	/// Receives the changes committed to this overlay, if enabled.
+	journal: Option<journal::Journal>,
+	/// The first error returned by the journal. Nothing is journaled after it.
+	journal_failure: Option<String>,
+	/// The labels of the open transactions, outermost first.
+	transaction_labels: Vec<Option<&'static str>>,
 	/// True if extrinsics stats must be collected.
 	collect_extrinsics: bool,
+	/// True if no changes trie must be built for the current block.
+	changes_trie_disabled: bool,
+	/// True if the extrinsic boundaries are noted explicitly, instead of being read from
+	/// `EXTRINSIC_INDEX`.
+	notes_extrinsics: bool,
+	/// The extrinsic that is currently executed, if boundaries are noted explicitly.
+	current_extrinsic: Option<u32>,
 	/// Collect statistic on this execution.
 	stats: StateMachineStats,
 }
 
+/// The keys written to an overlay, see [`OverlayedChanges::take_dirty_keys`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DirtyKeys {
+	/// The written top keys.
+	pub top: BTreeSet<StorageKey>,
+	/// The written keys of the child tries. The map key is the child storage key.
+	pub children: BTreeMap<StorageKey, BTreeSet<StorageKey>>,
+}
+
+impl DirtyKeys {
+	/// True if no key was written.
+	pub fn is_empty(&self) -> bool {
+		self.top.is_empty() && self.children.is_empty()
+	}
+
+	fn child(&mut self, storage_key: &[u8]) -> &mut BTreeSet<StorageKey> {
+		if !self.children.contains_key(storage_key) {
+			self.children.insert(storage_key.to_vec(), BTreeSet::new());
+		}
+		self.children.get_mut(storage_key).expect("Inserted above; qed")
+	}
+
+	fn extend(&mut self, other: DirtyKeys) {
+		self.top.extend(other.top);
+		for (storage_key, keys) in other.children {
+			self.child(&storage_key).extend(keys);
+		}
+	}
+}
+
+/// The writes to a storage namespace, see [`OverlayedChanges::take_namespace_stats`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NamespaceStats {
+	/// The written keys.
+	pub dirty_keys: BTreeSet<StorageKey>,
+	/// Number of values that were set.
+	pub writes: u64,
+	/// Number of values that were removed.
+	pub removals: u64,
+	/// Size of the written keys and values in bytes.
+	pub bytes_written: u64,
+}
+
+impl NamespaceStats {
+	fn extend(&mut self, other: NamespaceStats) {
+		self.dirty_keys.extend(other.dirty_keys);
+		self.writes += other.writes;
+		self.removals += other.removals;
+		self.bytes_written += other.bytes_written;
+	}
+}
+
+/// Error returned by [`OverlayedChanges::merge`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeConflict {
+	/// One of the overlays has open transactions.
+	OpenTransaction,
+	/// The given top key was changed by both overlays.
+	Key(StorageKey),
+	/// The given key of the child trie with the given storage key was changed by both overlays.
+	ChildKey(StorageKey, StorageKey),
+}
+
+/// The changes of an overlay without open transactions, see
+/// [`OverlayedChanges::try_into_committed`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommittedChanges {
+	/// The changes of the main trie, ordered by key.
+	pub top: StorageCollection,
+	/// The changes of the child tries, ordered by the storage key without the common prefix.
+	pub children: ChildStorageCollection,
+}
+
+/// The transactions that are still open, see [`OverlayedChanges::try_into_committed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingLayers {
+	/// The labels of the open transactions, outermost first.
+	pub labels: Vec<Option<&'static str>>,
+	/// The changes of the open transactions, as they would be committed.
+	pub changes: JournalEntry,
+}
+
+/// Error returned by [`OverlayedChanges::set_child_storage_root_hint`] if the child trie has
+/// no changes the root could be calculated for.
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct NoChildChanges;
+
 /// A storage changes structure that can be generated by the data collected in [`OverlayedChanges`].
 ///
 /// This contains all the changes to the storage and transactions to apply theses changes to the
@@ -123,12 +269,44 @@ pub struct StorageTransactionCache<Transaction, H: Hasher, N: BlockNumber> {
 	pub(crate) changes_trie_transaction: Option<Option<ChangesTrieTransaction<H, N>>>,
 	/// The storage root after applying the changes trie transaction.
 	pub(crate) changes_trie_transaction_storage_root: Option<Option<H::Out>>,
+	/// Child trie roots that were calculated outside of the state machine.
+	pub(crate) child_root_hints: BTreeMap<StorageKey, ChildRootHint<Transaction, H>>,
+	/// The last storage root that was calculated incrementally, whose nodes are kept in
+	/// `transaction`.
+	pub(crate) incremental_root: Option<IncrementalRoot<H>>,
+}
+
+/// A storage root that was calculated with [`Backend::storage_root_incremental`].
+pub(crate) struct IncrementalRoot<H: Hasher> {
+	/// The root of the backend the root was calculated on.
+	backend_root: H::Out,
+	/// The calculated root.
+	root: H::Out,
+}
+
+/// A child trie root that was calculated outside of the state machine, together with the
+/// transaction that contains its nodes.
+pub(crate) struct ChildRootHint<Transaction, H: Hasher> {
+	root: H::Out,
+	transaction: Transaction,
+	/// Hash of the child changes the root was calculated for.
+	changes_hash: H::Out,
 }
 
 impl<Transaction, H: Hasher, N: BlockNumber> StorageTransactionCache<Transaction, H, N> {
 	/// Reset the cached transactions.
+	///
+	/// Child root hints are kept, they are checked against the child changes when used. If
+	/// the storage root was calculated incrementally, its transaction is kept as the base of
+	/// the next calculation.
 	pub fn reset(&mut self) {
-		*self = Self::default();
+		let child_root_hints = std::mem::take(&mut self.child_root_hints);
+		let incremental_root = self.incremental_root.take();
+		let transaction = match incremental_root {
+			Some(_) => self.transaction.take(),
+			None => None,
+		};
+		*self = Self { child_root_hints, incremental_root, transaction, ..Self::default() };
 	}
 }
 
@@ -139,6 +317,8 @@ impl<Transaction, H: Hasher, N: BlockNumber> Default for StorageTransactionCache
 			transaction_storage_root: None,
 			changes_trie_transaction: None,
 			changes_trie_transaction_storage_root: None,
+			child_root_hints: BTreeMap::new(),
+			incremental_root: None,
 		}
 	}
 }
@@ -156,21 +336,296 @@ impl<Transaction: Default, H: Hasher, N: BlockNumber> Default for StorageChanges
 	}
 }
 
+/// Builder for [`OverlayedChanges`] that are pre-sized for the expected usage.
+///
+/// Sizing the overlay up front avoids growing its collections over and over again while
+/// executing large blocks. All capacities default to `0`, which equals
+/// [`OverlayedChanges::default`].
+#[derive(Debug, Default, Clone)]
+pub struct OverlayedChangesBuilder {
+	top_keys: usize,
+	child_tries: usize,
+	history_depth: usize,
+	key_hasher: KeyHasher,
+}
+
+impl OverlayedChangesBuilder {
+	/// The number of top keys that are expected to be changed.
+	pub fn top_keys(mut self, top_keys: usize) -> Self {
+		self.top_keys = top_keys;
+		self
+	}
+
+	/// The number of child tries that are expected to be changed.
+	pub fn child_tries(mut self, child_tries: usize) -> Self {
+		self.child_tries = child_tries;
+		self
+	}
+
+	/// The expected maximum number of nested transactions.
+	pub fn history_depth(mut self, history_depth: usize) -> Self {
+		self.history_depth = history_depth;
+		self
+	}
+
+	/// The hash function used for the hash maps of the overlay.
+	///
+	/// See [`KeyHasher`] for when it is safe to deviate from the default.
+	pub fn key_hasher(mut self, key_hasher: KeyHasher) -> Self {
+		self.key_hasher = key_hasher;
+		self
+	}
+
+	/// Build the overlay.
+	pub fn build(self) -> OverlayedChanges {
+		let key_hasher = KeyHashBuilder::new(self.key_hasher);
+		OverlayedChanges {
+			top: OverlayedChangeSet::with_capacity(self.top_keys, self.history_depth)
+				.with_key_hasher(key_hasher.clone()),
+			child_transactions: HashMap::with_capacity_and_hasher(self.child_tries, key_hasher),
+			.. Default::default()
+		}
+	}
+}
+
 impl OverlayedChanges {
+	/// Create an overlay that is pre-sized for the expected usage.
+	///
+	/// See [`OverlayedChangesBuilder`] for the meaning of the arguments.
+	pub fn with_capacity(top_keys: usize, child_tries: usize, history_depth: usize) -> Self {
+		Self::builder()
+			.top_keys(top_keys)
+			.child_tries(child_tries)
+			.history_depth(history_depth)
+			.build()
+	}
+
+	/// Returns a builder to create pre-sized overlays.
+	pub fn builder() -> OverlayedChangesBuilder {
+		OverlayedChangesBuilder::default()
+	}
+
 	/// Whether no changes are contained in the top nor in any of the child changes.
+	///
+	/// Child tries whose changes were all rolled back are not counted.
 	pub fn is_empty(&self) -> bool {
-		self.top.is_empty() && self.children.is_empty()
+		self.top.is_empty() && self.len_children() == 0
+	}
+
+	/// Returns the number of changed top keys as seen by the current transaction.
+	///
+	/// Removed keys are counted, changes that were rolled back are not.
+	pub fn len_top(&self) -> usize {
+		self.top.len()
+	}
+
+	/// Returns the number of child tries with changes as seen by the current transaction.
+	pub fn len_children(&self) -> usize {
+		self.children.values().filter(|(changes, _)| !changes.is_empty()).count()
+	}
+
+	/// Returns the number of changed keys of the given child trie as seen by the current
+	/// transaction.
+	///
+	/// The storage key is given without the common prefix.
+	pub fn child_len(&self, storage_key: &[u8]) -> usize {
+		self.children.get(storage_key).map_or(0, |(changes, _)| changes.len())
+	}
+
+	/// Whether no changes are contained in the given child trie.
+	///
+	/// The storage key is given without the common prefix.
+	pub fn is_child_empty(&self, storage_key: &[u8]) -> bool {
+		self.child_len(storage_key) == 0
 	}
 
 	/// Ask to collect/not to collect extrinsics indices where key(s) has been changed.
+	///
+	/// This is independent of changes tries, e.g. tracing tools can use the collected
+	/// indices on chains without changes tries.
 	pub fn set_collect_extrinsics(&mut self, collect_extrinsics: bool) {
 		self.collect_extrinsics = collect_extrinsics;
 	}
 
+	/// Skip building the changes trie for the current block.
+	///
+	/// The changes trie configuration is kept, so changes tries are built again for the
+	/// following blocks. This is not affected by transactions and is reset once the changes
+	/// are drained.
+	pub fn disable_changes_trie_for_block(&mut self) {
+		self.changes_trie_disabled = true;
+	}
+
+	/// Returns true if no changes trie is built for the current block.
+	pub fn is_changes_trie_disabled_for_block(&self) -> bool {
+		self.changes_trie_disabled
+	}
+
+	/// Note that the extrinsic with the given `index` starts executing.
+	///
+	/// Once called, changes are attributed to the noted extrinsic instead of the one stored
+	/// under `EXTRINSIC_INDEX`, and changes outside of [`note_extrinsic_start`] and
+	/// [`note_extrinsic_end`] are attributed to no extrinsic. This lasts until the changes
+	/// of the block are drained.
+	///
+	/// [`note_extrinsic_start`]: OverlayedChanges::note_extrinsic_start
+	/// [`note_extrinsic_end`]: OverlayedChanges::note_extrinsic_end
+	pub fn note_extrinsic_start(&mut self, index: u32) {
+		self.notes_extrinsics = true;
+		self.current_extrinsic = Some(index);
+	}
+
+	/// Note that the current extrinsic finished executing.
+	pub fn note_extrinsic_end(&mut self) {
+		self.notes_extrinsics = true;
+		self.current_extrinsic = None;
+	}
+
+	/// Returns the extrinsic that is currently executed, if noted explicitly.
+	pub fn current_extrinsic(&self) -> Option<u32> {
+		self.current_extrinsic
+	}
+
+	/// Maintain a bloom filter over all top keys written to the overlay.
+	///
+	/// With the filter, looking up a key that was never written is cheaper than a lookup
+	/// in the overlay. This is beneficial for read heavy workloads on large overlays.
+	pub fn enable_key_filter(&mut self) {
+		let mut filter = KeyFilter::default();
+		for (key, _) in self.top.changes() {
+			filter.insert(key);
+		}
+		self.key_filter = Some(filter);
+	}
+
+	/// Remember the backend value of every top key the first time it is read or written.
+	///
+	/// This allows to check whether a key was restored to its original value at the end of
+	/// the block, see [`original_value`](Self::original_value). The values are noted by the
+	/// [`Ext`](crate::Ext), so writing a key that was not read before costs an additional
+	/// backend read.
+	pub fn enable_original_values(&mut self) {
+		if self.original_values.is_none() {
+			self.original_values = Some(Default::default());
+		}
+	}
+
+	/// Calculate the storage root incrementally.
+	///
+	/// Only the top keys that changed since the last calculation are folded into the previous
+	/// root, instead of calculating the root over all changes every time. This pays off if the
+	/// root is calculated repeatedly, e.g. after every extrinsic. The root is calculated from
+	/// scratch if the backend does not support [`Backend::storage_root_incremental`]. This is
+	/// also the case as soon as any child trie is changed, so that every later root of the
+	/// block is calculated from scratch as well.
+	pub fn enable_incremental_storage_root(&mut self) {
+		if self.root_dirty_keys.is_none() {
+			self.root_dirty_keys = Some(Default::default());
+		}
+	}
+
+	/// Note that the top `key` was written since the storage root was last calculated.
+	fn note_root_dirty(&self, key: &[u8]) {
+		if let Some(keys) = &self.root_dirty_keys {
+			keys.borrow_mut().insert(key.to_vec());
+		}
+	}
+
+	/// True if the original values of the accessed keys are tracked.
+	pub(crate) fn tracks_original_values(&self) -> bool {
+		self.original_values.is_some()
+	}
+
+	/// Note the backend `value` of the given `key`, if it was not accessed before.
+	///
+	/// The value is only noted if the key was not changed yet, as it is no longer the value
+	/// of the backend otherwise.
+	pub(crate) fn note_original_value(
+		&self,
+		key: &[u8],
+		value: impl FnOnce() -> Option<StorageValue>,
+	) {
+		if let Some(original_values) = &self.original_values {
+			if original_values.borrow().contains_key(key) || self.top.get(key).is_some() {
+				return
+			}
+			original_values.borrow_mut().insert(key.to_vec(), value());
+		}
+	}
+
+	/// Returns the backend value of the given `key` at its first access during the block.
+	///
+	/// Returns `None` if the key was not accessed or tracking was not enabled with
+	/// [`enable_original_values`](Self::enable_original_values).
+	pub fn original_value(&self, key: &[u8]) -> Option<Option<StorageValue>> {
+		self.original_values.as_ref()?.borrow().get(key).cloned()
+	}
+
+	/// Returns true if the committed value of the given `key` equals its original value.
+	///
+	/// Returns `None` if the original value of the key is unknown.
+	pub fn is_original_value_restored(&self, key: &[u8]) -> Option<bool> {
+		let original = self.original_value(key)?;
+		Some(match self.top.committed_value(key) {
+			Some(value) => value == original.as_ref(),
+			None => true,
+		})
+	}
+
+	/// Record writes to the offchain database issued by the runtime.
+	///
+	/// The recorded changes are returned as part of [`StorageChanges`], so that they are
+	/// written together with the block that caused them.
+	pub fn enable_offchain_indexing(&mut self) {
+		if let OffchainOverlayedChanges::Disabled = self.offchain {
+			self.offchain = OffchainOverlayedChanges::enabled();
+		}
+	}
+
+	/// Set or remove a value in the offchain database.
+	///
+	/// This is a no-op if offchain indexing is not enabled.
+	pub fn set_offchain_storage(&mut self, key: &[u8], value: Option<&[u8]>) {
+		use sp_core::offchain::STORAGE_PREFIX;
+		match value {
+			Some(value) => self.offchain.set(STORAGE_PREFIX, key, value),
+			None => self.offchain.remove(STORAGE_PREFIX, key),
+		}
+	}
+
+	/// Returns the recorded changes to the offchain database.
+	pub fn offchain_storage_changes(&self) -> &OffchainOverlayedChanges {
+		&self.offchain
+	}
+
+	/// Returns the statistics of the reads and writes served by the overlay.
+	pub fn stats(&self) -> &StateMachineStats {
+		&self.stats
+	}
+
 	/// Returns a double-Option: None if the key is unknown (i.e. and the query should be referred
 	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
 	/// value has been set.
 	pub fn storage(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+		if let Some(filter) = &self.key_filter {
+			if !filter.may_contain(key) {
+				return None
+			}
+		}
+		self.top_storage(key)
+	}
+
+	/// Like [`storage`](Self::storage), with the hash of the key computed in advance.
+	pub fn storage_ref(&self, key: KeyRef) -> Option<Option<&[u8]>> {
+		if let Some(filter) = &self.key_filter {
+			if !filter.may_contain_ref(key) {
+				return None
+			}
+		}
+		self.top_storage(key.key())
+	}
+
+	fn top_storage(&self, key: &[u8]) -> Option<Option<&[u8]>> {
 		self.top.get(key).map(|x| {
 			let value = x.value();
 			let size_read = value.map(|x| x.len() as u64).unwrap_or(0);
@@ -190,6 +645,11 @@ impl OverlayedChanges {
 		key: &[u8],
 		init: impl Fn() -> StorageValue,
 	) -> &mut StorageValue {
+		if let Some(filter) = &mut self.key_filter {
+			filter.insert(key);
+		}
+		self.written_keys.top.insert(key.to_vec());
+		self.note_root_dirty(key);
 		let value = self.top.modify(key.to_owned(), init, self.extrinsic_index());
 
 		// if the value was deleted initialise it back with an empty vec
@@ -213,9 +673,60 @@ impl OverlayedChanges {
 	pub(crate) fn set_storage(&mut self, key: StorageKey, val: Option<StorageValue>) {
 		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
 		self.stats.tally_write_overlay(size_write);
+		if let Some(filter) = &mut self.key_filter {
+			filter.insert(&key);
+		}
+		self.written_keys.top.insert(key.clone());
+		self.note_root_dirty(&key);
 		self.top.set(key, val, self.extrinsic_index());
 	}
 
+	/// Set a new value for the specified key and account the write to the namespace `ns`.
+	///
+	/// The namespace is only kept for the statistics of the overlay, the key is stored
+	/// unchanged and the namespace never reaches the trie. See [`Self::set_storage`].
+	///
+	/// The runtime tags its writes through [`Externalities::place_storage_ns`].
+	///
+	/// [`Externalities::place_storage_ns`]: sp_externalities::Externalities::place_storage_ns
+	pub(crate) fn set_storage_ns(
+		&mut self,
+		ns: StorageNamespace,
+		key: StorageKey,
+		val: Option<StorageValue>,
+	) {
+		let stats = self.namespaces.entry(ns).or_default();
+		match &val {
+			Some(_) => stats.writes += 1,
+			None => stats.removals += 1,
+		}
+		stats.bytes_written += (key.len() + val.as_ref().map_or(0, |v| v.len())) as u64;
+		stats.dirty_keys.insert(key.clone());
+		self.set_storage(key, val);
+	}
+
+	/// Returns the statistics of the namespace `ns`, if anything was written to it.
+	pub fn namespace_stats(&self, ns: StorageNamespace) -> Option<&NamespaceStats> {
+		self.namespaces.get(&ns)
+	}
+
+	/// Returns the transient value of the given `key`.
+	///
+	/// Transient values are never read from the backend, `None` means that the key was not set
+	/// or was deleted.
+	pub fn transient_storage(&self, key: &[u8]) -> Option<&[u8]> {
+		self.transient.get(key)?.value().map(AsRef::as_ref)
+	}
+
+	/// Set the transient value of the given `key`, `None` deletes it.
+	///
+	/// Transient values are not written to the backend and are discarded when the changes are
+	/// drained. Like all other changes they can be rolled back or committed when set inside a
+	/// transaction.
+	pub fn set_transient_storage(&mut self, key: StorageKey, val: Option<StorageValue>) {
+		self.transient.set(key, val, None);
+	}
+
 	/// Set a new value for the specified key and child.
 	///
 	/// `None` can be used to delete a value specified by the given key.
@@ -240,9 +751,82 @@ impl OverlayedChanges {
 		);
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
+		self.written_keys.child(child_info.storage_key()).insert(key.clone());
 		changeset.set(key, val, extrinsic_index);
 	}
 
+	/// Insert a pre-computed change set, e.g. a recorded block delta, into the overlay.
+	///
+	/// If extrinsics are collected, the changes are attributed to `extrinsic_index` or,
+	/// if that is `None`, to the current extrinsic.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn apply_delta<C, I>(
+		&mut self,
+		top: impl IntoIterator<Item=(StorageKey, Option<StorageValue>)>,
+		children: C,
+		extrinsic_index: Option<u32>,
+	) where
+		C: IntoIterator<Item=(ChildInfo, I)>,
+		I: IntoIterator<Item=(StorageKey, Option<StorageValue>)>,
+	{
+		let extrinsic_index = if self.collect_extrinsics {
+			extrinsic_index.or_else(|| self.extrinsic_index())
+		} else {
+			None
+		};
+
+		for (key, val) in top {
+			self.stats.tally_write_overlay(val.as_ref().map(|x| x.len() as u64).unwrap_or(0));
+			if let Some(filter) = &mut self.key_filter {
+				filter.insert(&key);
+			}
+			self.written_keys.top.insert(key.clone());
+			self.note_root_dirty(&key);
+			self.top.set(key, val, extrinsic_index);
+		}
+
+		for (child_info, changes) in children {
+			let top = &self.top;
+			let (changeset, info) = self.children.entry(child_info.storage_key().to_vec())
+				.or_insert_with(|| (top.spawn_child(), child_info.clone()));
+			let updatable = info.try_update(&child_info);
+			debug_assert!(updatable);
+			let written_keys = self.written_keys.child(child_info.storage_key());
+			for (key, val) in changes {
+				self.stats.tally_write_overlay(val.as_ref().map(|x| x.len() as u64).unwrap_or(0));
+				written_keys.insert(key.clone());
+				changeset.set(key, val, extrinsic_index);
+			}
+		}
+	}
+
+	/// Insert top changes that are ordered by key, e.g. to build a genesis state.
+	///
+	/// Unlike [`apply_delta`](Self::apply_delta) the changes are not attributed to any
+	/// extrinsic, so they are inserted without looking up the current extrinsic.
+	///
+	/// Can be rolled back or committed when called inside a transaction.
+	pub fn extend_from_sorted(
+		&mut self,
+		changes: impl IntoIterator<Item=(StorageKey, Option<StorageValue>)>,
+	) {
+		let stats = &self.stats;
+		let key_filter = &mut self.key_filter;
+		let written_keys = &mut self.written_keys.top;
+		let root_dirty_keys = &self.root_dirty_keys;
+		self.top.extend_sorted(changes.into_iter().inspect(|(key, value)| {
+			stats.tally_write_overlay(value.as_ref().map(|x| x.len() as u64).unwrap_or(0));
+			if let Some(filter) = key_filter.as_mut() {
+				filter.insert(key);
+			}
+			written_keys.insert(key.clone());
+			if let Some(keys) = root_dirty_keys {
+				keys.borrow_mut().insert(key.clone());
+			}
+		}));
+	}
+
 	/// Clear child storage of given storage key.
 	///
 	/// Can be rolled back or committed when called inside a transaction.
@@ -262,6 +846,8 @@ impl OverlayedChanges {
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
 		changeset.clear_where(|_, _| true, extrinsic_index);
+		self.written_keys.child(child_info.storage_key())
+			.extend(changeset.changes().map(|(key, _)| key.clone()));
 	}
 
 	/// Removes all key-value pairs which keys share the given prefix.
@@ -269,6 +855,18 @@ impl OverlayedChanges {
 	/// Can be rolled back or committed when called inside a transaction.
 	pub(crate) fn clear_prefix(&mut self, prefix: &[u8]) {
 		self.top.clear_where(|key, _| key.starts_with(prefix), self.extrinsic_index());
+		self.written_keys.top.extend(
+			self.top.changes()
+				.filter(|(key, _)| key.starts_with(prefix))
+				.map(|(key, _)| key.clone())
+		);
+		if let Some(keys) = &self.root_dirty_keys {
+			keys.borrow_mut().extend(
+				self.top.changes()
+					.filter(|(key, _)| key.starts_with(prefix))
+					.map(|(key, _)| key.clone())
+			);
+		}
 	}
 
 	/// Removes all key-value pairs which keys share the given prefix.
@@ -291,6 +889,26 @@ impl OverlayedChanges {
 		let updatable = info.try_update(child_info);
 		debug_assert!(updatable);
 		changeset.clear_where(|key, _| key.starts_with(prefix), extrinsic_index);
+		self.written_keys.child(child_info.storage_key()).extend(
+			changeset.changes()
+				.filter(|(key, _)| key.starts_with(prefix))
+				.map(|(key, _)| key.clone())
+		);
+	}
+
+	/// Returns the keys written since the last call and clears them.
+	///
+	/// The keys are collected while writing, including keys of changes that were rolled back
+	/// later. This is meant for clients that need to invalidate a cache of the state.
+	pub fn take_dirty_keys(&mut self) -> DirtyKeys {
+		std::mem::take(&mut self.written_keys)
+	}
+
+	/// Returns the statistics of all namespaces written since the last call and clears them.
+	///
+	/// Like [`Self::take_dirty_keys`], this includes writes that were rolled back later.
+	pub fn take_namespace_stats(&mut self) -> BTreeMap<StorageNamespace, NamespaceStats> {
+		std::mem::take(&mut self.namespaces)
 	}
 
 	/// Returns the current nesting depth of the transaction stack.
@@ -302,6 +920,94 @@ impl OverlayedChanges {
 		self.top.transaction_depth()
 	}
 
+	/// Returns all versions of the top value of `key`, oldest first, each with the
+	/// transaction depth at which it was written.
+	///
+	/// Depth zero is the version committed without any open transaction. Returns an empty
+	/// list if the key was not changed. This is meant for debugging.
+	pub fn value_history(&self, key: &[u8]) -> Vec<(usize, Option<&StorageValue>)> {
+		self.top.value_history(key)
+	}
+
+	/// Check the internal consistency of the top, child and transient changes and panic if
+	/// it is violated.
+	///
+	/// This is expensive and meant for debug builds and fuzzers.
+	pub fn assert_invariants(&self) {
+		self.top.assert_invariants();
+		self.transient.assert_invariants();
+		for (changes, _) in self.children.values() {
+			changes.assert_invariants();
+		}
+	}
+
+	/// Rebase the changes of `other` on top of the changes of this overlay.
+	///
+	/// This fails if any of both overlays has open transactions or if a key was changed by
+	/// both of them. Reads are not tracked, so `other` must not depend on values changed
+	/// by this overlay.
+	pub fn merge(mut self, other: OverlayedChanges) -> Result<OverlayedChanges, MergeConflict> {
+		if self.transaction_depth() > 0 || other.transaction_depth() > 0 {
+			return Err(MergeConflict::OpenTransaction)
+		}
+
+		if let Some(key) = self.top.first_conflict(&other.top) {
+			return Err(MergeConflict::Key(key.clone()))
+		}
+		for (storage_key, (other_changes, _)) in &other.children {
+			if let Some((changes, _)) = self.children.get(storage_key) {
+				if let Some(key) = changes.first_conflict(other_changes) {
+					return Err(MergeConflict::ChildKey(storage_key.clone(), key.clone()))
+				}
+			}
+		}
+
+		if let Some(filter) = &mut self.key_filter {
+			for (key, _) in other.top.changes() {
+				filter.insert(key);
+			}
+		}
+		for (key, _) in other.top.changes() {
+			self.note_root_dirty(key);
+		}
+		self.top.merge(other.top);
+		self.written_keys.extend(other.written_keys);
+		for (ns, stats) in other.namespaces {
+			self.namespaces.entry(ns).or_default().extend(stats);
+		}
+		self.transient.merge(other.transient);
+		for (storage_key, (other_changes, other_info)) in other.children {
+			match self.children.entry(storage_key) {
+				Entry::Occupied(mut entry) => {
+					let (changes, info) = entry.get_mut();
+					let updatable = info.try_update(&other_info);
+					debug_assert!(updatable);
+					changes.merge(other_changes);
+				},
+				Entry::Vacant(entry) => {
+					entry.insert((other_changes, other_info));
+				},
+			}
+		}
+
+		match self.offchain {
+			OffchainOverlayedChanges::Disabled => self.offchain = other.offchain,
+			OffchainOverlayedChanges::Enabled(_) => {
+				for ((prefix, key), change) in other.offchain.into_iter() {
+					match change {
+						OffchainOverlayedChange::SetValue(value) =>
+							self.offchain.set(&prefix, &key, &value),
+						OffchainOverlayedChange::Remove => self.offchain.remove(&prefix, &key),
+					}
+				}
+			},
+		}
+		self.changes_trie_disabled |= other.changes_trie_disabled;
+		self.stats.add(&other.stats);
+
+		Ok(self)
+	}
+
 	/// Start a new nested transaction.
 	///
 	/// This allows to either commit or roll back all changes that where made while this
@@ -310,10 +1016,61 @@ impl OverlayedChanges {
 	///
 	/// Changes made without any open transaction are committed immediatly.
 	pub fn start_transaction(&mut self) {
+		self.open_transaction(None)
+	}
+
+	/// Start a new nested transaction with a label.
+	///
+	/// The label names the code path that opened the transaction and is reported in trace
+	/// output and when the transaction is left open by mistake.
+	pub fn start_transaction_labeled(&mut self, label: &'static str) {
+		self.open_transaction(Some(label))
+	}
+
+	/// Returns the labels of the open transactions, outermost first.
+	pub fn transaction_labels(&self) -> &[Option<&'static str>] {
+		&self.transaction_labels
+	}
+
+	fn open_transaction(&mut self, label: Option<&'static str>) {
+		if self.top.transaction_depth() == 0 {
+			self.flush_journal();
+		}
 		self.top.start_transaction();
+		self.transient.start_transaction();
 		for (_, (changeset, _)) in self.children.iter_mut() {
 			changeset.start_transaction();
 		}
+		self.transaction_labels.push(label);
+		log::trace!(
+			target: "state",
+			"Started transaction {} at depth {}",
+			label.unwrap_or(UNLABELED),
+			self.transaction_depth(),
+		);
+	}
+
+	/// Remove the labels of the transactions that are no longer open.
+	fn close_transaction_labels(&mut self, rollback: bool) {
+		let depth = self.top.transaction_depth();
+		if depth < self.transaction_labels.len() {
+			for label in self.transaction_labels.drain(depth..).rev() {
+				log::trace!(
+					target: "state",
+					"{} transaction {}",
+					if rollback { "Rolled back" } else { "Committed" },
+					label.unwrap_or(UNLABELED),
+				);
+			}
+		}
+	}
+
+	/// Describe the open transactions for diagnostics, outermost first.
+	fn describe_open_transactions(&self) -> String {
+		let labels: Vec<_> = self.transaction_labels.iter()
+			.map(|label| label.unwrap_or(UNLABELED))
+			.collect();
+		labels.join(", ")
 	}
 
 	/// Rollback the last transaction started by `start_transaction`.
@@ -321,12 +1078,29 @@ impl OverlayedChanges {
 	/// Any changes made during that transaction are discarded. Returns an error if
 	/// there is no open transaction that can be rolled back.
 	pub fn rollback_transaction(&mut self) -> Result<(), NoOpenTransaction> {
+		let depth = self.top.transaction_depth();
+		// The discarded values may already be folded into the incremental storage root.
+		if depth > 0 && self.root_dirty_keys.is_some() {
+			for (key, _) in self.top.changes_since(depth - 1) {
+				self.note_root_dirty(key);
+			}
+		}
 		self.top.rollback_transaction()?;
-		self.children.retain(|_, (changeset, _)| {
+		self.transient.rollback_transaction()
+			.expect("Top and transient changesets are started in lockstep; qed");
+		self.close_child_transactions(depth, true);
+		let mut emptied = Vec::new();
+		for (storage_key, (changeset, _)) in self.children.iter_mut() {
 			changeset.rollback_transaction()
 				.expect("Top and children changesets are started in lockstep; qed");
-			!changeset.is_empty()
-		});
+			if changeset.is_empty() && !self.child_transactions.contains_key(storage_key) {
+				emptied.push(storage_key.clone());
+			}
+		}
+		for storage_key in emptied {
+			self.children.remove(&storage_key);
+		}
+		self.close_transaction_labels(true);
 		Ok(())
 	}
 
@@ -335,20 +1109,133 @@ impl OverlayedChanges {
 	/// Any changes made during that transaction are committed. Returns an error if there
 	/// is no open transaction that can be committed.
 	pub fn commit_transaction(&mut self) -> Result<(), NoOpenTransaction> {
+		let depth = self.top.transaction_depth();
+		let journal_entry = match &self.journal {
+			Some(_) if depth == 1 => Some(self.journal_entry()),
+			_ => None,
+		};
 		self.top.commit_transaction()?;
+		self.transient.commit_transaction()
+			.expect("Top and transient changesets are started in lockstep; qed");
+		self.close_child_transactions(depth, false);
 		for (_, (changeset, _)) in self.children.iter_mut() {
 			changeset.commit_transaction()
 				.expect("Top and children changesets are started in lockstep; qed");
 		}
+		self.close_transaction_labels(false);
+		if let Some(entry) = journal_entry {
+			self.append_to_journal(entry.into());
+		}
 		Ok(())
 	}
 
+	/// Append the changes committed to this overlay from now on to the given journal.
+	///
+	/// Every committed outermost transaction is journaled as one entry. Changes made without
+	/// any open transaction are journaled as one entry before the next transaction is started
+	/// and when the changes are drained. Draining the changes is journaled as a
+	/// [`JournalRecord::Boundary`]. The overlay can be rebuilt from the journal with
+	/// [`Self::from_journal`].
+	///
+	/// If the journal fails, nothing is journaled anymore and
+	/// [`drain_storage_changes`](Self::drain_storage_changes) returns the error. A failure to
+	/// journal the boundary of a drain is returned by the next drain.
+	pub fn enable_journal(&mut self, sink: Arc<dyn JournalSink>) {
+		self.journal = Some(journal::Journal(sink));
+		self.top.track_unjournaled();
+		for (changeset, _) in self.children.values_mut() {
+			changeset.track_unjournaled();
+		}
+	}
+
+	/// Returns the error of the journal, if it failed.
+	pub fn journal_failure(&self) -> Option<&str> {
+		self.journal_failure.as_deref()
+	}
+
+	fn append_to_journal(&mut self, record: JournalRecord) {
+		if self.journal_failure.is_some() {
+			return
+		}
+		if let Some(journal) = &self.journal {
+			if let Err(e) = journal.0.append(&record) {
+				log::warn!(target: "state", "Failed to append to the overlay journal: {}", e);
+				self.journal_failure = Some(e);
+			}
+		}
+	}
+
+	/// Journal the changes made without any open transaction since the last call.
+	fn flush_journal(&mut self) {
+		if self.journal.is_none() {
+			return
+		}
+		let entry = JournalEntry {
+			top: self.top.take_unjournaled(),
+			children: self.children.iter_mut()
+				.map(|(storage_key, (changeset, _))| {
+					(storage_key.clone(), changeset.take_unjournaled())
+				})
+				.filter(|(_, changes)| !changes.is_empty())
+				.collect(),
+		};
+		if !entry.top.is_empty() || !entry.children.is_empty() {
+			self.append_to_journal(entry.into());
+		}
+	}
+
+	/// Rebuild an overlay from the records of a journal, see [`Self::enable_journal`].
+	///
+	/// The changes of all entries after the last [`JournalRecord::Boundary`] are committed to
+	/// the returned overlay.
+	pub fn from_journal(records: impl IntoIterator<Item=impl Into<JournalRecord>>) -> Self {
+		let mut overlay = Self::default();
+		for record in records {
+			let entry = match record.into() {
+				JournalRecord::Changes(entry) => entry,
+				JournalRecord::Boundary => {
+					overlay = Self::default();
+					continue
+				},
+			};
+			for (key, value) in entry.top {
+				overlay.set_storage(key, value);
+			}
+			for (storage_key, changes) in entry.children {
+				let child_info = ChildInfo::new_default(&storage_key);
+				for (key, value) in changes {
+					overlay.set_child_storage(&child_info, key, value);
+				}
+			}
+		}
+		overlay
+	}
+
+	/// The changes of all open transactions, as they are committed.
+	fn journal_entry(&self) -> JournalEntry {
+		fn to_owned(changes: impl Iterator<Item=(&[u8], Option<&[u8]>)>) -> StorageCollection {
+			changes.map(|(key, value)| (key.to_vec(), value.map(<[u8]>::to_vec))).collect()
+		}
+
+		JournalEntry {
+			top: to_owned(self.top.changes_since(0)),
+			children: self.children.iter()
+				.map(|(storage_key, (changeset, _))| {
+					(storage_key.clone(), to_owned(changeset.changes_since(0)))
+				})
+				.filter(|(_, changes)| !changes.is_empty())
+				.collect(),
+		}
+	}
+
 	/// Call this before transfering control to the runtime.
 	///
 	/// This protects all existing transactions from being removed by the runtime.
 	/// Calling this while already inside the runtime will return an error.
 	pub fn enter_runtime(&mut self) -> Result<(), AlreadyInRuntime> {
 		self.top.enter_runtime()?;
+		self.transient.enter_runtime()
+			.expect("Top and transient changesets are entering runtime in lockstep; qed");
 		for (_, (changeset, _)) in self.children.iter_mut() {
 			changeset.enter_runtime()
 				.expect("Top and children changesets are entering runtime in lockstep; qed")
@@ -362,27 +1249,162 @@ impl OverlayedChanges {
 	/// Calling this while outside the runtime will return an error.
 	pub fn exit_runtime(&mut self) -> Result<(), NotInRuntime> {
 		self.top.exit_runtime()?;
+		self.transient.exit_runtime()
+			.expect("Top and transient changesets are entering runtime in lockstep; qed");
 		for (_, (changeset, _)) in self.children.iter_mut() {
 			changeset.exit_runtime()
 				.expect("Top and children changesets are entering runtime in lockstep; qed");
 		}
+		self.close_transaction_labels(true);
+
+		// Child transactions left open by the runtime were rolled back above.
+		let depth = self.top.transaction_depth();
+		let children = &self.children;
+		self.child_transactions.retain(|storage_key, open| {
+			let child_depth = children.get(storage_key)
+				.map_or(depth, |(changeset, _)| changeset.transaction_depth());
+			open.truncate(child_depth.saturating_sub(depth));
+			!open.is_empty()
+		});
 		Ok(())
 	}
 
-	/// Consume all changes (top + children) and return them.
+	/// Start a new nested transaction that only covers the given child trie.
 	///
-	/// After calling this function no more changes are contained in this changeset.
+	/// In contrast to `start_transaction` the top storage and all other child tries are left
+	/// untouched. This allows to roll back the changes of a single child trie (e.g. of a
+	/// contract) without affecting anything else.
 	///
-	/// Panics:
-	/// Panics if `transaction_depth() > 0`
-	fn drain_committed(&mut self) -> (
-		impl Iterator<Item=(StorageKey, Option<StorageValue>)>,
-		impl Iterator<Item=(StorageKey, (impl Iterator<Item=(StorageKey, Option<StorageValue>)>, ChildInfo))>,
-	) {
-		use std::mem::take;
-		(
-			take(&mut self.top).drain_commited(),
-			take(&mut self.children).into_iter()
+	/// A child transaction must be closed before the transaction it was started in. Otherwise
+	/// it is closed implicitly together with the enclosing transaction. Returns an error if no
+	/// transaction is open, because nothing would close the child transaction then.
+	pub fn start_child_transaction(
+		&mut self,
+		child_info: &ChildInfo,
+	) -> Result<(), NoOpenTransaction> {
+		if self.top.transaction_depth() == 0 {
+			return Err(NoOpenTransaction)
+		}
+
+		let storage_key = child_info.storage_key().to_vec();
+		let top = &self.top;
+		let (changeset, info) = self.children.entry(storage_key.clone()).or_insert_with(||
+			(
+				top.spawn_child(),
+				child_info.to_owned()
+			)
+		);
+		let updatable = info.try_update(child_info);
+		debug_assert!(updatable);
+		changeset.start_transaction();
+		self.child_transactions.entry(storage_key).or_default().push(top.transaction_depth());
+		Ok(())
+	}
+
+	/// Rollback the last transaction started by `start_child_transaction` for the given child.
+	///
+	/// Returns an error if there is no child transaction that was started in the current
+	/// transaction.
+	pub fn rollback_child_transaction(
+		&mut self,
+		child_info: &ChildInfo,
+	) -> Result<(), NoOpenTransaction> {
+		self.close_child_transaction(child_info.storage_key(), true)
+	}
+
+	/// Commit the last transaction started by `start_child_transaction` for the given child.
+	///
+	/// Returns an error if there is no child transaction that was started in the current
+	/// transaction.
+	pub fn commit_child_transaction(
+		&mut self,
+		child_info: &ChildInfo,
+	) -> Result<(), NoOpenTransaction> {
+		self.close_child_transaction(child_info.storage_key(), false)
+	}
+
+	fn close_child_transaction(
+		&mut self,
+		storage_key: &[u8],
+		rollback: bool,
+	) -> Result<(), NoOpenTransaction> {
+		let depth = self.top.transaction_depth();
+		match self.child_transactions.get_mut(storage_key) {
+			Some(open) if open.last() == Some(&depth) => {
+				open.pop();
+				if open.is_empty() {
+					self.child_transactions.remove(storage_key);
+				}
+			},
+			_ => return Err(NoOpenTransaction),
+		}
+
+		let (changeset, _) = self.children.get_mut(storage_key)
+			.expect("Child changeset is created when the child transaction is started; qed");
+		if rollback {
+			changeset.rollback_transaction()
+		} else {
+			changeset.commit_transaction()
+		}.expect("Child transaction was started on this changeset; qed");
+		Ok(())
+	}
+
+	/// Close all child transactions that were started at the given top transaction `depth`.
+	fn close_child_transactions(&mut self, depth: usize, rollback: bool) {
+		let children = &mut self.children;
+		self.child_transactions.retain(|storage_key, open| {
+			let (changeset, _) = children.get_mut(storage_key)
+				.expect("Child changeset is created when the child transaction is started; qed");
+			while open.last() == Some(&depth) {
+				open.pop();
+				if rollback {
+					changeset.rollback_transaction()
+				} else {
+					changeset.commit_transaction()
+				}.expect("Child transaction was started on this changeset; qed");
+			}
+			!open.is_empty()
+		});
+	}
+
+	/// Consume all changes (top + children) and return them.
+	///
+	/// After calling this function no more changes are contained in this changeset.
+	///
+	/// Panics:
+	/// Panics if `transaction_depth() > 0`
+	fn drain_committed(&mut self) -> (
+		impl Iterator<Item=(StorageKey, Option<StorageValue>)>,
+		impl Iterator<Item=(StorageKey, (impl Iterator<Item=(StorageKey, Option<StorageValue>)>, ChildInfo))>,
+	) {
+		use std::mem::{take, replace};
+		if self.transaction_depth() > 0 {
+			panic!(
+				"Drain is not allowed with open transactions, opened by: {}",
+				self.describe_open_transactions(),
+			);
+		}
+		if let Some(filter) = &mut self.key_filter {
+			filter.clear();
+		}
+		self.flush_journal();
+		if self.journal.is_some() {
+			self.append_to_journal(JournalRecord::Boundary);
+		}
+		let mut top = self.top.empty_with_same_capacity();
+		self.transient = top.spawn_child();
+		if self.journal.is_some() {
+			top.track_unjournaled();
+		}
+		if let Some(original_values) = &self.original_values {
+			original_values.borrow_mut().clear();
+		}
+		if let Some(keys) = &self.root_dirty_keys {
+			keys.borrow_mut().clear();
+		}
+		(
+			replace(&mut self.top, top).drain_commited(),
+			take(&mut self.children).into_iter()
 				.map(|(key, (val, info))| (
 						key,
 						(val.drain_commited(), info)
@@ -391,6 +1413,27 @@ impl OverlayedChanges {
 		)
 	}
 
+	/// Consume the overlay and return its changes.
+	///
+	/// Unlike [`into_storage_changes`](Self::into_storage_changes) this does not panic if
+	/// transactions are still open, but returns the overlay unchanged together with a
+	/// description of the open transactions, so that the caller can close them or give up.
+	pub fn try_into_committed(mut self) -> Result<CommittedChanges, (Self, PendingLayers)> {
+		if self.transaction_depth() > 0 {
+			let pending = PendingLayers {
+				labels: self.transaction_labels.clone(),
+				changes: self.journal_entry(),
+			};
+			return Err((self, pending))
+		}
+
+		let (top, children) = self.drain_committed();
+		Ok(CommittedChanges {
+			top: top.collect(),
+			children: children.map(|(storage_key, (changes, _))| (storage_key, changes.collect())).collect(),
+		})
+	}
+
 	/// Get an iterator over all child changes as seen by the current transaction.
 	pub fn children(&self)
 		-> impl Iterator<Item=(impl Iterator<Item=(&StorageKey, &OverlayedValue)>, &ChildInfo)> {
@@ -408,6 +1451,160 @@ impl OverlayedChanges {
 		self.children.get(key).map(|(overlay, info)| (overlay.changes(), info))
 	}
 
+	/// Get the extrinsics that changed each child key as seen by the current transaction.
+	///
+	/// Yields `(storage_key, key, extrinsics)` ordered by the storage key of the child trie,
+	/// which is given without the common prefix, and the key. Keys that are not attributed
+	/// to any extrinsic are skipped, so this is empty if extrinsics are not collected.
+	pub fn child_changes_extrinsics(&self)
+		-> impl Iterator<Item=(&StorageKey, &StorageKey, BTreeSet<u32>)> {
+		self.children.iter().flat_map(|(storage_key, (changes, _))| {
+			changes.changes()
+				.map(move |(key, value)| (storage_key, key, value.extrinsics_set()))
+				.filter(|(_, _, extrinsics)| !extrinsics.is_empty())
+		})
+	}
+
+	/// Get the top keys that are deleted as seen by the current transaction.
+	pub fn deleted_keys_iter(&self) -> impl Iterator<Item=&StorageKey> {
+		self.top.deleted_keys_iter()
+	}
+
+	/// Get the top keys that are deleted by changes made without any open transaction.
+	pub fn committed_deleted_keys_iter(&self) -> impl Iterator<Item=&StorageKey> {
+		self.top.committed_deleted_keys_iter()
+	}
+
+	/// Get the child keys that are deleted as seen by the current transaction, together with
+	/// the child trie they belong to.
+	pub fn child_deleted_keys_iter(&self) -> impl Iterator<Item=(&ChildInfo, &StorageKey)> {
+		self.children.values().flat_map(|(changeset, info)| {
+			changeset.deleted_keys_iter().map(move |key| (info, key))
+		})
+	}
+
+	/// Get the child keys that are deleted by changes made without any open transaction,
+	/// together with the child trie they belong to.
+	pub fn committed_child_deleted_keys_iter(
+		&self,
+	) -> impl Iterator<Item=(&ChildInfo, &StorageKey)> {
+		self.children.values().flat_map(|(changeset, info)| {
+			changeset.committed_deleted_keys_iter().map(move |key| (info, key))
+		})
+	}
+
+	/// Get the top changes made since the transaction depth was `layer`, ordered by key.
+	///
+	/// Only changes of transactions that are still open are reported, so `layer` has to be a
+	/// depth as returned by [`Self::transaction_depth`] before the transaction was started.
+	pub fn changes_since(&self, layer: usize) -> impl Iterator<Item=(&[u8], Option<&[u8]>)> {
+		self.top.changes_since(layer)
+	}
+
+	/// Same as [`Self::changes_since`], but for the child trie with the given `storage_key`.
+	pub fn child_changes_since(
+		&self,
+		storage_key: &[u8],
+		layer: usize,
+	) -> impl Iterator<Item=(&[u8], Option<&[u8]>)> {
+		self.children.get(storage_key)
+			.into_iter()
+			.flat_map(move |(changeset, _)| changeset.changes_since(layer))
+	}
+
+	/// Returns the changes of all open transactions as a JSON array.
+	///
+	/// Every entry holds the `child` storage key, which is `null` for the main trie, the `key`
+	/// and the `newValue`. The `oldValue` is the committed value of the overlay, it is missing
+	/// if the key was not changed before, i.e. the old value is the one of the backend.
+	/// Deleted values are `null`, keys and values are hex encoded with a `0x` prefix.
+	pub fn pending_diff(&self) -> serde_json::Value {
+		fn hex(data: &[u8]) -> String {
+			format!("0x{}", HexDisplay::from(&data))
+		}
+
+		fn entry(
+			child: Option<&[u8]>,
+			changeset: &OverlayedChangeSet,
+			key: &[u8],
+			value: Option<&[u8]>,
+		) -> serde_json::Value {
+			let mut entry = serde_json::json!({
+				"child": child.map(hex),
+				"key": hex(key),
+				"newValue": value.map(hex),
+			});
+			if let Some(old_value) = changeset.committed_value(key) {
+				entry["oldValue"] = old_value.map(|v| hex(v)).into();
+			}
+			entry
+		}
+
+		let top = self.top.changes_since(0)
+			.map(|(key, value)| entry(None, &self.top, key, value));
+		let children = self.children.iter()
+			.flat_map(|(storage_key, (changeset, _))| changeset.changes_since(0)
+				.map(move |(key, value)| entry(Some(&storage_key[..]), changeset, key, value))
+			);
+		serde_json::Value::Array(top.chain(children).collect())
+	}
+
+	/// Returns a digest of all changes as seen by the current transaction.
+	///
+	/// The digest covers the SCALE encoded, sorted key/value pairs of the main trie followed by
+	/// the ones of every changed child trie, deleted values included. Two overlays with the same
+	/// effective changes have the same fingerprint, independent of how they were reached.
+	pub fn fingerprint<H: Hasher>(&self) -> H::Out {
+		fn pairs<'a>(
+			changes: impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue)>,
+		) -> Vec<(&'a [u8], Option<&'a [u8]>)> {
+			changes.map(|(k, v)| (&k[..], v.value().map(|v| &v[..]))).collect()
+		}
+
+		let top = pairs(self.top.changes());
+		let children: Vec<_> = self.children.iter()
+			.filter(|(_, (changeset, _))| !changeset.is_empty())
+			.map(|(storage_key, (changeset, _))| (&storage_key[..], pairs(changeset.changes())))
+			.collect();
+		H::hash(&(top, children).encode())
+	}
+
+	/// Use the given precalculated `root` for the child trie with the given `storage_key`.
+	///
+	/// `transaction` has to contain the nodes of the child trie that are changed by the
+	/// current child changes. The hint is only used as long as the changes of the child trie
+	/// are the ones it was set for, otherwise the root is calculated as usual. A hint is used
+	/// for at most one storage root calculation, as its transaction is moved into the
+	/// resulting transaction.
+	pub fn set_child_storage_root_hint<H: Hasher, N: BlockNumber, Transaction>(
+		&self,
+		storage_key: &[u8],
+		root: H::Out,
+		transaction: Transaction,
+		cache: &mut StorageTransactionCache<Transaction, H, N>,
+	) -> Result<(), NoChildChanges> {
+		let (changes, _) = self.children.get(storage_key).ok_or(NoChildChanges)?;
+		cache.child_root_hints.insert(storage_key.to_vec(), ChildRootHint {
+			root,
+			transaction,
+			changes_hash: child_changes_hash::<H>(changes),
+		});
+		Ok(())
+	}
+
+	/// Returns the hinted root of the child trie with the given `storage_key`, if the hint
+	/// still matches the changes of the child trie.
+	pub(crate) fn child_storage_root_hint<H: Hasher, N: BlockNumber, Transaction>(
+		&self,
+		storage_key: &[u8],
+		cache: &StorageTransactionCache<Transaction, H, N>,
+	) -> Option<H::Out> {
+		let (changes, _) = self.children.get(storage_key)?;
+		cache.child_root_hints.get(storage_key)
+			.filter(|hint| hint.changes_hash == child_changes_hash::<H>(changes))
+			.map(|hint| hint.root)
+	}
+
 	/// Convert this instance with all changes into a [`StorageChanges`] instance.
 	pub fn into_storage_changes<
 		B: Backend<H>, H: Hasher, N: BlockNumber
@@ -429,14 +1626,20 @@ impl OverlayedChanges {
 		parent_hash: H::Out,
 		mut cache: &mut StorageTransactionCache<B::Transaction, H, N>,
 	) -> Result<StorageChanges<B::Transaction, H, N>, String> where H::Out: Ord + Encode + 'static {
+		self.flush_journal();
+		if let Some(e) = &self.journal_failure {
+			return Err(format!("Failed to append to the overlay journal: {}", e))
+		}
+
 		// If the transaction does not exist, we generate it.
-		if cache.transaction.is_none() {
+		if cache.transaction.is_none() || cache.transaction_storage_root.is_none() {
 			self.storage_root(backend, &mut cache);
 		}
 
 		let (transaction, transaction_storage_root) = cache.transaction.take()
 			.and_then(|t| cache.transaction_storage_root.take().map(|tr| (t, tr)))
 			.expect("Transaction was be generated as part of `storage_root`; qed");
+		cache.incremental_root = None;
 
 		// If the transaction does not exist, we generate it.
 		if cache.changes_trie_transaction.is_none() {
@@ -453,8 +1656,17 @@ impl OverlayedChanges {
 			.take()
 			.expect("Changes trie transaction was generated by `changes_trie_root`; qed");
 
-		let offchain_storage_changes = Default::default();
+		let offchain_storage_changes = match self.offchain {
+			OffchainOverlayedChanges::Disabled => OffchainOverlayedChanges::Disabled,
+			OffchainOverlayedChanges::Enabled(_) => std::mem::replace(
+				&mut self.offchain,
+				OffchainOverlayedChanges::enabled(),
+			),
+		};
 		let (main_storage_changes, child_storage_changes) = self.drain_committed();
+		self.changes_trie_disabled = false;
+		self.notes_extrinsics = false;
+		self.current_extrinsic = None;
 
 		Ok(StorageChanges {
 			main_storage_changes: main_storage_changes.collect(),
@@ -473,13 +1685,17 @@ impl OverlayedChanges {
 	}
 
 	/// Returns current extrinsic index to use in changes trie construction.
-	/// None is returned if it is not set or changes trie config is not set.
+	/// None is returned if it is not set or collecting extrinsics is disabled.
 	/// Persistent value (from the backend) can be ignored because runtime must
 	/// set this index before first and unset after last extrinsic is executed.
 	/// Changes that are made outside of extrinsics, are marked with
 	/// `NO_EXTRINSIC_INDEX` index.
+	///
+	/// If the extrinsic boundaries are noted explicitly, `EXTRINSIC_INDEX` is ignored.
 	fn extrinsic_index(&self) -> Option<u32> {
 		match self.collect_extrinsics {
+			true if self.notes_extrinsics =>
+				Some(self.current_extrinsic.unwrap_or(NO_EXTRINSIC_INDEX)),
 			true => Some(
 				self.storage(EXTRINSIC_INDEX)
 					.and_then(|idx| idx.and_then(|idx| Decode::decode(&mut &*idx).ok()))
@@ -499,13 +1715,22 @@ impl OverlayedChanges {
 	) -> H::Out
 		where H::Out: Ord + Encode,
 	{
+		if let Some(root) = self.incremental_storage_root(backend, cache) {
+			return root
+		}
+		cache.incremental_root = None;
+
 		let delta = self.changes().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])));
-		let child_delta = self.children()
-			.map(|(changes, info)| (info, changes.map(
-				|(k, v)| (&k[..], v.value().map(|v| &v[..]))
-			)));
 
-		let (root, transaction) = backend.full_storage_root(delta, child_delta);
+		let (root, transaction) = if cache.child_root_hints.is_empty() {
+			let child_delta = self.children()
+				.map(|(changes, info)| (info, changes.map(
+					|(k, v)| (&k[..], v.value().map(|v| &v[..]))
+				)));
+			backend.full_storage_root(delta, child_delta)
+		} else {
+			self.full_storage_root_with_hints(backend, delta, &mut cache.child_root_hints)
+		};
 
 		cache.transaction = Some(transaction);
 		cache.transaction_storage_root = Some(root);
@@ -513,9 +1738,110 @@ impl OverlayedChanges {
 		root
 	}
 
+	/// Calculate the storage root by folding the top keys that changed since the last
+	/// calculation into the previous root.
+	///
+	/// Returns `None` if incremental roots are not enabled or can not be used for the
+	/// current changes, see [`Self::enable_incremental_storage_root`].
+	fn incremental_storage_root<H: Hasher, N: BlockNumber, B: Backend<H>>(
+		&self,
+		backend: &B,
+		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
+	) -> Option<H::Out>
+		where H::Out: Ord + Encode,
+	{
+		let dirty_keys = self.root_dirty_keys.as_ref()?;
+		if !backend.supports_incremental_storage_root() {
+			return None
+		}
+		// The roots of the child tries are part of the top trie, so they would need to be
+		// folded in as well.
+		if self.len_children() > 0 || !cache.child_root_hints.is_empty() {
+			return None
+		}
+
+		let backend_root = backend.storage_root(std::iter::empty()).0;
+		let base = cache.incremental_root.take();
+		let (root, mut transaction, keys) = match (base, cache.transaction.take()) {
+			(Some(base), Some(transaction)) if base.backend_root == backend_root =>
+				(base.root, transaction, dirty_keys.borrow().clone()),
+			_ => (
+				backend_root,
+				Default::default(),
+				self.top.changes().map(|(key, _)| key.clone()).collect(),
+			),
+		};
+
+		let mut delta = Vec::with_capacity(keys.len());
+		for key in keys {
+			let value = match self.top.get(&key) {
+				Some(value) => value.value().cloned(),
+				// The change was rolled back, so the value of the backend is restored.
+				None => backend.storage(&key).ok()?,
+			};
+			delta.push((key, value));
+		}
+		let root = backend.storage_root_incremental(
+			root,
+			&mut transaction,
+			delta.iter().map(|(key, value)| (&key[..], value.as_deref())),
+		)?;
+		// The keys are only forgotten once the backend has folded them into the root.
+		dirty_keys.borrow_mut().clear();
+
+		cache.transaction = Some(transaction);
+		cache.transaction_storage_root = Some(root);
+		cache.incremental_root = Some(IncrementalRoot { backend_root, root });
+		Some(root)
+	}
+
+	/// Same as [`Backend::full_storage_root`], but uses the valid child root `hints`
+	/// instead of calculating the roots of the respective child tries.
+	///
+	/// All hints are consumed.
+	fn full_storage_root_with_hints<'a, H: Hasher, B: Backend<H>>(
+		&'a self,
+		backend: &B,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		hints: &mut BTreeMap<StorageKey, ChildRootHint<B::Transaction, H>>,
+	) -> (H::Out, B::Transaction)
+		where H::Out: Ord + Encode,
+	{
+		let mut transaction = B::Transaction::default();
+		let mut child_roots = Vec::new();
+		for (storage_key, (changes, info)) in self.children.iter() {
+			let hint = hints.remove(storage_key)
+				.filter(|hint| hint.changes_hash == child_changes_hash::<H>(changes));
+			let (root, is_empty) = match hint {
+				Some(hint) => {
+					transaction.consolidate(hint.transaction);
+					(hint.root, hint.root == empty_child_trie_root::<Layout<H>>())
+				},
+				None => {
+					let child_delta = changes.changes()
+						.map(|(k, v)| (&k[..], v.value().map(|v| &v[..])));
+					let (root, is_empty, child_transaction) =
+						backend.child_storage_root(info, child_delta);
+					transaction.consolidate(child_transaction);
+					(root, is_empty)
+				},
+			};
+			let prefixed_storage_key = info.prefixed_storage_key().into_inner();
+			child_roots.push((prefixed_storage_key, if is_empty { None } else { Some(root.encode()) }));
+		}
+		hints.clear();
+
+		let (root, top_transaction) = backend.storage_root(delta.chain(
+			child_roots.iter().map(|(k, v)| (&k[..], v.as_ref().map(|v| &v[..])))
+		));
+		transaction.consolidate(top_transaction);
+		(root, transaction)
+	}
+
 	/// Generate the changes trie root.
 	///
 	/// Returns the changes trie root and caches the storage transaction into the given `cache`.
+	/// Returns `None` if the changes trie is disabled for the current block.
 	///
 	/// # Panics
 	///
@@ -528,12 +1854,19 @@ impl OverlayedChanges {
 		panic_on_storage_error: bool,
 		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
 	) -> Result<Option<H::Out>, ()> where H::Out: Ord + Encode + 'static {
+		if self.changes_trie_disabled {
+			cache.changes_trie_transaction = Some(None);
+			cache.changes_trie_transaction_storage_root = Some(None);
+			return Ok(None)
+		}
+
 		build_changes_trie::<_, H, N>(
 			backend,
 			changes_trie_state,
 			self,
 			parent_hash,
 			panic_on_storage_error,
+			None,
 		).map(|r| {
 			let root = r.as_ref().map(|r| r.1).clone();
 			cache.changes_trie_transaction = Some(r.map(|(db, _, cache)| (db, cache)));
@@ -563,6 +1896,12 @@ impl OverlayedChanges {
 	}
 }
 
+/// Hash of the given child `changes`, used to check whether a child root hint still applies.
+fn child_changes_hash<H: Hasher>(changes: &OverlayedChangeSet) -> H::Out {
+	let changes: Vec<_> = changes.changes().map(|(k, v)| (k, v.value())).collect();
+	H::hash(&changes.encode())
+}
+
 #[cfg(test)]
 mod tests {
 	use hex_literal::hex;
@@ -616,6 +1955,531 @@ mod tests {
 		assert!(overlayed.storage(&key).unwrap().is_none());
 	}
 
+	#[test]
+	fn child_transaction_is_isolated() {
+		let child_info = ChildInfo::new_default(b"contract");
+		let other_info = ChildInfo::new_default(b"other");
+		let mut overlayed = OverlayedChanges::default();
+
+		overlayed.start_transaction();
+		overlayed.set_storage(vec![1], Some(vec![1]));
+		overlayed.set_child_storage(&child_info, vec![2], Some(vec![2]));
+
+		overlayed.start_child_transaction(&child_info).unwrap();
+		overlayed.set_child_storage(&child_info, vec![2], Some(vec![3]));
+		overlayed.set_child_storage(&child_info, vec![4], Some(vec![4]));
+		overlayed.set_storage(vec![5], Some(vec![5]));
+		overlayed.set_child_storage(&other_info, vec![6], Some(vec![6]));
+		overlayed.rollback_child_transaction(&child_info).unwrap();
+
+		assert_eq!(overlayed.child_storage(&child_info, &[2]).unwrap(), Some(&[2][..]));
+		assert!(overlayed.child_storage(&child_info, &[4]).is_none());
+		assert_eq!(overlayed.storage(&[5]).unwrap(), Some(&[5][..]));
+		assert_eq!(overlayed.child_storage(&other_info, &[6]).unwrap(), Some(&[6][..]));
+
+		overlayed.start_child_transaction(&child_info).unwrap();
+		overlayed.set_child_storage(&child_info, vec![4], Some(vec![4]));
+		overlayed.commit_child_transaction(&child_info).unwrap();
+		assert!(overlayed.commit_child_transaction(&child_info).is_err());
+
+		overlayed.commit_transaction().unwrap();
+		assert_eq!(overlayed.child_storage(&child_info, &[4]).unwrap(), Some(&[4][..]));
+	}
+
+	#[test]
+	fn child_transaction_is_closed_with_enclosing_transaction() {
+		let child_info = ChildInfo::new_default(b"contract");
+		let mut overlayed = OverlayedChanges::default();
+
+		overlayed.start_transaction();
+		overlayed.start_child_transaction(&child_info).unwrap();
+		overlayed.set_child_storage(&child_info, vec![1], Some(vec![1]));
+
+		overlayed.start_transaction();
+		assert!(overlayed.commit_child_transaction(&child_info).is_err());
+		overlayed.commit_transaction().unwrap();
+
+		overlayed.rollback_transaction().unwrap();
+		assert!(overlayed.child_storage(&child_info, &[1]).is_none());
+		assert!(overlayed.commit_child_transaction(&child_info).is_err());
+		assert_eq!(overlayed.transaction_depth(), 0);
+	}
+
+	#[test]
+	fn child_transaction_requires_enclosing_transaction() {
+		let child_info = ChildInfo::new_default(b"contract");
+		let mut overlayed = OverlayedChanges::default();
+
+		assert_eq!(overlayed.start_child_transaction(&child_info), Err(NoOpenTransaction));
+		overlayed.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		assert_eq!(overlayed.try_into_committed().unwrap().children.len(), 1);
+	}
+
+	#[test]
+	fn children_are_iterated_in_order() {
+		let mut overlayed = OverlayedChanges::default();
+		for storage_key in &[&b"c"[..], b"a", b"d", b"b"] {
+			overlayed.set_child_storage(&ChildInfo::new_default(storage_key), vec![1], Some(vec![1]));
+		}
+
+		let order: Vec<_> = overlayed.children()
+			.map(|(_, info)| info.storage_key().to_vec())
+			.collect();
+		assert_eq!(order, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+	}
+
+	#[test]
+	fn presized_overlay_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlayed = OverlayedChanges::with_capacity(16, 4, 8);
+		assert!(overlayed.child_transactions.capacity() >= 4);
+
+		overlayed.set_storage(vec![1], Some(vec![1]));
+		for depth in 0..10u8 {
+			overlayed.start_transaction();
+			overlayed.set_storage(vec![1], Some(vec![depth]));
+			overlayed.set_child_storage(&child_info, vec![depth], Some(vec![depth]));
+		}
+		overlayed.rollback_transaction().unwrap();
+		assert_eq!(overlayed.storage(&[1]).unwrap(), Some(&[8][..]));
+		for _ in 0..9 {
+			overlayed.commit_transaction().unwrap();
+		}
+
+		let (top, children) = overlayed.drain_committed();
+		assert_eq!(top.collect::<Vec<_>>(), vec![(vec![1], Some(vec![8]))]);
+		assert_eq!(children.map(|(_, (changes, _))| changes.count()).sum::<usize>(), 9);
+		assert!(overlayed.is_empty());
+	}
+
+	#[test]
+	fn fnv_key_hasher_works() {
+		use std::hash::{BuildHasher, Hasher};
+
+		let mut hasher = KeyHashBuilder::new(KeyHasher::Fnv).build_hasher();
+		hasher.write(b"a");
+		assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlayed = OverlayedChanges::builder().key_hasher(KeyHasher::Fnv).build();
+		overlayed.start_transaction();
+		overlayed.set_storage(vec![1], Some(vec![1]));
+		overlayed.start_child_transaction(&child_info).unwrap();
+		overlayed.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		overlayed.rollback_child_transaction(&child_info).unwrap();
+		overlayed.commit_transaction().unwrap();
+
+		assert_eq!(overlayed.storage(&[1]).unwrap(), Some(&[1][..]));
+		assert!(overlayed.child_storage(&child_info, &[2]).is_none());
+	}
+
+	#[test]
+	fn deleted_keys_are_iterated() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(vec![1], None);
+		overlayed.set_storage(vec![2], Some(vec![2]));
+		overlayed.set_child_storage(&child_info, vec![3], None);
+
+		overlayed.start_transaction();
+		overlayed.set_storage(vec![2], None);
+		overlayed.set_storage(vec![1], Some(vec![1]));
+		overlayed.set_child_storage(&child_info, vec![4], None);
+
+		let keys = |keys: Vec<&StorageKey>| keys.into_iter().cloned().collect::<Vec<_>>();
+		assert_eq!(keys(overlayed.deleted_keys_iter().collect()), vec![vec![2]]);
+		assert_eq!(keys(overlayed.committed_deleted_keys_iter().collect()), vec![vec![1]]);
+
+		let child_keys = |keys: Vec<(&ChildInfo, &StorageKey)>| keys.into_iter()
+			.map(|(info, key)| (info.storage_key().to_vec(), key.clone()))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			child_keys(overlayed.child_deleted_keys_iter().collect()),
+			vec![(b"child".to_vec(), vec![3]), (b"child".to_vec(), vec![4])],
+		);
+		assert_eq!(
+			child_keys(overlayed.committed_child_deleted_keys_iter().collect()),
+			vec![(b"child".to_vec(), vec![3])],
+		);
+	}
+
+	#[test]
+	fn dirty_keys_are_taken() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(vec![1], Some(vec![1]));
+		overlayed.set_storage(vec![2, 1], Some(vec![2]));
+		overlayed.set_child_storage(&child_info, vec![3], Some(vec![3]));
+
+		overlayed.start_transaction();
+		*overlayed.value_mut_or_insert_with(&[4], || vec![]) = vec![4];
+		overlayed.rollback_transaction().unwrap();
+
+		let dirty = overlayed.take_dirty_keys();
+		assert_eq!(dirty.top, vec![vec![1], vec![2, 1], vec![4]].into_iter().collect());
+		assert_eq!(
+			dirty.children.get(&b"child".to_vec()),
+			Some(&vec![vec![3]].into_iter().collect()),
+		);
+		assert!(overlayed.take_dirty_keys().is_empty());
+
+		overlayed.clear_prefix(&[2]);
+		overlayed.clear_child_storage(&child_info);
+		let dirty = overlayed.take_dirty_keys();
+		assert_eq!(dirty.top, vec![vec![2, 1]].into_iter().collect());
+		assert_eq!(
+			dirty.children.get(&b"child".to_vec()),
+			Some(&vec![vec![3]].into_iter().collect()),
+		);
+	}
+
+	#[test]
+	fn child_changes_extrinsics_are_reported() {
+		let child_a = ChildInfo::new_default(b"a");
+		let child_b = ChildInfo::new_default(b"b");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_child_storage(&child_a, vec![9], Some(vec![9]));
+		overlay.set_collect_extrinsics(true);
+		overlay.set_extrinsic_index(1);
+		overlay.set_child_storage(&child_b, vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_a, vec![2], Some(vec![2]));
+		overlay.set_extrinsic_index(3);
+		overlay.set_child_storage(&child_b, vec![1], None);
+		overlay.clear_child_prefix(&child_a, &[2]);
+
+		let changes: Vec<_> = overlay.child_changes_extrinsics()
+			.map(|(storage_key, key, extrinsics)| {
+				(storage_key.clone(), key.clone(), extrinsics.into_iter().collect::<Vec<_>>())
+			})
+			.collect();
+		assert_eq!(changes, vec![
+			(b"a".to_vec(), vec![2], vec![1, 3]),
+			(b"b".to_vec(), vec![1], vec![1, 3]),
+		]);
+	}
+
+	#[test]
+	fn change_counts_exclude_rolled_back_changes() {
+		let child_a = ChildInfo::new_default(b"a");
+		let child_b = ChildInfo::new_default(b"b");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_child_storage(&child_a, vec![1], Some(vec![1]));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_child_storage(&child_a, vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child_b, vec![1], Some(vec![1]));
+		assert_eq!(overlay.len_top(), 3);
+		assert_eq!(overlay.len_children(), 2);
+		assert_eq!(overlay.child_len(b"a"), 2);
+		assert!(!overlay.is_child_empty(b"b"));
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.len_top(), 2);
+		assert_eq!(overlay.len_children(), 1);
+		assert_eq!(overlay.child_len(b"a"), 1);
+		assert!(overlay.is_child_empty(b"b"));
+		assert!(overlay.is_child_empty(b"c"));
+	}
+
+	#[test]
+	fn transaction_labels_follow_open_transactions() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.start_transaction_labeled("outer");
+		overlay.start_transaction();
+		overlay.start_transaction_labeled("inner");
+		assert_eq!(overlay.transaction_labels(), &[Some("outer"), None, Some("inner")]);
+
+		overlay.rollback_transaction().unwrap();
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.transaction_labels(), &[Some("outer")]);
+
+		overlay.enter_runtime().unwrap();
+		overlay.start_transaction_labeled("runtime");
+		overlay.exit_runtime().unwrap();
+		assert_eq!(overlay.transaction_labels(), &[Some("outer")]);
+	}
+
+	#[test]
+	#[should_panic(expected = "opened by: outer, <unlabeled>")]
+	fn draining_reports_open_transactions() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.start_transaction_labeled("outer");
+		overlay.start_transaction();
+		let _ = overlay.drain_committed();
+	}
+
+	#[test]
+	fn try_into_committed_returns_open_transactions() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.start_transaction_labeled("call");
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![3], None);
+
+		let (mut overlay, pending) = overlay.try_into_committed().unwrap_err();
+		assert_eq!(pending.labels, vec![Some("call")]);
+		assert_eq!(pending.changes.top, vec![(vec![2], Some(vec![2]))]);
+		assert_eq!(pending.changes.children, vec![(b"child".to_vec(), vec![(vec![3], None)])]);
+
+		overlay.commit_transaction().unwrap();
+		let committed = overlay.try_into_committed().unwrap();
+		assert_eq!(committed, CommittedChanges {
+			top: vec![(vec![1], Some(vec![1])), (vec![2], Some(vec![2]))],
+			children: vec![(b"child".to_vec(), vec![(vec![3], None)])],
+		});
+	}
+
+	#[test]
+	fn extend_from_sorted_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.extend_from_sorted((0u8..100).map(|i| (vec![i], Some(vec![i]))));
+		assert_eq!(overlay.len_top(), 100);
+		assert_eq!(overlay.storage(&[42]), Some(Some(&[42][..])));
+
+		overlay.start_transaction();
+		overlay.extend_from_sorted(vec![(vec![1], None), (vec![200], Some(vec![2]))]);
+		assert_eq!(overlay.storage(&[1]), Some(None));
+		assert_eq!(overlay.len_top(), 101);
+		overlay.rollback_transaction().unwrap();
+
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[200]), None);
+		assert_eq!(overlay.take_dirty_keys().top.len(), 101);
+		overlay.assert_invariants();
+	}
+
+	#[test]
+	fn committed_transactions_are_journaled() {
+		let child_info = ChildInfo::new_default(b"child");
+		let entries = Arc::new(parking_lot::Mutex::new(Vec::new()));
+		let sink = entries.clone();
+		let mut overlay = OverlayedChanges::default();
+		overlay.enable_journal(Arc::new(move |record: &JournalRecord| -> Result<(), String> {
+			sink.lock().push(record.clone());
+			Ok(())
+		}));
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.start_transaction();
+		overlay.set_child_storage(&child_info, vec![3], Some(vec![3]));
+		overlay.commit_transaction().unwrap();
+		overlay.set_storage(vec![2], None);
+		overlay.commit_transaction().unwrap();
+		overlay.start_transaction();
+		overlay.set_storage(vec![4], Some(vec![4]));
+		overlay.rollback_transaction().unwrap();
+		overlay.set_child_storage(&child_info, vec![5], Some(vec![5]));
+		let _ = overlay.drain_committed();
+
+		let entries = entries.lock().clone();
+		assert_eq!(entries, vec![
+			JournalEntry { top: vec![(vec![1], Some(vec![1]))], children: Vec::new() }.into(),
+			JournalEntry {
+				top: vec![(vec![2], None)],
+				children: vec![(b"child".to_vec(), vec![(vec![3], Some(vec![3]))])],
+			}.into(),
+			JournalEntry {
+				top: Vec::new(),
+				children: vec![(b"child".to_vec(), vec![(vec![5], Some(vec![5]))])],
+			}.into(),
+			JournalRecord::Boundary,
+		]);
+
+		// A crash while appending leaves an incomplete entry behind.
+		let mut data = entries[0].encode();
+		data.extend_from_slice(&entries[1].encode());
+		data.extend_from_slice(&entries[2].encode()[..3]);
+		let entries = read_journal(&data);
+		assert_eq!(entries.len(), 2);
+
+		let rebuilt = OverlayedChanges::from_journal(entries);
+		assert_eq!(rebuilt.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(rebuilt.storage(&[2]), Some(None));
+		assert_eq!(rebuilt.child_storage(&child_info, &[3]), Some(Some(&[3][..])));
+		assert_eq!(rebuilt.child_storage(&child_info, &[5]), None);
+	}
+
+	#[test]
+	fn journal_is_replayed_from_the_last_drain() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let data = Arc::new(parking_lot::Mutex::new(Vec::new()));
+		let records = Arc::new(parking_lot::Mutex::new(Vec::new()));
+		let (data_sink, records_sink) = (data.clone(), records.clone());
+		let mut overlay = OverlayedChanges::default();
+		overlay.enable_journal(Arc::new(move |record: &JournalRecord| -> Result<(), String> {
+			data_sink.lock().extend_from_slice(&record.encode());
+			records_sink.lock().push(record.clone());
+			Ok(())
+		}));
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.commit_transaction().unwrap();
+		overlay.drain_storage_changes(
+			&backend,
+			crate::changes_trie::disabled_state::<_, u64>(),
+			Default::default(),
+			&mut StorageTransactionCache::default(),
+		).unwrap();
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.commit_transaction().unwrap();
+		overlay.set_storage(vec![1], None);
+		let _ = overlay.drain_committed();
+		overlay.set_storage(vec![4], Some(vec![4]));
+		overlay.start_transaction();
+
+		let entries = read_journal(&data.lock());
+		assert_eq!(entries, vec![
+			JournalEntry { top: vec![(vec![4], Some(vec![4]))], children: Vec::new() },
+		]);
+
+		// Only the changes after the last drain are rebuilt.
+		let records = records.lock().clone();
+		assert_eq!(records.iter().filter(|r| **r == JournalRecord::Boundary).count(), 2);
+		let rebuilt = OverlayedChanges::from_journal(records);
+		assert_eq!(rebuilt.storage(&[1]), None);
+		assert_eq!(rebuilt.storage(&[3]), None);
+		assert_eq!(rebuilt.storage(&[4]), Some(Some(&[4][..])));
+		assert_eq!(OverlayedChanges::from_journal(entries).storage(&[4]), Some(Some(&[4][..])));
+	}
+
+	#[test]
+	fn journal_failure_fails_the_drain() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let appended = Arc::new(parking_lot::Mutex::new(0));
+		let counter = appended.clone();
+		let mut overlay = OverlayedChanges::default();
+		overlay.enable_journal(Arc::new(move |_: &JournalRecord| -> Result<(), String> {
+			*counter.lock() += 1;
+			Err("disk full".into())
+		}));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.journal_failure(), Some("disk full"));
+
+		// Nothing is journaled after the failure.
+		overlay.set_storage(vec![2], Some(vec![2]));
+		let result = overlay.drain_storage_changes(
+			&backend,
+			crate::changes_trie::disabled_state::<_, u64>(),
+			Default::default(),
+			&mut StorageTransactionCache::default(),
+		);
+		assert!(result.is_err());
+		assert_eq!(*appended.lock(), 1);
+	}
+
+	#[test]
+	fn namespace_stats_are_tracked() {
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage_ns(1, vec![1], Some(vec![1, 1]));
+		overlayed.set_storage_ns(1, vec![2], None);
+		overlayed.set_storage_ns(2, vec![3], Some(vec![3]));
+		overlayed.set_storage(vec![4], Some(vec![4]));
+
+		assert_eq!(overlayed.namespace_stats(1), Some(&NamespaceStats {
+			dirty_keys: vec![vec![1], vec![2]].into_iter().collect(),
+			writes: 1,
+			removals: 1,
+			bytes_written: 4,
+		}));
+		assert_eq!(overlayed.namespace_stats(3), None);
+
+		// The namespace is not part of the stored keys.
+		let keys: Vec<_> = overlayed.changes().map(|(k, _)| k.clone()).collect();
+		assert_eq!(keys, vec![vec![1], vec![2], vec![3], vec![4]]);
+
+		let stats = overlayed.take_namespace_stats();
+		assert_eq!(stats.keys().cloned().collect::<Vec<_>>(), vec![1, 2]);
+		assert_eq!(stats[&2].dirty_keys, vec![vec![3]].into_iter().collect());
+		assert!(overlayed.take_namespace_stats().is_empty());
+	}
+
+	#[test]
+	fn pending_diff_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(vec![1], Some(vec![1]));
+		overlayed.set_storage(vec![2], Some(vec![2]));
+		assert_eq!(overlayed.pending_diff(), serde_json::json!([]));
+
+		overlayed.start_transaction();
+		overlayed.set_storage(vec![1], None);
+		overlayed.set_storage(vec![3], Some(vec![3]));
+		overlayed.set_child_storage(&child_info, vec![4], Some(vec![4]));
+
+		assert_eq!(overlayed.pending_diff(), serde_json::json!([
+			{ "child": null, "key": "0x01", "oldValue": "0x01", "newValue": null },
+			{ "child": null, "key": "0x03", "newValue": "0x03" },
+			{ "child": "0x6368696c64", "key": "0x04", "newValue": "0x04" },
+		]));
+	}
+
+	#[test]
+	fn fingerprint_only_depends_on_effective_changes() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut a = OverlayedChanges::default();
+		a.set_storage(vec![1], Some(vec![1]));
+		a.start_transaction();
+		a.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		a.set_storage(vec![3], None);
+
+		let mut b = OverlayedChanges::default();
+		b.set_storage(vec![3], Some(vec![3]));
+		b.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		b.set_storage(vec![3], None);
+		b.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(a.fingerprint::<Blake2Hasher>(), b.fingerprint::<Blake2Hasher>());
+
+		a.rollback_transaction().unwrap();
+		assert_ne!(a.fingerprint::<Blake2Hasher>(), b.fingerprint::<Blake2Hasher>());
+
+		// Child tries without changes left are not part of the fingerprint.
+		let mut c = OverlayedChanges::default();
+		c.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(a.fingerprint::<Blake2Hasher>(), c.fingerprint::<Blake2Hasher>());
+
+		let mut d = OverlayedChanges::default();
+		d.start_transaction();
+		d.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		d.rollback_transaction().unwrap();
+		assert!(d.is_empty());
+		assert_eq!(
+			d.fingerprint::<Blake2Hasher>(),
+			OverlayedChanges::default().fingerprint::<Blake2Hasher>(),
+		);
+	}
+
+	#[test]
+	fn key_filter_does_not_hide_changes() {
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(vec![1], Some(vec![1]));
+		overlayed.enable_key_filter();
+
+		overlayed.start_transaction();
+		overlayed.set_storage(vec![2], None);
+		*overlayed.value_mut_or_insert_with(&[3], || vec![]) = vec![3];
+
+		assert_eq!(overlayed.storage(&[1]).unwrap(), Some(&[1][..]));
+		assert_eq!(overlayed.storage(&[2]).unwrap(), None);
+		assert_eq!(overlayed.storage(&[3]).unwrap(), Some(&[3][..]));
+		assert!(overlayed.storage(&[4]).is_none());
+
+		overlayed.rollback_transaction().unwrap();
+		assert!(overlayed.storage(&[3]).is_none());
+	}
+
 	#[test]
 	fn overlayed_storage_root_works() {
 		let initial: BTreeMap<_, _> = vec![
@@ -653,6 +2517,227 @@ mod tests {
 		assert_eq!(&ext.storage_root()[..], &ROOT);
 	}
 
+	#[test]
+	fn merge_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut first = OverlayedChanges::default();
+		first.set_collect_extrinsics(true);
+		first.set_storage(b"a".to_vec(), Some(b"1".to_vec()));
+		first.set_child_storage(&child_info, b"c".to_vec(), Some(b"1".to_vec()));
+
+		let mut second = OverlayedChanges::default();
+		second.set_collect_extrinsics(true);
+		second.set_extrinsic_index(2);
+		second.set_storage(b"b".to_vec(), None);
+		second.set_child_storage(&child_info, b"d".to_vec(), Some(b"2".to_vec()));
+
+		let merged = first.merge(second).unwrap();
+		assert_eq!(merged.storage(b"a"), Some(Some(&b"1"[..])));
+		assert_eq!(merged.storage(b"b"), Some(None));
+		assert_eq!(merged.child_storage(&child_info, b"c"), Some(Some(&b"1"[..])));
+		assert_eq!(merged.child_storage(&child_info, b"d"), Some(Some(&b"2"[..])));
+		assert_eq!(
+			merged.top.get(b"b").unwrap().extrinsics().cloned().collect::<Vec<_>>(),
+			vec![2],
+		);
+	}
+
+	#[test]
+	fn merge_detects_conflicts() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut first = OverlayedChanges::default();
+		first.set_storage(b"a".to_vec(), Some(b"1".to_vec()));
+		first.set_child_storage(&child_info, b"c".to_vec(), Some(b"1".to_vec()));
+
+		let mut second = OverlayedChanges::default();
+		second.set_storage(b"a".to_vec(), None);
+		assert_eq!(first.clone().merge(second).unwrap_err(), MergeConflict::Key(b"a".to_vec()));
+
+		let mut second = OverlayedChanges::default();
+		second.set_child_storage(&child_info, b"c".to_vec(), None);
+		assert_eq!(
+			first.clone().merge(second).unwrap_err(),
+			MergeConflict::ChildKey(b"child".to_vec(), b"c".to_vec()),
+		);
+
+		let mut second = OverlayedChanges::default();
+		second.start_transaction();
+		assert_eq!(first.merge(second).unwrap_err(), MergeConflict::OpenTransaction);
+	}
+
+	#[test]
+	fn apply_delta_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(b"a".to_vec(), Some(b"old".to_vec()));
+
+		overlay.start_transaction();
+		overlay.apply_delta(
+			vec![(b"a".to_vec(), Some(b"new".to_vec())), (b"b".to_vec(), None)],
+			vec![(child_info.clone(), vec![(b"c".to_vec(), Some(b"child".to_vec()))])],
+			Some(3),
+		);
+		assert_eq!(overlay.storage(b"a"), Some(Some(&b"new"[..])));
+		assert_eq!(overlay.storage(b"b"), Some(None));
+		assert_eq!(overlay.child_storage(&child_info, b"c"), Some(Some(&b"child"[..])));
+		assert_eq!(
+			overlay.top.get(b"b").unwrap().extrinsics().cloned().collect::<Vec<_>>(),
+			vec![3],
+		);
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.storage(b"a"), Some(Some(&b"old"[..])));
+		assert_eq!(overlay.storage(b"b"), None);
+		assert_eq!(overlay.child_storage(&child_info, b"c"), None);
+	}
+
+	#[test]
+	fn offchain_storage_changes_are_drained() {
+		use sp_core::offchain::{STORAGE_PREFIX, storage::OffchainOverlayedChange};
+
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_offchain_storage(b"ignored", Some(b"value"));
+		overlay.enable_offchain_indexing();
+
+		let mut offchain_overlay = Default::default();
+		let mut cache = StorageTransactionCache::default();
+		let mut ext = Ext::new(
+			&mut overlay,
+			&mut offchain_overlay,
+			&mut cache,
+			&backend,
+			crate::changes_trie::disabled_state::<_, u64>(),
+			None,
+		);
+		ext.set_offchain_storage(b"key", Some(b"value"));
+		ext.set_offchain_storage(b"removed", None);
+		drop(ext);
+
+		let changes = overlay.drain_storage_changes(
+			&backend,
+			crate::changes_trie::disabled_state::<_, u64>(),
+			Default::default(),
+			&mut cache,
+		).unwrap();
+		assert!(changes.main_storage_changes.is_empty());
+		let mut offchain: Vec<_> = changes.offchain_storage_changes.into_iter().collect();
+		offchain.sort_by(|a, b| a.0.cmp(&b.0));
+		assert_eq!(offchain, vec![
+			((STORAGE_PREFIX.to_vec(), b"key".to_vec()), OffchainOverlayedChange::SetValue(b"value".to_vec())),
+			((STORAGE_PREFIX.to_vec(), b"removed".to_vec()), OffchainOverlayedChange::Remove),
+		]);
+		assert_eq!(overlay.offchain_storage_changes().iter().count(), 0);
+	}
+
+	#[test]
+	fn incremental_storage_root_matches_full_root() {
+		let initial: BTreeMap<_, _> = (0u8..10).map(|i| (vec![i], vec![i])).collect();
+		let backend = InMemoryBackend::<Blake2Hasher>::from(vec![
+			(None, initial.into_iter().map(|(k, v)| (k, Some(v))).collect()),
+		]);
+		let full_root = |overlay: &OverlayedChanges| {
+			let delta = overlay.changes().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])));
+			backend.storage_root(delta).0
+		};
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.enable_incremental_storage_root();
+		let mut cache = StorageTransactionCache::<_, _, u64>::default();
+
+		overlay.set_storage(vec![1], Some(vec![11]));
+		overlay.set_storage(vec![20], Some(vec![20]));
+		let root = overlay.storage_root(&backend, &mut cache);
+		assert_eq!(root, full_root(&overlay));
+
+		cache.reset();
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![12]));
+		overlay.set_storage(vec![2], None);
+		overlay.rollback_transaction().unwrap();
+		overlay.set_storage(vec![3], None);
+		overlay.clear_prefix(&[20]);
+		let root = overlay.storage_root(&backend, &mut cache);
+		assert_ne!(root, full_root(&OverlayedChanges::default()));
+		assert_eq!(root, full_root(&overlay));
+
+		let changes = overlay.drain_storage_changes(
+			&backend,
+			crate::changes_trie::disabled_state::<_, u64>(),
+			Default::default(),
+			&mut cache,
+		).unwrap();
+		assert_eq!(changes.transaction_storage_root, root);
+		let backend = backend.update_backend(root, changes.transaction);
+		assert_eq!(backend.storage(&[1]).unwrap(), Some(vec![11]));
+		assert_eq!(backend.storage(&[2]).unwrap(), Some(vec![2]));
+		assert_eq!(backend.storage(&[3]).unwrap(), None);
+		assert_eq!(backend.storage(&[20]).unwrap(), None);
+		assert!(cache.incremental_root.is_none());
+	}
+
+	#[test]
+	fn incremental_storage_root_forgets_rolled_back_changes() {
+		let backend = InMemoryBackend::<Blake2Hasher>::from(vec![
+			(None, vec![(vec![1], Some(vec![1])), (vec![2], Some(vec![2]))]),
+		]);
+		let full_root = |overlay: &OverlayedChanges| {
+			let delta = overlay.changes().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])));
+			backend.storage_root(delta).0
+		};
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.enable_incremental_storage_root();
+		let mut cache = StorageTransactionCache::<_, _, u64>::default();
+
+		overlay.set_storage(vec![1], Some(vec![11]));
+		overlay.storage_root(&backend, &mut cache);
+
+		// The root of the failed extrinsic includes its changes.
+		cache.reset();
+		overlay.start_transaction();
+		overlay.set_storage(vec![1], Some(vec![12]));
+		overlay.set_storage(vec![2], None);
+		let root = overlay.storage_root(&backend, &mut cache);
+		assert_eq!(root, full_root(&overlay));
+		overlay.rollback_transaction().unwrap();
+
+		cache.reset();
+		let root = overlay.storage_root(&backend, &mut cache);
+		assert_eq!(root, full_root(&overlay));
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[11][..])));
+	}
+
+	#[test]
+	fn transient_storage_is_not_part_of_the_changes() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let empty_root = backend.storage_root(std::iter::empty()).0;
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_transient_storage(b"cache".to_vec(), Some(b"1".to_vec()));
+
+		overlay.start_transaction();
+		overlay.set_transient_storage(b"cache".to_vec(), Some(b"2".to_vec()));
+		overlay.set_transient_storage(b"scratch".to_vec(), Some(b"3".to_vec()));
+		assert_eq!(overlay.transient_storage(b"cache"), Some(&b"2"[..]));
+		overlay.rollback_transaction().unwrap();
+
+		assert_eq!(overlay.transient_storage(b"cache"), Some(&b"1"[..]));
+		assert_eq!(overlay.transient_storage(b"scratch"), None);
+		assert_eq!(overlay.storage(b"cache"), None);
+		assert!(overlay.is_empty());
+
+		let changes = overlay.drain_storage_changes(
+			&backend,
+			crate::changes_trie::disabled_state::<_, u64>(),
+			Default::default(),
+			&mut StorageTransactionCache::default(),
+		).unwrap();
+		assert!(changes.main_storage_changes.is_empty());
+		assert_eq!(changes.transaction_storage_root, empty_root);
+		assert_eq!(overlay.transient_storage(b"cache"), None);
+	}
+
 	#[test]
 	fn extrinsic_changes_are_collected() {
 		let mut overlay = OverlayedChanges::default();
@@ -694,6 +2779,30 @@ mod tests {
 		assert_extrinsics(&overlay.top, vec![100], vec![NO_EXTRINSIC_INDEX]);
 	}
 
+	#[test]
+	fn noted_extrinsic_boundaries_are_used() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+
+		overlay.note_extrinsic_start(0);
+		overlay.set_storage(vec![1], Some(vec![2]));
+		// Ignored once the boundaries are noted explicitly.
+		overlay.set_extrinsic_index(5);
+		overlay.set_storage(vec![3], Some(vec![4]));
+		overlay.note_extrinsic_end();
+		overlay.set_storage(vec![100], Some(vec![101]));
+
+		overlay.note_extrinsic_start(1);
+		assert_eq!(overlay.current_extrinsic(), Some(1));
+		overlay.set_storage(vec![1], Some(vec![6]));
+		overlay.note_extrinsic_end();
+		assert_eq!(overlay.current_extrinsic(), None);
+
+		assert_extrinsics(&overlay.top, vec![1], vec![0, 1]);
+		assert_extrinsics(&overlay.top, vec![3], vec![0]);
+		assert_extrinsics(&overlay.top, vec![100], vec![NO_EXTRINSIC_INDEX]);
+	}
+
 	#[test]
 	fn next_storage_key_change_works() {
 		let mut overlay = OverlayedChanges::default();
@@ -17,7 +17,7 @@
 
 //! Proving state machine backend.
 
-use std::{sync::Arc, collections::HashMap};
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, collections::HashMap};
 use parking_lot::RwLock;
 use codec::{Decode, Codec};
 use log::debug;
@@ -113,6 +113,60 @@ impl<'a, S, H> ProvingBackendRecorder<'a, S, H>
 /// data.
 pub type ProofRecorder<H> = Arc<RwLock<HashMap<<H as Hasher>::Out, Option<DBValue>>>>;
 
+/// Handle to pause and resume the recording of a [`ProvingBackend`].
+///
+/// Trie nodes that are read while the recording is paused are not added to the proof. The
+/// verifier of the proof therefore has to be able to derive everything that was read during
+/// a paused phase on its own, e.g. because it re-creates the inherents that were read.
+/// Otherwise the proof is incomplete and verification fails.
+///
+/// Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingControl(Arc<AtomicBool>);
+
+impl RecordingControl {
+	/// Stop recording the nodes that are read.
+	pub fn pause(&self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+
+	/// Record the nodes that are read again.
+	pub fn resume(&self) {
+		self.0.store(false, Ordering::Relaxed);
+	}
+
+	/// Returns `true` if the recording is paused.
+	pub fn is_paused(&self) -> bool {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+/// Size statistics of a recorded proof.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProofRecordingStats {
+	/// Number of distinct trie nodes in the proof.
+	pub unique_nodes: usize,
+	/// Size of all trie nodes in bytes.
+	pub total_bytes: usize,
+	/// Size of the largest trie node in bytes.
+	pub largest_node: usize,
+	/// Number of distinct storage keys that were accessed while recording.
+	pub keys_touched: usize,
+}
+
+impl ProofRecordingStats {
+	/// Collect the statistics of the given `proof`.
+	pub fn new(proof: &StorageProof, keys_touched: usize) -> Self {
+		let mut stats = ProofRecordingStats { keys_touched, ..Default::default() };
+		for node in proof.clone().iter_nodes() {
+			stats.unique_nodes += 1;
+			stats.total_bytes += node.len();
+			stats.largest_node = std::cmp::max(stats.largest_node, node.len());
+		}
+		stats
+	}
+}
+
 /// Patricia trie-based backend which also tracks all touched storage trie values.
 /// These can be sent to remote node and used as a proof of execution.
 pub struct ProvingBackend<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> (
@@ -123,6 +177,7 @@ pub struct ProvingBackend<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> (
 pub struct ProofRecorderBackend<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> {
 	backend: &'a S,
 	proof_recorder: ProofRecorder<H>,
+	recording_control: RecordingControl,
 }
 
 impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> ProvingBackend<'a, S, H>
@@ -144,10 +199,28 @@ impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> ProvingBackend<'a, S, H>
 		let recorder = ProofRecorderBackend {
 			backend: essence.backend_storage(),
 			proof_recorder,
+			recording_control: RecordingControl::default(),
 		};
 		ProvingBackend(TrieBackend::new(recorder, root))
 	}
 
+	/// Stop recording the trie nodes that are read.
+	///
+	/// See [`RecordingControl`] for the requirements on the verifier.
+	pub fn pause_recording(&self) {
+		self.recording_control().pause()
+	}
+
+	/// Record the trie nodes that are read again.
+	pub fn resume_recording(&self) {
+		self.recording_control().resume()
+	}
+
+	/// Returns the handle that controls the recording of this backend.
+	pub fn recording_control(&self) -> &RecordingControl {
+		&self.0.essence().backend_storage().recording_control
+	}
+
 	/// Extracting the gathered unordered proof.
 	pub fn extract_proof(&self) -> StorageProof {
 		let trie_nodes = self.0.essence().backend_storage().proof_recorder
@@ -169,7 +242,9 @@ impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> TrieBackendStorage<H>
 			return Ok(v.clone());
 		}
 		let backend_value =  self.backend.get(key, prefix)?;
-		self.proof_recorder.write().insert(key.clone(), backend_value.clone());
+		if !self.recording_control.is_paused() {
+			self.proof_recorder.write().insert(key.clone(), backend_value.clone());
+		}
 		Ok(backend_value)
 	}
 }
@@ -241,6 +316,16 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 		self.0.for_child_keys_with_prefix( child_info, prefix, f)
 	}
 
+	fn child_keys_paged(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		count: usize,
+		start_key: Option<&[u8]>,
+	) -> Result<Vec<Vec<u8>>, Self::Error> {
+		self.0.child_keys_paged(child_info, prefix, count, start_key)
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		self.0.pairs()
 	}
@@ -264,6 +349,26 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 		self.0.storage_root(delta)
 	}
 
+	fn storage_root_presorted<'b>(
+		&self,
+		delta: impl Iterator<Item=(&'b [u8], Option<&'b [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.0.storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'b>(
+		&self,
+		root: H::Out,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'b [u8], Option<&'b [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		self.0.storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		self.0.supports_incremental_storage_root()
+	}
+
 	fn child_storage_root<'b>(
 		&self,
 		child_info: &ChildInfo,
@@ -277,6 +382,15 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 	fn usage_info(&self) -> crate::stats::UsageInfo {
 		self.0.usage_info()
 	}
+
+	fn proof_size(&self) -> Option<u32> {
+		let size = self.0.essence().backend_storage().proof_recorder
+			.read()
+			.values()
+			.filter_map(|v| v.as_ref().map(|v| v.len()))
+			.sum::<usize>();
+		Some(size as u32)
+	}
 }
 
 /// Create proof check backend.
@@ -326,6 +440,21 @@ mod tests {
 		assert!(!backend.extract_proof().is_empty());
 	}
 
+	#[test]
+	fn paused_reads_are_not_recorded() {
+		let trie_backend = test_trie();
+		let backend = test_proving(&trie_backend);
+		backend.pause_recording();
+		assert_eq!(backend.storage(b"key").unwrap(), Some(b"value".to_vec()));
+		assert!(backend.extract_proof().is_empty());
+
+		let control = backend.recording_control().clone();
+		control.resume();
+		assert!(!backend.recording_control().is_paused());
+		assert_eq!(backend.storage(b"key").unwrap(), Some(b"value".to_vec()));
+		assert!(!backend.extract_proof().is_empty());
+	}
+
 	#[test]
 	fn proof_is_invalid_when_does_not_contains_root() {
 		use sp_core::H256;
@@ -0,0 +1,345 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend decorator that limits the amount of data read from the wrapped backend.
+
+use std::{fmt, sync::atomic::{AtomicU64, Ordering}};
+use log::warn;
+use hash_db::Hasher;
+use sp_core::storage::ChildInfo;
+use crate::{Backend, KeyRef, StorageReadHint, StorageKey, StorageValue, UsageInfo};
+
+/// Limits enforced by [`QuotaBackend`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadQuota {
+	/// Maximum number of reads.
+	pub reads: u64,
+	/// Maximum number of bytes read, counting keys and values.
+	pub bytes: u64,
+}
+
+/// Message of [`QuotaError::Exceeded`].
+pub(crate) const QUOTA_EXCEEDED: &str = "Read quota exceeded";
+
+/// Error of a [`QuotaBackend`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum QuotaError<E> {
+	/// The wrapped backend failed.
+	Backend(E),
+	/// The read quota is exhausted.
+	Exceeded,
+}
+
+impl<E: fmt::Display> fmt::Display for QuotaError<E> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			QuotaError::Backend(e) => write!(f, "Backend error: {}", e),
+			QuotaError::Exceeded => write!(f, "{}", QUOTA_EXCEEDED),
+		}
+	}
+}
+
+/// Wraps a [`Backend`] and fails all reads once the given [`ReadQuota`] is exceeded.
+///
+/// Iterations can not return an error, they stop calling the given closure when the quota
+/// is exceeded and [`Backend::check_limits`] reports it afterwards. All following reads will
+/// fail.
+pub struct QuotaBackend<B> {
+	backend: B,
+	quota: ReadQuota,
+	reads: AtomicU64,
+	bytes: AtomicU64,
+}
+
+impl<B> QuotaBackend<B> {
+	/// Create a new instance that enforces `quota`.
+	pub fn new(backend: B, quota: ReadQuota) -> Self {
+		QuotaBackend {
+			backend,
+			quota,
+			reads: AtomicU64::new(0),
+			bytes: AtomicU64::new(0),
+		}
+	}
+
+	/// Returns the number of reads and the number of bytes read so far.
+	pub fn used(&self) -> (u64, u64) {
+		(self.reads.load(Ordering::Relaxed), self.bytes.load(Ordering::Relaxed))
+	}
+
+	/// Returns `true` if the quota is exceeded.
+	pub fn is_exceeded(&self) -> bool {
+		let (reads, bytes) = self.used();
+		reads > self.quota.reads || bytes > self.quota.bytes
+	}
+
+	/// Consumes self and returns the wrapped backend.
+	pub fn into_inner(self) -> B {
+		self.backend
+	}
+
+	/// Tally one read of `bytes` and return `false` if this read exceeded the quota.
+	fn tally(&self, bytes: usize) -> bool {
+		let was_exceeded = self.is_exceeded();
+		self.reads.fetch_add(1, Ordering::Relaxed);
+		self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+		if !self.is_exceeded() {
+			return true
+		}
+
+		if !was_exceeded {
+			warn!(target: "state", "Read quota of {:?} exceeded", self.quota);
+		}
+		false
+	}
+
+	fn check<E>(&self) -> Result<(), QuotaError<E>> {
+		if self.is_exceeded() {
+			Err(QuotaError::Exceeded)
+		} else {
+			Ok(())
+		}
+	}
+
+	fn tally_value<E>(
+		&self,
+		key: &[u8],
+		value: Result<Option<StorageValue>, E>,
+	) -> Result<Option<StorageValue>, QuotaError<E>> {
+		let value = value.map_err(QuotaError::Backend)?;
+		if self.tally(key.len() + value.as_ref().map_or(0, |v| v.len())) {
+			Ok(value)
+		} else {
+			Err(QuotaError::Exceeded)
+		}
+	}
+
+	fn tally_key<E>(
+		&self,
+		key: Result<Option<StorageKey>, E>,
+	) -> Result<Option<StorageKey>, QuotaError<E>> {
+		let key = key.map_err(QuotaError::Backend)?;
+		if self.tally(key.as_ref().map_or(0, |k| k.len())) {
+			Ok(key)
+		} else {
+			Err(QuotaError::Exceeded)
+		}
+	}
+}
+
+impl<B> fmt::Debug for QuotaBackend<B> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "QuotaBackend")
+	}
+}
+
+impl<H: Hasher, B: Backend<H>> Backend<H> for QuotaBackend<B> {
+	type Error = QuotaError<B::Error>;
+	type Transaction = B::Transaction;
+	type TrieBackendStorage = B::TrieBackendStorage;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error> {
+		self.check()?;
+		self.tally_value(key, self.backend.storage(key))
+	}
+
+	fn storage_with_hint(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		self.check()?;
+		let (value, hint) = self.backend.storage_with_hint(key).map_err(QuotaError::Backend)?;
+		self.tally_value::<B::Error>(key, Ok(value)).map(|value| (value, hint))
+	}
+
+	fn storage_with_hint_ref(
+		&self,
+		key: KeyRef,
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		self.check()?;
+		let (value, hint) = self.backend.storage_with_hint_ref(key).map_err(QuotaError::Backend)?;
+		self.tally_value::<B::Error>(key.key(), Ok(value)).map(|value| (value, hint))
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, Self::Error> {
+		self.check()?;
+		self.tally_value(key, self.backend.child_storage(child_info, key))
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+		self.check()?;
+		self.tally_key(self.backend.next_storage_key(key))
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageKey>, Self::Error> {
+		self.check()?;
+		self.tally_key(self.backend.next_child_storage_key(child_info, key))
+	}
+
+	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		mut f: F,
+	) {
+		self.backend.for_keys_in_child_storage(child_info, |k| if self.tally(k.len()) { f(k) })
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], mut f: F) {
+		self.backend.for_key_values_with_prefix(prefix, |k, v| {
+			if self.tally(k.len() + v.len()) {
+				f(k, v)
+			}
+		})
+	}
+
+	fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], mut f: F) {
+		self.backend.for_keys_with_prefix(prefix, |k| if self.tally(k.len()) { f(k) })
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		mut f: F,
+	) {
+		self.backend.for_child_keys_with_prefix(
+			child_info,
+			prefix,
+			|k| if self.tally(k.len()) { f(k) },
+		)
+	}
+
+	fn storage_root<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.backend.storage_root(delta)
+	}
+
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.backend.storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: H::Out,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		self.backend.storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		self.backend.supports_incremental_storage_root()
+	}
+
+	fn child_storage_root<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord {
+		self.backend.child_storage_root(child_info, delta)
+	}
+
+	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
+		let mut pairs = Vec::new();
+		self.for_key_values_with_prefix(&[], |k, v| pairs.push((k.to_vec(), v.to_vec())));
+		pairs
+	}
+
+	fn register_overlay_stats(&mut self, stats: &crate::stats::StateMachineStats) {
+		self.backend.register_overlay_stats(stats)
+	}
+
+	fn usage_info(&self) -> UsageInfo {
+		self.backend.usage_info()
+	}
+
+	fn proof_size(&self) -> Option<u32> {
+		self.backend.proof_size()
+	}
+
+	fn is_quota_exceeded(error: &Self::Error) -> bool {
+		match error {
+			QuotaError::Exceeded => true,
+			QuotaError::Backend(error) => B::is_quota_exceeded(error),
+		}
+	}
+
+	fn check_limits(&self) -> Result<(), Self::Error> {
+		self.check()?;
+		self.backend.check_limits().map_err(QuotaError::Backend)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::InMemoryBackend;
+	use sp_runtime::traits::BlakeTwo256;
+
+	fn backend(reads: u64, bytes: u64) -> QuotaBackend<InMemoryBackend<BlakeTwo256>> {
+		let inner: InMemoryBackend<BlakeTwo256> = vec![
+			(None, vec![
+				(b"a".to_vec(), Some(vec![1; 10])),
+				(b"b".to_vec(), Some(vec![2; 10])),
+				(b"c".to_vec(), Some(vec![3; 10])),
+			]),
+		].into();
+		QuotaBackend::new(inner, ReadQuota { reads, bytes })
+	}
+
+	#[test]
+	fn read_quota_is_enforced() {
+		let backend = backend(2, 1000);
+		assert_eq!(backend.storage(b"a").unwrap(), Some(vec![1; 10]));
+		assert_eq!(backend.storage(b"b").unwrap(), Some(vec![2; 10]));
+		assert_eq!(backend.storage(b"c"), Err(QuotaError::Exceeded));
+		assert_eq!(backend.storage(b"a"), Err(QuotaError::Exceeded));
+		assert!(backend.is_exceeded());
+	}
+
+	#[test]
+	fn byte_quota_is_enforced() {
+		let backend = backend(100, 15);
+		assert_eq!(backend.storage(b"a").unwrap(), Some(vec![1; 10]));
+		assert_eq!(backend.storage(b"b"), Err(QuotaError::Exceeded));
+		assert_eq!(backend.used(), (2, 22));
+	}
+
+	#[test]
+	fn iteration_stops_when_quota_is_exceeded() {
+		let backend = backend(2, 1000);
+		assert_eq!(backend.check_limits(), Ok(()));
+		let mut keys = Vec::new();
+		backend.for_keys_with_prefix(&[], |k| keys.push(k.to_vec()));
+		assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+		assert_eq!(backend.check_limits(), Err(QuotaError::Exceeded));
+		assert_eq!(backend.next_storage_key(b"a"), Err(QuotaError::Exceeded));
+	}
+}
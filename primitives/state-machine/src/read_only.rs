@@ -25,9 +25,9 @@ use crate::{Backend, StorageKey, StorageValue};
 use hash_db::Hasher;
 use sp_core::{
 	storage::{ChildInfo, TrackedStorageKey},
-	traits::Externalities, Blake2Hasher,
+	traits::Externalities,
 };
-use codec::Encode;
+use sp_externalities::{DeferredCheck, TrieProofLayout};
 
 /// Trait for inspecting state in any backend.
 ///
@@ -81,7 +81,7 @@ impl<'a, H: Hasher, B: 'a + Backend<H>> Externalities for ReadOnlyExternalities<
 	}
 
 	fn storage_hash(&self, key: &[u8]) -> Option<Vec<u8>> {
-		self.storage(key).map(|v| Blake2Hasher::hash(&v).encode())
+		self.storage(key).map(|v| H::hash(&v).as_ref().to_vec())
 	}
 
 	fn child_storage(
@@ -97,7 +97,7 @@ impl<'a, H: Hasher, B: 'a + Backend<H>> Externalities for ReadOnlyExternalities<
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> Option<Vec<u8>> {
-		self.child_storage(child_info, key).map(|v| Blake2Hasher::hash(&v).encode())
+		self.child_storage(child_info, key).map(|v| H::hash(&v).as_ref().to_vec())
 	}
 
 	fn next_storage_key(&self, key: &[u8]) -> Option<StorageKey> {
@@ -170,6 +170,44 @@ impl<'a, H: Hasher, B: 'a + Backend<H>> Externalities for ReadOnlyExternalities<
 		unimplemented!("storage_changes_root is not supported in ReadOnlyExternalities")
 	}
 
+	fn disable_changes_trie_for_block(&mut self) {
+		unimplemented!("disable_changes_trie_for_block is not supported in ReadOnlyExternalities")
+	}
+
+	fn register_deferred_check(&mut self, _check: DeferredCheck) {
+		unimplemented!("register_deferred_check is not supported in ReadOnlyExternalities")
+	}
+
+	fn verify_trie_proof(
+		&self,
+		root: &[u8],
+		proof: &[Vec<u8>],
+		items: &[(Vec<u8>, Option<Vec<u8>>)],
+		layout: TrieProofLayout,
+	) -> bool {
+		crate::verify_trie_proof(root, proof, items, layout)
+	}
+
+	fn blob_put(&mut self, _value: Vec<u8>) -> Vec<u8> {
+		unimplemented!("blob_put is not supported in ReadOnlyExternalities")
+	}
+
+	fn blob_get(&self, handle: &[u8]) -> Option<Vec<u8>> {
+		crate::blob::get::<H>(self, handle)
+	}
+
+	fn blob_remove(&mut self, _handle: &[u8]) -> bool {
+		unimplemented!("blob_remove is not supported in ReadOnlyExternalities")
+	}
+
+	fn transient_storage(&self, _key: &[u8]) -> Option<Vec<u8>> {
+		None
+	}
+
+	fn set_transient_storage(&mut self, _key: Vec<u8>, _value: Option<Vec<u8>>) {
+		unimplemented!("set_transient_storage is not supported in ReadOnlyExternalities")
+	}
+
 	fn storage_start_transaction(&mut self) {
 		unimplemented!("Transactions are not supported by ReadOnlyExternalities");
 	}
@@ -0,0 +1,382 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend decorator that records every storage access.
+
+use std::{collections::VecDeque, time::Instant};
+use hash_db::Hasher;
+use parking_lot::Mutex;
+use sp_core::storage::{ChildInfo, TrackedStorageKey};
+use crate::{
+	Backend, KeyRef, StorageReadHint, StorageKey, StorageValue, StorageCollection, ChildStorageCollection, UsageInfo,
+};
+
+/// The kind of a storage access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+	/// The value or the hash of the value was read.
+	Read,
+	/// The key was used to look up the next key.
+	NextKey,
+	/// The keys starting with the given prefix were iterated.
+	Iterate,
+	/// The key was written as part of a storage root calculation.
+	Write,
+}
+
+/// A single storage access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRecord {
+	/// The kind of access.
+	pub kind: AccessKind,
+	/// The storage key of the child trie, `None` for the main trie.
+	pub child_storage_key: Option<StorageKey>,
+	/// The accessed key. For iterations over a prefix this is the prefix.
+	pub key: StorageKey,
+	/// The moment of the access.
+	pub at: Instant,
+}
+
+/// Wraps a [`Backend`] and records every key it is asked for.
+///
+/// The records are kept in a ring buffer of a fixed capacity, once it is full the oldest
+/// records are dropped.
+pub struct RecordingBackend<B> {
+	backend: B,
+	capacity: usize,
+	records: Mutex<VecDeque<AccessRecord>>,
+	dropped: Mutex<u64>,
+}
+
+impl<B> RecordingBackend<B> {
+	/// Create a new instance that keeps at most `capacity` records.
+	pub fn new(backend: B, capacity: usize) -> Self {
+		RecordingBackend {
+			backend,
+			capacity,
+			records: Mutex::new(VecDeque::new()),
+			dropped: Mutex::new(0),
+		}
+	}
+
+	/// Returns the recorded accesses, oldest first.
+	pub fn records(&self) -> Vec<AccessRecord> {
+		self.records.lock().iter().cloned().collect()
+	}
+
+	/// Returns the recorded accesses and clears the buffer.
+	pub fn take_records(&self) -> Vec<AccessRecord> {
+		*self.dropped.lock() = 0;
+		self.records.lock().drain(..).collect()
+	}
+
+	/// Returns the number of records that were dropped because the buffer was full.
+	pub fn dropped(&self) -> u64 {
+		*self.dropped.lock()
+	}
+
+	/// Returns a reference to the wrapped backend.
+	pub fn inner(&self) -> &B {
+		&self.backend
+	}
+
+	/// Consumes self and returns the wrapped backend.
+	pub fn into_inner(self) -> B {
+		self.backend
+	}
+
+	fn record(&self, kind: AccessKind, child_info: Option<&ChildInfo>, key: &[u8]) {
+		if self.capacity == 0 {
+			*self.dropped.lock() += 1;
+			return
+		}
+
+		let mut records = self.records.lock();
+		if records.len() == self.capacity {
+			records.pop_front();
+			*self.dropped.lock() += 1;
+		}
+		records.push_back(AccessRecord {
+			kind,
+			child_storage_key: child_info.map(|c| c.storage_key().to_vec()),
+			key: key.to_vec(),
+			at: Instant::now(),
+		});
+	}
+}
+
+impl<B> std::fmt::Debug for RecordingBackend<B> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "RecordingBackend")
+	}
+}
+
+impl<H: Hasher, B: Backend<H>> Backend<H> for RecordingBackend<B> {
+	type Error = B::Error;
+	type Transaction = B::Transaction;
+	type TrieBackendStorage = B::TrieBackendStorage;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error> {
+		self.record(AccessKind::Read, None, key);
+		self.backend.storage(key)
+	}
+
+	fn storage_with_hint(
+		&self,
+		key: &[u8],
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		self.record(AccessKind::Read, None, key);
+		self.backend.storage_with_hint(key)
+	}
+
+	fn storage_with_hint_ref(
+		&self,
+		key: KeyRef,
+	) -> Result<(Option<StorageValue>, StorageReadHint), Self::Error> {
+		self.record(AccessKind::Read, None, key.key());
+		self.backend.storage_with_hint_ref(key)
+	}
+
+	fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, Self::Error> {
+		self.record(AccessKind::Read, None, key);
+		self.backend.storage_hash(key)
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, Self::Error> {
+		self.record(AccessKind::Read, Some(child_info), key);
+		self.backend.child_storage(child_info, key)
+	}
+
+	fn child_storage_hash(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<H::Out>, Self::Error> {
+		self.record(AccessKind::Read, Some(child_info), key);
+		self.backend.child_storage_hash(child_info, key)
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+		self.record(AccessKind::NextKey, None, key);
+		self.backend.next_storage_key(key)
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageKey>, Self::Error> {
+		self.record(AccessKind::NextKey, Some(child_info), key);
+		self.backend.next_child_storage_key(child_info, key)
+	}
+
+	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		f: F,
+	) {
+		self.record(AccessKind::Iterate, Some(child_info), &[]);
+		self.backend.for_keys_in_child_storage(child_info, f)
+	}
+
+	fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], f: F) {
+		self.record(AccessKind::Iterate, None, prefix);
+		self.backend.for_keys_with_prefix(prefix, f)
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
+		self.record(AccessKind::Iterate, None, prefix);
+		self.backend.for_key_values_with_prefix(prefix, f)
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		f: F,
+	) {
+		self.record(AccessKind::Iterate, Some(child_info), prefix);
+		self.backend.for_child_keys_with_prefix(child_info, prefix, f)
+	}
+
+	fn storage_root<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		let delta = delta.inspect(|(key, _)| self.record(AccessKind::Write, None, key));
+		self.backend.storage_root(delta)
+	}
+
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		let delta = delta.inspect(|(key, _)| self.record(AccessKind::Write, None, key));
+		self.backend.storage_root_presorted(delta)
+	}
+
+	fn storage_root_incremental<'a>(
+		&self,
+		root: H::Out,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		let delta = delta.inspect(|(key, _)| self.record(AccessKind::Write, None, key));
+		self.backend.storage_root_incremental(root, transaction, delta)
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		self.backend.supports_incremental_storage_root()
+	}
+
+	fn child_storage_root<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord {
+		let delta = delta.inspect(|(key, _)| self.record(AccessKind::Write, Some(child_info), key));
+		self.backend.child_storage_root(child_info, delta)
+	}
+
+	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
+		self.record(AccessKind::Iterate, None, &[]);
+		self.backend.pairs()
+	}
+
+	fn keys(&self, prefix: &[u8]) -> Vec<StorageKey> {
+		self.record(AccessKind::Iterate, None, prefix);
+		self.backend.keys(prefix)
+	}
+
+	fn child_keys(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+	) -> Vec<StorageKey> {
+		self.record(AccessKind::Iterate, Some(child_info), prefix);
+		self.backend.child_keys(child_info, prefix)
+	}
+
+	fn register_overlay_stats(&mut self, stats: &crate::stats::StateMachineStats) {
+		self.backend.register_overlay_stats(stats)
+	}
+
+	fn usage_info(&self) -> UsageInfo {
+		self.backend.usage_info()
+	}
+
+	fn proof_size(&self) -> Option<u32> {
+		self.backend.proof_size()
+	}
+
+	fn is_quota_exceeded(error: &Self::Error) -> bool {
+		B::is_quota_exceeded(error)
+	}
+
+	fn check_limits(&self) -> Result<(), Self::Error> {
+		self.backend.check_limits()
+	}
+
+	fn wipe(&self) -> Result<(), Self::Error> {
+		self.backend.wipe()
+	}
+
+	fn commit(
+		&self,
+		root: H::Out,
+		transaction: Self::Transaction,
+		main_storage_changes: StorageCollection,
+		child_storage_changes: ChildStorageCollection,
+	) -> Result<(), Self::Error> {
+		self.backend.commit(root, transaction, main_storage_changes, child_storage_changes)
+	}
+
+	fn read_write_count(&self) -> (u32, u32, u32, u32) {
+		self.backend.read_write_count()
+	}
+
+	fn reset_read_write_count(&self) {
+		self.backend.reset_read_write_count()
+	}
+
+	fn get_whitelist(&self) -> Vec<TrackedStorageKey> {
+		self.backend.get_whitelist()
+	}
+
+	fn set_whitelist(&self, whitelist: Vec<TrackedStorageKey>) {
+		self.backend.set_whitelist(whitelist)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::InMemoryBackend;
+	use sp_runtime::traits::BlakeTwo256;
+
+	fn backend() -> RecordingBackend<InMemoryBackend<BlakeTwo256>> {
+		let inner: InMemoryBackend<BlakeTwo256> = vec![
+			(None, vec![(b"a".to_vec(), Some(b"1".to_vec())), (b"b".to_vec(), Some(b"2".to_vec()))]),
+		].into();
+		RecordingBackend::new(inner, 3)
+	}
+
+	#[test]
+	fn accesses_are_recorded() {
+		let backend = backend();
+		let child_info = ChildInfo::new_default(b"child");
+
+		assert_eq!(backend.storage(b"a").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(backend.next_storage_key(b"a").unwrap(), Some(b"b".to_vec()));
+		assert_eq!(backend.child_storage(&child_info, b"c").unwrap(), None);
+
+		let records: Vec<_> = backend.records().into_iter()
+			.map(|r| (r.kind, r.child_storage_key, r.key))
+			.collect();
+		assert_eq!(records, vec![
+			(AccessKind::Read, None, b"a".to_vec()),
+			(AccessKind::NextKey, None, b"a".to_vec()),
+			(AccessKind::Read, Some(b"child".to_vec()), b"c".to_vec()),
+		]);
+		assert_eq!(backend.dropped(), 0);
+	}
+
+	#[test]
+	fn oldest_records_are_dropped() {
+		let backend = backend();
+		let _ = backend.storage_root(vec![
+			(&b"a"[..], None),
+			(&b"c"[..], Some(&b"3"[..])),
+		].into_iter());
+		backend.storage(b"b").unwrap();
+		backend.storage(b"d").unwrap();
+		assert_eq!(backend.dropped(), 1);
+
+		let keys: Vec<_> = backend.take_records().into_iter().map(|r| (r.kind, r.key)).collect();
+		assert_eq!(keys, vec![
+			(AccessKind::Write, b"c".to_vec()),
+			(AccessKind::Read, b"b".to_vec()),
+			(AccessKind::Read, b"d".to_vec()),
+		]);
+		assert_eq!(backend.dropped(), 0);
+		assert!(backend.records().is_empty());
+	}
+}
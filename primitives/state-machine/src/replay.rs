@@ -0,0 +1,183 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Re-execution of a block against the state of its parent.
+//!
+//! A replay runs the block with a given runtime and collects everything needed to debug the
+//! execution, like the storage accesses, the storage proof and the resulting changes.
+
+use std::sync::Arc;
+use hash_db::Hasher;
+use codec::Codec;
+use sp_core::{
+	offchain::storage::OffchainOverlayedChanges,
+	traits::{CodeExecutor, SpawnNamed},
+};
+use sp_externalities::Extensions;
+use crate::{
+	Error, ExecutionError, ExecutionStrategy, OverlayedChanges, StateMachine, StateTracer, StorageCollection,
+	ChildStorageCollection, StorageProof, StorageTransactionCache, TraceEntry, TrieBackend,
+	RuntimeCodeSubstitute, APPLY_EXTRINSIC_METHOD,
+	changes_trie::BlockNumber,
+	proving_backend::ProvingBackend,
+	trie_backend_essence::TrieBackendStorage,
+};
+
+/// The runtime method that initializes a block.
+const INITIALIZE_BLOCK_METHOD: &str = "Core_initialize_block";
+/// The runtime method that finalizes a block.
+const FINALIZE_BLOCK_METHOD: &str = "BlockBuilder_finalize_block";
+
+/// The block to replay.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayBlock<'a> {
+	/// The SCALE encoded header of the block.
+	pub header: &'a [u8],
+	/// The SCALE encoded extrinsics of the block.
+	pub extrinsics: &'a [Vec<u8>],
+}
+
+/// Everything that was collected while replaying a block.
+pub struct ReplayReport<H: Hasher> {
+	/// The runtime method and the SCALE encoded output of every call, in execution order.
+	pub outputs: Vec<(&'static str, Vec<u8>)>,
+	/// The storage operations of all calls.
+	pub trace: Vec<TraceEntry>,
+	/// Proof of all trie nodes of the parent state that were accessed.
+	pub proof: StorageProof,
+	/// The changes to the main storage.
+	pub main_storage_changes: StorageCollection,
+	/// The changes to the child storages.
+	pub child_storage_changes: ChildStorageCollection,
+	/// The storage root after applying the changes.
+	pub storage_root: H::Out,
+}
+
+/// Replay `block` on top of `trie_backend`, the state of its parent, using the given `runtime`.
+///
+/// The block is initialized, every extrinsic is applied and the block is finalized, like it is
+/// done when the block is built. Failed extrinsics don't abort the replay, their output is part
+/// of the report. Values in the trace are truncated to `max_traced_value_len` bytes.
+pub fn replay_block<S, H, N, Exec, Spawn>(
+	trie_backend: &TrieBackend<S, H>,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	block: ReplayBlock,
+	runtime: &RuntimeCodeSubstitute,
+	max_traced_value_len: usize,
+) -> Result<ReplayReport<H>, Box<dyn Error>>
+where
+	S: TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Ord + 'static + Codec,
+	Exec: CodeExecutor + Clone + 'static,
+	N: BlockNumber,
+	Spawn: SpawnNamed + Clone + Send + 'static,
+{
+	let proving_backend = ProvingBackend::new(trie_backend);
+	let tracer = Arc::new(StateTracer::new(max_traced_value_len));
+	let runtime_code = runtime.runtime_code();
+	let mut overlay = OverlayedChanges::default();
+	let mut offchain_overlay = OffchainOverlayedChanges::default();
+	let mut cache = StorageTransactionCache::<_, H, N>::default();
+
+	let calls = std::iter::once((INITIALIZE_BLOCK_METHOD, block.header))
+		.chain(block.extrinsics.iter().map(|xt| (APPLY_EXTRINSIC_METHOD, &xt[..])))
+		.chain(std::iter::once((FINALIZE_BLOCK_METHOD, &[][..])));
+
+	let mut outputs = Vec::new();
+	for (method, call_data) in calls {
+		let output = StateMachine::new(
+			&proving_backend,
+			None,
+			&mut overlay,
+			&mut offchain_overlay,
+			exec,
+			method,
+			call_data,
+			Extensions::default(),
+			&runtime_code,
+			spawn_handle.clone(),
+		)
+			.with_storage_transaction_cache(Some(&mut cache))
+			.with_tracer(tracer.clone())
+			.execute(ExecutionStrategy::AlwaysWasm)?;
+		outputs.push((method, output));
+	}
+
+	let changes = overlay.drain_storage_changes(
+		&proving_backend,
+		None,
+		Default::default(),
+		&mut cache,
+	).map_err(|e| Box::new(ExecutionError::Backend(e)) as Box<dyn Error>)?;
+
+	Ok(ReplayReport {
+		outputs,
+		trace: tracer.take_entries(),
+		proof: proving_backend.extract_proof(),
+		main_storage_changes: changes.main_storage_changes,
+		child_storage_changes: changes.child_storage_changes,
+		storage_root: changes.transaction_storage_root,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::testing::TaskExecutor;
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::{Backend, TraceOp, tests::DummyCodeExecutor, trie_backend::tests::test_trie};
+
+	#[test]
+	fn replay_block_works() {
+		let backend = test_trie();
+		let exec = DummyCodeExecutor {
+			change_changes_trie_config: true,
+			native_available: false,
+			native_succeeds: false,
+			fallback_succeeds: true,
+		};
+		let runtime = RuntimeCodeSubstitute::new::<BlakeTwo256>(vec![1, 2, 3], None);
+		let extrinsics = vec![vec![1], vec![2]];
+		let block = ReplayBlock { header: &[0], extrinsics: &extrinsics };
+
+		let report = replay_block::<_, _, u64, _, _>(
+			&backend,
+			&exec,
+			TaskExecutor::new(),
+			block,
+			&runtime,
+			32,
+		).unwrap();
+
+		let methods: Vec<_> = report.outputs.iter().map(|(method, _)| *method).collect();
+		assert_eq!(methods, vec![
+			INITIALIZE_BLOCK_METHOD,
+			APPLY_EXTRINSIC_METHOD,
+			APPLY_EXTRINSIC_METHOD,
+			FINALIZE_BLOCK_METHOD,
+		]);
+		assert!(report.trace.iter().any(|e| e.op == TraceOp::Get && e.key == b"value1".to_vec()));
+		assert!(!report.proof.is_empty());
+		assert_eq!(report.main_storage_changes.len(), 1);
+
+		let delta = report.main_storage_changes.iter()
+			.map(|(k, v)| (&k[..], v.as_ref().map(|v| &v[..])));
+		assert_eq!(report.storage_root, backend.storage_root(delta).0);
+	}
+}
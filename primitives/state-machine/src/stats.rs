@@ -19,6 +19,8 @@
 
 use std::time::{Instant, Duration};
 use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::StorageReadHint;
 
 /// Measured count of operations and total bytes.
 #[derive(Clone, Debug, Default)]
@@ -49,6 +51,10 @@ pub struct UsageInfo {
 	pub modified_reads: UsageUnit,
 	/// Memory used.
 	pub memory: usize,
+	/// Total time spent reading from the backend.
+	///
+	/// Reads served by a cache are not included.
+	pub read_duration: Duration,
 
 	/// Moment at which current statistics has been started being collected.
 	pub started: Instant,
@@ -73,6 +79,12 @@ pub struct StateMachineStats {
 	/// Size in bytes of the writes overlay
 	/// operation.
 	pub bytes_writes_overlay: RefCell<u64>,
+	/// Number of backend reads that the
+	/// backend served from its cache.
+	pub backend_reads_cached: RefCell<u64>,
+	/// Number of backend reads that required
+	/// disk IO.
+	pub backend_reads_uncached: RefCell<u64>,
 }
 
 impl StateMachineStats {
@@ -82,6 +94,8 @@ impl StateMachineStats {
 		*self.bytes_read_modified.borrow_mut() += *other.bytes_read_modified.borrow();
 		*self.writes_overlay.borrow_mut() += *other.writes_overlay.borrow();
 		*self.bytes_writes_overlay.borrow_mut() += *other.bytes_writes_overlay.borrow();
+		*self.backend_reads_cached.borrow_mut() += *other.backend_reads_cached.borrow();
+		*self.backend_reads_uncached.borrow_mut() += *other.backend_reads_uncached.borrow();
 	}
 }
 
@@ -99,10 +113,20 @@ impl UsageInfo {
 			cache_reads: UsageUnit::default(),
 			modified_reads: UsageUnit::default(),
 			memory: 0,
+			read_duration: Default::default(),
 			started: Instant::now(),
 			span: Default::default(),
 		}
 	}
+	/// Average time of a read that was not served by a cache.
+	pub fn average_read_duration(&self) -> Duration {
+		let uncached = self.reads.ops.saturating_sub(self.cache_reads.ops);
+		if uncached == 0 {
+			return Duration::default()
+		}
+		Duration::from_nanos((self.read_duration.as_nanos() / uncached as u128) as u64)
+	}
+
 	/// Add collected state machine to this state.
 	pub fn include_state_machine_states(&mut self, count: &StateMachineStats) {
 		self.modified_reads.ops += *count.reads_modified.borrow();
@@ -123,4 +147,97 @@ impl StateMachineStats {
 		*self.writes_overlay.borrow_mut() += 1;
 		*self.bytes_writes_overlay.borrow_mut() += data_bytes;
 	}
+	/// Tally one backend read with the given hint.
+	///
+	/// Reads the backend can't classify are ignored.
+	pub fn tally_backend_read(&self, hint: StorageReadHint) {
+		match hint {
+			StorageReadHint::Cache => *self.backend_reads_cached.borrow_mut() += 1,
+			StorageReadHint::Disk => *self.backend_reads_uncached.borrow_mut() += 1,
+			StorageReadHint::Unknown => (),
+		}
+	}
+	/// The share of the classified backend reads that were served from a cache.
+	///
+	/// `None` if no read was classified.
+	pub fn warm_cache_ratio(&self) -> Option<f64> {
+		let cached = *self.backend_reads_cached.borrow();
+		let total = cached + *self.backend_reads_uncached.borrow();
+		if total == 0 {
+			None
+		} else {
+			Some(cached as f64 / total as f64)
+		}
+	}
+}
+
+/// Thread safe accumulator for the reads of a backend.
+#[derive(Debug)]
+pub(crate) struct ReadStats {
+	started: Instant,
+	ops: AtomicU64,
+	bytes: AtomicU64,
+	nanos: AtomicU64,
+}
+
+impl Default for ReadStats {
+	fn default() -> Self {
+		ReadStats {
+			started: Instant::now(),
+			ops: Default::default(),
+			bytes: Default::default(),
+			nanos: Default::default(),
+		}
+	}
+}
+
+impl ReadStats {
+	/// Tally one read of `data_bytes` that took `duration`.
+	pub fn tally_read(&self, data_bytes: u64, duration: Duration) {
+		self.ops.fetch_add(1, Ordering::Relaxed);
+		self.bytes.fetch_add(data_bytes, Ordering::Relaxed);
+		self.nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+	}
+
+	/// Returns the collected statistics.
+	pub fn usage_info(&self) -> UsageInfo {
+		let mut info = UsageInfo::empty();
+		info.reads.ops = self.ops.load(Ordering::Relaxed);
+		info.reads.bytes = self.bytes.load(Ordering::Relaxed);
+		info.read_duration = Duration::from_nanos(self.nanos.load(Ordering::Relaxed));
+		info.started = self.started;
+		info.span = self.started.elapsed();
+		info
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn warm_cache_ratio_ignores_unknown_reads() {
+		let stats = StateMachineStats::default();
+		assert_eq!(stats.warm_cache_ratio(), None);
+
+		stats.tally_backend_read(StorageReadHint::Unknown);
+		assert_eq!(stats.warm_cache_ratio(), None);
+
+		stats.tally_backend_read(StorageReadHint::Cache);
+		stats.tally_backend_read(StorageReadHint::Cache);
+		stats.tally_backend_read(StorageReadHint::Cache);
+		stats.tally_backend_read(StorageReadHint::Disk);
+		assert_eq!(stats.warm_cache_ratio(), Some(0.75));
+	}
+
+	#[test]
+	fn average_read_duration_ignores_cache_reads() {
+		let mut info = UsageInfo::empty();
+		assert_eq!(info.average_read_duration(), Duration::default());
+
+		info.reads.ops = 6;
+		info.cache_reads.ops = 2;
+		info.read_duration = Duration::from_millis(8);
+		assert_eq!(info.average_read_duration(), Duration::from_millis(2));
+	}
 }
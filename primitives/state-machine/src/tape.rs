@@ -0,0 +1,702 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording of host calls and their deterministic replay.
+//!
+//! A [`RecordingExternalities`] records every call the runtime makes into the externalities,
+//! together with its result, on an [`ExternalitiesTape`]. A [`ReplayExternalities`] answers
+//! the calls of a later execution from the tape, without any access to the original state,
+//! and reports the first call that does not match the tape. This allows to reproduce
+//! non-deterministic runtime behaviour without the database it was observed on.
+
+use std::any::{Any, TypeId};
+use codec::{Decode, Encode};
+use parking_lot::Mutex;
+use sp_core::{
+	hexdisplay::HexDisplay,
+	storage::{ChildInfo, TrackedStorageKey},
+	traits::Externalities,
+};
+use sp_externalities::{DeferredCheck, Extension, ExtensionStore, Extensions, TrieProofLayout};
+
+/// A single recorded host call.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct TapeEntry {
+	/// The name of the called [`Externalities`] method.
+	pub call: String,
+	/// The SCALE encoded arguments of the call.
+	pub input: Vec<u8>,
+	/// The SCALE encoded result of the call.
+	pub output: Vec<u8>,
+}
+
+/// The host calls recorded by [`RecordingExternalities`], in the order they were made.
+///
+/// The tape can be shared by all calls of a block to record the whole block execution.
+/// Calls to register, access or deregister extensions are not recorded.
+#[derive(Default)]
+pub struct ExternalitiesTape {
+	entries: Mutex<Vec<TapeEntry>>,
+}
+
+impl ExternalitiesTape {
+	/// Create an empty tape.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	fn record<O: Encode>(&self, call: &str, input: Vec<u8>, output: &O) {
+		self.entries.lock().push(TapeEntry {
+			call: call.into(),
+			input,
+			output: output.encode(),
+		});
+	}
+
+	/// Returns the recorded calls, oldest first.
+	pub fn entries(&self) -> Vec<TapeEntry> {
+		self.entries.lock().clone()
+	}
+
+	/// Returns the recorded calls and clears the tape.
+	pub fn take_entries(&self) -> Vec<TapeEntry> {
+		std::mem::replace(&mut *self.entries.lock(), Vec::new())
+	}
+}
+
+impl std::fmt::Debug for ExternalitiesTape {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "ExternalitiesTape")
+	}
+}
+
+/// Wraps externalities and records every host call on an [`ExternalitiesTape`].
+pub struct RecordingExternalities<'a> {
+	inner: &'a mut dyn Externalities,
+	tape: &'a ExternalitiesTape,
+}
+
+impl<'a> RecordingExternalities<'a> {
+	/// Record the calls to `inner` on `tape`.
+	pub fn new(inner: &'a mut dyn Externalities, tape: &'a ExternalitiesTape) -> Self {
+		RecordingExternalities { inner, tape }
+	}
+
+	fn record<O: Encode>(&self, call: &str, input: Vec<u8>, output: O) -> O {
+		self.tape.record(call, input, &output);
+		output
+	}
+}
+
+impl<'a> Externalities for RecordingExternalities<'a> {
+	fn set_offchain_storage(&mut self, key: &[u8], value: Option<&[u8]>) {
+		self.inner.set_offchain_storage(key, value);
+		self.record("set_offchain_storage", (key, value).encode(), ())
+	}
+
+	fn storage(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.record("storage", key.encode(), self.inner.storage(key))
+	}
+
+	fn storage_hash(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.record("storage_hash", key.encode(), self.inner.storage_hash(key))
+	}
+
+	fn child_storage_hash(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Vec<u8>> {
+		let output = self.inner.child_storage_hash(child_info, key);
+		self.record("child_storage_hash", (child_info.storage_key(), key).encode(), output)
+	}
+
+	fn child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Vec<u8>> {
+		let output = self.inner.child_storage(child_info, key);
+		self.record("child_storage", (child_info.storage_key(), key).encode(), output)
+	}
+
+	fn set_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
+		let input = (&key, &value).encode();
+		self.inner.set_storage(key, value);
+		self.record("set_storage", input, ())
+	}
+
+	fn set_child_storage(&mut self, child_info: &ChildInfo, key: Vec<u8>, value: Vec<u8>) {
+		let input = (child_info.storage_key(), &key, &value).encode();
+		self.inner.set_child_storage(child_info, key, value);
+		self.record("set_child_storage", input, ())
+	}
+
+	fn clear_storage(&mut self, key: &[u8]) {
+		self.inner.clear_storage(key);
+		self.record("clear_storage", key.encode(), ())
+	}
+
+	fn clear_child_storage(&mut self, child_info: &ChildInfo, key: &[u8]) {
+		self.inner.clear_child_storage(child_info, key);
+		self.record("clear_child_storage", (child_info.storage_key(), key).encode(), ())
+	}
+
+	fn exists_storage(&self, key: &[u8]) -> bool {
+		self.record("exists_storage", key.encode(), self.inner.exists_storage(key))
+	}
+
+	fn exists_child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> bool {
+		let output = self.inner.exists_child_storage(child_info, key);
+		self.record("exists_child_storage", (child_info.storage_key(), key).encode(), output)
+	}
+
+	fn storage_size(&self, key: &[u8]) -> Option<u32> {
+		self.record("storage_size", key.encode(), self.inner.storage_size(key))
+	}
+
+	fn child_storage_size(&self, child_info: &ChildInfo, key: &[u8]) -> Option<u32> {
+		let output = self.inner.child_storage_size(child_info, key);
+		self.record("child_storage_size", (child_info.storage_key(), key).encode(), output)
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.record("next_storage_key", key.encode(), self.inner.next_storage_key(key))
+	}
+
+	fn next_child_storage_key(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Vec<u8>> {
+		let output = self.inner.next_child_storage_key(child_info, key);
+		self.record("next_child_storage_key", (child_info.storage_key(), key).encode(), output)
+	}
+
+	fn kill_child_storage(&mut self, child_info: &ChildInfo) {
+		self.inner.kill_child_storage(child_info);
+		self.record("kill_child_storage", child_info.storage_keyVec::new(), ())
+	}
+
+	fn clear_prefix(&mut self, prefix: &[u8]) {
+		self.inner.clear_prefix(prefix);
+		self.record("clear_prefix", prefix.encode(), ())
+	}
+
+	fn clear_child_prefix(&mut self, child_info: &ChildInfo, prefix: &[u8]) {
+		self.inner.clear_child_prefix(child_info, prefix);
+		self.record("clear_child_prefix", (child_info.storage_key(), prefix).encode(), ())
+	}
+
+	fn place_storage(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+		let input = (&key, &value).encode();
+		self.inner.place_storage(key, value);
+		self.record("place_storage", input, ())
+	}
+
+	fn place_storage_ns(&mut self, ns: u32, key: Vec<u8>, value: Option<Vec<u8>>) {
+		let input = (ns, &key, &value).encode();
+		self.inner.place_storage_ns(ns, key, value);
+		self.record("place_storage_ns", input, ())
+	}
+
+	fn place_child_storage(&mut self, child_info: &ChildInfo, key: Vec<u8>, value: Option<Vec<u8>>) {
+		let input = (child_info.storage_key(), &key, &value).encode();
+		self.inner.place_child_storage(child_info, key, value);
+		self.record("place_child_storage", input, ())
+	}
+
+	fn chain_id(&self) -> u64 {
+		self.record("chain_id", Vec::new(), self.inner.chain_id())
+	}
+
+	fn storage_root(&mut self) -> Vec<u8> {
+		let output = self.inner.storage_root();
+		self.record("storage_root", Vec::new(), output)
+	}
+
+	fn child_storage_root(&mut self, child_info: &ChildInfo) -> Vec<u8> {
+		let output = self.inner.child_storage_root(child_info);
+		self.record("child_storage_root", child_info.storage_keyVec::new(), output)
+	}
+
+	fn storage_append(&mut self, key: Vec<u8>, value: Vec<u8>) {
+		let input = (&key, &value).encode();
+		self.inner.storage_append(key, value);
+		self.record("storage_append", input, ())
+	}
+
+	fn storage_changes_root(&mut self, parent: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+		let output = self.inner.storage_changes_root(parent);
+		self.record("storage_changes_root", parent.encode(), output)
+	}
+
+	fn disable_changes_trie_for_block(&mut self) {
+		self.inner.disable_changes_trie_for_block();
+		self.record("disable_changes_trie_for_block", Vec::new(), ())
+	}
+
+	fn register_deferred_check(&mut self, check: DeferredCheck) {
+		self.inner.register_deferred_check(check);
+		self.record("register_deferred_check", Vec::new(), ())
+	}
+
+	fn verify_trie_proof(
+		&self,
+		root: &[u8],
+		proof: &[Vec<u8>],
+		items: &[(Vec<u8>, Option<Vec<u8>>)],
+		layout: TrieProofLayout,
+	) -> bool {
+		let output = self.inner.verify_trie_proof(root, proof, items, layout);
+		self.record("verify_trie_proof", (root, proof, items, layout as u8).encode(), output)
+	}
+
+	fn blob_put(&mut self, value: Vec<u8>) -> Vec<u8> {
+		let input = value.encode();
+		let output = self.inner.blob_put(value);
+		self.record("blob_put", input, output)
+	}
+
+	fn blob_get(&self, handle: &[u8]) -> Option<Vec<u8>> {
+		self.record("blob_get", handle.encode(), self.inner.blob_get(handle))
+	}
+
+	fn blob_remove(&mut self, handle: &[u8]) -> bool {
+		let output = self.inner.blob_remove(handle);
+		self.record("blob_remove", handle.encode(), output)
+	}
+
+	fn transient_storage(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.record("transient_storage", key.encode(), self.inner.transient_storage(key))
+	}
+
+	fn set_transient_storage(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+		let input = (&key, &value).encode();
+		self.inner.set_transient_storage(key, value);
+		self.record("set_transient_storage", input, ())
+	}
+
+	fn storage_start_transaction(&mut self) {
+		self.inner.storage_start_transaction();
+		self.record("storage_start_transaction", Vec::new(), ())
+	}
+
+	fn storage_rollback_transaction(&mut self) -> Result<(), ()> {
+		let output = self.inner.storage_rollback_transaction();
+		self.record("storage_rollback_transaction", Vec::new(), output)
+	}
+
+	fn storage_commit_transaction(&mut self) -> Result<(), ()> {
+		let output = self.inner.storage_commit_transaction();
+		self.record("storage_commit_transaction", Vec::new(), output)
+	}
+
+	fn wipe(&mut self) {
+		self.inner.wipe();
+		self.record("wipe", Vec::new(), ())
+	}
+
+	fn commit(&mut self) {
+		self.inner.commit();
+		self.record("commit", Vec::new(), ())
+	}
+
+	fn read_write_count(&self) -> (u32, u32, u32, u32) {
+		self.record("read_write_count", Vec::new(), self.inner.read_write_count())
+	}
+
+	fn reset_read_write_count(&mut self) {
+		self.inner.reset_read_write_count();
+		self.record("reset_read_write_count", Vec::new(), ())
+	}
+
+	fn get_whitelist(&self) -> Vec<TrackedStorageKey> {
+		self.record("get_whitelist", Vec::new(), self.inner.get_whitelist())
+	}
+
+	fn set_whitelist(&mut self, new: Vec<TrackedStorageKey>) {
+		let input = new.encode();
+		self.inner.set_whitelist(new);
+		self.record("set_whitelist", input, ())
+	}
+}
+
+impl<'a> ExtensionStore for RecordingExternalities<'a> {
+	fn extension_by_type_id(&mut self, type_id: TypeId) -> Option<&mut dyn Any> {
+		self.inner.extension_by_type_id(type_id)
+	}
+
+	fn register_extension_with_type_id(
+		&mut self,
+		type_id: TypeId,
+		extension: Box<dyn Extension>,
+	) -> Result<(), sp_externalities::Error> {
+		self.inner.register_extension_with_type_id(type_id, extension)
+	}
+
+	fn deregister_extension_by_type_id(&mut self, type_id: TypeId) -> Result<(), sp_externalities::Error> {
+		self.inner.deregister_extension_by_type_id(type_id)
+	}
+}
+
+/// The first host call of a replay that did not match the tape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeDivergence {
+	/// The position of the call on the tape.
+	pub position: usize,
+	/// The call that was recorded at this position, `None` if the tape ended.
+	pub expected: Option<TapeEntry>,
+	/// The name of the call that was made instead.
+	pub call: String,
+	/// The SCALE encoded arguments of the call that was made instead.
+	pub input: Vec<u8>,
+}
+
+impl std::fmt::Display for TapeDivergence {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.expected {
+			Some(expected) => write!(
+				f,
+				"Host call {} diverged from the tape: expected {}(0x{}), got {}(0x{})",
+				self.position,
+				expected.call,
+				HexDisplay::from(&expected.input),
+				self.call,
+				HexDisplay::from(&self.input),
+			),
+			None => write!(
+				f,
+				"Host call {} diverged from the tape: tape ended, got {}(0x{})",
+				self.position,
+				self.call,
+				HexDisplay::from(&self.input),
+			),
+		}
+	}
+}
+
+#[derive(Default)]
+struct ReplayState {
+	position: usize,
+	divergence: Option<TapeDivergence>,
+}
+
+/// Externalities that answer every host call from a tape recorded by
+/// [`RecordingExternalities`].
+///
+/// Every call has to match the next call on the tape, otherwise the replay panics and the
+/// divergence is available through [`ReplayExternalities::divergence`]. Deferred checks
+/// registered by the runtime are dropped, their outcome is not part of the tape.
+pub struct ReplayExternalities {
+	entries: Vec<TapeEntry>,
+	state: Mutex<ReplayState>,
+	extensions: Extensions,
+}
+
+impl ReplayExternalities {
+	/// Replay the given recorded calls.
+	pub fn new(entries: Vec<TapeEntry>) -> Self {
+		ReplayExternalities {
+			entries,
+			state: Default::default(),
+			extensions: Default::default(),
+		}
+	}
+
+	/// Returns the first call that did not match the tape, if any.
+	pub fn divergence(&self) -> Option<TapeDivergence> {
+		self.state.lock().divergence.clone()
+	}
+
+	/// Returns true if every call on the tape was replayed.
+	pub fn is_finished(&self) -> bool {
+		self.state.lock().position == self.entries.len()
+	}
+
+	fn replay<I: Encode, O: Decode>(&self, call: &str, input: I) -> O {
+		let input = input.encode();
+		let mut state = self.state.lock();
+		if let Some(divergence) = state.divergence.as_ref() {
+			panic!("{}", divergence)
+		}
+
+		let position = state.position;
+		let expected = self.entries.get(position);
+		match expected {
+			Some(entry) if entry.call == call && entry.input == input => {
+				state.position += 1;
+				O::decode(&mut &entry.output[..])
+					.unwrap_or_else(|_| panic!("Invalid output of host call {} on the tape", position))
+			},
+			_ => {
+				let divergence = TapeDivergence {
+					position,
+					expected: expected.cloned(),
+					call: call.into(),
+					input,
+				};
+				let message = divergence.to_string();
+				state.divergence = Some(divergence);
+				panic!("{}", message)
+			},
+		}
+	}
+}
+
+impl std::fmt::Debug for ReplayExternalities {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "ReplayExternalities")
+	}
+}
+
+impl Externalities for ReplayExternalities {
+	fn set_offchain_storage(&mut self, key: &[u8], value: Option<&[u8]>) {
+		self.replay("set_offchain_storage", (key, value))
+	}
+
+	fn storage(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.replay("storage", key)
+	}
+
+	fn storage_hash(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.replay("storage_hash", key)
+	}
+
+	fn child_storage_hash(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Vec<u8>> {
+		self.replay("child_storage_hash", (child_info.storage_key(), key))
+	}
+
+	fn child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Vec<u8>> {
+		self.replay("child_storage", (child_info.storage_key(), key))
+	}
+
+	fn set_storage(&mut self, key: Vec<u8>, value: Vec<u8>) {
+		self.replay("set_storage", (key, value))
+	}
+
+	fn set_child_storage(&mut self, child_info: &ChildInfo, key: Vec<u8>, value: Vec<u8>) {
+		self.replay("set_child_storage", (child_info.storage_key(), key, value))
+	}
+
+	fn clear_storage(&mut self, key: &[u8]) {
+		self.replay("clear_storage", key)
+	}
+
+	fn clear_child_storage(&mut self, child_info: &ChildInfo, key: &[u8]) {
+		self.replay("clear_child_storage", (child_info.storage_key(), key))
+	}
+
+	fn exists_storage(&self, key: &[u8]) -> bool {
+		self.replay("exists_storage", key)
+	}
+
+	fn exists_child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> bool {
+		self.replay("exists_child_storage", (child_info.storage_key(), key))
+	}
+
+	fn storage_size(&self, key: &[u8]) -> Option<u32> {
+		self.replay("storage_size", key)
+	}
+
+	fn child_storage_size(&self, child_info: &ChildInfo, key: &[u8]) -> Option<u32> {
+		self.replay("child_storage_size", (child_info.storage_key(), key))
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.replay("next_storage_key", key)
+	}
+
+	fn next_child_storage_key(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Vec<u8>> {
+		self.replay("next_child_storage_key", (child_info.storage_key(), key))
+	}
+
+	fn kill_child_storage(&mut self, child_info: &ChildInfo) {
+		self.replay("kill_child_storage", child_info.storage_key())
+	}
+
+	fn clear_prefix(&mut self, prefix: &[u8]) {
+		self.replay("clear_prefix", prefix)
+	}
+
+	fn clear_child_prefix(&mut self, child_info: &ChildInfo, prefix: &[u8]) {
+		self.replay("clear_child_prefix", (child_info.storage_key(), prefix))
+	}
+
+	fn place_storage(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+		self.replay("place_storage", (key, value))
+	}
+
+	fn place_storage_ns(&mut self, ns: u32, key: Vec<u8>, value: Option<Vec<u8>>) {
+		self.replay("place_storage_ns", (ns, key, value))
+	}
+
+	fn place_child_storage(&mut self, child_info: &ChildInfo, key: Vec<u8>, value: Option<Vec<u8>>) {
+		self.replay("place_child_storage", (child_info.storage_key(), key, value))
+	}
+
+	fn chain_id(&self) -> u64 {
+		self.replay("chain_id", ())
+	}
+
+	fn storage_root(&mut self) -> Vec<u8> {
+		self.replay("storage_root", ())
+	}
+
+	fn child_storage_root(&mut self, child_info: &ChildInfo) -> Vec<u8> {
+		self.replay("child_storage_root", child_info.storage_key())
+	}
+
+	fn storage_append(&mut self, key: Vec<u8>, value: Vec<u8>) {
+		self.replay("storage_append", (key, value))
+	}
+
+	fn storage_changes_root(&mut self, parent: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+		self.replay("storage_changes_root", parent)
+	}
+
+	fn disable_changes_trie_for_block(&mut self) {
+		self.replay("disable_changes_trie_for_block", ())
+	}
+
+	fn register_deferred_check(&mut self, _check: DeferredCheck) {
+		self.replay("register_deferred_check", ())
+	}
+
+	fn verify_trie_proof(
+		&self,
+		root: &[u8],
+		proof: &[Vec<u8>],
+		items: &[(Vec<u8>, Option<Vec<u8>>)],
+		layout: TrieProofLayout,
+	) -> bool {
+		self.replay("verify_trie_proof", (root, proof, items, layout as u8))
+	}
+
+	fn blob_put(&mut self, value: Vec<u8>) -> Vec<u8> {
+		self.replay("blob_put", value)
+	}
+
+	fn blob_get(&self, handle: &[u8]) -> Option<Vec<u8>> {
+		self.replay("blob_get", handle)
+	}
+
+	fn blob_remove(&mut self, handle: &[u8]) -> bool {
+		self.replay("blob_remove", handle)
+	}
+
+	fn transient_storage(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.replay("transient_storage", key)
+	}
+
+	fn set_transient_storage(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+		self.replay("set_transient_storage", (key, value))
+	}
+
+	fn storage_start_transaction(&mut self) {
+		self.replay("storage_start_transaction", ())
+	}
+
+	fn storage_rollback_transaction(&mut self) -> Result<(), ()> {
+		self.replay("storage_rollback_transaction", ())
+	}
+
+	fn storage_commit_transaction(&mut self) -> Result<(), ()> {
+		self.replay("storage_commit_transaction", ())
+	}
+
+	fn wipe(&mut self) {
+		self.replay("wipe", ())
+	}
+
+	fn commit(&mut self) {
+		self.replay("commit", ())
+	}
+
+	fn read_write_count(&self) -> (u32, u32, u32, u32) {
+		self.replay("read_write_count", ())
+	}
+
+	fn reset_read_write_count(&mut self) {
+		self.replay("reset_read_write_count", ())
+	}
+
+	fn get_whitelist(&self) -> Vec<TrackedStorageKey> {
+		self.replay("get_whitelist", ())
+	}
+
+	fn set_whitelist(&mut self, new: Vec<TrackedStorageKey>) {
+		self.replay("set_whitelist", new)
+	}
+}
+
+impl ExtensionStore for ReplayExternalities {
+	fn extension_by_type_id(&mut self, type_id: TypeId) -> Option<&mut dyn Any> {
+		self.extensions.get_mut(type_id)
+	}
+
+	fn register_extension_with_type_id(
+		&mut self,
+		type_id: TypeId,
+		extension: Box<dyn Extension>,
+	) -> Result<(), sp_externalities::Error> {
+		self.extensions.register_with_type_id(type_id, extension)
+	}
+
+	fn deregister_extension_by_type_id(&mut self, type_id: TypeId) -> Result<(), sp_externalities::Error> {
+		self.extensions
+			.deregister(type_id)
+			.ok_or(sp_externalities::Error::ExtensionIsNotRegistered(type_id))
+			.map(drop)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::BasicExternalities;
+
+	fn run(ext: &mut dyn Externalities) -> Option<Vec<u8>> {
+		ext.set_storage(b"a".to_vec(), b"1".to_vec());
+		ext.storage_start_transaction();
+		ext.clear_prefix(b"b");
+		ext.storage_rollback_transaction().unwrap();
+		ext.storage_root();
+		ext.storage(b"b")
+	}
+
+	#[test]
+	fn replay_answers_from_tape() {
+		let tape = ExternalitiesTape::new();
+		let mut ext = BasicExternalities::default();
+		ext.insert(b"b".to_vec(), b"2".to_vec());
+		let recorded = run(&mut RecordingExternalities::new(&mut ext, &tape));
+		assert_eq!(recorded, Some(b"2".to_vec()));
+
+		let mut replay = ReplayExternalities::new(tape.entries());
+		assert_eq!(run(&mut replay), recorded);
+		assert!(replay.is_finished());
+		assert_eq!(replay.divergence(), None);
+	}
+
+	#[test]
+	fn replay_detects_divergence() {
+		let tape = ExternalitiesTape::new();
+		run(&mut RecordingExternalities::new(&mut BasicExternalities::default(), &tape));
+
+		let mut replay = ReplayExternalities::new(tape.take_entries());
+		replay.set_storage(b"a".to_vec(), b"1".to_vec());
+		let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+			replay.clear_prefix(b"c");
+		}));
+		assert!(result.is_err());
+
+		let divergence = replay.divergence().unwrap();
+		assert_eq!(divergence.position, 1);
+		assert_eq!(divergence.expected.unwrap().call, "storage_start_transaction");
+		assert_eq!(divergence.call, "clear_prefix");
+		assert!(tape.entries().is_empty());
+	}
+}
@@ -17,12 +17,13 @@
 
 //! Test implementation for Externalities.
 
-use std::any::{Any, TypeId};
+use std::{any::{Any, TypeId}, collections::{BTreeMap, HashMap}, sync::Arc};
 use codec::Decode;
 use hash_db::Hasher;
+use parking_lot::RwLock;
 use crate::{
 	backend::Backend, OverlayedChanges, StorageTransactionCache, ext::Ext, InMemoryBackend,
-	StorageKey, StorageValue,
+	StorageKey, StorageValue, ProvingBackend, StorageProof,
 	changes_trie::{
 		Configuration as ChangesTrieConfiguration,
 		InMemoryStorage as ChangesTrieInMemoryStorage,
@@ -32,12 +33,13 @@ use crate::{
 };
 use sp_core::{
 	offchain::{
-		testing::TestPersistentOffchainDB,
+		OffchainExt,
+		testing::{OffchainState, TestOffchainExt, TestPersistentOffchainDB},
 		storage::OffchainOverlayedChanges
 	},
 	storage::{
 		well_known_keys::{CHANGES_TRIE_CONFIG, CODE, HEAP_PAGES, is_child_storage_key},
-		Storage,
+		ChildInfo, Storage, StorageChild,
 	},
 	traits::TaskExecutorExt,
 	testing::TaskExecutor,
@@ -127,6 +129,43 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		}
 	}
 
+	/// Create a new instance of `TestExternalities` from the raw genesis of a chain spec.
+	///
+	/// `json` needs to contain a `genesis.raw` section with hex encoded keys and values, as
+	/// produced by `build-spec --raw`.
+	pub fn from_chain_spec_json(json: &[u8]) -> Result<Self, String> {
+		let spec: serde_json::Value = serde_json::from_slice(json)
+			.map_err(|e| format!("Invalid chain spec: {}", e))?;
+		let raw = spec.get("genesis")
+			.and_then(|genesis| genesis.get("raw"))
+			.ok_or_else(|| "Chain spec contains no raw genesis".to_string())?;
+
+		let mut top = raw.get("top")
+			.map(parse_raw_storage_map)
+			.transpose()?
+			.unwrap_or_default();
+		let mut children_default = HashMap::new();
+		if let Some(children) = raw.get("childrenDefault") {
+			let children = children.as_object()
+				.ok_or_else(|| "`childrenDefault` is not an object".to_string())?;
+			for (storage_key, data) in children {
+				let child_info = ChildInfo::new_default_from_vec(parse_hex(storage_key)?);
+				children_default.insert(
+					child_info.prefixed_storage_key().into_inner(),
+					StorageChild { data: parse_raw_storage_map(data)?, child_info },
+				);
+			}
+		}
+
+		let code = top.remove(CODE).unwrap_or_default();
+		let heap_pages = top.remove(HEAP_PAGES);
+		let mut ext = Self::new_with_code(&code, Storage { top, children_default });
+		if let Some(heap_pages) = heap_pages {
+			ext.insert(HEAP_PAGES.to_vec(), heap_pages);
+		}
+		Ok(ext)
+	}
+
 	/// Move offchain changes from overlay to the persistent store.
 	pub fn persist_offchain_overlay(&mut self) {
 		self.offchain_db.apply_offchain_changes(&mut self.offchain_overlay);
@@ -142,6 +181,16 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		self.backend.insert(vec![(None, vec![(k, Some(v))])]);
 	}
 
+	/// Register a [`TestOffchainExt`] that uses the offchain database of this instance.
+	///
+	/// Returns the shared state of the extension, which allows to script HTTP responses and to
+	/// control the timestamp and the random seed seen by the offchain worker.
+	pub fn register_offchain_ext(&mut self) -> Arc<RwLock<OffchainState>> {
+		let (offchain, state) = TestOffchainExt::with_offchain_db(self.offchain_db());
+		self.register_extension(OffchainExt::new(offchain));
+		state
+	}
+
 	/// Registers the given extension for this instance.
 	pub fn register_extension<E: Any + Extension>(&mut self, ext: E) {
 		self.extensions.register(ext);
@@ -152,8 +201,10 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		&mut self.changes_trie_storage
 	}
 
-	/// Return a new backend with all pending value.
-	pub fn commit_all(&self) -> InMemoryBackend<H> {
+	/// Return a new backend with all pending changes.
+	///
+	/// In contrast to [`commit_all`](Self::commit_all), this does not modify `self`.
+	pub fn as_backend(&self) -> InMemoryBackend<H> {
 		let top: Vec<_> = self.overlay.changes()
 			.map(|(k, v)| (k.clone(), v.value().cloned()))
 			.collect();
@@ -171,6 +222,30 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		self.backend.update(transaction)
 	}
 
+	/// Commit all pending changes to the underlying backend.
+	///
+	/// Afterwards the overlay is empty and the backend root reflects all changes made so far.
+	/// This allows to simulate multiple blocks with sequential calls to `execute_with`.
+	///
+	/// Returns an error if the changes can not be drained from the overlay, e.g. because the
+	/// journal enabled on it failed, see [`OverlayedChanges::enable_journal`]. The backend is
+	/// left unchanged in that case.
+	///
+	/// Panics if there are still open transactions, as the changes of open transactions can
+	/// neither be committed nor dropped at this point.
+	pub fn commit_all(&mut self) -> Result<(), String> {
+		let changes = self.overlay.drain_storage_changes::<_, _, N>(
+			&self.backend,
+			None,
+			Default::default(),
+			&mut Default::default(),
+		)?;
+
+		self.storage_transaction_cache.reset();
+		self.backend.apply_transaction(changes.transaction_storage_root, changes.transaction);
+		Ok(())
+	}
+
 	/// Execute the given closure while `self` is set as externalities.
 	///
 	/// Returns the result of the given closure.
@@ -178,6 +253,47 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		let mut ext = self.ext();
 		sp_externalities::set_and_run_with_externalities(&mut ext, execute)
 	}
+
+	/// Execute the given closure while `self`, with a proving backend, is set as externalities.
+	///
+	/// Returns the result of the given closure, the proof of all storage accesses done by the
+	/// closure and the storage root the proof can be checked against.
+	pub fn execute_and_prove<R>(
+		&mut self,
+		execute: impl FnOnce() -> R,
+	) -> (R, StorageProof, H::Out) {
+		let root = self.backend.root().clone();
+		let proving_backend = ProvingBackend::new(&self.backend);
+		let mut proving_ext = Ext::new(
+			&mut self.overlay,
+			&mut self.offchain_overlay,
+			&mut self.storage_transaction_cache,
+			&proving_backend,
+			None,
+			Some(&mut self.extensions),
+		);
+
+		let outcome = sp_externalities::set_and_run_with_externalities(&mut proving_ext, execute);
+		let proof = proving_backend.extract_proof();
+
+		(outcome, proof, root)
+	}
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+	sp_core::bytes::from_hex(hex).map_err(|e| format!("Invalid hex `{}`: {}", hex, e))
+}
+
+fn parse_raw_storage_map(map: &serde_json::Value) -> Result<BTreeMap<StorageKey, StorageValue>, String> {
+	map.as_object()
+		.ok_or_else(|| "Raw storage is not an object".to_string())?
+		.iter()
+		.map(|(key, value)| {
+			let value = value.as_str()
+				.ok_or_else(|| format!("Value of `{}` is not a string", key))?;
+			Ok((parse_hex(key)?, parse_hex(value)?))
+		})
+		.collect()
 }
 
 impl<H: Hasher, N: ChangesTrieBlockNumber> std::fmt::Debug for TestExternalities<H, N>
@@ -195,7 +311,7 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> PartialEq for TestExternalities<H, N>
 	/// This doesn't test if they are in the same state, only if they contains the
 	/// same data at this state
 	fn eq(&self, other: &TestExternalities<H, N>) -> bool {
-		self.commit_all().eq(&other.commit_all())
+		self.as_backend().eq(&other.as_backend())
 	}
 }
 
@@ -258,6 +374,107 @@ mod tests {
 		assert_eq!(H256::from_slice(ext.storage_root().as_slice()), root);
 	}
 
+	#[test]
+	fn commit_all_and_execute_with_share_state() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+
+		ext.execute_with(|| {
+			sp_externalities::with_externalities(|ext| {
+				ext.set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+				ext.set_storage(b"dog".to_vec(), b"puppy".to_vec());
+			}).unwrap()
+		});
+		let root = ext.as_backend().storage_root(std::iter::empty()).0;
+
+		ext.commit_all().unwrap();
+		assert!(ext.overlay.is_empty());
+		assert_eq!(*ext.backend.root(), root);
+
+		ext.execute_with(|| {
+			sp_externalities::with_externalities(|ext| {
+				assert_eq!(ext.storage(b"doe"), Some(b"reindeer".to_vec()));
+				ext.set_storage(b"dogglesworth".to_vec(), b"cat".to_vec());
+			}).unwrap()
+		});
+		ext.commit_all().unwrap();
+
+		let mut expected = TestExternalities::<BlakeTwo256, u64>::default();
+		let mut expected = expected.ext();
+		expected.set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+		expected.set_storage(b"dog".to_vec(), b"puppy".to_vec());
+		expected.set_storage(b"dogglesworth".to_vec(), b"cat".to_vec());
+		assert_eq!(ext.backend.root().as_ref(), &expected.storage_root()[..]);
+	}
+
+	#[test]
+	fn execute_and_prove_works() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.insert(b"doe".to_vec(), b"reindeer".to_vec());
+		ext.insert(b"dog".to_vec(), b"puppy".to_vec());
+
+		let (value, proof, root) = ext.execute_and_prove(|| {
+			sp_externalities::with_externalities(|ext| ext.storage(b"doe")).unwrap()
+		});
+		assert_eq!(value, Some(b"reindeer".to_vec()));
+
+		let checked = crate::read_proof_check::<BlakeTwo256, _>(root, proof, &[b"doe"]).unwrap();
+		assert_eq!(checked.get(&b"doe"[..]), Some(&Some(b"reindeer".to_vec())));
+	}
+
+	#[test]
+	fn from_chain_spec_json_works() {
+		let json = br#"{
+			"name": "Test",
+			"genesis": {
+				"raw": {
+					"top": {
+						"0x3a636f6465": "0x010203",
+						"0x3a686561707061676573": "0x2000000000000000",
+						"0x646f65": "0x7265696e64656572"
+					},
+					"childrenDefault": {
+						"0x6368696c64": { "0x01": "0x02" }
+					}
+				}
+			}
+		}"#;
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::from_chain_spec_json(json).unwrap();
+		let child_info = ChildInfo::new_default(b"child");
+
+		let mut ext = ext.ext();
+		assert_eq!(ext.storage(CODE), Some(vec![1, 2, 3]));
+		assert_eq!(ext.storage(HEAP_PAGES), Some(32u64.encode()));
+		assert_eq!(ext.storage(b"doe"), Some(b"reindeer".to_vec()));
+		assert_eq!(ext.child_storage(&child_info, &[1]), Some(vec![2]));
+
+		assert!(TestExternalities::<BlakeTwo256, u64>::from_chain_spec_json(b"{}").is_err());
+	}
+
+	#[test]
+	fn offchain_ext_is_controllable() {
+		use sp_core::offchain::{OffchainStorage, Timestamp, StorageKind};
+		use sp_externalities::ExternalitiesExt;
+
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		let state = ext.register_offchain_ext();
+		state.write().timestamp = Timestamp::from_unix_millis(42);
+		state.write().seed = [7; 32];
+
+		ext.execute_with(|| {
+			sp_externalities::with_externalities(|ext| {
+				let offchain = ext.extension::<OffchainExt>().unwrap();
+				assert_eq!(offchain.timestamp(), Timestamp::from_unix_millis(42));
+				assert_eq!(offchain.random_seed(), [7; 32]);
+				offchain.local_storage_set(StorageKind::PERSISTENT, b"key", b"value");
+			}).unwrap()
+		});
+
+		assert_eq!(
+			ext.offchain_db().get(b"", b"key"),
+			Some(b"value".to_vec()),
+		);
+	}
+
 	#[test]
 	fn set_and_retrieve_code() {
 		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
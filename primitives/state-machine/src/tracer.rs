@@ -0,0 +1,200 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracing of the storage accesses of a runtime call.
+
+use std::time::Duration;
+use parking_lot::Mutex;
+use sp_core::{hexdisplay::HexDisplay, storage::ChildInfo};
+use crate::StorageKey;
+
+/// The kind of a traced operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+	/// A value, the hash of a value or the existence of a key was read.
+	Get,
+	/// A value was written, removed or appended to.
+	Set,
+	/// A storage root was calculated.
+	Root,
+	/// Any other call into the host, identified by its name.
+	HostCall(&'static str),
+}
+
+impl TraceOp {
+	fn name(&self) -> &'static str {
+		match *self {
+			TraceOp::Get => "get",
+			TraceOp::Set => "set",
+			TraceOp::Root => "root",
+			TraceOp::HostCall(name) => name,
+		}
+	}
+}
+
+/// Where a read value was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueSource {
+	/// The value was changed in the overlay.
+	Overlay,
+	/// The value was read from the backend.
+	Backend,
+}
+
+/// A single traced operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+	/// The kind of operation.
+	pub op: TraceOp,
+	/// The storage key of the child trie, `None` for the main trie.
+	pub child_storage_key: Option<StorageKey>,
+	/// The key or prefix the operation was done on, empty for operations without a key.
+	pub key: StorageKey,
+	/// The read or written value, truncated to the maximum length of the tracer.
+	pub value: Option<Vec<u8>>,
+	/// The length of the value before truncation.
+	pub value_len: usize,
+	/// Where a read value was found, `None` for writes.
+	pub source: Option<ValueSource>,
+	/// How long the operation took.
+	pub duration: Duration,
+}
+
+/// Records the storage operations of [`Ext`](crate::Ext) in the order they are done.
+///
+/// The tracer can be shared by all calls of a block to trace the whole block execution.
+pub struct StateTracer {
+	max_value_len: usize,
+	entries: Mutex<Vec<TraceEntry>>,
+}
+
+impl StateTracer {
+	/// Create a new tracer that keeps at most `max_value_len` bytes of every value.
+	pub fn new(max_value_len: usize) -> Self {
+		StateTracer {
+			max_value_len,
+			entries: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Record an operation.
+	pub fn record(
+		&self,
+		op: TraceOp,
+		child_info: Option<&ChildInfo>,
+		key: &[u8],
+		value: Option<&[u8]>,
+		source: Option<ValueSource>,
+		duration: Duration,
+	) {
+		let value_len = value.map_or(0, |v| v.len());
+		let value = value.map(|v| v[..v.len().min(self.max_value_len)].to_vec());
+		self.entries.lock().push(TraceEntry {
+			op,
+			child_storage_key: child_info.map(|c| c.storage_key().to_vec()),
+			key: key.to_vec(),
+			value,
+			value_len,
+			source,
+			duration,
+		});
+	}
+
+	/// Returns the recorded operations, oldest first.
+	pub fn entries(&self) -> Vec<TraceEntry> {
+		self.entries.lock().clone()
+	}
+
+	/// Returns the recorded operations and clears the tracer.
+	pub fn take_entries(&self) -> Vec<TraceEntry> {
+		std::mem::replace(&mut *self.entries.lock(), Vec::new())
+	}
+
+	/// Returns the recorded operations as a JSON array.
+	///
+	/// Keys and values are hex encoded with a `0x` prefix, durations are given in
+	/// nanoseconds.
+	pub fn to_json(&self) -> serde_json::Value {
+		let hex = |data: &[u8]| format!("0x{}", HexDisplay::from(&data));
+		let entries = self.entries.lock().iter().map(|entry| serde_json::json!({
+			"op": entry.op.name(),
+			"child": entry.child_storage_key.as_ref().map(|k| hex(k)),
+			"key": hex(&entry.key),
+			"value": entry.value.as_ref().map(|v| hex(v)),
+			"valueLen": entry.value_len,
+			"source": entry.source.map(|s| match s {
+				ValueSource::Overlay => "overlay",
+				ValueSource::Backend => "backend",
+			}),
+			"durationNs": entry.duration.as_nanos() as u64
+		})).collect();
+		serde_json::Value::Array(entries)
+	}
+}
+
+impl std::fmt::Debug for StateTracer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "StateTracer")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn values_are_truncated_and_serialized() {
+		let tracer = StateTracer::new(2);
+		let child_info = ChildInfo::new_default(b"child");
+		tracer.record(
+			TraceOp::Get,
+			None,
+			b"key",
+			Some(b"value"),
+			Some(ValueSource::Backend),
+			Duration::from_nanos(10),
+		);
+		tracer.record(TraceOp::Set, Some(&child_info), &[1], None, None, Duration::from_nanos(5));
+
+		let entries = tracer.entries();
+		assert_eq!(entries[0].value, Some(b"va".to_vec()));
+		assert_eq!(entries[0].value_len, 5);
+
+		assert_eq!(tracer.to_json(), serde_json::json!([
+			{
+				"op": "get",
+				"child": null,
+				"key": "0x6b6579",
+				"value": "0x7661",
+				"valueLen": 5,
+				"source": "backend",
+				"durationNs": 10
+			},
+			{
+				"op": "set",
+				"child": "0x6368696c64",
+				"key": "0x01",
+				"value": null,
+				"valueLen": 0,
+				"source": null,
+				"durationNs": 5
+			}
+		]));
+		assert_eq!(tracer.take_entries().len(), 2);
+		assert!(tracer.entries().is_empty());
+	}
+}
@@ -17,20 +17,26 @@
 
 //! Trie-based state machine backend.
 
+use std::time::Instant;
 use log::{warn, debug};
 use hash_db::Hasher;
-use sp_trie::{Trie, delta_trie_root, empty_child_trie_root, child_delta_trie_root};
+use sp_trie::{
+	Trie, KeySpacedDB, delta_trie_root_presorted, empty_child_trie_root, child_delta_trie_root,
+};
 use sp_trie::trie_types::{TrieDB, TrieError, Layout};
 use sp_core::storage::{ChildInfo, ChildType};
 use codec::{Codec, Decode};
 use crate::{
 	StorageKey, StorageValue, Backend,
 	trie_backend_essence::{TrieBackendEssence, TrieBackendStorage, Ephemeral},
+	stats::ReadStats,
+	trie_statistics::TrieStatistics,
 };
 
 /// Patricia trie-based backend. Transaction type is an overlay of changes to commit.
 pub struct TrieBackend<S: TrieBackendStorage<H>, H: Hasher> {
 	pub (crate) essence: TrieBackendEssence<S, H>,
+	reads: ReadStats,
 }
 
 impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackend<S, H> where H::Out: Codec {
@@ -38,6 +44,7 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackend<S, H> where H::Out: Codec
 	pub fn new(storage: S, root: H::Out) -> Self {
 		TrieBackend {
 			essence: TrieBackendEssence::new(storage, root),
+			reads: Default::default(),
 		}
 	}
 
@@ -65,6 +72,39 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackend<S, H> where H::Out: Codec
 	pub fn into_storage(self) -> S {
 		self.essence.into_storage()
 	}
+
+	/// Walk the nodes of the top trie and of all child tries and collect statistics about them.
+	///
+	/// The walk stops after `sample_limit` nodes if given, the statistics then only cover a
+	/// sample of the state, see [`TrieStatistics::complete`].
+	pub fn trie_statistics(&self, sample_limit: Option<u64>) -> Result<TrieStatistics, String> {
+		let mut stats = TrieStatistics::default();
+		stats.complete = stats.walk(self.essence(), self.essence.root(), sample_limit)?;
+
+		let mut child_roots = Vec::new();
+		let prefix = ChildType::ParentKeyId.parent_prefix();
+		self.essence.for_key_values_with_prefix(prefix, |key, value| {
+			child_roots.push((key[prefix.len()..].to_vec(), value.to_vec()));
+		});
+		stats.child_tries = child_roots.len() as u64;
+
+		for (storage_key, root) in child_roots {
+			if !stats.complete {
+				break
+			}
+			let root = H::Out::decode(&mut &root[..])
+				.map_err(|_| format!("Invalid child storage root at {:?}", storage_key))?;
+			let child_info = ChildInfo::new_default(&storage_key);
+			let db = KeySpacedDB::new(self.essence(), child_info.keyspace());
+			stats.complete = stats.walk(&db, &root, sample_limit)?;
+		}
+		Ok(stats)
+	}
+
+	fn tally_read(&self, key: &[u8], value: Option<&StorageValue>, started: Instant) {
+		let bytes = key.len() + value.map_or(0, |v| v.len());
+		self.reads.tally_read(bytes as u64, started.elapsed());
+	}
 }
 
 impl<S: TrieBackendStorage<H>, H: Hasher> std::fmt::Debug for TrieBackend<S, H> {
@@ -81,7 +121,10 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 	type TrieBackendStorage = S;
 
 	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error> {
-		self.essence.storage(key)
+		let started = Instant::now();
+		let value = self.essence.storage(key)?;
+		self.tally_read(key, value.as_ref(), started);
+		Ok(value)
 	}
 
 	fn child_storage(
@@ -89,7 +132,10 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> Result<Option<StorageValue>, Self::Error> {
-		self.essence.child_storage(child_info, key)
+		let started = Instant::now();
+		let value = self.essence.child_storage(child_info, key)?;
+		self.tally_read(key, value.as_ref(), started);
+		Ok(value)
 	}
 
 	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
@@ -129,6 +175,16 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 		self.essence.for_child_keys_with_prefix(child_info, prefix, f)
 	}
 
+	fn child_keys_paged(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		count: usize,
+		start_key: Option<&[u8]>,
+	) -> Result<Vec<StorageKey>, Self::Error> {
+		self.essence.keys_paged(Some(child_info), prefix, start_key, count)
+	}
+
 	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
 		let collect_all = || -> Result<_, Box<TrieError<H::Out>>> {
 			let trie = TrieDB::<H>::new(self.essence(), self.essence.root())?;
@@ -170,6 +226,15 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 	fn storage_root<'a>(
 		&self,
 		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		let mut delta = delta.collect::<Vec<_>>();
+		delta.sort_by(|l, r| l.0.cmp(r.0));
+		self.storage_root_presorted(delta.into_iter())
+	}
+
+	fn storage_root_presorted<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
 	) -> (H::Out, Self::Transaction) where H::Out: Ord {
 		let mut write_overlay = S::Overlay::default();
 		let mut root = *self.essence.root();
@@ -180,7 +245,7 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 				&mut write_overlay,
 			);
 
-			match delta_trie_root::<Layout<H>, _, _, _, _, _>(&mut eph, root, delta) {
+			match delta_trie_root_presorted::<Layout<H>, _, _, _, _, _>(&mut eph, root, delta) {
 				Ok(ret) => root = ret,
 				Err(e) => warn!(target: "trie", "Failed to write to trie: {}", e),
 			}
@@ -189,6 +254,26 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 		(root, write_overlay)
 	}
 
+	fn storage_root_incremental<'a>(
+		&self,
+		root: H::Out,
+		transaction: &mut Self::Transaction,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> Option<H::Out> where H::Out: Ord {
+		let mut eph = Ephemeral::new(self.essence.backend_storage(), transaction);
+		match delta_trie_root_presorted::<Layout<H>, _, _, _, _, _>(&mut eph, root, delta) {
+			Ok(root) => Some(root),
+			Err(e) => {
+				warn!(target: "trie", "Failed to write to trie: {}", e);
+				None
+			},
+		}
+	}
+
+	fn supports_incremental_storage_root(&self) -> bool {
+		true
+	}
+
 	fn child_storage_root<'a>(
 		&self,
 		child_info: &ChildInfo,
@@ -238,7 +323,7 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 	fn register_overlay_stats(&mut self, _stats: &crate::stats::StateMachineStats) { }
 
 	fn usage_info(&self) -> crate::UsageInfo {
-		crate::UsageInfo::empty()
+		self.reads.usage_info()
 	}
 
 	fn wipe(&self) -> Result<(), Self::Error> {
@@ -295,6 +380,17 @@ pub mod tests {
 		assert_eq!(test_trie().storage(b"key").unwrap(), Some(b"value".to_vec()));
 	}
 
+	#[test]
+	fn usage_info_counts_reads() {
+		let test_trie = test_trie();
+		test_trie.storage(b"key").unwrap();
+		test_trie.storage(b"missing").unwrap();
+
+		let usage = test_trie.usage_info();
+		assert_eq!(usage.reads.ops, 2);
+		assert_eq!(usage.reads.bytes, (b"key".len() + b"value".len() + b"missing".len()) as u64);
+	}
+
 	#[test]
 	fn read_from_child_storage_returns_some() {
 		let test_trie = test_trie();
@@ -341,6 +437,18 @@ pub mod tests {
 		assert!(new_root != test_trie().storage_root(iter::empty()).0);
 	}
 
+	#[test]
+	fn storage_root_presorted_matches_storage_root() {
+		let delta = vec![
+			(&b"key"[..], Some(&b"value"[..])),
+			(&b"new-key"[..], Some(&b"new-value"[..])),
+			(&b"value1"[..], None),
+		];
+		let (presorted_root, _) = test_trie().storage_root_presorted(delta.clone().into_iter());
+		let (root, _) = test_trie().storage_root(delta.into_iter().rev());
+		assert_eq!(presorted_root, root);
+	}
+
 	#[test]
 	fn prefix_walking_works() {
 		let trie = test_trie();
@@ -356,4 +464,23 @@ pub mod tests {
 		expected.insert(b"value2".to_vec());
 		assert_eq!(seen, expected);
 	}
+
+	#[test]
+	fn trie_statistics_cover_top_and_child_tries() {
+		let trie = test_trie();
+		let stats = trie.trie_statistics(None).unwrap();
+
+		assert!(stats.complete);
+		assert_eq!(stats.child_tries, 1);
+		// 132 values in the top trie and 2 in the child trie.
+		assert_eq!(stats.values, 134);
+		assert_eq!(stats.extension_nodes, 0);
+		// The roots of both tries.
+		assert_eq!(stats.depth_histogram[0], 2);
+		assert_eq!(stats.depth_histogram.iter().sum::<u64>(), stats.nodes());
+
+		let sample = trie.trie_statistics(Some(10)).unwrap();
+		assert!(!sample.complete);
+		assert_eq!(sample.nodes(), 10);
+	}
 }
@@ -18,13 +18,14 @@
 //! Trie-based state machine backend essence used to read values
 //! from storage.
 
+use std::marker::PhantomData;
 use std::ops::Deref;
 use std::sync::Arc;
 use log::{debug, warn};
 use hash_db::{self, Hasher, Prefix};
-use sp_trie::{Trie, MemoryDB, PrefixedMemoryDB, DBValue,
+use sp_trie::{Trie, PrefixedMemoryDB, GenericMemoryDB, KeyFunction, DBValue,
 	empty_child_trie_root, read_trie_value, read_child_trie_value,
-	for_keys_in_child_trie, KeySpacedDB, TrieDBIterator};
+	for_keys_in_child_trie, KeySpacedDB, TrieDBIterator, TrieDBKeyIterator};
 use sp_trie::trie_types::{TrieDB, TrieError, Layout};
 use crate::{backend::Consolidate, StorageKey, StorageValue};
 use sp_core::storage::ChildInfo;
@@ -263,6 +264,65 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out:
 	pub fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
 		self.keys_values_with_prefix_inner(&self.root, prefix, f, None)
 	}
+
+	/// Return at most `count` keys starting with `prefix` in lexicographic order.
+	///
+	/// Only keys that are strictly superior to `start_after` are returned, so a query can be
+	/// resumed by passing the last key returned by the previous one.
+	pub fn keys_paged(
+		&self,
+		child_info: Option<&ChildInfo>,
+		prefix: &[u8],
+		start_after: Option<&[u8]>,
+		count: usize,
+	) -> Result<Vec<StorageKey>, String> {
+		let root = match child_info {
+			Some(child_info) => {
+				let child_root = self.child_root(child_info)?
+					.unwrap_or_else(|| empty_child_trie_root::<Layout<H>>().encode());
+				let mut root = H::Out::default();
+				if child_root.len() != root.as_ref().len() {
+					return Err(format!("Invalid child storage hash at {:?}", child_info.storage_key()));
+				}
+				root.as_mut().copy_from_slice(&child_root[..]);
+				root
+			},
+			None => self.root,
+		};
+
+		let keys = |db: &dyn hash_db::HashDBRef<H, DBValue>| -> Result<_, Box<TrieError<H::Out>>> {
+			let trie = TrieDB::<H>::new(db, &root)?;
+			let mut iter = TrieDBKeyIterator::new(&trie)?;
+
+			match start_after {
+				Some(start_after) if start_after >= prefix => {
+					// Same reasoning as in `next_storage_key_from_root`, `key++0` is the
+					// next possible key.
+					let mut next_key = Vec::with_capacity(start_after.len() + 1);
+					next_key.extend_from_slice(start_after);
+					next_key.push(0);
+					iter.seek(&next_key)?;
+				},
+				_ => iter.seek(prefix)?,
+			}
+
+			let mut keys = Vec::new();
+			while keys.len() < count {
+				match iter.next_while_prefix(prefix) {
+					Some(key) => keys.push(key?),
+					None => break,
+				}
+			}
+			Ok(keys)
+		};
+
+		let result = if let Some(child_info) = child_info {
+			keys(&KeySpacedDB::new(self, child_info.keyspace()))
+		} else {
+			keys(self)
+		};
+		result.map_err(|e| format!("TrieDB iteration error: {}", e))
+	}
 }
 
 pub(crate) struct Ephemeral<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> {
@@ -349,20 +409,57 @@ impl<H: Hasher> TrieBackendStorage<H> for Arc<dyn Storage<H>> {
 	}
 }
 
-// This implementation is used by test storage trie clients.
-impl<H: Hasher> TrieBackendStorage<H> for PrefixedMemoryDB<H> {
-	type Overlay = PrefixedMemoryDB<H>;
+// This implementation is used by test storage trie clients, e.g. with `PrefixedMemoryDB`
+// or `MemoryDB`.
+impl<H: Hasher, KF: KeyFunction<H> + Send + Sync> TrieBackendStorage<H> for GenericMemoryDB<H, KF> {
+	type Overlay = GenericMemoryDB<H, KF>;
 
 	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
 		Ok(hash_db::HashDB::get(self, key, prefix))
 	}
 }
 
-impl<H: Hasher> TrieBackendStorage<H> for MemoryDB<H> {
-	type Overlay = MemoryDB<H>;
+/// Flat key value database that stores trie nodes.
+pub trait NodeStorage: Send + Sync {
+	/// Get the node stored at the given database key.
+	fn get(&self, key: &[u8]) -> Result<Option<DBValue>, String>;
+}
+
+/// Patricia trie-based storage that maps the location of a node to its database key with the
+/// given [`KeyFunction`].
+///
+/// With `HashKey` nodes are stored under their plain hash. With `PrefixedKey` nodes are stored
+/// under `(prefix, hash)` composite keys, so identical nodes referenced from different
+/// locations of the trie are stored separately.
+pub struct KeyFunctionStorage<S, KF> {
+	storage: S,
+	_marker: PhantomData<fn() -> KF>,
+}
+
+impl<S, KF> KeyFunctionStorage<S, KF> {
+	/// Create a new instance that stores nodes in `storage`.
+	pub fn new(storage: S) -> Self {
+		KeyFunctionStorage {
+			storage,
+			_marker: PhantomData,
+		}
+	}
+
+	/// Returns a reference to the wrapped node storage.
+	pub fn inner(&self) -> &S {
+		&self.storage
+	}
+}
 
+impl<H, S, KF> Storage<H> for KeyFunctionStorage<S, KF>
+	where
+		H: Hasher,
+		S: NodeStorage,
+		KF: KeyFunction<H>,
+		KF::Key: AsRef<[u8]>,
+{
 	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
-		Ok(hash_db::HashDB::get(self, key, prefix))
+		self.storage.get(KF::key(key, prefix).as_ref())
 	}
 }
 
@@ -483,4 +580,83 @@ mod test {
 			essence_2.next_child_storage_key(child_info, b"6"), Ok(None)
 		);
 	}
+
+	#[test]
+	fn key_function_storage_works() {
+		use std::collections::HashMap;
+		use sp_trie::PrefixedKey;
+
+		struct Nodes(HashMap<Vec<u8>, DBValue>);
+
+		impl NodeStorage for Nodes {
+			fn get(&self, key: &[u8]) -> Result<Option<DBValue>, String> {
+				Ok(self.0.get(key).cloned())
+			}
+		}
+
+		let child_info = ChildInfo::new_default(b"MyChild");
+		let mut root = H256::default();
+		let mut child_root = H256::default();
+		let mut mdb = PrefixedMemoryDB::<Blake2Hasher>::default();
+		{
+			let mut mdb = KeySpacedDBMut::new(&mut mdb, child_info.keyspace());
+			let mut trie = TrieDBMut::new(&mut mdb, &mut child_root);
+			trie.insert(b"key", b"child").expect("insert failed");
+		}
+		{
+			let mut trie = TrieDBMut::new(&mut mdb, &mut root);
+			trie.insert(b"key", b"top").expect("insert failed");
+			trie.insert(child_info.prefixed_storage_key().as_slice(), child_root.as_ref())
+				.expect("insert failed");
+		}
+
+		let nodes = Nodes(mdb.drain().into_iter().map(|(k, (v, _))| (k, v)).collect());
+		let storage: Arc<dyn Storage<Blake2Hasher>> =
+			Arc::new(KeyFunctionStorage::<_, PrefixedKey<Blake2Hasher>>::new(nodes));
+		let essence = TrieBackendEssence::new(storage, root);
+
+		assert_eq!(essence.storage(b"key"), Ok(Some(b"top".to_vec())));
+		assert_eq!(essence.child_storage(&child_info, b"key"), Ok(Some(b"child".to_vec())));
+	}
+
+	#[test]
+	fn keys_paged_works() {
+		let child_info = ChildInfo::new_default(b"MyChild");
+		let mut child_root = H256::default();
+		let mut root = H256::default();
+
+		let mut mdb = PrefixedMemoryDB::<Blake2Hasher>::default();
+		{
+			let mut mdb = KeySpacedDBMut::new(&mut mdb, child_info.keyspace());
+			let mut trie = TrieDBMut::new(&mut mdb, &mut child_root);
+			trie.insert(b"c1", &[1]).expect("insert failed");
+		}
+		{
+			let mut trie = TrieDBMut::new(&mut mdb, &mut root);
+			for key in &[&b"a"[..], b"b1", b"b2", b"b3", b"c"] {
+				trie.insert(key, &[1]).expect("insert failed");
+			}
+			trie.insert(child_info.prefixed_storage_key().as_slice(), child_root.as_ref())
+				.expect("insert failed");
+		}
+		let essence = TrieBackendEssence::new(mdb, root);
+
+		assert_eq!(
+			essence.keys_paged(None, b"b", None, 2),
+			Ok(vec![b"b1".to_vec(), b"b2".to_vec()]),
+		);
+		assert_eq!(
+			essence.keys_paged(None, b"b", Some(b"b2"), 2),
+			Ok(vec![b"b3".to_vec()]),
+		);
+		assert_eq!(essence.keys_paged(None, b"b", Some(b"b3"), 2), Ok(vec![]));
+		assert_eq!(
+			essence.keys_paged(None, b"", Some(b"b3"), 1),
+			Ok(vec![b"c".to_vec()]),
+		);
+		assert_eq!(
+			essence.keys_paged(Some(&child_info), b"", None, 10),
+			Ok(vec![b"c1".to_vec()]),
+		);
+	}
 }
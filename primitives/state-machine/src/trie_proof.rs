@@ -0,0 +1,91 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Native verification of compact trie proofs against arbitrary roots.
+
+use hash_db::Hasher;
+use sp_core::{Blake2Hasher, KeccakHasher};
+use sp_externalities::TrieProofLayout;
+use sp_trie::trie_types::Layout;
+
+/// Verify a compact trie `proof` of `items` against `root`, as created by
+/// [`sp_trie::generate_trie_proof`] with the given `layout`.
+///
+/// Items with a `None` value are checked for non-inclusion. Returns `false` if `root` is not
+/// a valid hash of the `layout`.
+pub fn verify_trie_proof(
+	root: &[u8],
+	proof: &[Vec<u8>],
+	items: &[(Vec<u8>, Option<Vec<u8>>)],
+	layout: TrieProofLayout,
+) -> bool {
+	match layout {
+		TrieProofLayout::Blake2 => verify::<Blake2Hasher>(root, proof, items),
+		TrieProofLayout::Keccak => verify::<KeccakHasher>(root, proof, items),
+	}
+}
+
+fn verify<H: Hasher>(
+	root: &[u8],
+	proof: &[Vec<u8>],
+	items: &[(Vec<u8>, Option<Vec<u8>>)],
+) -> bool {
+	let mut hash = H::Out::default();
+	if hash.as_ref().len() != root.len() {
+		return false
+	}
+	hash.as_mut().copy_from_slice(root);
+	sp_trie::verify_trie_proof::<Layout<H>, _, _, _>(&hash, proof, items).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_trie::{MemoryDB, TrieMut, trie_types::TrieDBMut};
+
+	fn proof<H: Hasher>(keys: &[&[u8]]) -> (Vec<u8>, Vec<Vec<u8>>) {
+		let mut db = MemoryDB::<H>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = TrieDBMut::<H>::new(&mut db, &mut root);
+			for i in 0u8..20 {
+				trie.insert(&[i], &[i; 40]).unwrap();
+			}
+		}
+		let proof = sp_trie::generate_trie_proof::<Layout<H>, _, _, _>(&db, root, keys).unwrap();
+		(root.as_ref().to_vec(), proof)
+	}
+
+	#[test]
+	fn verifies_inclusion_and_non_inclusion() {
+		let (root, proof) = proof::<Blake2Hasher>(&[&[1], &[30]]);
+		let items = vec![(vec![1], Some(vec![1; 40])), (vec![30], None)];
+		assert!(verify_trie_proof(&root, &proof, &items, TrieProofLayout::Blake2));
+
+		let wrong_value = vec![(vec![1], Some(vec![2; 40])), (vec![30], None)];
+		assert!(!verify_trie_proof(&root, &proof, &wrong_value, TrieProofLayout::Blake2));
+		assert!(!verify_trie_proof(&root, &proof, &items, TrieProofLayout::Keccak));
+		assert!(!verify_trie_proof(&root[1..], &proof, &items, TrieProofLayout::Blake2));
+	}
+
+	#[test]
+	fn verifies_keccak_proofs() {
+		let (root, proof) = proof::<KeccakHasher>(&[&[5]]);
+		let items = vec![(vec![5], Some(vec![5; 40]))];
+		assert!(verify_trie_proof(&root, &proof, &items, TrieProofLayout::Keccak));
+	}
+}
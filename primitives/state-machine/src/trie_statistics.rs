@@ -0,0 +1,129 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics about the nodes of a trie, for inspecting the state without external tooling.
+
+use hash_db::{HashDBRef, Hasher};
+use sp_trie::{DBValue, trie_types::{TrieDB, Layout}};
+use trie_db::{TrieDBNodeIterator, node::Node};
+
+/// Statistics about the nodes of the top trie and the child tries of a state.
+///
+/// See [`TrieBackend::trie_statistics`](crate::TrieBackend::trie_statistics).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TrieStatistics {
+	/// The number of leaf nodes.
+	pub leaf_nodes: u64,
+	/// The number of branch nodes, with or without a partial key.
+	pub branch_nodes: u64,
+	/// The number of extension nodes.
+	pub extension_nodes: u64,
+	/// The number of empty nodes, i.e. empty tries.
+	pub empty_nodes: u64,
+	/// The number of nodes that are inlined into their parent instead of being stored
+	/// under their hash. These are included in the counts by node type.
+	pub inline_nodes: u64,
+	/// The number of nodes by their depth, the root being at depth `0`.
+	pub depth_histogram: Vec<u64>,
+	/// The number of values stored in the nodes.
+	pub values: u64,
+	/// The total size of the values stored in the nodes.
+	pub value_bytes: u64,
+	/// The number of child tries referenced from the top trie.
+	pub child_tries: u64,
+	/// `false` if the walk stopped at the sample limit before visiting all nodes.
+	pub complete: bool,
+}
+
+impl TrieStatistics {
+	/// The number of visited nodes.
+	pub fn nodes(&self) -> u64 {
+		self.leaf_nodes + self.branch_nodes + self.extension_nodes + self.empty_nodes
+	}
+
+	/// The average size of the visited values, `0` if there are none.
+	pub fn average_value_size(&self) -> u64 {
+		if self.values == 0 {
+			0
+		} else {
+			self.value_bytes / self.values
+		}
+	}
+
+	/// Visit the nodes of the trie with the given `root` in `db`, depth first.
+	///
+	/// Returns `false` if the walk stopped because `limit` nodes were visited in total.
+	pub(crate) fn walk<H: Hasher>(
+		&mut self,
+		db: &dyn HashDBRef<H, DBValue>,
+		root: &H::Out,
+		limit: Option<u64>,
+	) -> Result<bool, String> {
+		let trie = TrieDB::<H>::new(db, root).map_err(|e| format!("TrieDB creation error: {}", e))?;
+		let iter = TrieDBNodeIterator::<Layout<H>>::new(&trie)
+			.map_err(|e| format!("TrieDB iteration error: {}", e))?;
+
+		// The prefix lengths of the ancestors of the current node. Siblings have prefixes of
+		// the same length and children have longer prefixes than their parents, so the
+		// ancestors of a node are the entries with a shorter prefix.
+		let mut path: Vec<usize> = Vec::new();
+		for item in iter {
+			if limit.map_or(false, |limit| self.nodes() >= limit) {
+				return Ok(false)
+			}
+			let (prefix, hash, node) = item.map_err(|e| format!("TrieDB iteration error: {}", e))?;
+
+			while path.last().map_or(false, |len| *len >= prefix.len()) {
+				path.pop();
+			}
+			let depth = path.len();
+			path.push(prefix.len());
+			if self.depth_histogram.len() <= depth {
+				self.depth_histogram.resize(depth + 1, 0);
+			}
+			self.depth_histogram[depth] += 1;
+
+			// The root is always stored under its hash, even if its encoding is short.
+			if hash.is_none() && depth > 0 {
+				self.inline_nodes += 1;
+			}
+			let value = match node.node() {
+				Node::Empty => {
+					self.empty_nodes += 1;
+					None
+				},
+				Node::Leaf(_, value) => {
+					self.leaf_nodes += 1;
+					Some(value)
+				},
+				Node::Extension(..) => {
+					self.extension_nodes += 1;
+					None
+				},
+				Node::Branch(_, value) | Node::NibbledBranch(_, _, value) => {
+					self.branch_nodes += 1;
+					value
+				},
+			};
+			if let Some(value) = value {
+				self.values += 1;
+				self.value_bytes += value.len() as u64;
+			}
+		}
+		Ok(true)
+	}
+}
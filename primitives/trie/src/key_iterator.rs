@@ -0,0 +1,109 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use sp_std::{boxed::Box, vec::Vec};
+use trie_db::{TrieDB, TrieDBIterator, TrieIterator, TrieLayout};
+use crate::TrieError;
+
+/// Stateful iterator over the keys of a trie.
+///
+/// In contrast to iterating with a fresh `TrieDBIterator` for every query, the iterator keeps
+/// its position in the trie, so consecutive queries (e.g. paging through all keys with a
+/// given prefix) do not start over from the root.
+pub struct TrieDBKeyIterator<'a, L: TrieLayout> {
+	inner: TrieDBIterator<'a, L>,
+	/// A key that was read from `inner`, but not yet returned.
+	peeked: Option<Vec<u8>>,
+}
+
+impl<'a, L: TrieLayout> TrieDBKeyIterator<'a, L> {
+	/// Create a new iterator positioned at the first key of the trie.
+	pub fn new(trie: &'a TrieDB<'a, L>) -> Result<Self, Box<TrieError<L>>> {
+		Ok(TrieDBKeyIterator {
+			inner: TrieDBIterator::new(trie)?,
+			peeked: None,
+		})
+	}
+
+	/// Position the iterator on the first key that is greater or equal to `key`.
+	pub fn seek(&mut self, key: &[u8]) -> Result<(), Box<TrieError<L>>> {
+		self.peeked = None;
+		self.inner.seek(key)
+	}
+
+	/// Returns the next key if it starts with `prefix`.
+	///
+	/// If the next key does not start with `prefix`, `None` is returned and the iterator
+	/// stays in front of that key.
+	pub fn next_while_prefix(&mut self, prefix: &[u8]) -> Option<Result<Vec<u8>, Box<TrieError<L>>>> {
+		let key = match self.peeked.take() {
+			Some(key) => key,
+			None => match self.inner.next()? {
+				Ok((key, _)) => key,
+				Err(e) => return Some(Err(e)),
+			},
+		};
+
+		if key.starts_with(prefix) {
+			Some(Ok(key))
+		} else {
+			self.peeked = Some(key);
+			None
+		}
+	}
+}
+
+impl<'a, L: TrieLayout> Iterator for TrieDBKeyIterator<'a, L> {
+	type Item = Result<Vec<u8>, Box<TrieError<L>>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_while_prefix(&[])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::Blake2Hasher;
+	use trie_db::TrieMut;
+	use crate::{Layout, MemoryDB, TrieDBMut};
+
+	#[test]
+	fn seek_and_next_while_prefix_work() {
+		let mut db = MemoryDB::<Blake2Hasher>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = TrieDBMut::<Layout<Blake2Hasher>>::new(&mut db, &mut root);
+			for key in &[&b"a"[..], b"ba", b"bb", b"bc", b"c"] {
+				trie.insert(key, key).unwrap();
+			}
+		}
+		let trie = TrieDB::<Layout<Blake2Hasher>>::new(&db, &root).unwrap();
+		let mut iter = TrieDBKeyIterator::new(&trie).unwrap();
+
+		iter.seek(b"b").unwrap();
+		assert_eq!(iter.next_while_prefix(b"b").unwrap().unwrap(), b"ba".to_vec());
+		assert_eq!(iter.next_while_prefix(b"b").unwrap().unwrap(), b"bb".to_vec());
+		assert_eq!(iter.next_while_prefix(b"b").unwrap().unwrap(), b"bc".to_vec());
+		assert!(iter.next_while_prefix(b"b").is_none());
+		assert_eq!(iter.next().unwrap().unwrap(), b"c".to_vec());
+		assert!(iter.next().is_none());
+
+		iter.seek(b"bb").unwrap();
+		let keys = iter.collect::<Result<Vec<_>, _>>().unwrap();
+		assert_eq!(keys, vec![b"bb".to_vec(), b"bc".to_vec(), b"c".to_vec()]);
+	}
+}
@@ -19,6 +19,7 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
 mod error;
+mod key_iterator;
 mod node_header;
 mod node_codec;
 mod storage_proof;
@@ -35,12 +36,13 @@ pub use trie_stream::TrieStream;
 /// The Substrate format implementation of `NodeCodec`.
 pub use node_codec::NodeCodec;
 pub use storage_proof::StorageProof;
+pub use key_iterator::TrieDBKeyIterator;
 /// Various re-exports from the `trie-db` crate.
 pub use trie_db::{
 	Trie, TrieMut, DBValue, Recorder, CError, Query, TrieLayout, TrieConfiguration, nibble_ops, TrieDBIterator,
 };
 /// Various re-exports from the `memory-db` crate.
-pub use memory_db::KeyFunction;
+pub use memory_db::{KeyFunction, HashKey, PrefixedKey};
 pub use memory_db::prefixed_key;
 /// Various re-exports from the `hash-db` crate.
 pub use hash_db::{HashDB as HashDBT, EMPTY_PREFIX};
@@ -173,6 +175,28 @@ pub fn verify_trie_proof<'a, L: TrieConfiguration, I, K, V>(
 
 /// Determine a trie root given a hash DB and delta values.
 pub fn delta_trie_root<L: TrieConfiguration, I, A, B, DB, V>(
+	db: &mut DB,
+	root: TrieHash<L>,
+	delta: I
+) -> Result<TrieHash<L>, Box<TrieError<L>>> where
+	I: IntoIterator<Item = (A, B)>,
+	A: Borrow<[u8]>,
+	B: Borrow<Option<V>>,
+	V: Borrow<[u8]>,
+	DB: hash_db::HashDB<L::Hash, trie_db::DBValue>,
+{
+	let mut delta = delta.into_iter().collect::<Vec<_>>();
+	delta.sort_by(|l, r| l.0.borrow().cmp(r.0.borrow()));
+
+	delta_trie_root_presorted::<L, _, _, _, _, _>(db, root, delta)
+}
+
+/// Determine a trie root given a hash DB and delta values that are sorted by key.
+///
+/// Same as [`delta_trie_root`] without sorting the delta first. The order in which the
+/// delta is applied decides which trie nodes are accessed, so an unsorted delta could need
+/// nodes that are missing from a storage proof. This is checked by a debug assertion.
+pub fn delta_trie_root_presorted<L: TrieConfiguration, I, A, B, DB, V>(
 	db: &mut DB,
 	mut root: TrieHash<L>,
 	delta: I
@@ -185,11 +209,19 @@ pub fn delta_trie_root<L: TrieConfiguration, I, A, B, DB, V>(
 {
 	{
 		let mut trie = TrieDBMut::<L>::from_existing(&mut *db, &mut root)?;
-
-		let mut delta = delta.into_iter().collect::<Vec<_>>();
-		delta.sort_by(|l, r| l.0.borrow().cmp(r.0.borrow()));
+		#[cfg(debug_assertions)]
+		let mut last_key: Option<Vec<u8>> = None;
 
 		for (key, change) in delta {
+			#[cfg(debug_assertions)]
+			{
+				debug_assert!(
+					last_key.as_ref().map_or(true, |last| &last[..] <= key.borrow()),
+					"Delta is not sorted by key",
+				);
+				last_key = Some(key.borrow().to_vec());
+			}
+
 			match change.borrow() {
 				Some(val) => trie.insert(key.borrow(), val.borrow())?,
 				None => trie.remove(key.borrow())?,
@@ -882,4 +914,30 @@ mod tests {
 
 		assert_eq!(first_storage_root, second_storage_root);
 	}
+
+	#[test]
+	fn delta_trie_root_presorted_works() {
+		let mut db = MemoryDB::default();
+		let mut root = Default::default();
+		populate_trie::<Layout>(&mut db, &mut root, &[
+			(b"doe".to_vec(), b"reindeer".to_vec()),
+			(b"dog".to_vec(), b"puppy".to_vec()),
+		]);
+
+		let delta = vec![
+			(b"do".to_vec(), Some(b"verb".to_vec())),
+			(b"dog".to_vec(), None),
+			(b"dogglesworth".to_vec(), Some(b"cat".to_vec())),
+		];
+		let mut unsorted = delta.clone();
+		unsorted.reverse();
+
+		let presorted = delta_trie_root_presorted::<Layout, _, _, _, _, _>(
+			&mut db.clone(),
+			root,
+			delta,
+		).unwrap();
+		let sorted = delta_trie_root::<Layout, _, _, _, _, _>(&mut db, root, unsorted).unwrap();
+		assert_eq!(presorted, sorted);
+	}
 }